@@ -451,15 +451,12 @@ pub fn serialize_with_config<'a, S: serde::Serialize, Size: TryFrom<usize> + Ser
         }
     }
 
-    match serializer.config.body_crc {
-        Some(v) => {
-            let crc = crc::Crc::<u16>::new(v).checksum(serializer.data.as_slice());
-            match serializer.config.endianess {
-                Endianess::Little => serializer.data.extend_from_slice(&crc.to_le_bytes()),
-                Endianess::Big => serializer.data.extend_from_slice(&crc.to_be_bytes()),
-            }
+    if let Some(v) = serializer.config.body_crc {
+        let crc = crc::Crc::<u16>::new(v).checksum(serializer.data.as_slice());
+        match serializer.config.endianess {
+            Endianess::Little => serializer.data.extend_from_slice(&crc.to_le_bytes()),
+            Endianess::Big => serializer.data.extend_from_slice(&crc.to_be_bytes()),
         }
-        None =>{},
     }
 
     Ok(serializer.data)
@@ -594,7 +591,7 @@ pub fn deserialize_with_config<'de, 'a, S: serde::Deserialize<'de>, Size: TryFro
             };
             let string = std::str::from_utf8(data).map_err(DeserializeError::InvalidUtf8)?;
             match string.chars().next() {
-                None => return Err(DeserializeError::InvalidLength),
+                None => Err(DeserializeError::InvalidLength),
                 Some(first) => visitor.visit_char(first)
             }
         }
@@ -604,7 +601,7 @@ pub fn deserialize_with_config<'de, 'a, S: serde::Deserialize<'de>, Size: TryFro
             V: Visitor<'de>
         {
             let size = self.get_usize()?;
-            let data = match self.data.split_at_checked(usize::from(size)) {
+            let data = match self.data.split_at_checked(size) {
                 Some((first, rest)) => {
                     self.data = rest;
                     first
@@ -620,7 +617,7 @@ pub fn deserialize_with_config<'de, 'a, S: serde::Deserialize<'de>, Size: TryFro
             V: Visitor<'de>
         {
             let size = self.get_usize()?;
-            let data = match self.data.split_at_checked(usize::from(size)) {
+            let data = match self.data.split_at_checked(size) {
                 Some((first, rest)) => {
                     self.data = rest;
                     first
@@ -636,7 +633,7 @@ pub fn deserialize_with_config<'de, 'a, S: serde::Deserialize<'de>, Size: TryFro
             V: Visitor<'de>
         {
             let size = self.get_usize()?;
-            let data = match self.data.split_at_checked(usize::from(size)) {
+            let data = match self.data.split_at_checked(size) {
                 Some((first, rest)) => {
                     self.data = rest;
                     first
@@ -651,7 +648,7 @@ pub fn deserialize_with_config<'de, 'a, S: serde::Deserialize<'de>, Size: TryFro
             V: Visitor<'de>
         {
             let size = self.get_usize()?;
-            let data = match self.data.split_at_checked(usize::from(size)) {
+            let data = match self.data.split_at_checked(size) {
                 Some((first, rest)) => {
                     self.data = rest;
                     first
@@ -733,7 +730,7 @@ pub fn deserialize_with_config<'de, 'a, S: serde::Deserialize<'de>, Size: TryFro
             }
 
             visitor.visit_seq(SeqAccess{
-                elements: usize::from(size),
+                elements: size,
                 deserializer: self,
             })
         }
@@ -771,7 +768,7 @@ pub fn deserialize_with_config<'de, 'a, S: serde::Deserialize<'de>, Size: TryFro
             V: Visitor<'de>
         { self.deserialize_tuple(len, visitor) }
 
-        fn deserialize_map<V>(mut self, visitor: V) -> Result<V::Value, Self::Error>
+        fn deserialize_map<V>(self, visitor: V) -> Result<V::Value, Self::Error>
         where
             V: Visitor<'de>
         {
@@ -800,7 +797,7 @@ pub fn deserialize_with_config<'de, 'a, S: serde::Deserialize<'de>, Size: TryFro
             }
             let left = self.get_usize()?;
             visitor.visit_map(MapAccess{
-                deserializer: &mut self,
+                deserializer: self,
                 left,
             })
         }
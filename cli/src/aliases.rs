@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use crate::device::messages::MetaData;
+
+///Key of the alias, when it is mirrored into the [`MetaData`] of a device.
+const META_DATA_KEY: &str = "alias=";
+
+///User-facing names for devices, keyed by [`crate::device::messages::Id::serial`] and persisted as JSON.
+#[derive(Debug, Default)]
+pub struct AliasRegistry {
+    path: Option<PathBuf>,
+    aliases: BTreeMap<String, String>,
+}
+impl AliasRegistry {
+    ///Loads the registry from `path`. A missing file is treated as an empty registry.
+    pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let aliases = match std::fs::read_to_string(&path) {
+            Ok(v) => match serde_json::from_str(&v) {
+                Ok(v) => v,
+                Err(err) => anyhow::bail!("Failed to parse aliases from {}: {err}", path.display()),
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => anyhow::bail!("Failed to read aliases from {}: {err}", path.display()),
+        };
+        Ok(Self {
+            path: Some(path),
+            aliases,
+        })
+    }
+    fn save(&self) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let json = serde_json::to_string_pretty(&self.aliases)?;
+        match std::fs::write(path, json) {
+            Ok(()) => Ok(()),
+            Err(err) => anyhow::bail!("Failed to write aliases to {}: {err}", path.display()),
+        }
+    }
+    pub const fn aliases(&self) -> &BTreeMap<String, String> {
+        &self.aliases
+    }
+    pub fn alias(&self, serial: &str) -> Option<&String> {
+        self.aliases.get(serial)
+    }
+    ///Returns the serial for `name`, if `name` is a known alias.
+    pub fn serial(&self, name: &str) -> Option<&String> {
+        self.aliases.iter().find(|(_, alias)| alias.as_str() == name).map(|(serial, _)| serial)
+    }
+    ///Maps `name` to a serial, if it is an alias. Otherwise `name` is assumed to already be a serial.
+    pub fn resolve<'a>(&'a self, name: &'a str) -> &'a str {
+        self.serial(name).map(String::as_str).unwrap_or(name)
+    }
+    ///Sets the alias of the device with `serial`.
+    ///
+    ///The alias must neither be used by another device, nor be the serial of another one, known from the registry or from the `connected` serials.
+    pub fn set(&mut self, serial: String, alias: String, connected: &[String]) -> anyhow::Result<()> {
+        if alias.is_empty() || alias.chars().any(char::is_whitespace) {
+            anyhow::bail!("Alias must not be empty or contain whitespace, because the websocket separates devices by spaces");
+        }
+        if let Some(other) = self.serial(&alias) && other != &serial {
+            anyhow::bail!("Alias {alias} is already used by {other}");
+        }
+        if alias != serial && (self.aliases.contains_key(&alias) || connected.contains(&alias)) {
+            anyhow::bail!("Alias {alias} is the serial of another device");
+        }
+        let old = self.aliases.insert(serial.clone(), alias);
+        if let Err(err) = self.save() {
+            match old {
+                Some(old) => self.aliases.insert(serial, old),
+                None => self.aliases.remove(&serial),
+            };
+            return Err(err);
+        }
+        Ok(())
+    }
+    pub fn remove(&mut self, serial: &str) -> anyhow::Result<Option<String>> {
+        let old = self.aliases.remove(serial);
+        if let Some(old) = &old && let Err(err) = self.save() {
+            self.aliases.insert(serial.to_string(), old.clone());
+            return Err(err);
+        }
+        Ok(old)
+    }
+}
+
+///Returns the alias stored in the metadata of a device, if any.
+///
+///The metadata is treated as newline separated `key=value` pairs.
+pub fn meta_data_alias(meta_data: &MetaData) -> Option<&str> {
+    meta_data.data().lines().find_map(|line| line.strip_prefix(META_DATA_KEY))
+}
+
+///Replaces the alias in `meta_data` with `alias`, keeping all other lines intact.
+pub fn with_meta_data_alias(meta_data: Option<&MetaData>, alias: Option<&str>) -> String {
+    let mut lines: Vec<_> = meta_data
+        .map(|v| v.data().lines().filter(|line| !line.starts_with(META_DATA_KEY)).map(str::to_string).collect())
+        .unwrap_or_default();
+    if let Some(alias) = alias {
+        lines.push(format!("{META_DATA_KEY}{alias}"));
+    }
+    lines.join("\n")
+}
@@ -0,0 +1,275 @@
+//! Conversion of the raw samples of devices into engineering units, e.g. volts.
+//!
+//! A [`Calibration`] first linearizes a raw sample with an optional polynomial or lookup table,
+//! and then scales it with `gain` and `offset`: `value = offset + gain * linearize(raw)`.
+//! Calibrations are keyed by [`crate::device::messages::Id::serial`] and persisted as JSON, like the aliases.
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use crate::device::messages::MetaData;
+
+///Key of the calibration, when it is mirrored into the [`MetaData`] of a device.
+const META_DATA_KEY: &str = "calibration=";
+
+const fn default_gain() -> f64 {
+    1.
+}
+
+///How the raw samples of a device are converted into `unit`.
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct Calibration {
+    #[serde(default)]
+    offset: f64,
+    #[serde(default = "default_gain")]
+    gain: f64,
+    ///Coefficients `c0, c1, c2 ...` of the polynomial `c0 + c1 * raw + c2 * raw² ...`, which linearizes a raw sample.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    polynomial: Vec<f64>,
+    ///Pairs of a raw sample and its linearized value, sorted by the raw sample. Samples in between are interpolated linearly,
+    ///samples outside are clamped to the first or last pair.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    table: Vec<[f64; 2]>,
+    ///E.g. `V` or `°C`.
+    #[serde(default)]
+    unit: String,
+}
+impl Calibration {
+    pub const fn unit(&self) -> &String { &self.unit }
+    ///Checks, that the calibration converts every raw sample into a finite value.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.offset.is_finite() || !self.gain.is_finite() || self.gain == 0. {
+            anyhow::bail!("Offset and gain must be finite, and the gain must not be 0");
+        }
+        if !self.polynomial.is_empty() && !self.table.is_empty() {
+            anyhow::bail!("Give either a polynomial or a lookup table, not both");
+        }
+        if self.polynomial.iter().any(|v| !v.is_finite()) {
+            anyhow::bail!("The coefficients of the polynomial must be finite");
+        }
+        if self.table.iter().flatten().any(|v| !v.is_finite()) {
+            anyhow::bail!("The lookup table must only hold finite values");
+        }
+        if self.table.len() == 1 {
+            anyhow::bail!("The lookup table needs at least two pairs");
+        }
+        if self.table.windows(2).any(|pairs| pairs[0][0] >= pairs[1][0]) {
+            anyhow::bail!("The raw samples of the lookup table must be strictly increasing");
+        }
+        Ok(())
+    }
+    ///Applies the polynomial or the lookup table to `raw`.
+    fn linearize(&self, raw: f64) -> f64 {
+        if !self.polynomial.is_empty() {
+            //Horner's method
+            return self.polynomial.iter().rev().fold(0., |sum, coefficient| sum * raw + coefficient);
+        }
+        let (Some(first), Some(last)) = (self.table.first(), self.table.last()) else {
+            return raw;
+        };
+        if raw <= first[0] {
+            return first[1];
+        }
+        if raw >= last[0] {
+            return last[1];
+        }
+        let i = self.table.partition_point(|pair| pair[0] <= raw);
+        let ([x0, y0], [x1, y1]) = (self.table[i - 1], self.table[i]);
+        y0 + (raw - x0) * (y1 - y0) / (x1 - x0)
+    }
+    ///Converts a raw sample, or a mean of raw samples, into `unit`.
+    pub fn convert(&self, raw: f64) -> f64 {
+        self.offset + self.gain * self.linearize(raw)
+    }
+    pub fn apply(&self, raw: u16) -> f64 {
+        self.convert(f64::from(raw))
+    }
+    ///Fits gain and offset, so the raw samples of two known reference inputs convert into their values.
+    ///The polynomial or lookup table is kept, so only the linear part is recalibrated.
+    pub fn fit(mut self, points: [CalibrationPoint; 2], unit: Option<String>) -> anyhow::Result<Self> {
+        let [a, b] = points.map(|point| (self.linearize(point.raw), point.value));
+        if !(a.0.is_finite() && a.1.is_finite() && b.0.is_finite() && b.1.is_finite()) {
+            anyhow::bail!("The reference points must be finite");
+        }
+        if a.0 == b.0 {
+            anyhow::bail!("The reference inputs must give different raw samples");
+        }
+        self.gain = (b.1 - a.1) / (b.0 - a.0);
+        self.offset = a.1 - self.gain * a.0;
+        if let Some(unit) = unit {
+            self.unit = unit;
+        }
+        self.validate()?;
+        Ok(self)
+    }
+}
+impl Default for Calibration {
+    fn default() -> Self {
+        Self {
+            offset: 0.,
+            gain: default_gain(),
+            polynomial: Vec::new(),
+            table: Vec::new(),
+            unit: String::new(),
+        }
+    }
+}
+
+///A reference input given to [`Calibration::fit`].
+#[derive(Debug, Clone, Copy, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct CalibrationPoint {
+    ///The (mean) raw sample measured at the reference input.
+    pub raw: f64,
+    ///The known value of the reference input.
+    pub value: f64,
+}
+
+///Calibrations of devices, keyed by [`crate::device::messages::Id::serial`] and persisted as JSON.
+#[derive(Debug, Default)]
+pub struct CalibrationRegistry {
+    path: Option<PathBuf>,
+    profiles: BTreeMap<String, Calibration>,
+}
+impl CalibrationRegistry {
+    ///Loads the registry from `path`. A missing file is treated as an empty registry.
+    pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let profiles: BTreeMap<String, Calibration> = match std::fs::read_to_string(&path) {
+            Ok(v) => match serde_json::from_str(&v) {
+                Ok(v) => v,
+                Err(err) => anyhow::bail!("Failed to parse calibrations from {}: {err}", path.display()),
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => anyhow::bail!("Failed to read calibrations from {}: {err}", path.display()),
+        };
+        for (serial, calibration) in &profiles {
+            if let Err(err) = calibration.validate() {
+                anyhow::bail!("Invalid calibration of {serial} in {}: {err}", path.display());
+            }
+        }
+        Ok(Self {
+            path: Some(path),
+            profiles,
+        })
+    }
+    fn save(&self) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let json = serde_json::to_string_pretty(&self.profiles)?;
+        match std::fs::write(path, json) {
+            Ok(()) => Ok(()),
+            Err(err) => anyhow::bail!("Failed to write calibrations to {}: {err}", path.display()),
+        }
+    }
+    pub const fn profiles(&self) -> &BTreeMap<String, Calibration> {
+        &self.profiles
+    }
+    pub fn calibration(&self, serial: &str) -> Option<&Calibration> {
+        self.profiles.get(serial)
+    }
+    pub fn set(&mut self, serial: String, calibration: Calibration) -> anyhow::Result<()> {
+        calibration.validate()?;
+        let old = self.profiles.insert(serial.clone(), calibration);
+        if let Err(err) = self.save() {
+            match old {
+                Some(old) => self.profiles.insert(serial, old),
+                None => self.profiles.remove(&serial),
+            };
+            return Err(err);
+        }
+        Ok(())
+    }
+    pub fn remove(&mut self, serial: &str) -> anyhow::Result<Option<Calibration>> {
+        let old = self.profiles.remove(serial);
+        if let Some(old) = &old && let Err(err) = self.save() {
+            self.profiles.insert(serial.to_string(), old.clone());
+            return Err(err);
+        }
+        Ok(old)
+    }
+}
+
+///Returns the calibration stored in the metadata of a device, if there is a valid one.
+///
+///The metadata is treated as newline separated `key=value` pairs, and the calibration is stored as JSON.
+pub fn meta_data_calibration(meta_data: &MetaData) -> Option<Calibration> {
+    let json = meta_data.data().lines().find_map(|line| line.strip_prefix(META_DATA_KEY))?;
+    serde_json::from_str::<Calibration>(json).ok().filter(|calibration| calibration.validate().is_ok())
+}
+
+///Replaces the calibration in `meta_data` with `calibration`, keeping all other lines intact.
+pub fn with_meta_data_calibration(meta_data: Option<&MetaData>, calibration: Option<&Calibration>) -> anyhow::Result<String> {
+    let mut lines: Vec<_> = meta_data
+        .map(|v| v.data().lines().filter(|line| !line.starts_with(META_DATA_KEY)).map(str::to_string).collect())
+        .unwrap_or_default();
+    if let Some(calibration) = calibration {
+        //Compact JSON has no line breaks.
+        lines.push(format!("{META_DATA_KEY}{}", serde_json::to_string(calibration)?));
+    }
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibration(json: serde_json::Value) -> Calibration {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn point(raw: f64, value: f64) -> CalibrationPoint {
+        CalibrationPoint { raw, value }
+    }
+
+    #[test]
+    fn fit_two_points() {
+        //0 V reads 1000, 5 V reads 3000.
+        let fitted = Calibration::default().fit([point(1000., 0.), point(3000., 5.)], Some("V".to_string())).unwrap();
+        assert_eq!((fitted.gain, fitted.offset), (0.0025, -2.5));
+        assert_eq!(fitted.unit(), "V");
+        assert_eq!(fitted.apply(2000), 2.5);
+        //The polynomial is kept, so gain and offset apply to the linearized samples.
+        let fitted = calibration(serde_json::json!({"polynomial": [0., 0., 1.]})).fit([point(10., 1.), point(20., 4.)], None).unwrap();
+        assert_eq!((fitted.gain, fitted.offset), (0.01, 0.));
+        assert_eq!(fitted.convert(30.), 9.);
+    }
+
+    #[test]
+    fn fit_rejects_equal_raw_samples() {
+        assert!(Calibration::default().fit([point(1000., 0.), point(1000., 5.)], None).is_err());
+        //Different raw samples, which linearize to the same value.
+        assert!(calibration(serde_json::json!({"table": [[0., 1.], [10., 2.]]})).fit([point(20., 0.), point(30., 5.)], None).is_err());
+        assert!(Calibration::default().fit([point(f64::NAN, 0.), point(1000., 5.)], None).is_err());
+    }
+
+    #[test]
+    fn polynomial() {
+        //1 + 2x + 3x²
+        let calibration = calibration(serde_json::json!({"polynomial": [1., 2., 3.], "gain": 2., "offset": 1.}));
+        assert_eq!(calibration.linearize(0.), 1.);
+        assert_eq!(calibration.linearize(2.), 17.);
+        assert_eq!(calibration.linearize(-1.), 2.);
+        assert_eq!(calibration.convert(2.), 35.);
+    }
+
+    #[test]
+    fn table() {
+        let calibration = calibration(serde_json::json!({"table": [[100., 0.], [200., 10.], [400., 20.]]}));
+        assert!(calibration.validate().is_ok());
+        assert_eq!(calibration.linearize(150.), 5.);
+        assert_eq!(calibration.linearize(200.), 10.);
+        assert_eq!(calibration.linearize(300.), 15.);
+        //Clamped to the ends.
+        assert_eq!(calibration.linearize(0.), 0.);
+        assert_eq!(calibration.linearize(1000.), 20.);
+    }
+
+    #[test]
+    fn validate() {
+        assert!(Calibration::default().validate().is_ok());
+        assert!(calibration(serde_json::json!({"polynomial": [0., 1.], "table": [[0., 0.], [1., 1.]]})).validate().is_err());
+        assert!(calibration(serde_json::json!({"gain": 0.})).validate().is_err());
+        assert!(calibration(serde_json::json!({"table": [[0., 0.]]})).validate().is_err());
+        assert!(calibration(serde_json::json!({"table": [[1., 0.], [0., 1.]]})).validate().is_err());
+        assert!(calibration(serde_json::json!({"table": [[0., 0.], [0., 1.]]})).validate().is_err());
+    }
+}
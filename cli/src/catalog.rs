@@ -0,0 +1,300 @@
+//! Local SQLite catalog of the recorded sessions, see `--catalog`.
+//!
+//! Every file written by [`crate::record::Recorder`] becomes a session with its devices, so recordings can be found again
+//! by device, time, tag or annotation.
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use rusqlite::{named_params, OptionalExtension};
+use crate::record::capture::DeviceHeader;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    path TEXT NOT NULL,
+    format TEXT NOT NULL,
+    started REAL NOT NULL,
+    ended REAL,
+    sample_rate INTEGER NOT NULL,
+    devices TEXT NOT NULL,
+    annotation TEXT NOT NULL DEFAULT ''
+);
+CREATE INDEX IF NOT EXISTS sessions_started ON sessions (started);
+CREATE TABLE IF NOT EXISTS session_devices (
+    session INTEGER NOT NULL REFERENCES sessions (id) ON DELETE CASCADE,
+    serial TEXT NOT NULL,
+    alias TEXT,
+    sw_version TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS session_devices_serial ON session_devices (serial);
+CREATE TABLE IF NOT EXISTS tags (
+    session INTEGER NOT NULL REFERENCES sessions (id) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (session, tag)
+);
+";
+const SELECT_SESSION: &str = "SELECT id, path, format, started, ended, sample_rate, devices, annotation FROM sessions";
+///How many sessions a search returns, if the filter doesn't say otherwise.
+const DEFAULT_LIMIT: u32 = 100;
+
+///Parses a point in time given in ms since the UNIX epoch or as RFC 3339, e.g. `2025-01-31T12:00:00Z`.
+pub fn parse_time(value: &str) -> Result<f64, String> {
+    if let Ok(v) = value.parse::<f64>() {
+        return Ok(v);
+    }
+    match chrono::DateTime::parse_from_rfc3339(value) {
+        Ok(v) => Ok(v.timestamp_micros() as f64 / 1000.),
+        Err(err) => Err(format!("Invalid time {value}, expected ms since the UNIX epoch or RFC 3339: {err}")),
+    }
+}
+
+///Which sessions [`Catalog::search`] returns. Every given condition has to match.
+#[derive(clap_derive::Args, rocket::FromForm, Debug, Clone, Default)]
+pub struct SessionFilter {
+    #[arg(long)]
+    ///Only sessions, which recorded the device with this serial or alias
+    device: Option<String>,
+    #[arg(long)]
+    ///Only sessions with this tag
+    tag: Option<String>,
+    #[arg(long, value_parser = parse_time)]
+    ///Only sessions, which recorded after this time, given in ms since the UNIX epoch or as RFC 3339
+    from: Option<f64>,
+    #[arg(long, value_parser = parse_time)]
+    ///Only sessions, which recorded before this time, given in ms since the UNIX epoch or as RFC 3339
+    to: Option<f64>,
+    #[arg(long)]
+    ///Only sessions, whose annotation or path contains this text, ignoring case
+    text: Option<String>,
+    #[arg(long)]
+    ///Returns at most this many sessions, the latest first
+    limit: Option<u32>,
+}
+
+///A recorded file.
+#[derive(serde_derive::Serialize, Debug, Clone)]
+pub struct Session {
+    id: i64,
+    path: PathBuf,
+    ///The [`crate::record::RecordFormat`] of the file.
+    format: String,
+    ///Milliseconds since the UNIX epoch.
+    start: f64,
+    ///Milliseconds since the UNIX epoch, `None` whilst recording or if the recording was interrupted.
+    end: Option<f64>,
+    ///The highest sample rate of the devices.
+    sample_rate: u32,
+    devices: Vec<DeviceHeader>,
+    annotation: String,
+    tags: Vec<String>,
+}
+impl Session {
+    pub const fn path(&self) -> &PathBuf { &self.path }
+    pub const fn end(&self) -> Option<f64> { self.end }
+}
+
+#[derive(Debug)]
+pub struct Catalog {
+    connection: Mutex<rusqlite::Connection>,
+}
+impl Catalog {
+    ///Opens the catalog at `path`, and creates it, if it doesn't exist.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let connection = match rusqlite::Connection::open(path) {
+            Ok(v) => v,
+            Err(err) => anyhow::bail!("Failed to open the catalog {}: {err}", path.display()),
+        };
+        connection.pragma_update(None, "foreign_keys", true)?;
+        if let Err(err) = connection.execute_batch(SCHEMA) {
+            anyhow::bail!("Failed to create the tables of the catalog {}: {err}", path.display());
+        }
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+    fn connection(&self) -> std::sync::MutexGuard<'_, rusqlite::Connection> {
+        //A panic can't leave a transaction open, so the connection is still usable.
+        self.connection.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+    ///Adds a session recording `devices` into the file at `path` in `format`, starting at `start` in ms since the UNIX epoch.
+    ///Returns the id of the session.
+    pub fn insert(&self, path: &Path, format: &str, start: f64, devices: &[DeviceHeader]) -> anyhow::Result<i64> {
+        let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        let sample_rate = devices.iter().map(|device| device.id().sample_rate()).max().unwrap_or_default();
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO sessions (path, format, started, sample_rate, devices) VALUES (:path, :format, :started, :sample_rate, :devices)",
+            named_params! {
+                ":path": path.to_string_lossy(),
+                ":format": format,
+                ":started": start,
+                ":sample_rate": sample_rate,
+                ":devices": serde_json::to_string(devices)?,
+            },
+        )?;
+        let id = transaction.last_insert_rowid();
+        for device in devices {
+            transaction.execute(
+                "INSERT INTO session_devices (session, serial, alias, sw_version) VALUES (?1, ?2, ?3, ?4)",
+                (id, device.id().serial(), device.alias(), device.id().sw_version().to_string()),
+            )?;
+        }
+        transaction.commit()?;
+        Ok(id)
+    }
+    ///Sets the end of the session `id` in ms since the UNIX epoch.
+    pub fn finish(&self, id: i64, end: f64) -> anyhow::Result<()> {
+        self.connection().execute("UPDATE sessions SET ended = ?1 WHERE id = ?2", (end, id))?;
+        Ok(())
+    }
+    pub fn search(&self, filter: &SessionFilter) -> anyhow::Result<Vec<Session>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(&format!("{SELECT_SESSION} s WHERE
+            (:device IS NULL OR EXISTS (SELECT 1 FROM session_devices d WHERE d.session = s.id AND (d.serial = :device OR d.alias = :device)))
+            AND (:tag IS NULL OR EXISTS (SELECT 1 FROM tags t WHERE t.session = s.id AND t.tag = :tag))
+            AND (:from IS NULL OR coalesce(s.ended, s.started) >= :from)
+            AND (:to IS NULL OR s.started <= :to)
+            AND (:text IS NULL OR instr(lower(s.annotation), lower(:text)) > 0 OR instr(lower(s.path), lower(:text)) > 0)
+            ORDER BY s.started DESC, s.id DESC LIMIT :limit"))?;
+        let rows = statement.query_map(named_params! {
+            ":device": filter.device,
+            ":tag": filter.tag,
+            ":from": filter.from,
+            ":to": filter.to,
+            ":text": filter.text,
+            ":limit": filter.limit.unwrap_or(DEFAULT_LIMIT),
+        }, row_session)?;
+        let mut sessions = rows.collect::<Result<Vec<_>, _>>()?;
+        for session in &mut sessions {
+            session.tags = tags(&connection, session.id)?;
+        }
+        Ok(sessions)
+    }
+    pub fn get(&self, id: i64) -> anyhow::Result<Option<Session>> {
+        let connection = self.connection();
+        let session = connection.query_row(&format!("{SELECT_SESSION} WHERE id = ?1"), [id], row_session).optional()?;
+        match session {
+            Some(mut session) => {
+                session.tags = tags(&connection, id)?;
+                Ok(Some(session))
+            },
+            None => Ok(None),
+        }
+    }
+    ///Replaces the annotation of the session `id`. Returns `false`, if there is no such session.
+    pub fn annotate(&self, id: i64, annotation: &str) -> anyhow::Result<bool> {
+        let changed = self.connection().execute("UPDATE sessions SET annotation = ?1 WHERE id = ?2", (annotation, id))?;
+        Ok(changed > 0)
+    }
+    ///Tags the session `id`. Returns `false`, if there is no such session.
+    pub fn add_tag(&self, id: i64, tag: &str) -> anyhow::Result<bool> {
+        if tag.trim().is_empty() {
+            anyhow::bail!("Tag must not be empty");
+        }
+        let connection = self.connection();
+        let exists = connection.query_row("SELECT 1 FROM sessions WHERE id = ?1", [id], |_| Ok(())).optional()?.is_some();
+        if exists {
+            connection.execute("INSERT OR IGNORE INTO tags (session, tag) VALUES (?1, ?2)", (id, tag))?;
+        }
+        Ok(exists)
+    }
+    ///Removes a tag of the session `id`. Returns `false`, if the session didn't have the tag.
+    pub fn remove_tag(&self, id: i64, tag: &str) -> anyhow::Result<bool> {
+        let changed = self.connection().execute("DELETE FROM tags WHERE session = ?1 AND tag = ?2", (id, tag))?;
+        Ok(changed > 0)
+    }
+    ///Removes the session `id` from the catalog, but keeps its file. Returns `false`, if there is no such session.
+    pub fn delete(&self, id: i64) -> anyhow::Result<bool> {
+        let changed = self.connection().execute("DELETE FROM sessions WHERE id = ?1", [id])?;
+        Ok(changed > 0)
+    }
+}
+
+fn row_session(row: &rusqlite::Row) -> rusqlite::Result<Session> {
+    let devices: String = row.get(6)?;
+    Ok(Session {
+        id: row.get(0)?,
+        path: PathBuf::from(row.get::<_, String>(1)?),
+        format: row.get(2)?,
+        start: row.get(3)?,
+        end: row.get(4)?,
+        sample_rate: row.get(5)?,
+        devices: serde_json::from_str(&devices).map_err(|err| rusqlite::Error::FromSqlConversionFailure(6, rusqlite::types::Type::Text, Box::new(err)))?,
+        annotation: row.get(7)?,
+        tags: Vec::new(),
+    })
+}
+
+fn tags(connection: &rusqlite::Connection, id: i64) -> rusqlite::Result<Vec<String>> {
+    let mut statement = connection.prepare_cached("SELECT tag FROM tags WHERE session = ?1 ORDER BY tag")?;
+    statement.query_map([id], |row| row.get(0))?.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(serial: &str, alias: Option<&str>) -> DeviceHeader {
+        serde_json::from_value(serde_json::json!({
+            "model": "OmnAIScope",
+            "alias": alias,
+            "serial": serial,
+            "type": "scope",
+            "sample_rate": 1000,
+            "hw_version": {"major": 1, "minor": 0, "patch": 0},
+            "sw_version": {"major": 1, "minor": 2, "patch": 0},
+            "sw_git_hash": "test",
+        })).unwrap()
+    }
+
+    fn ids(sessions: &[Session]) -> Vec<i64> {
+        sessions.iter().map(|session| session.id).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let catalog = Catalog::open(Path::new(":memory:")).unwrap();
+        let first = catalog.insert(Path::new("first.csv"), "csv", 1000., &[device("A1", Some("left"))]).unwrap();
+        let second = catalog.insert(Path::new("second.cap"), "capture", 5000., &[device("A1", None), device("B2", None)]).unwrap();
+        catalog.finish(first, 2000.).unwrap();
+
+        let session = catalog.get(first).unwrap().unwrap();
+        assert_eq!((session.start, session.end, session.sample_rate), (1000., Some(2000.), 1000));
+        assert!(session.path.is_absolute());
+        assert_eq!(session.devices[0].alias().map(String::as_str), Some("left"));
+        //Still recording.
+        assert_eq!(catalog.get(second).unwrap().unwrap().end, None);
+        assert!(catalog.get(second + 1).unwrap().is_none());
+
+        let search = |filter: SessionFilter| ids(&catalog.search(&filter).unwrap());
+        //The latest first.
+        assert_eq!(search(SessionFilter::default()), [second, first]);
+        assert_eq!(search(SessionFilter { device: Some("B2".to_string()), ..SessionFilter::default() }), [second]);
+        assert_eq!(search(SessionFilter { device: Some("left".to_string()), ..SessionFilter::default() }), [first]);
+        //A session ends within the range, or one still recording started before its end.
+        assert_eq!(search(SessionFilter { from: Some(1500.), to: Some(3000.), ..SessionFilter::default() }), [first]);
+        assert_eq!(search(SessionFilter { from: Some(2500.), ..SessionFilter::default() }), [second]);
+        assert_eq!(search(SessionFilter { to: Some(999.), ..SessionFilter::default() }), Vec::<i64>::new());
+        assert_eq!(search(SessionFilter { limit: Some(1), ..SessionFilter::default() }), [second]);
+
+        assert!(catalog.add_tag(first, "calibration").unwrap());
+        assert!(catalog.add_tag(first, "bench").unwrap());
+        //Tagging twice keeps a single tag.
+        assert!(catalog.add_tag(first, "bench").unwrap());
+        assert!(!catalog.add_tag(second + 1, "bench").unwrap());
+        assert!(catalog.add_tag(first, " ").is_err());
+        assert_eq!(catalog.get(first).unwrap().unwrap().tags, ["bench", "calibration"]);
+        assert_eq!(search(SessionFilter { tag: Some("bench".to_string()), ..SessionFilter::default() }), [first]);
+        assert!(catalog.remove_tag(first, "calibration").unwrap());
+        assert!(!catalog.remove_tag(first, "calibration").unwrap());
+
+        assert!(catalog.annotate(second, "Relay test").unwrap());
+        assert_eq!(search(SessionFilter { text: Some("relay".to_string()), ..SessionFilter::default() }), [second]);
+
+        assert!(catalog.delete(first).unwrap());
+        assert!(!catalog.delete(first).unwrap());
+        assert_eq!(search(SessionFilter::default()), [second]);
+        //Devices and tags of the session are deleted with it.
+        let count = |table: &str| catalog.connection().query_row(&format!("SELECT count(*) FROM {table} WHERE session = ?1"), [first], |row| row.get::<_, i64>(0)).unwrap();
+        assert_eq!(count("tags"), 0);
+        assert_eq!(count("session_devices"), 0);
+    }
+}
@@ -1,18 +1,41 @@
+pub mod annotation;
+pub mod clock;
+pub mod history;
 pub mod messages;
+pub mod model;
+pub mod reader;
+pub mod replay;
+pub mod sequence;
 
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 use std::time::Duration;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
 use tokio::time::MissedTickBehavior;
-use crate::{MAX_MESSAGE_SIZE, USB_PID, USB_VID};
+use crate::aliases;
+use crate::aliases::AliasRegistry;
+use crate::calibration::{self, Calibration, CalibrationRegistry};
+use crate::record::capture::CaptureReader;
+
+///How many packets a device buffers for slow consumers.
+const CHANNEL_CAPACITY: usize = 1024;
+///How many annotations a device buffers for slow consumers.
+const ANNOTATION_CAPACITY: usize = 64;
+///How long to wait for the device to answer a request, e.g. for its metadata.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub(super) struct DeviceList {
-    list: Vec<Device>
+    list: Vec<Device>,
+    models: model::DeviceModelRegistry,
+    history_limit: history::HistoryLimit,
 }
 impl DeviceList {
-    pub fn new() -> Self{
+    pub fn new(models: model::DeviceModelRegistry, history_limit: history::HistoryLimit) -> Self{
         DeviceList{
-            list: Vec::new()
+            list: Vec::new(),
+            models,
+            history_limit,
         }
     }
     pub async fn scan_for_new_devices(&mut self) -> anyhow::Result<()>{
@@ -33,18 +56,21 @@ impl DeviceList {
                 }
             };
             if self.list.iter().any(|v|{
-                let device = v.device_handle.device();
-                device.bus_number() == bus_number &&
-                    device.address() == address &&
-                    device.port_number() == port_number
+                let Backend::Usb { device: known, .. } = &v.backend else {
+                    return false;
+                };
+                known.bus_number() == bus_number &&
+                    known.address() == address &&
+                    known.port_number() == port_number
             }) {
                 eprintln!("Skipping probably already connected device(bus =  {}, address = {}, port_number= {})", bus_number, address, port_number);
                 continue;
             }
-            if descriptor.vendor_id() != USB_VID || descriptor.product_id() != USB_PID {
-                continue;
-            }
-            match tokio::task::block_in_place(||Device::new(device, descriptor)) {
+            let model = match self.models.find(descriptor.vendor_id(), descriptor.product_id()) {
+                Some(v) => v.clone(),
+                None => continue,
+            };
+            match tokio::task::block_in_place(||Device::new(device, descriptor, model, self.history_limit)) {
                 Ok(device) => {
                     self.list.push(device);
                 }
@@ -56,35 +82,116 @@ impl DeviceList {
 
         Ok(())
     }
+    ///Adds a virtual device for every device recorded in the capture file at `path`, which replay the recording at `speed` times the recorded speed.
+    pub fn add_replay(&mut self, path: PathBuf, speed: f64) -> anyhow::Result<()> {
+        let reader = CaptureReader::open(&path)?;
+        let path = Arc::new(path);
+        for (index, header) in reader.devices().iter().enumerate() {
+            let device = Device::replay(path.clone(), index, header, speed, self.history_limit)?;
+            println!("Replaying {}({}) from {}", header.model(), header.id().serial(), path.display());
+            self.list.push(device);
+        }
+        Ok(())
+    }
     pub fn list(&self) -> &Vec<Device> {
         &self.list
     }
+    ///Finds the device with the given serial or alias.
+    pub async fn find(&self, name: &str, aliases: &AliasRegistry) -> Option<&Device> {
+        let serial = aliases.resolve(name);
+        for device in &self.list {
+            let id = match device.id().await {
+                Some(id) => id,
+                None => continue,
+            };
+            if id.serial() == serial {
+                return Some(device);
+            }
+        }
+        for device in &self.list {
+            if device.meta_data().await.as_ref().and_then(aliases::meta_data_alias) == Some(name) {
+                return Some(device);
+            }
+        }
+        None
+    }
+    ///Serials of the devices, which reported their id.
+    pub async fn serials(&self) -> Vec<String> {
+        let mut serials = Vec::with_capacity(self.list.len());
+        for device in &self.list {
+            if let Some(id) = device.id().await {
+                serials.push(id.serial().clone());
+            }
+        }
+        serials
+    }
     pub fn list_send(&self) -> Vec<SendDevice> {
         self.list.iter().map(SendDevice::from).collect()
     }
 }
 
+///A [`messages::MeasureData`] packet, as forwarded to consumers of a device.
+#[derive(Debug, Clone)]
+pub struct Packet {
+    data: messages::MeasureData,
+    sequence: sequence::SequenceEvent,
+    counter: u64,
+    timestamps: clock::PacketTimestamps,
+}
+impl Packet {
+    pub const fn data(&self) -> &messages::MeasureData { &self.data }
+    pub const fn sequence(&self) -> sequence::SequenceEvent { self.sequence }
+    ///The unwrapped [`messages::MeasureData::counter`], which does not wrap around.
+    pub const fn counter(&self) -> u64 { self.counter }
+    pub const fn timestamps(&self) -> &clock::PacketTimestamps { &self.timestamps }
+    #[cfg(test)]
+    pub const fn new(data: messages::MeasureData, sequence: sequence::SequenceEvent, counter: u64, timestamps: clock::PacketTimestamps) -> Self {
+        Self { data, sequence, counter, timestamps }
+    }
+}
+
 #[derive(serde_derive::Serialize, serde_derive::Deserialize)]
 pub struct SerializableDevice {
     descriptor: String,
+    ///The capture file, which a virtual device replays.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    replay: Option<String>,
+    model: String,
     id: Option<messages::Id>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alias: Option<String>,
     meta_data: Option<messages::MetaData>,
     rgb: messages::SetRGB,
+    loss_statistics: sequence::LossStatistics,
+    clock: clock::ClockEstimate,
+    usb: reader::UsbStatisticsSnapshot,
 }
 pub struct SendDevice {
-    descriptor: Arc<rusb::DeviceDescriptor>,
-    rx_queue: tokio::sync::broadcast::Receiver<messages::MeasureData>,
+    descriptor: Option<Arc<rusb::DeviceDescriptor>>,
+    replay: Option<Arc<PathBuf>>,
+    model: Arc<model::DeviceModel>,
+    packets: tokio::sync::broadcast::WeakSender<Packet>,
+    annotations: tokio::sync::broadcast::Sender<Arc<annotation::Annotation>>,
     id: Arc<Mutex<Option<messages::Id>>>,
     meta_data: Arc<Mutex<Option<messages::MetaData>>>,
     rgb: Arc<Mutex<messages::SetRGB>>,
+    loss_statistics: Arc<Mutex<sequence::LossStatistics>>,
+    clock: Arc<Mutex<clock::ClockEstimate>>,
+    usb_statistics: Arc<reader::UsbStatistics>,
+    history: Arc<RwLock<history::History>>,
     users: Arc<Mutex<Vec<u64>>>,
 }
 impl SendDevice {
     pub async fn id(&self) -> Option<messages::Id> {
         self.id.lock().await.clone()
     }
-    pub fn rx_queue(&self) -> &tokio::sync::broadcast::Receiver<messages::MeasureData> {
-        &self.rx_queue
+    ///Receives the packets of the device from now on.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<Packet> {
+        subscribe(&self.packets)
+    }
+    ///Receives the annotations of the device made from now on.
+    pub fn annotations(&self) -> tokio::sync::broadcast::Receiver<Arc<annotation::Annotation>> {
+        self.annotations.subscribe()
     }
     pub async fn meta_data(&self) -> Option<messages::MetaData> {
         self.meta_data.lock().await.clone()
@@ -92,32 +199,94 @@ impl SendDevice {
     pub async fn rgb(&self) -> messages::SetRGB {
         self.rgb.lock().await.clone()
     }
-    pub fn descriptor(&self) -> &rusb::DeviceDescriptor {
-        &self.descriptor
+    pub async fn loss_statistics(&self) -> sequence::LossStatistics {
+        self.loss_statistics.lock().await.clone()
+    }
+    pub async fn clock(&self) -> clock::ClockEstimate {
+        *self.clock.lock().await
     }
-    pub async fn serializable_device(&self) -> SerializableDevice {
+    pub fn usb_statistics(&self) -> reader::UsbStatisticsSnapshot {
+        self.usb_statistics.snapshot()
+    }
+    ///The recent samples of the device between `tmin` and `tmax`, in milliseconds since the UNIX epoch.
+    pub async fn history(&self, tmin: f64, tmax: f64) -> history::HistorySnapshot {
+        self.history.read().await.snapshot(tmin, tmax)
+    }
+    ///The USB descriptor of the device. `None` for a replay.
+    pub fn descriptor(&self) -> Option<&rusb::DeviceDescriptor> {
+        self.descriptor.as_deref()
+    }
+    pub fn model(&self) -> &model::DeviceModel {
+        &self.model
+    }
+    ///The alias of the device from `aliases`, or the alias stored in the device metadata.
+    pub async fn alias(&self, aliases: &AliasRegistry) -> Option<String> {
+        alias(self.id().await.as_ref(), self.meta_data().await.as_ref(), aliases)
+    }
+    pub async fn serializable_device(&self, aliases: &AliasRegistry) -> SerializableDevice {
         SerializableDevice{
-            descriptor: format!("{:?}", self.descriptor),
+            descriptor: match &self.descriptor {
+                Some(descriptor) => format!("{descriptor:?}"),
+                None => "Replay".to_string(),
+            },
+            replay: self.replay.as_ref().map(|path| path.display().to_string()),
+            model: self.model.name().clone(),
             id: self.id().await,
+            alias: self.alias(aliases).await,
             meta_data: self.meta_data().await,
             rgb: self.rgb().await,
+            loss_statistics: self.loss_statistics().await,
+            clock: self.clock().await,
+            usb: self.usb_statistics(),
         }
     }
 }
+///Subscribes to the packets sent by the reader of a device. The receiver is closed, if the reader is gone.
+///
+///Devices only hold a weak sender, as a receiver, which never receives, would keep the channel full.
+fn subscribe(packets: &tokio::sync::broadcast::WeakSender<Packet>) -> tokio::sync::broadcast::Receiver<Packet> {
+    match packets.upgrade() {
+        Some(tx) => tx.subscribe(),
+        None => tokio::sync::broadcast::channel(1).1,
+    }
+}
+fn alias(id: Option<&messages::Id>, meta_data: Option<&messages::MetaData>, aliases: &AliasRegistry) -> Option<String> {
+    id.and_then(|id| aliases.alias(id.serial()))
+        .map(String::as_str)
+        .or_else(|| meta_data.and_then(aliases::meta_data_alias))
+        .map(str::to_string)
+}
 impl From<&Device> for SendDevice {
     fn from(device: &Device) -> Self {
         let descriptor = device.descriptor.clone();
-        let rx_queue = device.rx_queue.resubscribe();
+        let replay = match &device.backend {
+            Backend::Usb { .. } => None,
+            Backend::Replay { path, .. } => Some(path.clone()),
+        };
+        let model = device.model.clone();
+        let packets = device.packets.clone();
+        let annotations = device.annotations.clone();
         let id = device.id.clone();
         let meta_data = device.meta_data.clone();
         let rgb = device.rgb.clone();
+        let loss_statistics = device.loss_statistics.clone();
+        let clock = device.clock.clone();
+        let usb_statistics = device.usb_statistics.clone();
+        let history = device.history.clone();
         let users = device.users.clone();
         Self{
             descriptor,
-            rx_queue,
+            replay,
+            model,
+            packets,
+            annotations,
             id,
             meta_data,
             rgb,
+            loss_statistics,
+            clock,
+            usb_statistics,
+            history,
             users,
         }
     }
@@ -125,42 +294,96 @@ impl From<&Device> for SendDevice {
 impl Clone for SendDevice {
     fn clone(&self) -> Self {
         let descriptor = self.descriptor.clone();
-        let rx_queue = self.rx_queue.resubscribe();
+        let replay = self.replay.clone();
+        let model = self.model.clone();
+        let packets = self.packets.clone();
+        let annotations = self.annotations.clone();
         let id = self.id.clone();
         let meta_data = self.meta_data.clone();
         let rgb = self.rgb.clone();
+        let loss_statistics = self.loss_statistics.clone();
+        let clock = self.clock.clone();
+        let usb_statistics = self.usb_statistics.clone();
+        let history = self.history.clone();
         let users = self.users.clone();
         Self{
             descriptor,
-            rx_queue,
+            replay,
+            model,
+            packets,
+            annotations,
             id,
             meta_data,
             rgb,
+            loss_statistics,
+            clock,
+            usb_statistics,
+            history,
             users,
         }
     }
 }
 
+///Where the packets of a [`Device`] come from.
+enum Backend {
+    Usb {
+        device: rusb::Device<rusb::GlobalContext>,
+        handle: Arc<rusb::DeviceHandle<rusb::GlobalContext>>,
+        ///Stops reading from the device, when dropped.
+        _reader: reader::Reader,
+        tx_close_ping: tokio::sync::oneshot::Sender<()>,
+        jh_ping: tokio::task::JoinHandle<()>,
+    },
+    ///A recording, see [`replay`].
+    Replay {
+        path: Arc<PathBuf>,
+        ///Stops replaying, when dropped.
+        _replay: replay::Replay,
+    },
+}
+
+///The answer to [`Device::request_meta_data`]. It doesn't borrow the device, so e.g. the [`DeviceList`] can be unlocked while waiting.
+pub struct PendingMetaData {
+    meta_data: Arc<Mutex<Option<messages::MetaData>>>,
+    received: tokio::sync::watch::Receiver<()>,
+}
+impl PendingMetaData {
+    ///Waits for the metadata. Returns `None`, if the device doesn't answer within [`REPLY_TIMEOUT`].
+    pub async fn wait(mut self) -> Option<messages::MetaData> {
+        match tokio::time::timeout(REPLY_TIMEOUT, self.received.changed()).await {
+            Ok(Ok(())) => self.meta_data.lock().await.clone(),
+            //Timed out, or the device is gone.
+            Ok(Err(_)) | Err(_) => None,
+        }
+    }
+}
+
 pub struct Device{
-    device: rusb::Device<rusb::GlobalContext>,
-    device_handle: Arc<rusb::DeviceHandle<rusb::GlobalContext>>,
-    descriptor: Arc<rusb::DeviceDescriptor>,
+    backend: Backend,
+    model: Arc<model::DeviceModel>,
+    descriptor: Option<Arc<rusb::DeviceDescriptor>>,
     id: Arc<Mutex<Option<messages::Id>>>,
+    id_received: tokio::sync::watch::Sender<()>,
     meta_data: Arc<Mutex<Option<messages::MetaData>>>,
+    meta_data_received: tokio::sync::watch::Sender<()>,
     rgb: Arc<Mutex<messages::SetRGB>>,
-    rx_queue: tokio::sync::broadcast::Receiver<messages::MeasureData>,
-    tx_close: tokio::sync::oneshot::Sender<()>,
-    jh: tokio::task::JoinHandle<()>,
-    tx_close_ping: tokio::sync::oneshot::Sender<()>,
-    jh_ping: tokio::task::JoinHandle<()>,
-    capturing: std::sync::atomic::AtomicBool,
+    identifying: Arc<std::sync::atomic::AtomicBool>,
+    loss_statistics: Arc<Mutex<sequence::LossStatistics>>,
+    clock: Arc<Mutex<clock::ClockEstimate>>,
+    usb_statistics: Arc<reader::UsbStatistics>,
+    history: Arc<RwLock<history::History>>,
+    packets: tokio::sync::broadcast::WeakSender<Packet>,
+    annotations: tokio::sync::broadcast::Sender<Arc<annotation::Annotation>>,
+    capturing: Arc<AtomicBool>,
     users: Arc<Mutex<Vec<u64>>>,
 }
 
 impl Device {
     fn new(
         device: rusb::Device<rusb::GlobalContext>,
-        descriptor: rusb::DeviceDescriptor
+        descriptor: rusb::DeviceDescriptor,
+        model: Arc<model::DeviceModel>,
+        history_limit: history::HistoryLimit,
     ) -> anyhow::Result<Self> {
         const CONNECTED_RGB: messages::SetRGB = messages::SetRGB{
             r: 0,
@@ -176,22 +399,28 @@ impl Device {
             Ok(()) => (),
             Err(err) => anyhow::bail!("Failed to set auto detach kernel driver: {err}")
         };
-        match device_handle.claim_interface(0) {
+        match device_handle.claim_interface(model.interface()) {
             Ok(()) => (),
-            Err(err) => anyhow::bail!("Failed to claim device on interface 0: {err}")
+            Err(err) => anyhow::bail!("Failed to claim {} on interface {}: {err}", model.name(), model.interface())
         };
+        println!("Connected {}(bus = {}, address = {}, port_number = {})", model.name(), device.bus_number(), device.address(), device.port_number());
 
         let id = Arc::new(Mutex::new(None));
         let meta_data = Arc::new(Mutex::new(None));
+        let id_received = tokio::sync::watch::Sender::new(());
+        let meta_data_received = tokio::sync::watch::Sender::new(());
         let rgb = Arc::new(Mutex::new(CONNECTED_RGB));
+        let loss_statistics = Arc::new(Mutex::new(sequence::LossStatistics::default()));
+        let clock = Arc::new(Mutex::new(clock::ClockEstimate::default()));
         let users = Arc::new(Mutex::new(Vec::new()));
 
-        let (tx, rx) = tokio::sync::broadcast::channel(1024);
-        let (tx_close, rx_close) = tokio::sync::oneshot::channel();
+        let (tx, _) = tokio::sync::broadcast::channel(CHANNEL_CAPACITY);
+        let packets = tx.downgrade();
         let (tx_close_ping, rx_close_ping) = tokio::sync::oneshot::channel();
         let jh_ping = {
             let device_handle2 = device_handle.clone();
-            tokio::task::spawn(async{
+            let model = model.clone();
+            tokio::task::spawn(async move{
                 let device_handle = device_handle2;
                 let mut rx_close_ping = rx_close_ping;
                 let mut interval = tokio::time::interval(Duration::from_millis(500));
@@ -203,7 +432,7 @@ impl Device {
                             break;
                         },
                         _ = interval.tick() => {
-                            match Self::send_internal(&device_handle, &messages::TxMessage::Ping) {
+                            match Self::send_internal(&device_handle, &model, &messages::TxMessage::Ping) {
                                 Ok(()) => (),
                                 Err(err) => {
                                     eprintln!("Failed to send ping message: {err}");
@@ -215,98 +444,114 @@ impl Device {
                 }
             })
         };
-        let jh = {
-            let id = id.clone();
-            let meta_data = meta_data.clone();
-            let device_handle2 = device_handle.clone();
-            tokio::task::spawn(async move{
-                let mut rx_close = rx_close;
-                let tx = tx;
-                let device_handle = device_handle2;
-                loop{
-                    let device_handle = device_handle.clone();
-                    tokio::select! {
-                        biased;
-                        _ = &mut rx_close => {
-                            break;
-                        },
-                        result = tokio::task::spawn_blocking(move ||{
-                            let mut buf = [0u8; MAX_MESSAGE_SIZE as usize];
-                            let out = device_handle.read_bulk(0x81, &mut buf, std::time::Duration::from_secs(120));
-                            (buf, out)
-                        }) => {
-                            let result = match result {
-                                Ok(v) => v,
-                                Err(err) => {
-                                    eprintln!("Panicked, whilst trying to read from device: {err}");
-                                    break;
-                                }
-                            };
-                            let (buf, result) = result;
-                            let buf = match result {
-                                Ok(len) => &buf[..len],
-                                Err(err) => {
-                                    eprintln!("Failed to read from device: {err}");
-                                    break;
-                                }
-                            };
-                            match aglio::deserialize(buf) {
-                                Ok(messages::RxMessage::Id(new_id)) => {
-                                    let mut lock = id.lock().await;
-                                    *lock = Some(new_id);
-                                    continue;
-                                },
-                                Ok(messages::RxMessage::MetaData(new_meta_data)) => {
-                                    let mut lock = meta_data.lock().await;
-                                    *lock = Some(new_meta_data);
-                                    continue;
-                                },
-                                Ok(messages::RxMessage::MeasureData(measure_data)) => {
-                                    match tx.send(measure_data) {
-                                        Ok(_) => (),
-                                        Err(err) => {
-                                            eprintln!("Failed to send message to channel: {err}");
-                                            continue;
-                                        }
-                                    }
-                                },
-                                Err(err) => {
-                                    eprintln!("Failed to deserialize message: {err}");
-                                    continue;
-                                }
-                            };
-                        }
-                    }
-                }
-            })
-        };
-
+        let usb_statistics = Arc::new(reader::UsbStatistics::default());
+        let history = Arc::new(RwLock::new(history::History::new(history_limit)));
+        let reader = reader::Reader::spawn(device_handle.clone(), model.clone(), reader::Shared{
+            id: id.clone(),
+            id_received: id_received.clone(),
+            meta_data: meta_data.clone(),
+            meta_data_received: meta_data_received.clone(),
+            loss_statistics: loss_statistics.clone(),
+            clock: clock.clone(),
+            usb_statistics: usb_statistics.clone(),
+            history: history.clone(),
+            tx,
+        })?;
 
         let device = Device{
-            device,
-            device_handle,
-            descriptor,
+            backend: Backend::Usb {
+                device,
+                handle: device_handle,
+                _reader: reader,
+                tx_close_ping,
+                jh_ping,
+            },
+            model,
+            descriptor: Some(descriptor),
             id,
+            id_received,
             meta_data,
+            meta_data_received,
             rgb,
-            rx_queue: rx,
-            tx_close,
-            jh,
-            tx_close_ping,
-            jh_ping,
-            capturing: std::sync::atomic::AtomicBool::new(false),
+            identifying: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            loss_statistics,
+            clock,
+            usb_statistics,
+            history,
+            packets,
+            annotations: tokio::sync::broadcast::channel(ANNOTATION_CAPACITY).0,
+            capturing: Arc::new(AtomicBool::new(false)),
             users,
         };
 
         device.send(&messages::TxMessage::GetId)?;
-        device.send(&messages::TxMessage::GetMetaData)?;
-        device.send(&messages::TxMessage::SetRGB(CONNECTED_RGB))?;
+        if device.model.messages().meta_data() {
+            device.send(&messages::TxMessage::GetMetaData)?;
+        }
+        if device.model.messages().rgb() {
+            device.send(&messages::TxMessage::SetRGB(CONNECTED_RGB))?;
+        }
         Ok(device)
     }
 
-    fn send_internal(device_handle: &rusb::DeviceHandle<rusb::GlobalContext>, message: &messages::TxMessage) -> anyhow::Result<()> {
-        match aglio::serialize(message) {
-            Ok(v) => match device_handle.write_bulk(1, v.as_slice(), std::time::Duration::from_secs(1)){
+    ///A virtual device, which replays the `index`th device of the capture file at `path`, see [`replay`].
+    fn replay(
+        path: Arc<PathBuf>,
+        index: usize,
+        header: &crate::record::capture::DeviceHeader,
+        speed: f64,
+        history_limit: history::HistoryLimit,
+    ) -> anyhow::Result<Self> {
+        let model = Arc::new(model::DeviceModel::replay(header.model().clone()));
+        let id = Arc::new(Mutex::new(Some(header.id().clone())));
+        let meta_data = Arc::new(Mutex::new(None));
+        let id_received = tokio::sync::watch::Sender::new(());
+        let meta_data_received = tokio::sync::watch::Sender::new(());
+        let loss_statistics = Arc::new(Mutex::new(sequence::LossStatistics::default()));
+        let clock = Arc::new(Mutex::new(clock::ClockEstimate::default()));
+        let usb_statistics = Arc::new(reader::UsbStatistics::default());
+        let history = Arc::new(RwLock::new(history::History::new(history_limit)));
+        let capturing = Arc::new(AtomicBool::new(false));
+        let (tx, _) = tokio::sync::broadcast::channel(CHANNEL_CAPACITY);
+        let packets = tx.downgrade();
+        let replay = replay::Replay::spawn(path.clone(), index, speed, model.clone(), capturing.clone(), reader::Shared{
+            id: id.clone(),
+            id_received: id_received.clone(),
+            meta_data: meta_data.clone(),
+            meta_data_received: meta_data_received.clone(),
+            loss_statistics: loss_statistics.clone(),
+            clock: clock.clone(),
+            usb_statistics: usb_statistics.clone(),
+            history: history.clone(),
+            tx,
+        })?;
+        Ok(Device{
+            backend: Backend::Replay {
+                path,
+                _replay: replay,
+            },
+            model,
+            descriptor: None,
+            id,
+            id_received,
+            meta_data,
+            meta_data_received,
+            rgb: Arc::new(Mutex::new(messages::SetRGB::new(0, 0, 0))),
+            identifying: Arc::new(AtomicBool::new(false)),
+            loss_statistics,
+            clock,
+            usb_statistics,
+            history,
+            packets,
+            annotations: tokio::sync::broadcast::channel(ANNOTATION_CAPACITY).0,
+            capturing,
+            users: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    fn send_internal(device_handle: &rusb::DeviceHandle<rusb::GlobalContext>, model: &model::DeviceModel, message: &messages::TxMessage) -> anyhow::Result<()> {
+        match model.protocol().serialize(message) {
+            Ok(v) => match device_handle.write_bulk(model.endpoint_out(), v.as_slice(), std::time::Duration::from_secs(1)){
                 Ok(_) => Ok(()),
                 Err(err) => anyhow::bail!("Failed to write to device: {err}")
             },
@@ -314,11 +559,25 @@ impl Device {
         }
     }
 
+    ///Like [`Self::send_internal`], but on the blocking thread pool, for tasks which don't borrow the device.
+    async fn send_blocking(device_handle: &Arc<rusb::DeviceHandle<rusb::GlobalContext>>, model: &Arc<model::DeviceModel>, message: messages::TxMessage) -> anyhow::Result<()> {
+        let device_handle = device_handle.clone();
+        let model = model.clone();
+        tokio::task::spawn_blocking(move ||Self::send_internal(&device_handle, &model, &message)).await?
+    }
+
     #[inline]
     fn send(&self, message: &messages::TxMessage) -> anyhow::Result<()> {
-        Self::send_internal(&self.device_handle, message)
+        match &self.backend {
+            Backend::Usb { handle, .. } => Self::send_internal(handle, &self.model, message),
+            //A replay follows `capturing` and ignores everything else.
+            Backend::Replay { .. } => Ok(()),
+        }
     }
 
+    pub fn is_capturing(&self) -> bool {
+        self.capturing.load(std::sync::atomic::Ordering::Acquire)
+    }
     pub fn start_capture(&self) -> anyhow::Result<()> {
         if self.capturing.compare_exchange(false, true, std::sync::atomic::Ordering::AcqRel, std::sync::atomic::Ordering::Acquire).is_ok() {
             match self.send(&messages::TxMessage::Start) {
@@ -333,6 +592,115 @@ impl Device {
         Ok(())
     }
 
+    ///Asks the device to sample at `sample_rate`.
+    ///
+    ///Returns `false` without contacting the device, if the firmware can't change its sample rate.
+    ///Otherwise the device is asked for a new [`messages::Id`], and `true` is returned only, if it reports `sample_rate`.
+    pub async fn set_sample_rate(&self, id: &messages::Id, sample_rate: u32) -> anyhow::Result<bool> {
+        if !self.model.messages().set_sample_rate().unwrap_or_else(||id.supports_set_sample_rate()) {
+            return Ok(false);
+        }
+        if id.sample_rate() == sample_rate {
+            return Ok(true);
+        }
+        let mut received = self.id_received.subscribe();
+        tokio::task::block_in_place(||{
+            self.send(&messages::TxMessage::SetSampleRate(messages::SetSampleRate::new(sample_rate)))?;
+            self.send(&messages::TxMessage::GetId)
+        })?;
+        if !matches!(tokio::time::timeout(REPLY_TIMEOUT, received.changed()).await, Ok(Ok(()))) {
+            anyhow::bail!("Device didn't confirm the sample rate in time");
+        }
+        Ok(self.id().await.is_some_and(|id| id.sample_rate() == sample_rate))
+    }
+
+    ///Returns `true`, if the device has an LED, which [`Self::set_rgb`] and [`Self::identify`] can control.
+    ///Replayed devices have none.
+    pub fn has_led(&self) -> bool {
+        self.model.messages().rgb() && matches!(self.backend, Backend::Usb { .. })
+    }
+
+    ///Sets the LED colour of the device.
+    pub async fn set_rgb(&self, rgb: messages::SetRGB) -> anyhow::Result<()> {
+        if !self.has_led() {
+            anyhow::bail!("{} has no controllable LED", self.model.name());
+        }
+        let mut lock = self.rgb.lock().await;
+        //Don't interrupt a running identification. It restores the colour we cache here, once it's done.
+        if !self.identifying.load(std::sync::atomic::Ordering::Acquire) {
+            tokio::task::block_in_place(||self.send(&messages::TxMessage::SetRGB(rgb.clone())))?;
+        }
+        *lock = rgb;
+        Ok(())
+    }
+
+    ///Cycles the LED of the device through a few colours for `duration`, to make it easy to spot on a bench.
+    ///Afterwards the LED returns to the colour set via [`Self::set_rgb`].
+    ///
+    ///Returns `false`, if the device is already identifying itself.
+    pub fn identify(&self, duration: Duration) -> anyhow::Result<bool> {
+        const COLORS: [messages::SetRGB; 3] = [
+            messages::SetRGB::new(255, 0, 0),
+            messages::SetRGB::new(0, 255, 0),
+            messages::SetRGB::new(0, 0, 255),
+        ];
+        let Backend::Usb { handle, .. } = &self.backend else {
+            anyhow::bail!("{} has no controllable LED", self.model.name());
+        };
+        if !self.model.messages().rgb() {
+            anyhow::bail!("{} has no controllable LED", self.model.name());
+        }
+        if self.identifying.compare_exchange(false, true, std::sync::atomic::Ordering::AcqRel, std::sync::atomic::Ordering::Acquire).is_err() {
+            return Ok(false);
+        }
+        let device_handle = handle.clone();
+        let model = self.model.clone();
+        let rgb = self.rgb.clone();
+        let identifying = self.identifying.clone();
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(250));
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            let end = tokio::time::Instant::now() + duration;
+            for color in COLORS.iter().cycle() {
+                if interval.tick().await >= end {
+                    break;
+                }
+                if let Err(err) = Self::send_blocking(&device_handle, &model, messages::TxMessage::SetRGB(color.clone())).await {
+                    eprintln!("Failed to set colour whilst identifying: {err}");
+                    break;
+                }
+            }
+            let rgb = rgb.lock().await;
+            identifying.store(false, std::sync::atomic::Ordering::Release);
+            if let Err(err) = Self::send_blocking(&device_handle, &model, messages::TxMessage::SetRGB(rgb.clone())).await {
+                eprintln!("Failed to restore colour after identifying: {err}");
+            }
+        });
+        Ok(true)
+    }
+
+    ///Asks the device for its metadata. The answer is awaited with [`PendingMetaData::wait`].
+    pub fn request_meta_data(&self) -> anyhow::Result<PendingMetaData> {
+        if !self.model.messages().meta_data() {
+            anyhow::bail!("{} has no metadata", self.model.name());
+        }
+        let received = self.meta_data_received.subscribe();
+        tokio::task::block_in_place(||self.send(&messages::TxMessage::GetMetaData))?;
+        Ok(PendingMetaData {
+            meta_data: self.meta_data.clone(),
+            received,
+        })
+    }
+
+    ///Stores `data` on the device. The metadata the device reports afterwards is awaited with [`PendingMetaData::wait`].
+    pub fn set_meta_data(&self, data: String) -> anyhow::Result<PendingMetaData> {
+        if !self.model.messages().meta_data() {
+            anyhow::bail!("{} has no metadata", self.model.name());
+        }
+        tokio::task::block_in_place(||self.send(&messages::TxMessage::SetMetaData(messages::SetMetaData::new(data))))?;
+        self.request_meta_data()
+    }
+
     pub fn stop_capture(&self) -> anyhow::Result<()> {
         if self.capturing.compare_exchange(true, false, std::sync::atomic::Ordering::AcqRel, std::sync::atomic::Ordering::Acquire).is_ok() {
             match self.send(&messages::TxMessage::Stop) {
@@ -349,20 +717,42 @@ impl Device {
     pub async fn id(&self) -> Option<messages::Id> {
         self.id.lock().await.clone()
     }
-    pub fn descriptor(&self) -> &rusb::DeviceDescriptor {
-        &self.descriptor
+    ///The USB descriptor of the device. `None` for a replay.
+    pub fn descriptor(&self) -> Option<&rusb::DeviceDescriptor> {
+        self.descriptor.as_deref()
     }
     pub async fn meta_data(&self) -> Option<messages::MetaData> {
         self.meta_data.lock().await.clone()
     }
-    pub const fn rx_queue(&self) -> &tokio::sync::broadcast::Receiver<messages::MeasureData> {
-        &self.rx_queue
+    ///The recent samples of the device between `tmin` and `tmax`, in milliseconds since the UNIX epoch.
+    pub async fn history(&self, tmin: f64, tmax: f64) -> history::HistorySnapshot {
+        self.history.read().await.snapshot(tmin, tmax)
+    }
+    ///Host time of the oldest and the newest recent sample of the device.
+    pub async fn history_bounds(&self) -> Option<(f64, f64)> {
+        self.history.read().await.bounds()
+    }
+    ///The alias of the device from `aliases`, or the alias stored in the device metadata.
+    pub async fn alias(&self, aliases: &AliasRegistry) -> Option<String> {
+        alias(self.id().await.as_ref(), self.meta_data().await.as_ref(), aliases)
+    }
+    ///The calibration of the device from `calibrations`, or the calibration stored in the device metadata.
+    pub async fn calibration(&self, calibrations: &CalibrationRegistry) -> Option<Calibration> {
+        if let Some(id) = self.id().await && let Some(calibration) = calibrations.calibration(id.serial()) {
+            return Some(calibration.clone());
+        }
+        self.meta_data().await.as_ref().and_then(calibration::meta_data_calibration)
+    }
+    ///Sends `annotation` to everyone receiving the annotations of the device.
+    pub fn annotate(&self, annotation: Arc<annotation::Annotation>) {
+        //Nobody may be listening.
+        self.annotations.send(annotation).ok();
     }
 
 }
 impl Drop for Device {
     fn drop(&mut self) {
-        if *self.capturing.get_mut() {
+        if self.capturing.load(std::sync::atomic::Ordering::Acquire) {
             match self.stop_capture() {
                 Ok(()) => (),
                 Err(err) => {
@@ -371,14 +761,11 @@ impl Drop for Device {
             }
         }
 
-        let (mut tx, _) = tokio::sync::oneshot::channel();
-        core::mem::swap(&mut self.tx_close, &mut tx);
-        tx.send(()).ok();
-        self.jh.abort();
-
-        let (mut tx, _) = tokio::sync::oneshot::channel();
-        core::mem::swap(&mut self.tx_close_ping, &mut tx);
-        tx.send(()).ok();
-        self.jh_ping.abort();
+        if let Backend::Usb { tx_close_ping, jh_ping, .. } = &mut self.backend {
+            let (mut tx, _) = tokio::sync::oneshot::channel();
+            core::mem::swap(tx_close_ping, &mut tx);
+            tx.send(()).ok();
+            jh_ping.abort();
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,101 @@
+//! Time-stamped labels, which operators attach to the samples of devices whilst capturing, e.g. "relay closed".
+//!
+//! An annotation is broadcast to everyone using an annotated device, see [`super::Device::annotate`].
+//! The websocket sends it to the other subscribers, and the [`crate::record::Recorder`] stores it with the recording.
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+///How many recent annotations [`Seen`] remembers.
+const SEEN_CAPACITY: usize = 64;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+///A label of a point in time, or of a time range, of the samples of devices.
+#[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct Annotation {
+    ///Unique whilst the server runs, so the copies of an annotation of several devices can be told apart from others.
+    #[serde(default)]
+    id: u64,
+    ///Serials of the annotated devices.
+    devices: Vec<String>,
+    ///Milliseconds since the UNIX epoch.
+    timestamp: f64,
+    ///End of the annotated range in ms since the UNIX epoch. `None` for a point in time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    end: Option<f64>,
+    label: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    values: BTreeMap<String, serde_json::Value>,
+    ///Id of the websocket session, which made the annotation, so it isn't sent back to it.
+    #[serde(skip)]
+    origin: Option<u64>,
+}
+impl Annotation {
+    pub const fn timestamp(&self) -> f64 { self.timestamp }
+    pub const fn end(&self) -> Option<f64> { self.end }
+    pub const fn label(&self) -> &String { &self.label }
+    pub const fn values(&self) -> &BTreeMap<String, serde_json::Value> { &self.values }
+    pub const fn origin(&self) -> Option<u64> { self.origin }
+}
+
+///An annotation, as requested over the websocket or REST.
+#[derive(Debug, Clone, serde_derive::Deserialize)]
+pub struct AnnotationRequest {
+    ///Serials or aliases of the annotated devices.
+    #[serde(default)]
+    devices: Vec<String>,
+    ///Defaults to now.
+    #[serde(default)]
+    timestamp: Option<chrono::DateTime<chrono::FixedOffset>>,
+    ///Makes the annotation a time range.
+    #[serde(default)]
+    end: Option<chrono::DateTime<chrono::FixedOffset>>,
+    label: String,
+    #[serde(default)]
+    values: BTreeMap<String, serde_json::Value>,
+}
+impl AnnotationRequest {
+    pub fn devices(&self) -> &[String] { self.devices.as_slice() }
+    ///Checks the request, and makes it an annotation of the devices with the given `serials`, made by the websocket session `origin`.
+    pub fn into_annotation(self, serials: Vec<String>, origin: Option<u64>) -> anyhow::Result<Annotation> {
+        if self.label.trim().is_empty() {
+            anyhow::bail!("The label must not be empty");
+        }
+        if serials.is_empty() {
+            anyhow::bail!("No devices given");
+        }
+        let timestamp = self.timestamp.map_or_else(|| chrono::Utc::now().timestamp_micros(), |v| v.timestamp_micros()) as f64 / 1000.;
+        let end = self.end.map(|v| v.timestamp_micros() as f64 / 1000.);
+        if let Some(end) = end && end < timestamp {
+            anyhow::bail!("The end must not be before the timestamp");
+        }
+        Ok(Annotation {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            devices: serials,
+            timestamp,
+            end,
+            label: self.label,
+            values: self.values,
+            origin,
+        })
+    }
+}
+
+///The recent annotations received from several devices, so an annotation of several of them is only handled once.
+#[derive(Debug, Default)]
+pub struct Seen {
+    ids: VecDeque<u64>,
+}
+impl Seen {
+    ///Returns `true`, if `annotation` wasn't seen recently.
+    pub fn insert(&mut self, annotation: &Annotation) -> bool {
+        if self.ids.contains(&annotation.id) {
+            return false;
+        }
+        if self.ids.len() == SEEN_CAPACITY {
+            self.ids.pop_front();
+        }
+        self.ids.push_back(annotation.id);
+        true
+    }
+}
@@ -0,0 +1,246 @@
+use std::time::{Duration, SystemTime};
+
+///How much the host arrival time of a packet may disagree with the time predicted from the device clock,
+///before we give up on the current anchor and re-anchor the device clock to the wall-clock.
+const MAX_DEVIATION: f64 = 1.0;
+///Weight of past observations in the drift estimate. Closer to 1 means a slower, but smoother estimate.
+const DRIFT_FORGETTING_FACTOR: f64 = 0.999;
+
+///Timestamps of the samples in a single [`super::Packet`].
+///
+///The timestamp of sample `i` is `first + i * interval` in either time base.
+#[derive(Debug, Clone, Copy, Default, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct PacketTimestamps {
+    ///Seconds since the capture start, according to the device clock.
+    device: f64,
+    ///Milliseconds since the UNIX epoch, according to the host clock.
+    host: f64,
+    ///Seconds between two samples, according to the device clock.
+    device_interval: f64,
+    ///Milliseconds between two samples, according to the host clock.
+    host_interval: f64,
+}
+impl PacketTimestamps {
+    pub const fn host(&self) -> f64 { self.host }
+    pub const fn device_interval(&self) -> f64 { self.device_interval }
+    pub const fn host_interval(&self) -> f64 { self.host_interval }
+    ///Host time of the `index`th sample in the packet, in milliseconds since the UNIX epoch.
+    pub fn host_at(&self, index: usize) -> f64 {
+        self.host + index as f64 * self.host_interval
+    }
+    ///Device time of the sample taken at host time `host`, in seconds since the capture start,
+    ///or `None`, if the sample rate of the device isn't known.
+    pub fn device_time(&self, host: f64) -> Option<f64> {
+        (self.host_interval > 0.).then(|| self.device + (host - self.host) / self.host_interval * self.device_interval)
+    }
+    ///Timestamps of every `ratio`th sample, starting with the sample at host time `host`.
+    pub fn decimated(&self, host: f64, ratio: usize) -> Self {
+        let offset = if self.host_interval > 0. { (host - self.host) / self.host_interval } else { 0. };
+        Self {
+            device: self.device + offset * self.device_interval,
+            host,
+            device_interval: self.device_interval * ratio as f64,
+            host_interval: self.host_interval * ratio as f64,
+        }
+    }
+}
+
+///The relation between the device time base and the host time base.
+#[derive(Debug, Clone, Copy, Default, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct ClockEstimate {
+    ///Wall-clock time of device time `0`, in milliseconds since the UNIX epoch.
+    anchor: f64,
+    ///The nominal sample rate reported by the device.
+    sample_rate: u32,
+    ///How much faster the device clock runs compared to the host clock, in parts per million.
+    drift_ppm: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    ///Wall-clock time at the capture start.
+    host: Duration,
+    ///Unwrapped counter of the first packet since the anchor, or since the packet length last changed.
+    counter: u64,
+    ///Samples between the anchor and the first sample of the packet with `counter`.
+    samples: u64,
+    ///Samples per packet since `counter`.
+    packet_len: usize,
+}
+
+///Derives per-sample timestamps from the device sample rate and the packet counter.
+///
+///The device clock is anchored to the wall-clock at the start of a capture.
+///From there on, the drift between the device and the host clock is continuously estimated
+///by an exponentially weighted linear regression of the packet arrival times against the device time.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceClock {
+    anchor: Option<Anchor>,
+    sample_rate: u32,
+    //Weighted sums for the regression of `host - device` against `device`.
+    weight: f64,
+    sum_x: f64,
+    sum_y: f64,
+    sum_xx: f64,
+    sum_xy: f64,
+    ///Relative drift of the host clock against the device clock (e.g. `1e-6` is 1ppm).
+    drift: f64,
+}
+impl DeviceClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    ///Forgets the current anchor. The next packet will start a new capture.
+    pub fn reset(&mut self) {
+        *self = Self {
+            drift: self.drift,
+            ..Self::default()
+        };
+    }
+    pub fn estimate(&self) -> ClockEstimate {
+        ClockEstimate {
+            anchor: self.anchor.map(|anchor| anchor.host.as_secs_f64() * 1000.).unwrap_or_default(),
+            sample_rate: self.sample_rate,
+            drift_ppm: -self.drift * 1e6,
+        }
+    }
+    ///Computes the timestamps of a packet with `packet_len` samples and the unwrapped `counter`, which arrived at `arrival`.
+    ///
+    ///If the sample rate of the device is not known (yet), all samples get the arrival time as their timestamp.
+    pub fn timestamps(&mut self, sample_rate: Option<u32>, counter: u64, packet_len: usize, arrival: SystemTime) -> PacketTimestamps {
+        let arrival = arrival.duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::ZERO);
+        let sample_rate = match sample_rate {
+            Some(v) if v > 0 => v,
+            _ => {
+                return PacketTimestamps {
+                    device: 0.,
+                    host: arrival.as_secs_f64() * 1000.,
+                    device_interval: 0.,
+                    host_interval: 0.,
+                };
+            }
+        };
+        if sample_rate != self.sample_rate {
+            self.reset();
+            self.sample_rate = sample_rate;
+        }
+        let period = 1. / f64::from(sample_rate);
+        let packet_duration = packet_len as f64 * period;
+
+        let mut anchor = match self.anchor {
+            Some(anchor) if counter >= anchor.counter => anchor,
+            //The packet most likely completed just before it arrived, so the first sample was taken one packet earlier.
+            _ => self.anchor(arrival.saturating_sub(Duration::from_secs_f64(packet_duration)), counter, packet_len),
+        };
+        if anchor.packet_len != packet_len {
+            anchor = Anchor {
+                samples: anchor.samples + (counter - anchor.counter) * anchor.packet_len as u64,
+                counter,
+                packet_len,
+                ..anchor
+            };
+            self.anchor = Some(anchor);
+        }
+        let samples = anchor.samples + (counter - anchor.counter) * packet_len as u64;
+        let mut device = samples as f64 * period;
+
+        let observed = (arrival.as_secs_f64() - anchor.host.as_secs_f64()) - (device + packet_duration);
+        if (observed - self.drift * device).abs() > MAX_DEVIATION {
+            //The device clock and the wall-clock disagree too much (e.g. the capture was stopped and restarted).
+            anchor = self.anchor(arrival.saturating_sub(Duration::from_secs_f64(packet_duration)), counter, packet_len);
+            device = 0.;
+        } else {
+            self.observe(device, observed);
+        }
+
+        let scale = 1. + self.drift;
+        PacketTimestamps {
+            device,
+            host: (anchor.host.as_secs_f64() + device * scale) * 1000.,
+            device_interval: period,
+            host_interval: period * scale * 1000.,
+        }
+    }
+
+    fn anchor(&mut self, host: Duration, counter: u64, packet_len: usize) -> Anchor {
+        let drift = self.drift;
+        let sample_rate = self.sample_rate;
+        *self = Self {
+            drift,
+            sample_rate,
+            ..Self::default()
+        };
+        let anchor = Anchor {
+            host,
+            counter,
+            samples: 0,
+            packet_len,
+        };
+        self.anchor = Some(anchor);
+        anchor
+    }
+
+    fn observe(&mut self, x: f64, y: f64) {
+        self.weight = self.weight * DRIFT_FORGETTING_FACTOR + 1.;
+        self.sum_x = self.sum_x * DRIFT_FORGETTING_FACTOR + x;
+        self.sum_y = self.sum_y * DRIFT_FORGETTING_FACTOR + y;
+        self.sum_xx = self.sum_xx * DRIFT_FORGETTING_FACTOR + x * x;
+        self.sum_xy = self.sum_xy * DRIFT_FORGETTING_FACTOR + x * y;
+        let denominator = self.weight * self.sum_xx - self.sum_x * self.sum_x;
+        //Only update the drift, once the observations span enough device time to make the slope meaningful.
+        if self.weight >= 2. && denominator > f64::EPSILON * self.weight * self.weight {
+            self.drift = (self.weight * self.sum_xy - self.sum_x * self.sum_y) / denominator;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 1000;
+    const PACKET_LEN: usize = 100;
+
+    fn at(seconds: f64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000) + Duration::from_secs_f64(seconds)
+    }
+    ///Host time of [`at`] in milliseconds since the UNIX epoch.
+    fn millis(seconds: f64) -> f64 {
+        at(seconds).duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64() * 1000.
+    }
+
+    #[test]
+    fn converges_to_a_constant_drift() {
+        //The host clock runs 50ppm faster than the device clock.
+        let drift = 50e-6;
+        let mut clock = DeviceClock::new();
+        let mut last = PacketTimestamps::default();
+        for counter in 0..5000 {
+            let arrival = at((counter + 1) as f64 * 0.1 * (1. + drift));
+            last = clock.timestamps(Some(SAMPLE_RATE), counter, PACKET_LEN, arrival);
+        }
+        let drift_ppm = clock.estimate().drift_ppm;
+        assert!((drift_ppm + 50.).abs() < 0.5, "Estimated {drift_ppm}ppm");
+        assert!((last.host_interval() - (1. + drift)).abs() < 1e-6);
+        //The last packet started 499.9 device seconds after the first.
+        let expected = millis(4999. * 0.1 * (1. + drift));
+        assert!((last.host() - expected).abs() < 1., "{} instead of {expected}", last.host());
+        assert!((last.device_time(last.host()).unwrap() - 499.9).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reanchors_after_a_jump() {
+        let mut clock = DeviceClock::new();
+        for counter in 0..10 {
+            clock.timestamps(Some(SAMPLE_RATE), counter, PACKET_LEN, at((counter + 1) as f64 * 0.1));
+        }
+        //The next packet arrives two seconds late, e.g. after the capture was paused.
+        let late = clock.timestamps(Some(SAMPLE_RATE), 10, PACKET_LEN, at(3.1));
+        assert_eq!(late.device_time(late.host()), Some(0.));
+        assert!((late.host() - millis(3.)).abs() < 1e-3);
+        //The following packets continue from the new anchor.
+        let next = clock.timestamps(Some(SAMPLE_RATE), 11, PACKET_LEN, at(3.2));
+        assert!((next.device_time(next.host()).unwrap() - 0.1).abs() < 1e-9);
+        assert!((next.host() - late.host() - 100.).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use super::clock::PacketTimestamps;
+use super::Packet;
+
+///How much history is kept per device. If both limits are set, whichever is reached first applies.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HistoryLimit {
+    max_samples: Option<usize>,
+    max_duration: Option<Duration>,
+}
+impl HistoryLimit {
+    pub const fn new(max_samples: Option<usize>, max_duration: Option<Duration>) -> Self {
+        Self { max_samples, max_duration }
+    }
+    pub const fn is_disabled(&self) -> bool {
+        matches!(self.max_samples, Some(0))
+    }
+}
+
+///A sample read back from a [`History`].
+#[derive(Debug, Clone, Copy)]
+pub struct HistorySample {
+    ///Host time in milliseconds since the UNIX epoch.
+    timestamp: f64,
+    value: u16,
+    ///Set on the first sample after a discontinuity in the recorded stream.
+    gap: bool,
+}
+impl HistorySample {
+    pub const fn new(timestamp: f64, value: u16, gap: bool) -> Self {
+        Self { timestamp, value, gap }
+    }
+    pub const fn timestamp(&self) -> f64 { self.timestamp }
+    pub const fn value(&self) -> u16 { self.value }
+    pub const fn gap(&self) -> bool { self.gap }
+}
+
+///The samples of one packet. Samples are stored per packet, so the timestamps don't need to be stored per sample.
+#[derive(Debug, Clone)]
+struct Chunk {
+    timestamps: PacketTimestamps,
+    gap: bool,
+    ///Shared, so copying chunks out of the [`History`] is cheap.
+    data: Arc<[u16]>,
+}
+impl Chunk {
+    fn start(&self) -> f64 {
+        self.timestamps.host()
+    }
+    fn end(&self) -> f64 {
+        self.timestamps.host_at(self.data.len().saturating_sub(1))
+    }
+}
+
+///An in-memory ring buffer of the most recent timestamped samples of a device.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    limit: HistoryLimit,
+    chunks: VecDeque<Chunk>,
+    samples: usize,
+}
+impl History {
+    pub fn new(limit: HistoryLimit) -> Self {
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+    ///Host time of the oldest and the newest sample, in milliseconds since the UNIX epoch.
+    pub fn bounds(&self) -> Option<(f64, f64)> {
+        Some((self.chunks.front()?.start(), self.chunks.back()?.end()))
+    }
+    pub fn push(&mut self, packet: &Packet) {
+        if self.limit.is_disabled() || packet.data().data().is_empty() {
+            return;
+        }
+        let chunk = Chunk {
+            timestamps: *packet.timestamps(),
+            gap: packet.sequence().is_discontinuity(),
+            data: packet.data().data().as_slice().into(),
+        };
+        self.samples += chunk.data.len();
+        self.chunks.push_back(chunk);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        let newest = match self.chunks.back() {
+            Some(v) => v.end(),
+            None => return,
+        };
+        while let Some(oldest) = self.chunks.front() {
+            let too_many = self.limit.max_samples.is_some_and(|max| self.samples - oldest.data.len() >= max);
+            let too_old = self.limit.max_duration.is_some_and(|max| newest - oldest.end() > max.as_secs_f64() * 1000.);
+            if !too_many && !too_old {
+                break;
+            }
+            self.samples -= oldest.data.len();
+            self.chunks.pop_front();
+        }
+    }
+
+    ///Copies the chunks with samples between `tmin` and `tmax` out of the history.
+    ///Reading the copy doesn't block the decoder, which has to lock the history for every packet.
+    pub fn snapshot(&self, tmin: f64, tmax: f64) -> HistorySnapshot {
+        let first = self.chunks.partition_point(|chunk| chunk.end() < tmin);
+        HistorySnapshot {
+            chunks: self.chunks.range(first..).take_while(|chunk| chunk.start() <= tmax).cloned().collect(),
+            tmin,
+            tmax,
+        }
+    }
+}
+
+///Samples copied out of a [`History`] by [`History::snapshot`].
+#[derive(Debug, Clone)]
+pub struct HistorySnapshot {
+    chunks: Vec<Chunk>,
+    tmin: f64,
+    tmax: f64,
+}
+impl HistorySnapshot {
+    ///Calls `f` for every sample with `tmin <= timestamp <= tmax` of the snapshot in chronological order.
+    ///Timestamps are host times in milliseconds since the UNIX epoch.
+    pub fn for_each(&self, mut f: impl FnMut(HistorySample)) {
+        let (tmin, tmax) = (self.tmin, self.tmax);
+        for chunk in &self.chunks {
+            for (i, value) in chunk.data.iter().enumerate() {
+                let timestamp = chunk.timestamps.host_at(i);
+                if timestamp < tmin {
+                    continue;
+                }
+                if timestamp > tmax {
+                    break;
+                }
+                f(HistorySample {
+                    timestamp,
+                    value: *value,
+                    gap: chunk.gap && i == 0,
+                });
+            }
+        }
+    }
+    ///Returns all samples of the snapshot.
+    pub fn samples(&self) -> Vec<HistorySample> {
+        let mut out = Vec::new();
+        self.for_each(|sample| out.push(sample));
+        out
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug, Clone, Copy, serde_derive::Deserialize, serde_derive::Serialize)]
+#[derive(Debug, Clone, Copy, serde_derive::Deserialize, serde_derive::Serialize, Ord, PartialOrd, Eq, PartialEq)]
 pub struct Version {
     major: u8,
     minor: u8,
@@ -12,6 +12,11 @@ impl Version {
     pub const fn minor(&self) -> u8 { self.minor }
     pub const fn patch(&self) -> u8 { self.patch }
 }
+impl core::fmt::Display for Version {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
 #[repr(u8)]
 pub enum MessageType {
     Id = 0,
@@ -41,6 +46,10 @@ impl Id {
     pub const fn hw_version(&self) -> Version { self.hw_version }
     pub const fn sw_version(&self) -> Version { self.sw_version }
     pub const fn sw_git_hash(&self) -> &String { &self.sw_git_hash }
+    ///Returns `true`, if the firmware of the device understands [`TxMessage::SetSampleRate`].
+    pub fn supports_set_sample_rate(&self) -> bool {
+        SetSampleRate::MIN_SW_VERSION.is_some_and(|min| self.sw_version >= min)
+    }
 }
 #[derive(Debug, Clone, serde_derive::Deserialize, serde_derive::Serialize, Ord, PartialOrd, Eq, PartialEq)]
 pub struct StartOfFrame{
@@ -62,6 +71,14 @@ pub struct MeasureData{
     data: Vec<u16>,
 }
 impl MeasureData {
+    ///A packet with the given [`Self::counter`], e.g. to replay a recording.
+    pub fn new(counter: u32, data: Vec<u16>) -> Self {
+        Self {
+            package_counter: (counter & 0b11) as u8,
+            sof: StartOfFrame { content: (counter >> 2) as u16 },
+            data,
+        }
+    }
     pub const fn package_counter(&self) -> u8 { self.package_counter }
     pub const fn sof(&self) -> &StartOfFrame { &self.sof }
     pub const fn counter(&self) -> u32 {
@@ -73,6 +90,9 @@ impl MeasureData {
 pub struct MetaData{
     data: String,
 }
+impl MetaData {
+    pub const fn data(&self) -> &String { &self.data }
+}
 
 #[repr(u8)]
 #[derive(serde_derive::Serialize)]
@@ -84,6 +104,8 @@ pub enum TxMessage {
     SetRGB(SetRGB) = 4,
     SetMetaData(SetMetaData) = 5,
     GetMetaData = 6,
+    ///Provisional, no firmware defines this message yet. It is only sent to models, which opt in via [`super::model::MessageSet`].
+    SetSampleRate(SetSampleRate) = 7,
 }
 #[derive(Clone, serde_derive::Serialize, serde_derive::Deserialize)]
 pub struct SetRGB {
@@ -92,6 +114,9 @@ pub struct SetRGB {
     pub(super) b: u8,
 }
 impl SetRGB {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
     pub const fn r(&self) -> u8 { self.r }
     pub const fn g(&self) -> u8 { self.g }
     pub const fn b(&self) -> u8 { self.b }
@@ -99,4 +124,21 @@ impl SetRGB {
 #[derive(serde_derive::Serialize)]
 pub struct SetMetaData {
     data: String,
-}
\ No newline at end of file
+}
+impl SetMetaData {
+    pub const fn new(data: String) -> Self {
+        Self { data }
+    }
+}
+#[derive(Clone, serde_derive::Serialize)]
+pub struct SetSampleRate {
+    sample_rate: u32,
+}
+impl SetSampleRate {
+    ///The first firmware version, which accepts [`TxMessage::SetSampleRate`].
+    ///`None`, until a firmware release defines the message.
+    pub const MIN_SW_VERSION: Option<Version> = None;
+    pub const fn new(sample_rate: u32) -> Self {
+        Self { sample_rate }
+    }
+}
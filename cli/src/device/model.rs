@@ -0,0 +1,233 @@
+use std::path::Path;
+use std::sync::Arc;
+use serde::de::DeserializeOwned;
+
+///Byte order of a [`ProtocolProfile`].
+#[derive(Debug, Clone, Copy, Default, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endianess {
+    #[default]
+    Little,
+    Big,
+}
+impl From<Endianess> for aglio::Endianess {
+    fn from(value: Endianess) -> Self {
+        match value {
+            Endianess::Little => aglio::Endianess::Little,
+            Endianess::Big => aglio::Endianess::Big,
+        }
+    }
+}
+
+///Width of the length prefix of sequences and strings in a [`ProtocolProfile`].
+#[derive(Debug, Clone, Copy, Default, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LengthWidth {
+    U8,
+    U16,
+    #[default]
+    U32,
+}
+
+///The aglio framing a device model speaks.
+#[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct ProtocolProfile {
+    endianess: Endianess,
+    packet_start: Vec<u8>,
+    ///Whether packets end with a CRC-16 over the body.
+    crc: bool,
+    length_width: LengthWidth,
+}
+impl Default for ProtocolProfile {
+    fn default() -> Self {
+        Self {
+            endianess: Endianess::Little,
+            packet_start: aglio::AglioConfig::<u32, u16>::default().packet_start.to_vec(),
+            crc: true,
+            length_width: LengthWidth::U32,
+        }
+    }
+}
+impl ProtocolProfile {
+    fn config<S: TryFrom<usize> + serde::Serialize + DeserializeOwned + TryInto<usize>>(&self) -> aglio::AglioConfig<'_, S, u16> {
+        let default = aglio::AglioConfig::<S, u16>::default();
+        aglio::AglioConfig {
+            endianess: self.endianess.into(),
+            packet_start: &self.packet_start,
+            body_crc: if self.crc { default.body_crc } else { None },
+            phantom_data: default.phantom_data,
+        }
+    }
+    pub fn serialize<T: serde::Serialize>(&self, value: &T) -> Result<Vec<u8>, aglio::SerializeError> {
+        match self.length_width {
+            LengthWidth::U8 => aglio::serialize_with_config(self.config::<u8>(), value),
+            LengthWidth::U16 => aglio::serialize_with_config(self.config::<u16>(), value),
+            LengthWidth::U32 => aglio::serialize_with_config(self.config::<u32>(), value),
+        }
+    }
+    pub fn deserialize<'de, T: serde::Deserialize<'de>>(&self, data: &'de [u8]) -> Result<T, aglio::DeserializeError> {
+        match self.length_width {
+            LengthWidth::U8 => aglio::deserialize_with_config(self.config::<u8>(), data),
+            LengthWidth::U16 => aglio::deserialize_with_config(self.config::<u16>(), data),
+            LengthWidth::U32 => aglio::deserialize_with_config(self.config::<u32>(), data),
+        }
+    }
+}
+
+///Which of the optional [`super::messages::TxMessage`]s a device model understands.
+#[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct MessageSet {
+    ///`None` decides by the firmware version in [`super::messages::Id`].
+    ///No firmware defines [`super::messages::TxMessage::SetSampleRate`] yet, so only enable it for firmware known to understand it.
+    set_sample_rate: Option<bool>,
+    rgb: bool,
+    meta_data: bool,
+}
+impl Default for MessageSet {
+    fn default() -> Self {
+        Self {
+            set_sample_rate: None,
+            rgb: true,
+            meta_data: true,
+        }
+    }
+}
+impl MessageSet {
+    pub const fn set_sample_rate(&self) -> Option<bool> { self.set_sample_rate }
+    pub const fn rgb(&self) -> bool { self.rgb }
+    pub const fn meta_data(&self) -> bool { self.meta_data }
+}
+
+///A kind of USB device this server can talk to, and how to talk to it.
+#[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct DeviceModel {
+    name: String,
+    #[serde(deserialize_with = "deserialize_id")]
+    vendor_id: u16,
+    #[serde(deserialize_with = "deserialize_id")]
+    product_id: u16,
+    #[serde(default)]
+    interface: u8,
+    #[serde(default = "DeviceModel::default_endpoint_in")]
+    endpoint_in: u8,
+    #[serde(default = "DeviceModel::default_endpoint_out")]
+    endpoint_out: u8,
+    #[serde(default)]
+    protocol: ProtocolProfile,
+    #[serde(default)]
+    messages: MessageSet,
+    ///Sample rate to assume, if the device reports none in its [`super::messages::Id`].
+    #[serde(default)]
+    default_sample_rate: Option<u32>,
+}
+impl DeviceModel {
+    const fn default_endpoint_in() -> u8 { 0x81 }
+    const fn default_endpoint_out() -> u8 { 0x01 }
+    ///The OmnAIScope, as supported by the original OmnAIScope-DataServer.
+    pub fn omnaiscope() -> Self {
+        Self::with_ids("OmnAIScope".to_string(), 0x2e8a, 0x000a)
+    }
+    ///A device speaking the OmnAIScope protocol under different USB ids.
+    pub fn with_ids(name: String, vendor_id: u16, product_id: u16) -> Self {
+        Self {
+            name,
+            vendor_id,
+            product_id,
+            interface: 0,
+            endpoint_in: Self::default_endpoint_in(),
+            endpoint_out: Self::default_endpoint_out(),
+            protocol: ProtocolProfile::default(),
+            messages: MessageSet::default(),
+            default_sample_rate: None,
+        }
+    }
+    ///A recording of a device of the model `name`, see [`super::replay`]. It understands none of the optional messages.
+    pub fn replay(name: String) -> Self {
+        Self {
+            messages: MessageSet {
+                set_sample_rate: Some(false),
+                rgb: false,
+                meta_data: false,
+            },
+            ..Self::with_ids(name, 0, 0)
+        }
+    }
+    pub const fn name(&self) -> &String { &self.name }
+    pub const fn interface(&self) -> u8 { self.interface }
+    pub const fn endpoint_in(&self) -> u8 { self.endpoint_in }
+    pub const fn endpoint_out(&self) -> u8 { self.endpoint_out }
+    pub const fn protocol(&self) -> &ProtocolProfile { &self.protocol }
+    pub const fn messages(&self) -> &MessageSet { &self.messages }
+    pub const fn default_sample_rate(&self) -> Option<u32> { self.default_sample_rate }
+}
+
+///Accepts USB ids as numbers or as hexadecimal strings like `"0x2e8a"`.
+fn deserialize_id<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    #[derive(serde_derive::Deserialize)]
+    #[serde(untagged)]
+    enum Id {
+        Number(u16),
+        String(String),
+    }
+    match <Id as serde::Deserialize>::deserialize(deserializer)? {
+        Id::Number(v) => Ok(v),
+        Id::String(v) => parse_hex(&v).map_err(serde::de::Error::custom),
+    }
+}
+fn parse_hex(value: &str) -> Result<u16, std::num::ParseIntError> {
+    let value = value.trim();
+    u16::from_str_radix(value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")).unwrap_or(value), 16)
+}
+
+///Parses `VID:PID` or `VID:PID:NAME` with hexadecimal ids, as given on the command line.
+pub fn parse_usb_device(value: &str) -> Result<DeviceModel, String> {
+    let mut parts = value.splitn(3, ':');
+    let (vendor_id, product_id) = match (parts.next(), parts.next()) {
+        (Some(vendor_id), Some(product_id)) => (vendor_id, product_id),
+        _ => return Err(format!("Expected VID:PID or VID:PID:NAME, got {value}")),
+    };
+    let vendor_id = parse_hex(vendor_id).map_err(|err| format!("Invalid vendor id {vendor_id}: {err}"))?;
+    let product_id = parse_hex(product_id).map_err(|err| format!("Invalid product id {product_id}: {err}"))?;
+    let name = parts.next().map(str::to_string).unwrap_or_else(|| format!("{vendor_id:04x}:{product_id:04x}"));
+    Ok(DeviceModel::with_ids(name, vendor_id, product_id))
+}
+
+///All device models the server looks for when scanning the USB bus.
+#[derive(Debug, Clone)]
+pub struct DeviceModelRegistry {
+    models: Vec<Arc<DeviceModel>>,
+}
+impl Default for DeviceModelRegistry {
+    fn default() -> Self {
+        Self {
+            models: vec![Arc::new(DeviceModel::omnaiscope())],
+        }
+    }
+}
+impl DeviceModelRegistry {
+    ///Adds the models in the JSON array at `path`.
+    pub fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let json = match std::fs::read_to_string(path) {
+            Ok(v) => v,
+            Err(err) => anyhow::bail!("Failed to read device models from {}: {err}", path.display()),
+        };
+        let models: Vec<DeviceModel> = match serde_json::from_str(&json) {
+            Ok(v) => v,
+            Err(err) => anyhow::bail!("Failed to parse device models from {}: {err}", path.display()),
+        };
+        for model in models {
+            self.add(model);
+        }
+        Ok(())
+    }
+    ///Adds `model`, replacing a previously known model with the same USB ids.
+    pub fn add(&mut self, model: DeviceModel) {
+        self.models.retain(|v| v.vendor_id != model.vendor_id || v.product_id != model.product_id);
+        self.models.push(Arc::new(model));
+    }
+    pub fn find(&self, vendor_id: u16, product_id: u16) -> Option<&Arc<DeviceModel>> {
+        self.models.iter().find(|v| v.vendor_id == vendor_id && v.product_id == product_id)
+    }
+}
@@ -0,0 +1,455 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, SystemTime};
+use libusb1_sys::constants::{LIBUSB_ERROR_INTERRUPTED, LIBUSB_TRANSFER_CANCELLED, LIBUSB_TRANSFER_COMPLETED};
+use rusb::UsbContext;
+use tokio::sync::Mutex;
+use crate::MAX_MESSAGE_SIZE;
+use super::{clock, history, messages, model, sequence, Packet};
+
+///How long the threads of a [`Reader`] wait for something to happen. This bounds how long stopping a [`Reader`] takes.
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+///How long the USB thread waits, before it looks for a free buffer again, while transfers are parked for lack of one.
+const STALL_RETRY: Duration = Duration::from_millis(1);
+///How many bulk transfers are submitted at once, so the endpoint is serviced while completed transfers are handed over.
+const TRANSFERS_IN_FLIGHT: usize = 8;
+///How many filled transfer buffers may wait for decoding, before transfers have to wait for the decoder.
+const QUEUED_TRANSFERS: usize = 16;
+///Transfer buffers in circulation. The channels between the threads have room for all of them, so handing one over never blocks.
+const BUFFERS: usize = TRANSFERS_IN_FLIGHT + QUEUED_TRANSFERS;
+
+///Throughput counters of a [`Reader`].
+#[derive(Debug, Default)]
+pub struct UsbStatistics {
+    transfers: AtomicU64,
+    bytes: AtomicU64,
+    ///How often a completed transfer found no free buffer to be submitted again with, because decoding fell behind.
+    stalls: AtomicU64,
+}
+impl UsbStatistics {
+    pub fn snapshot(&self) -> UsbStatisticsSnapshot {
+        UsbStatisticsSnapshot {
+            transfers: self.transfers.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            stalls: self.stalls.load(Ordering::Relaxed),
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, Default, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct UsbStatisticsSnapshot {
+    transfers: u64,
+    bytes: u64,
+    stalls: u64,
+}
+
+///Everything the decoder thread updates, when messages from the device arrive.
+pub(super) struct Shared {
+    pub(super) id: Arc<Mutex<Option<messages::Id>>>,
+    ///Notified, whenever the device sends its [`messages::Id`].
+    pub(super) id_received: tokio::sync::watch::Sender<()>,
+    pub(super) meta_data: Arc<Mutex<Option<messages::MetaData>>>,
+    ///Notified, whenever the device sends its [`messages::MetaData`].
+    pub(super) meta_data_received: tokio::sync::watch::Sender<()>,
+    pub(super) loss_statistics: Arc<Mutex<sequence::LossStatistics>>,
+    pub(super) clock: Arc<Mutex<clock::ClockEstimate>>,
+    pub(super) usb_statistics: Arc<UsbStatistics>,
+    pub(super) history: Arc<tokio::sync::RwLock<history::History>>,
+    pub(super) tx: tokio::sync::broadcast::Sender<Packet>,
+}
+
+///Reads from a device on dedicated threads.
+///
+///One thread keeps [`TRANSFERS_IN_FLIGHT`] asynchronous bulk transfers submitted and handles their completion,
+///so the endpoint is serviced without a gap between two transfers.
+///The transfers never time out, so no partially received transfer is lost.
+///A second thread decodes the transfers and forwards them.
+///Dropping the reader cancels the transfers and stops both threads within [`POLL_TIMEOUT`].
+pub(super) struct Reader {
+    stop: Arc<AtomicBool>,
+    threads: Vec<std::thread::JoinHandle<()>>,
+}
+impl Reader {
+    pub(super) fn spawn(
+        device_handle: Arc<rusb::DeviceHandle<rusb::GlobalContext>>,
+        model: Arc<model::DeviceModel>,
+        shared: Shared,
+    ) -> anyhow::Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx_filled, rx_filled) = mpsc::sync_channel::<(Vec<u8>, SystemTime)>(BUFFERS);
+        let (tx_free, rx_free) = mpsc::sync_channel::<Vec<u8>>(BUFFERS);
+        for _ in 0..BUFFERS {
+            //Can't fail, the channel has room for every buffer.
+            tx_free.send(vec![0; MAX_MESSAGE_SIZE as usize]).ok();
+        }
+
+        let usb_statistics = shared.usb_statistics.clone();
+        let usb = {
+            let stop = stop.clone();
+            let endpoint = model.endpoint_in();
+            std::thread::Builder::new()
+                .name("usb-read".to_string())
+                .spawn(move || Self::read(&device_handle, endpoint, stop, usb_statistics, rx_free, tx_filled))
+        };
+        let usb = match usb {
+            Ok(v) => v,
+            Err(err) => anyhow::bail!("Failed to spawn usb reader thread: {err}"),
+        };
+        let decode = {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("usb-decode".to_string())
+                .spawn(move || Self::decode(&model, &stop, &shared, rx_filled, tx_free))
+        };
+        let decode = match decode {
+            Ok(v) => v,
+            Err(err) => {
+                stop.store(true, Ordering::Release);
+                anyhow::bail!("Failed to spawn usb decoder thread: {err}")
+            }
+        };
+        Ok(Self {
+            stop,
+            threads: vec![usb, decode],
+        })
+    }
+
+    ///Keeps the transfers submitted and handles libusb events, until `stop` is set and every transfer is cancelled.
+    fn read(
+        device_handle: &rusb::DeviceHandle<rusb::GlobalContext>,
+        endpoint: u8,
+        stop: Arc<AtomicBool>,
+        statistics: Arc<UsbStatistics>,
+        rx_free: mpsc::Receiver<Vec<u8>>,
+        tx_filled: mpsc::SyncSender<(Vec<u8>, SystemTime)>,
+    ) {
+        let transfers = Transfers {
+            device_handle: device_handle.as_raw(),
+            endpoint,
+            stop,
+            statistics,
+            rx_free: std::sync::Mutex::new(rx_free),
+            tx_filled,
+            parked: std::sync::Mutex::new(Vec::new()),
+            in_flight: AtomicUsize::new(0),
+        };
+        let mut slots = Vec::with_capacity(TRANSFERS_IN_FLIGHT);
+        for _ in 0..TRANSFERS_IN_FLIGHT {
+            //SAFETY: Allocating a transfer has no preconditions. It is freed below.
+            let transfer = unsafe { libusb1_sys::libusb_alloc_transfer(0) };
+            if transfer.is_null() {
+                eprintln!("Failed to allocate a usb transfer");
+                break;
+            }
+            slots.push(Box::into_raw(Box::new(Slot {
+                transfer,
+                buf: Vec::new(),
+                transfers: &raw const transfers,
+            })));
+        }
+        for slot in &slots {
+            transfers.submit(*slot);
+        }
+
+        let context = device_handle.context().as_raw();
+        loop {
+            if transfers.stop.load(Ordering::Acquire) {
+                if transfers.in_flight.load(Ordering::Acquire) == 0 {
+                    break;
+                }
+                //Cancelled again on every round, in case a callback submitted its transfer while stopping.
+                for slot in &slots {
+                    //SAFETY: The transfer is valid until it is freed below. Cancelling a transfer, which isn't submitted, does nothing.
+                    unsafe { libusb1_sys::libusb_cancel_transfer((**slot).transfer) };
+                }
+            } else {
+                let parked = std::mem::take(&mut *transfers.parked.lock().unwrap_or_else(std::sync::PoisonError::into_inner));
+                for slot in parked {
+                    transfers.submit(slot);
+                }
+            }
+            let any_parked = !transfers.parked.lock().unwrap_or_else(std::sync::PoisonError::into_inner).is_empty();
+            let timeout = if any_parked { STALL_RETRY } else { POLL_TIMEOUT };
+            let timeout = libc::timeval {
+                tv_sec: 0,
+                tv_usec: timeout.as_micros() as libc::suseconds_t,
+            };
+            //SAFETY: The context outlives the device handle, and `timeout` outlives the call.
+            let result = unsafe { libusb1_sys::libusb_handle_events_timeout_completed(context, &raw const timeout, std::ptr::null_mut()) };
+            if result < 0 && result != LIBUSB_ERROR_INTERRUPTED {
+                eprintln!("Failed to handle usb events: {}", error_name(result));
+                transfers.stop.store(true, Ordering::Release);
+            }
+        }
+        for slot in slots {
+            //SAFETY: No transfer is in flight anymore, so no callback uses the slot.
+            let slot = unsafe { Box::from_raw(slot) };
+            //SAFETY: The transfer was allocated above and isn't submitted.
+            unsafe { libusb1_sys::libusb_free_transfer(slot.transfer) };
+        }
+    }
+
+    fn decode(
+        model: &model::DeviceModel,
+        stop: &AtomicBool,
+        shared: &Shared,
+        rx_filled: mpsc::Receiver<(Vec<u8>, SystemTime)>,
+        tx_free: mpsc::SyncSender<Vec<u8>>,
+    ) {
+        let mut sequence_tracker = sequence::SequenceTracker::new();
+        let mut device_clock = clock::DeviceClock::new();
+        while !stop.load(Ordering::Acquire) {
+            let (buf, arrival) = match rx_filled.recv_timeout(POLL_TIMEOUT) {
+                Ok(v) => v,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            Self::handle(model, shared, &mut sequence_tracker, &mut device_clock, &buf, arrival);
+            if tx_free.send(buf).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn handle(
+        model: &model::DeviceModel,
+        shared: &Shared,
+        sequence_tracker: &mut sequence::SequenceTracker,
+        device_clock: &mut clock::DeviceClock,
+        buf: &[u8],
+        arrival: SystemTime,
+    ) {
+        match model.protocol().deserialize(buf) {
+            Ok(message) => Self::handle_message(model, shared, sequence_tracker, device_clock, message, arrival),
+            Err(err) => {
+                eprintln!("Failed to deserialize message: {err}");
+            }
+        }
+    }
+
+    ///Updates `shared` with a message of the device, which arrived at `arrival`.
+    pub(super) fn handle_message(
+        model: &model::DeviceModel,
+        shared: &Shared,
+        sequence_tracker: &mut sequence::SequenceTracker,
+        device_clock: &mut clock::DeviceClock,
+        message: messages::RxMessage,
+        arrival: SystemTime,
+    ) {
+        match message {
+            messages::RxMessage::Id(new_id) => {
+                *shared.id.blocking_lock() = Some(new_id);
+                shared.id_received.send_replace(());
+            },
+            messages::RxMessage::MetaData(new_meta_data) => {
+                *shared.meta_data.blocking_lock() = Some(new_meta_data);
+                shared.meta_data_received.send_replace(());
+            },
+            messages::RxMessage::MeasureData(measure_data) => {
+                let (sequence, counter) = sequence_tracker.track(&measure_data);
+                *shared.loss_statistics.blocking_lock() = sequence_tracker.statistics().clone();
+                if !sequence.is_forwarded() {
+                    return;
+                }
+                if sequence == sequence::SequenceEvent::Restart {
+                    device_clock.reset();
+                }
+                let sample_rate = shared.id.blocking_lock().as_ref().map(messages::Id::sample_rate).filter(|v| *v > 0).or(model.default_sample_rate());
+                let timestamps = device_clock.timestamps(sample_rate, counter, measure_data.data().len(), arrival);
+                *shared.clock.blocking_lock() = device_clock.estimate();
+                let packet = Packet{ data: measure_data, sequence, counter, timestamps };
+                shared.history.blocking_write().push(&packet);
+                //Nobody may be listening, the samples are still in the history.
+                shared.tx.send(packet).ok();
+            },
+        }
+    }
+}
+impl Drop for Reader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                eprintln!("Usb reader thread panicked");
+            }
+        }
+    }
+}
+
+///State of the transfers of a [`Reader`], shared between its USB thread and the completion callbacks.
+///
+///Callbacks run on whichever thread handles libusb events, so everything they touch is synchronized.
+struct Transfers {
+    device_handle: *mut libusb1_sys::libusb_device_handle,
+    endpoint: u8,
+    stop: Arc<AtomicBool>,
+    statistics: Arc<UsbStatistics>,
+    rx_free: std::sync::Mutex<mpsc::Receiver<Vec<u8>>>,
+    tx_filled: mpsc::SyncSender<(Vec<u8>, SystemTime)>,
+    ///Slots, which found no free buffer and wait for the USB thread to submit them again.
+    parked: std::sync::Mutex<Vec<*mut Slot>>,
+    ///Number of submitted transfers, whose callback hasn't returned yet.
+    in_flight: AtomicUsize,
+}
+impl Transfers {
+    ///Submits the transfer of `slot` with a free buffer, or parks it, if there is none.
+    fn submit(&self, slot: *mut Slot) {
+        if self.stop.load(Ordering::Acquire) {
+            return;
+        }
+        let buf = self.rx_free.lock().unwrap_or_else(std::sync::PoisonError::into_inner).try_recv();
+        let mut buf = match buf {
+            Ok(v) => v,
+            Err(mpsc::TryRecvError::Empty) => {
+                self.statistics.stalls.fetch_add(1, Ordering::Relaxed);
+                self.parked.lock().unwrap_or_else(std::sync::PoisonError::into_inner).push(slot);
+                return;
+            },
+            Err(mpsc::TryRecvError::Disconnected) => {
+                self.stop.store(true, Ordering::Release);
+                return;
+            },
+        };
+        buf.resize(MAX_MESSAGE_SIZE as usize, 0);
+        //SAFETY: The slot isn't submitted, so nothing else uses it.
+        let slot = unsafe { &mut *slot };
+        slot.buf = buf;
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        //SAFETY: The buffer stays in the slot and the slot stays allocated, until the transfer completed.
+        let result = unsafe {
+            libusb1_sys::libusb_fill_bulk_transfer(
+                slot.transfer,
+                self.device_handle,
+                self.endpoint,
+                slot.buf.as_mut_ptr(),
+                MAX_MESSAGE_SIZE.into(),
+                transfer_completed,
+                std::ptr::from_mut(slot).cast(),
+                0,
+            );
+            libusb1_sys::libusb_submit_transfer(slot.transfer)
+        };
+        if result != 0 {
+            eprintln!("Failed to submit usb transfer: {}", error_name(result));
+            self.stop.store(true, Ordering::Release);
+            self.in_flight.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+///A transfer of a [`Reader`] and the buffer it reads into.
+struct Slot {
+    transfer: *mut libusb1_sys::libusb_transfer,
+    buf: Vec<u8>,
+    transfers: *const Transfers,
+}
+
+///Hands the buffer of a completed transfer to the decoder and submits the transfer again.
+extern "system" fn transfer_completed(transfer: *mut libusb1_sys::libusb_transfer) {
+    //SAFETY: The transfer was filled by `Transfers::submit` with its slot as user data.
+    let (slot, status, len) = unsafe { ((*transfer).user_data.cast::<Slot>(), (*transfer).status, (*transfer).actual_length) };
+    //SAFETY: The transfers outlive all slots, and this callback is the only one using the slot of a submitted transfer.
+    let transfers = unsafe { &*(*slot).transfers };
+    match status {
+        LIBUSB_TRANSFER_COMPLETED => {
+            let arrival = SystemTime::now();
+            //SAFETY: See above.
+            let mut buf = std::mem::take(unsafe { &mut (*slot).buf });
+            buf.truncate(usize::try_from(len).unwrap_or(0));
+            transfers.statistics.transfers.fetch_add(1, Ordering::Relaxed);
+            transfers.statistics.bytes.fetch_add(buf.len() as u64, Ordering::Relaxed);
+            //Can only fail, once the decoder is gone, as the channel has room for every buffer.
+            if transfers.tx_filled.try_send((buf, arrival)).is_err() {
+                transfers.stop.store(true, Ordering::Release);
+            }
+            transfers.submit(slot);
+        },
+        LIBUSB_TRANSFER_CANCELLED => {},
+        status => {
+            eprintln!("Failed to read from device: {}", transfer_status_name(status));
+            transfers.stop.store(true, Ordering::Release);
+        },
+    }
+    //Last, as the USB thread frees the transfers once none is in flight anymore.
+    transfers.in_flight.fetch_sub(1, Ordering::AcqRel);
+}
+
+fn transfer_status_name(status: std::ffi::c_int) -> &'static str {
+    use libusb1_sys::constants::*;
+    match status {
+        LIBUSB_TRANSFER_ERROR => "Transfer failed",
+        LIBUSB_TRANSFER_TIMED_OUT => "Transfer timed out",
+        LIBUSB_TRANSFER_STALL => "Endpoint stalled",
+        LIBUSB_TRANSFER_NO_DEVICE => "No such device (it may have been disconnected)",
+        LIBUSB_TRANSFER_OVERFLOW => "Device sent more data than requested",
+        _ => "Unknown transfer status",
+    }
+}
+
+fn error_name(code: std::ffi::c_int) -> String {
+    //SAFETY: libusb returns a static, NUL-terminated string for every code.
+    unsafe { std::ffi::CStr::from_ptr(libusb1_sys::libusb_error_name(code)) }.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES_PER_PACKET: usize = 64;
+    const PACKETS: u32 = 20_000;
+
+    ///Feeds packets as fast as possible through the decoder thread, like the USB thread does,
+    ///and checks that every buffer is returned and every packet forwarded.
+    #[test]
+    fn decoder_forwards_every_packet() {
+        let model = Arc::new(model::DeviceModel::omnaiscope());
+        let (tx, mut rx) = tokio::sync::broadcast::channel(PACKETS as usize);
+        let shared = Shared {
+            id: Arc::new(Mutex::new(None)),
+            id_received: tokio::sync::watch::Sender::new(()),
+            meta_data: Arc::new(Mutex::new(None)),
+            meta_data_received: tokio::sync::watch::Sender::new(()),
+            loss_statistics: Arc::new(Mutex::new(sequence::LossStatistics::default())),
+            clock: Arc::new(Mutex::new(clock::ClockEstimate::default())),
+            usb_statistics: Arc::new(UsbStatistics::default()),
+            history: Arc::new(tokio::sync::RwLock::new(history::History::new(history::HistoryLimit::default()))),
+            tx,
+        };
+        let packets = (0..PACKETS)
+            .map(|counter| {
+                let data = (0..SAMPLES_PER_PACKET).map(|v| v as u16).collect();
+                model.protocol().serialize(&messages::RxMessage::MeasureData(messages::MeasureData::new(counter, data))).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx_filled, rx_filled) = mpsc::sync_channel(BUFFERS);
+        let (tx_free, rx_free) = mpsc::sync_channel(BUFFERS);
+        let decode = {
+            let model = model.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || Reader::decode(&model, &stop, &shared, rx_filled, tx_free))
+        };
+        let start = std::time::Instant::now();
+        let mut returned = 0;
+        for packet in packets {
+            tx_filled.send((packet, SystemTime::now())).unwrap();
+            //Take back the buffers like the USB thread, so the decoder never waits for room.
+            while rx_free.try_recv().is_ok() {
+                returned += 1;
+            }
+        }
+        drop(tx_filled);
+        //Ends, once the decoder is done and drops its sender.
+        returned += rx_free.iter().count();
+        decode.join().unwrap();
+        let throughput = f64::from(PACKETS) * SAMPLES_PER_PACKET as f64 / start.elapsed().as_secs_f64();
+        println!("Decoded {throughput:.0} samples/s");
+        assert_eq!(returned, PACKETS as usize);
+        let mut counters = Vec::new();
+        while let Ok(packet) = rx.try_recv() {
+            assert_eq!(packet.data().data().len(), SAMPLES_PER_PACKET);
+            counters.push(packet.counter());
+        }
+        assert_eq!(counters, (0..u64::from(PACKETS)).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,147 @@
+//! Virtual devices, which replay a capture file recorded with `--capture`.
+//!
+//! The recorded packets run through the same [`reader::Reader::handle_message`] as packets read from a device,
+//! so consumers can't tell a replay from hardware.
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use crate::record::capture::CaptureReader;
+use super::{clock, messages, model, reader, sequence};
+
+///How long a paused replay sleeps, before it checks again whether it was resumed or stopped.
+const IDLE_POLL: Duration = Duration::from_millis(50);
+///How long a replay at maximum speed sleeps, before it checks again whether the consumers caught up.
+const BACKLOG_POLL: Duration = Duration::from_millis(1);
+///Packets a replay at maximum speed leaves queued for the slowest consumer, so it doesn't lag.
+const MAX_BACKLOG: usize = super::CHANNEL_CAPACITY / 2;
+
+///Parses a replay speed: a factor of the recorded speed, or `max` to replay as fast as possible.
+pub fn parse_speed(value: &str) -> Result<f64, String> {
+    if value.eq_ignore_ascii_case("max") {
+        return Ok(f64::INFINITY);
+    }
+    match value.parse::<f64>() {
+        Ok(v) if v > 0. && v.is_finite() => Ok(v),
+        Ok(_) => Err("the speed must be greater than 0".to_string()),
+        Err(err) => Err(format!("invalid speed: {err}")),
+    }
+}
+
+///Replays a single device of a capture file on a dedicated thread, whilst the device is capturing.
+///Dropping the replay stops the thread.
+pub(super) struct Replay {
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+impl Replay {
+    ///Replays the `device`th device of the capture file at `path` at `speed` times the recorded speed.
+    pub(super) fn spawn(
+        path: Arc<PathBuf>,
+        device: usize,
+        speed: f64,
+        model: Arc<model::DeviceModel>,
+        capturing: Arc<AtomicBool>,
+        shared: reader::Shared,
+    ) -> anyhow::Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("replay".to_string())
+                .spawn(move || {
+                    match Self::run(&path, device, speed, &model, &capturing, &stop, &shared) {
+                        Ok(true) => println!("Finished replaying device {device} of {}", path.display()),
+                        Ok(false) => (),
+                        Err(err) => eprintln!("Failed to replay device {device} of {}: {err}", path.display()),
+                    }
+                })
+        };
+        match thread {
+            Ok(thread) => Ok(Self { stop, thread: Some(thread) }),
+            Err(err) => anyhow::bail!("Failed to spawn replay thread: {err}"),
+        }
+    }
+
+    ///Returns `true`, if the whole recording was replayed, and `false`, if the replay was stopped.
+    fn run(
+        path: &Path,
+        device: usize,
+        speed: f64,
+        model: &model::DeviceModel,
+        capturing: &AtomicBool,
+        stop: &AtomicBool,
+        shared: &reader::Shared,
+    ) -> anyhow::Result<bool> {
+        let mut capture = CaptureReader::open(path)?;
+        let mut sequence_tracker = sequence::SequenceTracker::new();
+        let mut device_clock = clock::DeviceClock::new();
+        //When the replay (re)started, in wall clock time and in recorded time in ms since the UNIX epoch.
+        let mut anchor: Option<(Instant, f64)> = None;
+        //Added to recorded timestamps, so the replay appears to happen now.
+        let mut shift = 0.;
+        for chunk in capture.chunks(Some(device)) {
+            let chunk = chunk?;
+            let packet_len = chunk.packet_len();
+            if packet_len == 0 {
+                anyhow::bail!("The capture file doesn't store how many samples the device sent per packet");
+            }
+            for (k, data) in chunk.samples().chunks(packet_len as usize).enumerate() {
+                //Packets arrive, once their last sample was taken.
+                let recorded = chunk.timestamp() + ((k * packet_len as usize + data.len()).saturating_sub(1)) as f64 * chunk.interval();
+                while !capturing.load(Ordering::Acquire) {
+                    if stop.load(Ordering::Acquire) {
+                        return Ok(false);
+                    }
+                    anchor = None;
+                    std::thread::sleep(IDLE_POLL);
+                }
+                let (anchor_wall, anchor_recorded) = *anchor.get_or_insert_with(|| {
+                    shift = now_ms() - recorded;
+                    (Instant::now(), recorded)
+                });
+                if speed.is_finite() {
+                    let due = anchor_wall + Duration::from_secs_f64(((recorded - anchor_recorded) / speed / 1000.).max(0.));
+                    loop {
+                        if stop.load(Ordering::Acquire) {
+                            return Ok(false);
+                        }
+                        let now = Instant::now();
+                        if now >= due {
+                            break;
+                        }
+                        std::thread::sleep((due - now).min(IDLE_POLL));
+                    }
+                } else {
+                    //Consumers lose packets, which they don't receive before the channel is full.
+                    while shared.tx.len() >= MAX_BACKLOG {
+                        if stop.load(Ordering::Acquire) {
+                            return Ok(false);
+                        }
+                        std::thread::sleep(BACKLOG_POLL);
+                    }
+                    if stop.load(Ordering::Acquire) {
+                        return Ok(false);
+                    }
+                }
+                let counter = chunk.counter() + k as u64;
+                let message = messages::RxMessage::MeasureData(messages::MeasureData::new(counter as u32, data.to_vec()));
+                let arrival = SystemTime::UNIX_EPOCH + Duration::from_secs_f64(((recorded + shift) / 1000.).max(0.));
+                reader::Reader::handle_message(model, shared, &mut sequence_tracker, &mut device_clock, message, arrival);
+            }
+        }
+        Ok(true)
+    }
+}
+impl Drop for Replay {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() && thread.join().is_err() {
+            eprintln!("Replay thread panicked");
+        }
+    }
+}
+
+fn now_ms() -> f64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs_f64() * 1000.
+}
@@ -0,0 +1,199 @@
+use super::messages::MeasureData;
+
+///Number of distinct values [`MeasureData::counter`] can take, before it wraps around.
+///The counter is made up of the 16-bit `sof` and the lower 2 bits of the `package_counter`.
+pub const COUNTER_MODULUS: u32 = 1 << 18;
+///How many packets before the newest one we remember, to tell duplicates and reordered packets apart.
+const WINDOW: u32 = 64;
+
+///How a received packet relates to the packets received before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SequenceEvent {
+    ///The first packet received from the device.
+    First,
+    ///The packet directly follows the previous one.
+    InOrder,
+    ///`missing` packets were lost between the previous packet and this one.
+    Gap { missing: u32 },
+    ///The packet was already received.
+    Duplicate,
+    ///The packet was previously counted as lost, but arrived late.
+    Reordered,
+    ///The counter jumped backwards too far to be a late packet. The device most likely restarted its counter.
+    ///
+    ///A jump forwards by half of [`COUNTER_MODULUS`] or more is a restart as well, as it can't be told apart from a jump backwards.
+    Restart,
+}
+impl SequenceEvent {
+    ///Returns `true`, if the samples of this packet should be forwarded to consumers.
+    ///Duplicated and late packets are not forwarded, as the stream has already moved past them.
+    pub const fn is_forwarded(&self) -> bool {
+        !matches!(self, Self::Duplicate | Self::Reordered)
+    }
+    ///Returns `true`, if the samples of this packet do not continue the previously forwarded samples.
+    pub const fn is_discontinuity(&self) -> bool {
+        matches!(self, Self::Gap { .. } | Self::Restart)
+    }
+}
+
+///Per-device packet-loss statistics.
+#[derive(Debug, Clone, Default, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct LossStatistics {
+    received: u64,
+    lost: u64,
+    gaps: u64,
+    duplicates: u64,
+    reordered: u64,
+    restarts: u64,
+}
+impl LossStatistics {
+    ///Fraction of packets lost, relative to all packets the device should have sent.
+    pub fn loss_ratio(&self) -> f64 {
+        let expected = self.received.saturating_sub(self.duplicates) + self.lost;
+        if expected == 0 {
+            0.0
+        } else {
+            self.lost as f64 / expected as f64
+        }
+    }
+}
+
+///Tracks the expected next [`MeasureData::counter`] of a device.
+///
+///Also unwraps the counter into a monotonically increasing 64-bit sequence number.
+#[derive(Debug, Clone, Default)]
+pub struct SequenceTracker {
+    ///Unwrapped counter of the newest packet received.
+    newest: Option<u64>,
+    ///Raw counter of the newest packet received.
+    counter: u32,
+    ///Bit `n` is set, if the packet `n` packets before the newest one was received.
+    seen: u64,
+    statistics: LossStatistics,
+}
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub const fn statistics(&self) -> &LossStatistics {
+        &self.statistics
+    }
+    ///Classifies `data` and returns the event alongside the unwrapped counter of the packet.
+    pub fn track(&mut self, data: &MeasureData) -> (SequenceEvent, u64) {
+        self.track_counter(data.counter())
+    }
+
+    fn track_counter(&mut self, counter: u32) -> (SequenceEvent, u64) {
+        self.statistics.received += 1;
+        let newest = match self.newest {
+            Some(v) => v,
+            None => {
+                self.newest = Some(u64::from(counter));
+                self.counter = counter;
+                self.seen = 1;
+                return (SequenceEvent::First, u64::from(counter));
+            }
+        };
+        let ahead = counter.wrapping_sub(self.counter) % COUNTER_MODULUS;
+        if ahead != 0 && ahead < COUNTER_MODULUS / 2 {
+            let unwrapped = newest + u64::from(ahead);
+            self.newest = Some(unwrapped);
+            self.counter = counter;
+            self.seen = self.seen.checked_shl(ahead).unwrap_or(0) | 1;
+            return if ahead == 1 {
+                (SequenceEvent::InOrder, unwrapped)
+            } else {
+                let missing = ahead - 1;
+                self.statistics.lost += u64::from(missing);
+                self.statistics.gaps += 1;
+                (SequenceEvent::Gap { missing }, unwrapped)
+            };
+        }
+        let behind = (COUNTER_MODULUS - ahead) % COUNTER_MODULUS;
+        if behind < WINDOW && u64::from(behind) <= newest {
+            let unwrapped = newest - u64::from(behind);
+            let bit = 1u64 << behind;
+            if self.seen & bit != 0 {
+                self.statistics.duplicates += 1;
+                (SequenceEvent::Duplicate, unwrapped)
+            } else {
+                self.seen |= bit;
+                self.statistics.lost = self.statistics.lost.saturating_sub(1);
+                self.statistics.reordered += 1;
+                (SequenceEvent::Reordered, unwrapped)
+            }
+        } else {
+            //Keep the unwrapped counter monotonic across the restart, so consumers never see it go backwards.
+            let unwrapped = newest + 1;
+            self.newest = Some(unwrapped);
+            self.counter = counter;
+            self.seen = 1;
+            self.statistics.restarts += 1;
+            (SequenceEvent::Restart, unwrapped)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(tracker: &mut SequenceTracker, counters: impl IntoIterator<Item = u32>) -> Vec<(SequenceEvent, u64)> {
+        counters.into_iter().map(|counter| tracker.track_counter(counter)).collect()
+    }
+
+    #[test]
+    fn wraps_at_the_modulus() {
+        let mut tracker = SequenceTracker::new();
+        let events = track(&mut tracker, [COUNTER_MODULUS - 2, COUNTER_MODULUS - 1, 0, 1]);
+        let modulus = u64::from(COUNTER_MODULUS);
+        assert_eq!(
+            events,
+            [(SequenceEvent::First, modulus - 2), (SequenceEvent::InOrder, modulus - 1), (SequenceEvent::InOrder, modulus), (SequenceEvent::InOrder, modulus + 1)]
+        );
+        //A gap across the wrap.
+        assert_eq!(tracker.track_counter(4), (SequenceEvent::Gap { missing: 2 }, modulus + 4));
+    }
+
+    #[test]
+    fn late_packet_after_a_gap() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(track(&mut tracker, [10, 13]), [(SequenceEvent::First, 10), (SequenceEvent::Gap { missing: 2 }, 13)]);
+        assert_eq!(tracker.statistics().lost, 2);
+        assert_eq!(tracker.track_counter(11), (SequenceEvent::Reordered, 11));
+        assert_eq!(tracker.statistics().lost, 1);
+        assert_eq!(tracker.statistics().reordered, 1);
+        //The late packet doesn't move the stream backwards.
+        assert_eq!(tracker.track_counter(14), (SequenceEvent::InOrder, 14));
+    }
+
+    #[test]
+    fn duplicate_within_the_window() {
+        let mut tracker = SequenceTracker::new();
+        track(&mut tracker, 100..=100 + WINDOW);
+        assert_eq!(tracker.track_counter(101), (SequenceEvent::Duplicate, 101));
+        assert_eq!(tracker.track_counter(100 + WINDOW), (SequenceEvent::Duplicate, u64::from(100 + WINDOW)));
+        assert_eq!(tracker.statistics().duplicates, 2);
+        assert_eq!(tracker.statistics().lost, 0);
+    }
+
+    #[test]
+    fn backwards_beyond_the_window_restarts() {
+        let mut tracker = SequenceTracker::new();
+        track(&mut tracker, [1000, 1001]);
+        assert_eq!(tracker.track_counter(1001 - WINDOW), (SequenceEvent::Restart, 1002));
+        assert_eq!(tracker.track_counter(1002 - WINDOW), (SequenceEvent::InOrder, 1003));
+        assert_eq!(tracker.statistics().restarts, 1);
+    }
+
+    #[test]
+    fn forwards_by_half_the_modulus_restarts() {
+        let mut tracker = SequenceTracker::new();
+        track(&mut tracker, [5]);
+        assert_eq!(tracker.track_counter(5 + COUNTER_MODULUS / 2 - 1), (SequenceEvent::Gap { missing: COUNTER_MODULUS / 2 - 2 }, u64::from(5 + COUNTER_MODULUS / 2 - 1)));
+        let mut tracker = SequenceTracker::new();
+        track(&mut tracker, [5]);
+        assert_eq!(tracker.track_counter(5 + COUNTER_MODULUS / 2), (SequenceEvent::Restart, 6));
+    }
+}
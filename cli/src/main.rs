@@ -2,16 +2,22 @@ use std::sync::Arc;
 use std::time::Duration;
 use clap::Parser;
 use tokio::sync::RwLock;
+use crate::aliases::AliasRegistry;
+use crate::calibration::CalibrationRegistry;
+use crate::catalog::Catalog;
 use crate::device::DeviceList;
 use crate::options::Options;
 
+mod aliases;
+mod calibration;
+mod catalog;
 mod options;
 mod webserver;
 mod device;
 mod routes;
+mod signal;
+mod record;
 
-const USB_VID: u16 = 0x2e8a;
-const USB_PID: u16 = 0x000a;
 const MAX_MESSAGE_SIZE: u16 = 2_u16.pow(12);
 const MAX_MESSAGE_BUF: u32 = 2_u32.pow(15);
 
@@ -24,14 +30,100 @@ async fn main() {
             None => println!("Running Version: {}-development", env!("CARGO_PKG_VERSION")),
         }
     }
-    let mut device_list = device::DeviceList::new();
+    if let Some(command) = options.command() {
+        match command.clone() {
+            options::Command::Export { input, output, calibrated } => {
+                let calibrations = match CalibrationRegistry::load(options.calibrations().clone()) {
+                    Ok(v) => v,
+                    Err(err) => {
+                        eprintln!("Error loading calibrations: {err}");
+                        return;
+                    }
+                };
+                match tokio::task::spawn_blocking(move || record::export(&input, &output, calibrations.profiles(), calibrated)).await {
+                    Ok(Ok(())) => {},
+                    Ok(Err(err)) => eprintln!("Error exporting: {err}"),
+                    Err(err) => eprintln!("Error exporting: {err}"),
+                }
+            },
+            options::Command::Sessions { filter } => {
+                let path = options.catalog().clone();
+                let sessions = tokio::task::spawn_blocking(move || Catalog::open(&path)?.search(&filter)).await;
+                match sessions {
+                    Ok(Ok(sessions)) => for session in sessions {
+                        match serde_json::to_string(&session) {
+                            Ok(json) => println!("{json}"),
+                            Err(err) => eprintln!("Error serializing session: {err}"),
+                        }
+                    },
+                    Ok(Err(err)) => eprintln!("Error querying the catalog: {err}"),
+                    Err(err) => eprintln!("Error querying the catalog: {err}"),
+                }
+            },
+        }
+        return;
+    }
+    let aliases = match AliasRegistry::load(options.aliases().clone()) {
+        Ok(v) => v,
+        Err(err) => {
+            eprintln!("Error loading aliases: {err}");
+            return;
+        }
+    };
+    let calibrations = match CalibrationRegistry::load(options.calibrations().clone()) {
+        Ok(v) => v,
+        Err(err) => {
+            eprintln!("Error loading calibrations: {err}");
+            return;
+        }
+    };
+    //Only recordings and the sessions routes need the catalog. Without it, files are still recorded, but not cataloged.
+    let catalog = if options.output().is_some() || options.websocket() {
+        match Catalog::open(options.catalog()) {
+            Ok(v) => Some(Arc::new(v)),
+            Err(err) => {
+                eprintln!("Error opening catalog, sessions won't be cataloged: {err}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut models = device::model::DeviceModelRegistry::default();
+    if let Some(path) = options.models() && let Err(err) = models.load(path) {
+        eprintln!("Error loading device models: {err}");
+        return;
+    }
+    for model in options.usb_device() {
+        models.add(model.clone());
+    }
+    let history_seconds = match Duration::try_from_secs_f64(options.history_seconds()) {
+        Ok(v) => v,
+        Err(err) => {
+            eprintln!("Invalid history duration: {err}");
+            return;
+        }
+    };
+    let history_limit = device::history::HistoryLimit::new(options.history_samples(), Some(history_seconds));
+    let mut device_list = device::DeviceList::new(models, history_limit);
+    if let Some(path) = options.replay() && let Err(err) = device_list.add_replay(path.clone(), options.replay_speed()) {
+        eprintln!("Error loading replay {}: {err}", path.display());
+        return;
+    }
     if options.search() {
         match device_list.scan_for_new_devices().await {
             Ok(()) => {
                 println!("Found devices:");
                 tokio::time::sleep(Duration::from_secs(1)).await;
                 for device in device_list.list_send() {
-                    let serializable_device = device.serializable_device().await;
+                    if !options.device().is_empty() {
+                        let serial = device.id().await.map(|id|id.serial().clone());
+                        let alias = device.alias(&aliases).await;
+                        if !options.device().iter().any(|name| Some(aliases.resolve(name)) == serial.as_deref() || Some(name) == alias.as_ref()) {
+                            continue;
+                        }
+                    }
+                    let serializable_device = device.serializable_device(&aliases).await;
                     match serde_json::to_string(&serializable_device) {
                         Ok(json) => println!("{json}"),
                         Err(err) => {
@@ -44,27 +136,106 @@ async fn main() {
             Err(err) => eprintln!("Error scanning for devices: {}", err),
         }
     }
+    let mut recorder = None;
+    if let Some(output) = options.output() {
+        let rotation = record::Rotation {
+            max_bytes: options.rotate_size(),
+            max_duration: match options.rotate_seconds().map(Duration::try_from_secs_f64) {
+                Some(Ok(v)) if !v.is_zero() => Some(v),
+                Some(Ok(_)) => {
+                    eprintln!("Invalid rotation duration: must be greater than 0");
+                    return;
+                },
+                Some(Err(err)) => {
+                    eprintln!("Invalid rotation duration: {err}");
+                    return;
+                },
+                None => None,
+            },
+        };
+        if !options.search() {
+            if let Err(err) = device_list.scan_for_new_devices().await {
+                eprintln!("Error scanning for devices: {}", err);
+                return;
+            }
+            //Give the devices time to report their ids.
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        let format = if options.capture() {
+            record::RecordFormat::Capture { compression: options.zstd() }
+        } else if options.wav() {
+            record::RecordFormat::Wav
+        } else if options.json() {
+            record::RecordFormat::JsonLines
+        } else {
+            record::RecordFormat::Csv
+        };
+        match record::Recorder::start(&device_list, &aliases, &calibrations, options.device(), record::Output { path: output.clone(), format, rotation }, catalog.clone()).await {
+            Ok(v) => recorder = Some(v),
+            Err(err) => {
+                eprintln!("Error starting recording: {err}");
+                return;
+            }
+        }
+    }
+    //The recorder needs the devices and aliases after the websocket server ended.
+    let device_list = Arc::new(RwLock::new(device_list));
+    let aliases = Arc::new(RwLock::new(aliases));
     if options.websocket() {
         if options.port() == 0 {
             eprintln!("Port must be greater than 0");
-            return;
-        }
-        if let Err(err) = run_websocket(options.clone(), device_list).await {
+        } else if let Err(err) = run_websocket(options.clone(), device_list.clone(), aliases.clone(), calibrations, catalog).await {
             eprintln!("Error starting websocket server: {}", err);
-            return;
         }
+    } else if recorder.is_some() && let Err(err) = tokio::signal::ctrl_c().await {
+        eprintln!("Error waiting for Ctrl-C: {err}");
+    }
+    if let Some(recorder) = recorder {
+        eprintln!("Finishing recording");
+        recorder.stop(&*device_list.read().await, &*aliases.read().await).await;
     }
 }
 
-async fn run_websocket(option: Options, device_list: DeviceList) -> Result<rocket::Rocket<rocket::Ignite>, rocket::Error>{
+async fn run_websocket(option: Options, device_list: Arc<RwLock<DeviceList>>, aliases: Arc<RwLock<AliasRegistry>>, calibrations: CalibrationRegistry, catalog: Option<Arc<Catalog>>) -> Result<rocket::Rocket<rocket::Ignite>, rocket::Error>{
     let rocket = rocket::build();
     let figment = rocket.figment().clone()
                 .merge((rocket::Config::PORT, option.port()));
     rocket
         .configure(figment)
-        .manage(Arc::new(RwLock::new(device_list)))
+        .manage(device_list)
+        .manage(aliases)
+        .manage(Arc::new(RwLock::new(calibrations)))
+        .manage(Arc::new(RwLock::new(routes::metrics::SessionRegistry::default())))
+        .manage(catalog)
+        .manage(Arc::new(routes::recordings::Recordings::new(option.recordings().clone())))
         .mount("/", rocket::routes![
             routes::get_devices,
+            routes::get_statistics,
+            routes::get_metrics,
+            routes::get_recordings,
+            routes::get_recording_downsampled,
+            routes::get_recording_export,
+            routes::put_rgb,
+            routes::get_metadata,
+            routes::put_metadata,
+            routes::identify,
+            routes::get_history,
+            routes::post_annotation,
+            routes::get_aliases,
+            routes::put_alias,
+            routes::delete_alias,
+            routes::get_calibrations,
+            routes::get_calibration,
+            routes::put_calibration,
+            routes::delete_calibration,
+            routes::get_calibration_measurement,
+            routes::post_calibration_fit,
+            routes::get_sessions,
+            routes::get_session,
+            routes::put_session_annotation,
+            routes::put_session_tag,
+            routes::delete_session_tag,
+            routes::delete_session,
             routes::help,
             routes::ws_impl,
         ])
@@ -7,31 +7,112 @@ pub struct Options {
     ///Prints all connected devices, color identical to current LED color
     search: bool,
     #[arg(long)]
-    ///Start the devices with the given UUIDs
-    device: Vec<uuid::Uuid>,
+    ///Start the devices with the given UUIDs (serials) or aliases
+    device: Vec<String>,
+    #[arg(long, default_value = "aliases.json")]
+    ///File in which device aliases are stored
+    aliases: std::path::PathBuf,
+    #[arg(long, default_value = "calibrations.json")]
+    ///File in which the calibrations of devices into engineering units are stored
+    calibrations: std::path::PathBuf,
+    #[arg(long)]
+    ///JSON file with additional device models (USB ids, endpoints and protocol) to look for
+    models: Option<std::path::PathBuf>,
+    #[arg(long, value_parser = crate::device::model::parse_usb_device)]
+    ///Additionally look for devices speaking the OmnAIScope protocol under these hexadecimal USB ids, given as VID:PID or VID:PID:NAME
+    usb_device: Vec<crate::device::model::DeviceModel>,
+    #[arg(long)]
+    ///Keep at most this many recent samples per device in memory
+    history_samples: Option<usize>,
+    #[arg(long, default_value = "60")]
+    ///Keep the samples of at most this many recent seconds per device in memory
+    history_seconds: f64,
     #[arg(short, long, default_value = "false")]
     ///Add extra for debugging information
     verbose: bool,
     #[arg(short, long)]
-    ///Add a file you want the data to be saved in
+    ///Records the samples of the devices given by --device (or of all devices) into this file, until Ctrl-C
     output: Option<std::path::PathBuf>,
     #[arg(short, long, default_value = "false")]
-    ///Add if you want the file to be in a JSON format
+    ///Records in the JSON lines format instead of CSV
     json: bool,
+    #[arg(long, default_value = "false", conflicts_with = "json")]
+    ///Records in the compact native capture format instead of CSV
+    capture: bool,
+    #[arg(long, num_args = 0..=1, default_missing_value = "3", requires = "capture")]
+    ///Compresses the chunks of a --capture recording with zstd at this level
+    zstd: Option<i32>,
+    #[arg(long, default_value = "false", conflicts_with_all = ["json", "capture"])]
+    ///Records into a 16-bit PCM WAV file with a channel per device instead of CSV
+    wav: bool,
+    #[arg(long, value_parser = crate::record::parse_size)]
+    ///Continues recording in a new file, once the current one has this size in bytes. Accepts k, M and G suffixes
+    rotate_size: Option<u64>,
+    #[arg(long)]
+    ///Continues recording in a new file, once the current one has been written to for this many seconds
+    rotate_seconds: Option<f64>,
+    #[arg(long, default_value = "recordings")]
+    ///Directory of the capture files served under /recordings
+    recordings: std::path::PathBuf,
+    #[arg(long, default_value = "catalog.sqlite")]
+    ///SQLite database, in which every recorded file is entered as a session
+    catalog: std::path::PathBuf,
+    #[arg(long)]
+    ///Adds a virtual device for every device recorded in this --capture file, which replays the recording while it is capturing
+    replay: Option<std::path::PathBuf>,
+    #[arg(long, default_value = "1", value_parser = crate::device::replay::parse_speed)]
+    ///Replays at this multiple of the recorded speed, or as fast as possible with "max"
+    replay_speed: f64,
     #[arg(short, long, default_value = "true")]
     ///Starts the websocket. To send data a UUID has to be given
     websocket: bool,
     #[arg(short, long, default_value = "8080")]
     ///Sets the port for the websocket to start on.
     port: u16,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+#[derive(clap_derive::Subcommand, Debug, Clone)]
+pub enum Command {
+    ///Converts a capture file recorded with --capture. The format is chosen by the extension of OUTPUT: .wav, .sr (sigrok session for PulseView), .parquet or .arrows (Arrow IPC stream)
+    Export {
+        ///The capture file
+        input: std::path::PathBuf,
+        ///The file to create
+        output: std::path::PathBuf,
+        #[arg(long, default_value = "false")]
+        ///Exports calibrated values instead of raw samples into .sr files. Parquet and Arrow always have both
+        calibrated: bool,
+    },
+    ///Lists the recorded sessions of the --catalog as JSON lines, the latest first
+    Sessions {
+        #[command(flatten)]
+        filter: crate::catalog::SessionFilter,
+    },
 }
 impl Options{
     pub const fn version(&self) -> bool { self.version }
     pub const fn search(&self) -> bool { self.search }
-    pub fn device(&self) -> &[uuid::Uuid] { &self.device.as_slice() }
+    pub fn device(&self) -> &[String] { self.device.as_slice() }
+    pub const fn aliases(&self) -> &std::path::PathBuf { &self.aliases }
+    pub const fn calibrations(&self) -> &std::path::PathBuf { &self.calibrations }
+    pub const fn models(&self) -> Option<&std::path::PathBuf> { self.models.as_ref() }
+    pub fn usb_device(&self) -> &[crate::device::model::DeviceModel] { self.usb_device.as_slice() }
+    pub const fn history_samples(&self) -> Option<usize> { self.history_samples }
+    pub const fn history_seconds(&self) -> f64 { self.history_seconds }
     pub const fn verbose(&self) -> bool { self.verbose }
     pub const fn output(&self) -> Option<&std::path::PathBuf> { self.output.as_ref() }
     pub const fn json(&self) -> bool { self.json }
+    pub const fn capture(&self) -> bool { self.capture }
+    pub const fn zstd(&self) -> Option<i32> { self.zstd }
+    pub const fn wav(&self) -> bool { self.wav }
+    pub const fn rotate_size(&self) -> Option<u64> { self.rotate_size }
+    pub const fn rotate_seconds(&self) -> Option<f64> { self.rotate_seconds }
+    pub const fn recordings(&self) -> &std::path::PathBuf { &self.recordings }
+    pub const fn catalog(&self) -> &std::path::PathBuf { &self.catalog }
+    pub const fn replay(&self) -> Option<&std::path::PathBuf> { self.replay.as_ref() }
+    pub const fn replay_speed(&self) -> f64 { self.replay_speed }
     pub const fn websocket(&self) -> bool { self.websocket }
     pub const fn port(&self) -> u16 { self.port }
+    pub const fn command(&self) -> Option<&Command> { self.command.as_ref() }
 }
\ No newline at end of file
@@ -0,0 +1,708 @@
+//! Headless recording of the samples of devices to disk, see `--output`.
+pub mod capture;
+pub mod columnar;
+pub mod frames;
+pub mod sigrok;
+pub mod wav;
+
+use std::collections::BTreeMap;
+use std::io::{Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use crate::aliases::AliasRegistry;
+use crate::calibration::{Calibration, CalibrationRegistry};
+use crate::catalog::Catalog;
+use crate::device::{Device, DeviceList, Packet, SendDevice};
+use crate::device::annotation::{Annotation, Seen};
+use crate::device::messages::Id;
+use crate::MAX_MESSAGE_BUF;
+use capture::{CaptureWriter, DeviceHeader};
+use frames::Frames;
+use wav::WavWriter;
+
+///How often buffered samples are written to disk. A crash loses at most the samples of this period.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+///How long a WAV recording waits for a device, which sends no samples, before its channel holds its last value.
+const WAV_MAX_SKEW: Duration = Duration::from_secs(5);
+
+///How [`Recorder`] writes samples.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecordFormat {
+    ///A `timestamp,device,value,gap` row per sample, after a header of `#` comment lines.
+    #[default]
+    Csv,
+    ///A JSON object per line. Every file starts with a `header` line, followed by the `samples` of every packet and the `annotation`s.
+    JsonLines,
+    ///The native [`capture`] format, with chunks compressed at the given zstd level.
+    Capture {
+        compression: Option<i32>,
+    },
+    ///A 16-bit PCM [`wav`] file with a channel per device.
+    Wav,
+}
+impl RecordFormat {
+    ///Name of the format in the [`crate::catalog`].
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::JsonLines => "jsonl",
+            Self::Capture { .. } => "capture",
+            Self::Wav => "wav",
+        }
+    }
+}
+
+///A file format, into which capture files can be exported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Wav,
+    ///A sigrok session file for PulseView.
+    Sigrok,
+    Parquet,
+    ///An Arrow IPC stream.
+    ArrowIpc,
+}
+impl ExportFormat {
+    ///The format of files with the given extension.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_ascii_lowercase().as_str() {
+            "wav" => Some(Self::Wav),
+            "sr" => Some(Self::Sigrok),
+            "parquet" => Some(Self::Parquet),
+            "arrows" => Some(Self::ArrowIpc),
+            _ => None,
+        }
+    }
+    ///Fails, if the format can't hold calibrated values, but they were asked for.
+    pub fn check(self, calibrated: bool) -> anyhow::Result<()> {
+        if calibrated && self == Self::Wav {
+            anyhow::bail!("WAV files only hold raw samples, export calibrated values into .sr, .parquet or .arrows");
+        }
+        Ok(())
+    }
+    ///Exports the capture file at `path` into `out`.
+    ///
+    ///Devices recorded without a calibration are calibrated with `calibrations`. With `calibrated`, sigrok files get the
+    ///calibrated values instead of the raw samples. WAV files only hold raw samples, Parquet and Arrow always hold both.
+    pub fn export<W: Write + Seek + Send>(self, path: &Path, out: W, calibrations: &BTreeMap<String, Calibration>, calibrated: bool) -> anyhow::Result<W> {
+        self.check(calibrated)?;
+        let mut reader = capture::CaptureReader::open(path)?;
+        let calibrations: Vec<_> = reader.devices().iter()
+            .map(|device| device.calibration().or_else(|| calibrations.get(device.id().serial())).cloned())
+            .collect();
+        match self {
+            Self::Wav => wav::export(&mut reader, out),
+            Self::Sigrok => sigrok::export(&mut reader, out, calibrated.then_some(calibrations.as_slice())),
+            Self::Parquet => columnar::export_parquet(&mut reader, out, &calibrations),
+            Self::ArrowIpc => columnar::export_ipc(&mut reader, out, &calibrations),
+        }
+    }
+}
+
+///Exports the capture file at `input` into `output`, in the format given by the extension of `output`.
+///See [`ExportFormat::export`] for `calibrations` and `calibrated`.
+pub fn export(input: &Path, output: &Path, calibrations: &BTreeMap<String, Calibration>, calibrated: bool) -> anyhow::Result<()> {
+    let extension = output.extension().map(|v| v.to_string_lossy()).unwrap_or_default();
+    let Some(format) = ExportFormat::from_extension(&extension) else {
+        anyhow::bail!("Unknown export format {extension:?}, expected .wav, .sr, .parquet or .arrows");
+    };
+    format.check(calibrated)?;
+    let file = match std::fs::File::create(output) {
+        Ok(v) => v,
+        Err(err) => anyhow::bail!("Failed to create {}: {err}", output.display()),
+    };
+    let mut file = format.export(input, std::io::BufWriter::new(file), calibrations, calibrated)?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    Ok(())
+}
+
+///When [`Recorder`] continues in a new file. Without any limit, everything is recorded into a single file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rotation {
+    pub max_bytes: Option<u64>,
+    pub max_duration: Option<Duration>,
+}
+impl Rotation {
+    const fn is_enabled(&self) -> bool {
+        self.max_bytes.is_some() || self.max_duration.is_some()
+    }
+}
+
+///Where and how [`Recorder`] records.
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub path: PathBuf,
+    pub format: RecordFormat,
+    pub rotation: Rotation,
+}
+
+///Parses a file size in bytes, optionally with a binary `k`, `M` or `G` suffix, e.g. `512M`.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim();
+    let trimmed = trimmed.strip_suffix(['b', 'B']).unwrap_or(trimmed);
+    let trimmed = trimmed.strip_suffix(['i', 'I']).unwrap_or(trimmed);
+    let (number, factor) = match trimmed.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&trimmed[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&trimmed[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&trimmed[..i], 1 << 30),
+        _ => (trimmed, 1),
+    };
+    match number.trim().parse::<u64>() {
+        Ok(0) => Err("Size must be greater than 0".to_string()),
+        Ok(v) => v.checked_mul(factor).ok_or_else(|| format!("Size {value} is too large")),
+        Err(err) => Err(format!("Invalid size {value}: {err}")),
+    }
+}
+
+#[derive(serde_derive::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonLine<'a> {
+    Header {
+        started: String,
+        part: u32,
+        devices: &'a [DeviceHeader],
+    },
+    ///The samples of a packet. The `i`th value was sampled at `timestamp + i * interval`.
+    Samples {
+        device: &'a str,
+        ///Milliseconds since the UNIX epoch.
+        timestamp: f64,
+        ///Milliseconds between two samples.
+        interval: f64,
+        ///Samples were lost before this packet.
+        gap: bool,
+        values: &'a [u16],
+    },
+    Annotation {
+        #[serde(flatten)]
+        annotation: &'a Annotation,
+    },
+}
+
+enum Sink {
+    Text(std::io::BufWriter<std::fs::File>),
+    Capture(CaptureWriter<std::io::BufWriter<std::fs::File>>),
+    Wav {
+        writer: WavWriter<std::io::BufWriter<std::fs::File>>,
+        frames: Frames,
+    },
+}
+
+///Writes samples into the current file, and rotates it according to [`Rotation`].
+///It lives on the writer thread of a [`Recorder`], as the files and the catalog block.
+struct RecordWriter {
+    path: PathBuf,
+    format: RecordFormat,
+    rotation: Rotation,
+    ///Number of the current file, starting at 1.
+    part: u32,
+    file: Option<Sink>,
+    ///Bytes written into a text file.
+    bytes: u64,
+    opened: Instant,
+    ///Where every file is entered as a session.
+    catalog: Option<Arc<Catalog>>,
+    ///Id of the current file in the catalog.
+    session: Option<i64>,
+}
+impl RecordWriter {
+    fn new(Output { path, format, rotation }: Output, catalog: Option<Arc<Catalog>>) -> Self {
+        Self {
+            path,
+            format,
+            rotation,
+            part: 0,
+            file: None,
+            bytes: 0,
+            opened: Instant::now(),
+            catalog,
+            session: None,
+        }
+    }
+    ///Path of the current file. With rotation, the part number is appended to the file stem, e.g. `capture-002.csv`.
+    fn part_path(&self) -> PathBuf {
+        if !self.rotation.is_enabled() {
+            return self.path.clone();
+        }
+        let stem = self.path.file_stem().map(|v| v.to_string_lossy().into_owned()).unwrap_or_default();
+        let name = match self.path.extension() {
+            Some(extension) => format!("{stem}-{:03}.{}", self.part, extension.to_string_lossy()),
+            None => format!("{stem}-{:03}", self.part),
+        };
+        self.path.with_file_name(name)
+    }
+    ///Returns `true`, if the next samples have to go into a new file.
+    fn is_due(&self) -> bool {
+        self.file.is_none()
+            || self.rotation.max_bytes.is_some_and(|max| self.written() >= max)
+            || self.rotation.max_duration.is_some_and(|max| self.opened.elapsed() >= max)
+    }
+    ///Starts the next file, if the next samples have to go into a new one.
+    fn open_if_due(&mut self, devices: &[DeviceHeader]) -> anyhow::Result<()> {
+        if self.is_due() {
+            self.open(devices)?;
+        }
+        Ok(())
+    }
+    ///Finishes the current file, and starts the next one with a header describing `devices`.
+    fn open(&mut self, devices: &[DeviceHeader]) -> anyhow::Result<()> {
+        self.finish()?;
+        self.part += 1;
+        let path = self.part_path();
+        let file = match std::fs::File::create(&path) {
+            Ok(v) => v,
+            Err(err) => anyhow::bail!("Failed to create {}: {err}", path.display()),
+        };
+        eprintln!("Recording to {}", path.display());
+        let file = std::io::BufWriter::new(file);
+        self.bytes = 0;
+        self.opened = Instant::now();
+        let now = chrono::Utc::now();
+        let started = now.to_rfc3339();
+        if let Some(catalog) = &self.catalog {
+            //The recording doesn't depend on the catalog.
+            match catalog.insert(&path, self.format.name(), now.timestamp_micros() as f64 / 1000., devices) {
+                Ok(id) => self.session = Some(id),
+                Err(err) => eprintln!("Failed to add {} to the catalog: {err}", path.display()),
+            }
+        }
+        self.file = Some(match self.format {
+            RecordFormat::Capture { compression } => Sink::Capture(CaptureWriter::new(file, now.timestamp_micros() as f64 / 1000., devices, compression)?),
+            RecordFormat::Wav => {
+                let rates: Vec<u32> = devices.iter().map(|device| device.id().sample_rate()).collect();
+                let sample_rate = rates.iter().copied().max().unwrap_or_default();
+                let max_skew = (WAV_MAX_SKEW.as_secs_f64() * f64::from(sample_rate)) as usize;
+                let start = now.timestamp_micros() as f64 / 1000.;
+                Sink::Wav {
+                    writer: WavWriter::new(file, start, sample_rate, devices)?,
+                    frames: Frames::new(start, &rates, Some(max_skew), wav::SILENCE)?,
+                }
+            },
+            RecordFormat::Csv | RecordFormat::JsonLines => Sink::Text(file),
+        });
+        let header = match self.format {
+            RecordFormat::Csv => {
+                let mut header = format!("# OmnAIScope-DataServer recording, part {}, started {started}\n", self.part);
+                header.push_str("# timestamp in milliseconds since the UNIX epoch, gap is 1 if samples were lost before the sample\n");
+                header.push_str("# annotations are lines of \"# annotation \" followed by JSON\n");
+                for device in devices {
+                    let id = device.id();
+                    header.push_str(&format!("# device {}", id.serial()));
+                    if let Some(alias) = device.alias() {
+                        header.push_str(&format!(", alias {alias}"));
+                    }
+                    header.push_str(&format!(
+                        ", model {}, sample rate {} Hz, firmware {}, hardware {}\n",
+                        device.model(),
+                        id.sample_rate(),
+                        id.sw_version(),
+                        id.hw_version(),
+                    ));
+                    if let Some(calibration) = device.calibration() {
+                        header.push_str(&format!("# calibration of {} {}\n", id.serial(), serde_json::to_string(calibration)?));
+                    }
+                }
+                header.push_str("timestamp,device,value,gap\n");
+                header
+            },
+            RecordFormat::JsonLines => {
+                let mut header = serde_json::to_string(&JsonLine::Header { started, part: self.part, devices })?;
+                header.push('\n');
+                header
+            },
+            //The header is written by `CaptureWriter` or `WavWriter`.
+            RecordFormat::Capture { .. } | RecordFormat::Wav => return Ok(()),
+        };
+        self.write(&header)
+    }
+    ///Bytes written into the current file.
+    fn written(&self) -> u64 {
+        match &self.file {
+            Some(Sink::Capture(writer)) => writer.position(),
+            Some(Sink::Wav { writer, .. }) => writer.position(),
+            _ => self.bytes,
+        }
+    }
+    ///Writes the samples of `packet` of the `index`th device with the id `device`.
+    fn write_packet(&mut self, index: usize, device: &str, packet: &Packet, gap: bool) -> anyhow::Result<()> {
+        let timestamps = packet.timestamps();
+        let values = packet.data().data();
+        let text = match self.format {
+            RecordFormat::Csv => {
+                let mut text = String::with_capacity(values.len() * (device.len() + 24));
+                for (i, value) in values.iter().enumerate() {
+                    text.push_str(&format!("{:.3},{device},{value},{}\n", timestamps.host_at(i), u8::from(gap && i == 0)));
+                }
+                text
+            },
+            RecordFormat::JsonLines => {
+                let mut text = serde_json::to_string(&JsonLine::Samples {
+                    device,
+                    timestamp: timestamps.host(),
+                    interval: timestamps.host_interval(),
+                    gap,
+                    values,
+                })?;
+                text.push('\n');
+                text
+            },
+            RecordFormat::Capture { .. } => {
+                let Some(Sink::Capture(writer)) = &mut self.file else {
+                    anyhow::bail!("No capture file open for recording");
+                };
+                return writer.push(index, packet, gap);
+            },
+            RecordFormat::Wav => {
+                let Some(Sink::Wav { frames, .. }) = &mut self.file else {
+                    anyhow::bail!("No WAV file open for recording");
+                };
+                return frames.push_packet(index, packet, gap);
+            },
+        };
+        self.write(&text)
+    }
+    ///Writes `annotation` into the current file.
+    fn write_annotation(&mut self, annotation: &Annotation) -> anyhow::Result<()> {
+        let text = match self.format {
+            RecordFormat::Csv => format!("# annotation {}\n", serde_json::to_string(annotation)?),
+            RecordFormat::JsonLines => {
+                let mut text = serde_json::to_string(&JsonLine::Annotation { annotation })?;
+                text.push('\n');
+                text
+            },
+            RecordFormat::Capture { .. } => {
+                let Some(Sink::Capture(writer)) = &mut self.file else {
+                    anyhow::bail!("No capture file open for recording");
+                };
+                return writer.annotate(annotation);
+            },
+            RecordFormat::Wav => {
+                let Some(Sink::Wav { writer, .. }) = &mut self.file else {
+                    anyhow::bail!("No WAV file open for recording");
+                };
+                writer.annotate(annotation.clone());
+                return Ok(());
+            },
+        };
+        self.write(&text)
+    }
+    fn write(&mut self, text: &str) -> anyhow::Result<()> {
+        let Some(Sink::Text(file)) = self.file.as_mut() else {
+            anyhow::bail!("No text file open for recording");
+        };
+        file.write_all(text.as_bytes())?;
+        self.bytes += text.len() as u64;
+        Ok(())
+    }
+    fn flush(&mut self) -> anyhow::Result<()> {
+        match self.file.as_mut() {
+            Some(Sink::Text(file)) => file.flush()?,
+            Some(Sink::Capture(writer)) => writer.flush()?,
+            Some(Sink::Wav { writer, frames }) => {
+                writer.write(&frames.take(false))?;
+                writer.flush()?;
+            },
+            None => {},
+        }
+        Ok(())
+    }
+    ///Writes everything buffered to disk and closes the current file.
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if let Some(catalog) = &self.catalog && let Some(session) = self.session.take() {
+            let end = chrono::Utc::now().timestamp_micros() as f64 / 1000.;
+            if let Err(err) = catalog.finish(session, end) {
+                eprintln!("Failed to set the end of session {session} in the catalog: {err}");
+            }
+        }
+        let mut file = match self.file.take() {
+            Some(Sink::Text(file)) => file,
+            Some(Sink::Capture(writer)) => writer.finish()?,
+            Some(Sink::Wav { mut writer, mut frames }) => {
+                writer.write(&frames.take(true))?;
+                writer.finish()?
+            },
+            None => return Ok(()),
+        };
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+enum Received {
+    Packet(Packet),
+    Lagged(u64),
+    Annotation(Arc<Annotation>),
+}
+
+///Work for the writer thread of a [`Recorder`].
+enum Job {
+    ///Describes the devices in the next file.
+    Headers(Vec<DeviceHeader>),
+    Packet {
+        index: usize,
+        packet: Packet,
+        gap: bool,
+    },
+    Annotation(Arc<Annotation>),
+    Flush,
+}
+
+///State of the recording task.
+struct Recording {
+    devices: Vec<SendDevice>,
+    ///The id of every device at the start, in case a device forgets it.
+    ids: Vec<Id>,
+    aliases: Vec<Option<String>>,
+    calibrations: Vec<Option<Calibration>>,
+    ///Whether samples of the device were lost since its last packet.
+    gaps: Vec<bool>,
+    ///Annotations of several devices arrive once per device.
+    seen: Seen,
+    jobs: mpsc::Sender<Job>,
+}
+impl Recording {
+    async fn headers(&self) -> Vec<DeviceHeader> {
+        let mut headers = Vec::with_capacity(self.devices.len());
+        for (i, device) in self.devices.iter().enumerate() {
+            //The sample rate may have changed since the start.
+            let id = device.id().await.unwrap_or_else(|| self.ids[i].clone());
+            headers.push(DeviceHeader::new(device.model().name().clone(), self.aliases[i].clone(), id, device.meta_data().await, self.calibrations[i].clone()));
+        }
+        headers
+    }
+    ///Hands `job` to the writer thread. Fails, if the writer stopped because of an error.
+    async fn send(&self, job: Job) -> anyhow::Result<()> {
+        match self.jobs.send(job).await {
+            Ok(()) => Ok(()),
+            Err(_) => anyhow::bail!("The recording writer stopped"),
+        }
+    }
+    async fn flush(&self) -> anyhow::Result<()> {
+        //The devices may have changed since, so the next file gets fresh headers.
+        self.send(Job::Headers(self.headers().await)).await?;
+        self.send(Job::Flush).await
+    }
+    async fn receive(&mut self, index: usize, received: Received) -> anyhow::Result<()> {
+        match received {
+            Received::Packet(packet) => {
+                let gap = core::mem::take(&mut self.gaps[index]) || packet.sequence().is_discontinuity();
+                self.send(Job::Packet { index, packet, gap }).await
+            },
+            Received::Lagged(packets) => {
+                eprintln!("Recording of {} lagged {packets} packets", self.ids[index].serial());
+                self.gaps[index] = true;
+                Ok(())
+            },
+            Received::Annotation(annotation) => {
+                if !self.seen.insert(&annotation) {
+                    return Ok(());
+                }
+                self.send(Job::Annotation(annotation)).await
+            },
+        }
+    }
+}
+
+///Writes the jobs of a [`Recording`] on a dedicated thread, so disk I/O and compression never block the async runtime.
+fn write(mut writer: RecordWriter, serials: &[String], mut jobs: mpsc::Receiver<Job>) {
+    let mut headers = Vec::new();
+    let mut result = Ok(());
+    while result.is_ok() && let Some(job) = jobs.blocking_recv() {
+        result = match job {
+            Job::Headers(new) => {
+                headers = new;
+                Ok(())
+            },
+            Job::Packet { index, packet, gap } => writer.open_if_due(&headers)
+                .and_then(|()| writer.write_packet(index, &serials[index], &packet, gap)),
+            Job::Annotation(annotation) => writer.open_if_due(&headers)
+                .and_then(|()| writer.write_annotation(&annotation)),
+            Job::Flush => writer.flush(),
+        };
+    }
+    if let Err(err) = result {
+        eprintln!("Error recording: {err}");
+    }
+    if let Err(err) = writer.finish() {
+        eprintln!("Error finishing recording: {err}");
+    }
+}
+
+///Records the samples of devices in the background, until it is stopped or all devices disconnect.
+pub struct Recorder {
+    stop: oneshot::Sender<()>,
+    task: tokio::task::JoinHandle<()>,
+    ///Serials of the devices, which only capture because of the recorder.
+    started: Vec<String>,
+}
+impl Recorder {
+    ///Starts capturing from the devices with the given serials or aliases, or from every device, if `names` is empty,
+    ///and records their samples into `output`.
+    pub async fn start(
+        device_list: &DeviceList,
+        aliases: &AliasRegistry,
+        calibrations: &CalibrationRegistry,
+        names: &[String],
+        output: Output,
+        catalog: Option<Arc<Catalog>>,
+    ) -> anyhow::Result<Self> {
+        let mut devices: Vec<&Device> = Vec::new();
+        if names.is_empty() {
+            devices.extend(device_list.list());
+        }
+        for name in names {
+            match device_list.find(name, aliases).await {
+                Some(device) => if !devices.iter().any(|v| core::ptr::eq(*v, device)) {
+                    devices.push(device);
+                },
+                None => anyhow::bail!("Device {name} not found"),
+            }
+        }
+        if devices.is_empty() {
+            anyhow::bail!("No devices found to record");
+        }
+        let mut ids = Vec::with_capacity(devices.len());
+        let mut device_aliases = Vec::with_capacity(devices.len());
+        let mut device_calibrations = Vec::with_capacity(devices.len());
+        for device in &devices {
+            match device.id().await {
+                Some(id) => ids.push(id),
+                None => anyhow::bail!("Device {:?} didn't report its id", device.descriptor()),
+            }
+            device_aliases.push(device.alias(aliases).await);
+            device_calibrations.push(device.calibration(calibrations).await);
+        }
+        let started = devices.iter().zip(&ids).filter(|(device, _)| !device.is_capturing()).map(|(_, id)| id.serial().clone()).collect();
+        tokio::task::block_in_place(|| devices.iter().try_for_each(|device| device.start_capture()))?;
+
+        let devices: Vec<SendDevice> = devices.into_iter().map(SendDevice::from).collect();
+        let (tx, mut rx) = mpsc::channel(MAX_MESSAGE_BUF as usize*8);
+        let mut forwarders = tokio::task::JoinSet::new();
+        for (i, device) in devices.iter().enumerate() {
+            let mut device_rx = device.subscribe();
+            let tx = tx.clone();
+            let mut annotations = device.annotations();
+            forwarders.spawn(async move {
+                loop {
+                    let received = tokio::select! {
+                        received = device_rx.recv() => match received {
+                            Ok(packet) => Received::Packet(packet),
+                            Err(tokio::sync::broadcast::error::RecvError::Lagged(num)) => Received::Lagged(num),
+                            Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                                eprintln!("Recorded device disconnected");
+                                break;
+                            },
+                        },
+                        //Everyone holding the device can annotate it, so only the end of the packets tells that the device is gone.
+                        Ok(annotation) = annotations.recv() => Received::Annotation(annotation),
+                    };
+                    if tx.send((i, received)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        let (jobs, rx_jobs) = mpsc::channel(MAX_MESSAGE_BUF as usize*8);
+        let serials: Vec<String> = ids.iter().map(|id| id.serial().clone()).collect();
+        let writer = RecordWriter::new(output, catalog);
+        let writer = match std::thread::Builder::new().name("record-write".to_string()).spawn(move || write(writer, &serials, rx_jobs)) {
+            Ok(v) => v,
+            Err(err) => anyhow::bail!("Failed to spawn recording writer thread: {err}"),
+        };
+        let mut recording = Recording {
+            gaps: vec![false; devices.len()],
+            seen: Seen::default(),
+            devices,
+            ids,
+            aliases: device_aliases,
+            calibrations: device_calibrations,
+            jobs,
+        };
+        recording.send(Job::Headers(recording.headers().await)).await?;
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+            flush.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let result: anyhow::Result<()> = loop {
+                tokio::select! {
+                    _ = &mut stopped => {
+                        //Keep what was already received.
+                        forwarders.abort_all();
+                        let mut result = Ok(());
+                        while let Ok((i, received)) = rx.try_recv() && result.is_ok() {
+                            result = recording.receive(i, received).await;
+                        }
+                        break result;
+                    },
+                    _ = flush.tick() => if let Err(err) = recording.flush().await {
+                        break Err(err);
+                    },
+                    received = rx.recv() => match received {
+                        Some((i, received)) => if let Err(err) = recording.receive(i, received).await {
+                            break Err(err);
+                        },
+                        None => {
+                            eprintln!("All recorded devices disconnected");
+                            break Ok(());
+                        },
+                    },
+                }
+            };
+            if let Err(err) = result {
+                eprintln!("Error recording: {err}");
+            }
+            //The writer finishes the file, once it has written all jobs.
+            drop(recording);
+            match tokio::task::spawn_blocking(move || writer.join()).await {
+                Ok(Ok(())) => {},
+                Ok(Err(_)) => eprintln!("Recording writer thread panicked"),
+                Err(err) => eprintln!("Failed to wait for the recording writer: {err}"),
+            }
+        });
+        Ok(Self { stop, task, started })
+    }
+    ///Stops recording, and returns once everything received so far is on disk.
+    ///Devices of `device_list`, which only captured for the recording, stop capturing.
+    pub async fn stop(self, device_list: &DeviceList, aliases: &AliasRegistry) {
+        //The task may have ended on its own already.
+        self.stop.send(()).ok();
+        if let Err(err) = self.task.await {
+            eprintln!("Recording task failed: {err}");
+        }
+        for serial in &self.started {
+            if let Some(device) = device_list.find(serial, aliases).await
+                && let Err(err) = tokio::task::block_in_place(|| device.stop_capture()) {
+                eprintln!("Failed to stop capturing from {serial}: {err}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///The extension of the output picks the format, and calibrated values are refused for WAV files.
+    #[test]
+    fn export_by_extension() {
+        let (input, _) = capture::tests::write("export", None);
+        let dir = tempfile::tempdir().unwrap();
+        let calibrations = BTreeMap::new();
+        for (extension, magic) in [("wav", &b"RIFF"[..]), ("sr", b"PK\x03\x04"), ("parquet", b"PAR1"), ("arrows", b"\xff\xff\xff\xff")] {
+            let output = dir.path().join(format!("export.{extension}"));
+            export(&input, &output, &calibrations, false).unwrap();
+            assert!(std::fs::read(&output).unwrap().starts_with(magic), "{extension}");
+        }
+        assert!(export(&input, &dir.path().join("export.csv"), &calibrations, false).is_err());
+        let calibrated = dir.path().join("calibrated.wav");
+        assert!(export(&input, &calibrated, &calibrations, true).is_err());
+        assert!(!calibrated.exists());
+        std::fs::remove_file(input).unwrap();
+    }
+}
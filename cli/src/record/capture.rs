@@ -0,0 +1,675 @@
+//! The native capture file format, a compact chunked format for long captures at high sample rates.
+//!
+//! All numbers are little-endian. A file starts with a header:
+//!
+//! | size | content                                                              |
+//! |------|----------------------------------------------------------------------|
+//! | 8    | magic `OMNICAP\0`                                                    |
+//! | 2    | `u16` version, currently 1                                           |
+//! | 2    | `u16` flags, bit 0 is set, if chunks may be zstd compressed           |
+//! | 8    | `f64` start time in ms since the UNIX epoch                          |
+//! | 4    | `u32` length `n` of the device list                                  |
+//! | n    | JSON array of [`DeviceHeader`], with the `Id`, alias, `MetaData` and calibration of every device |
+//!
+//! It is followed by chunks of consecutive samples of a single device:
+//!
+//! | size | content                                                              |
+//! |------|----------------------------------------------------------------------|
+//! | 4    | magic `CHNK`                                                         |
+//! | 2    | `u16` index of the device in the device list                         |
+//! | 1    | flags, bit 0 is set, if samples were lost before the chunk, bit 1 if the payload is zstd compressed |
+//! | 1    | reserved                                                             |
+//! | 8    | `u64` unwrapped packet counter of the first sample                   |
+//! | 8    | `f64` timestamp of the first sample in ms since the UNIX epoch       |
+//! | 8    | `f64` sample interval in ms                                          |
+//! | 4    | `u32` number of samples `m`                                          |
+//! | 4    | `u32` samples per packet, as received from the device                |
+//! | 4    | `u32` length `p` of the payload                                      |
+//! | p    | `m` `u16` samples, zstd compressed, if flagged                       |
+//!
+//! [`Annotation`]s made whilst recording are written between the chunks:
+//!
+//! | size | content                                                              |
+//! |------|----------------------------------------------------------------------|
+//! | 4    | magic `NOTE`                                                         |
+//! | 4    | `u32` length `j` of the annotation                                   |
+//! | j    | JSON of the [`Annotation`]                                           |
+//!
+//! A finished file ends with a seek index of all chunks and a trailer pointing to it:
+//!
+//! | size   | content                                                            |
+//! |--------|--------------------------------------------------------------------|
+//! | 4      | magic `INDX`                                                       |
+//! | 4      | `u32` number of entries `k`                                        |
+//! | 30 × k | `u64` chunk offset, `u16` device, `u32` samples, `f64` first and `f64` last timestamp |
+//! | 4      | `u32` length `j` of the annotations                                |
+//! | j      | JSON array of all [`Annotation`]s                                  |
+//! | 8      | `u64` offset of the index                                          |
+//! | 8      | magic `OMNIEND\0`                                                  |
+//!
+//! Files without an index, e.g. because the recording crashed, are still readable. The index is rebuilt from the chunks.
+//! A chunk only holds packets of the same length, so the packets can be replayed, see [`crate::device::replay`].
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::calibration::Calibration;
+use crate::device::Packet;
+use crate::device::annotation::Annotation;
+use crate::device::history::HistorySample;
+use crate::device::messages::{Id, MetaData};
+
+const MAGIC: &[u8; 8] = b"OMNICAP\0";
+const VERSION: u16 = 1;
+const FLAG_COMPRESSED: u16 = 1;
+const CHUNK_MAGIC: &[u8; 4] = b"CHNK";
+const CHUNK_FLAG_GAP: u8 = 1;
+const CHUNK_FLAG_COMPRESSED: u8 = 2;
+const CHUNK_HEADER_LEN: u64 = 44;
+const NOTE_MAGIC: &[u8; 4] = b"NOTE";
+const INDEX_MAGIC: &[u8; 4] = b"INDX";
+const INDEX_ENTRY_LEN: usize = 30;
+const TRAILER_MAGIC: &[u8; 8] = b"OMNIEND\0";
+const TRAILER_LEN: u64 = 16;
+///Most samples in a chunk. Chunks are also ended by gaps and by [`CaptureWriter::flush`].
+const MAX_CHUNK_SAMPLES: usize = 1 << 16;
+
+///A recorded device, as described at the start of every file.
+#[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct DeviceHeader {
+    model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alias: Option<String>,
+    #[serde(flatten)]
+    id: Id,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    meta_data: Option<MetaData>,
+    ///The calibration of the device at the time of the recording.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    calibration: Option<Calibration>,
+}
+impl DeviceHeader {
+    pub const fn new(model: String, alias: Option<String>, id: Id, meta_data: Option<MetaData>, calibration: Option<Calibration>) -> Self {
+        Self { model, alias, id, meta_data, calibration }
+    }
+    pub const fn model(&self) -> &String { &self.model }
+    pub const fn alias(&self) -> Option<&String> { self.alias.as_ref() }
+    pub const fn id(&self) -> &Id { &self.id }
+    pub const fn meta_data(&self) -> Option<&MetaData> { self.meta_data.as_ref() }
+    pub const fn calibration(&self) -> Option<&Calibration> { self.calibration.as_ref() }
+}
+
+///Where a chunk is, and what it holds.
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    offset: u64,
+    device: u16,
+    samples: u32,
+    first: f64,
+    last: f64,
+}
+
+///A chunk being filled by [`CaptureWriter`].
+#[derive(Debug)]
+struct OpenChunk {
+    gap: bool,
+    counter: u64,
+    timestamp: f64,
+    interval: f64,
+    packet_len: usize,
+    samples: Vec<u16>,
+}
+impl OpenChunk {
+    ///Returns `true`, if a packet of `packet_len` samples starting at `timestamp` with `interval` continues this chunk.
+    fn continues(&self, timestamp: f64, interval: f64, packet_len: usize) -> bool {
+        let expected = self.timestamp + self.samples.len() as f64 * self.interval;
+        self.samples.len() < MAX_CHUNK_SAMPLES
+            && packet_len == self.packet_len
+            && (interval - self.interval).abs() <= self.interval * 1e-6
+            && (timestamp - expected).abs() <= self.interval / 2.
+    }
+}
+
+///Writes a capture file.
+#[derive(Debug)]
+pub struct CaptureWriter<W: Write> {
+    out: W,
+    position: u64,
+    ///zstd compression level, if chunks are compressed.
+    compression: Option<i32>,
+    chunks: Vec<Option<OpenChunk>>,
+    index: Vec<IndexEntry>,
+    annotations: Vec<Annotation>,
+}
+impl<W: Write> CaptureWriter<W> {
+    ///Writes the header of a capture of `devices`, started at `start` in ms since the UNIX epoch.
+    pub fn new(mut out: W, start: f64, devices: &[DeviceHeader], compression: Option<i32>) -> anyhow::Result<Self> {
+        let devices_json = serde_json::to_vec(devices)?;
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&(if compression.is_some() { FLAG_COMPRESSED } else { 0 }).to_le_bytes())?;
+        out.write_all(&start.to_le_bytes())?;
+        out.write_all(&u32::try_from(devices_json.len())?.to_le_bytes())?;
+        out.write_all(&devices_json)?;
+        Ok(Self {
+            out,
+            position: 24 + devices_json.len() as u64,
+            compression,
+            chunks: devices.iter().map(|_| None).collect(),
+            index: Vec::new(),
+            annotations: Vec::new(),
+        })
+    }
+    ///Bytes written so far.
+    pub const fn position(&self) -> u64 {
+        self.position
+    }
+    ///Appends the samples of `packet` of the `device`th device. `gap` is set, if samples were lost before the packet.
+    pub fn push(&mut self, device: usize, packet: &Packet, gap: bool) -> anyhow::Result<()> {
+        let timestamps = packet.timestamps();
+        let packet_len = packet.data().data().len();
+        let Some(chunk) = self.chunks.get_mut(device) else {
+            anyhow::bail!("Unknown device {device}");
+        };
+        if let Some(open) = chunk.take_if(|open| gap || !open.continues(timestamps.host(), timestamps.host_interval(), packet_len)) {
+            self.write_chunk(device, open)?;
+        }
+        let chunk = self.chunks[device].get_or_insert_with(|| OpenChunk {
+            gap,
+            counter: packet.counter(),
+            timestamp: timestamps.host(),
+            interval: timestamps.host_interval(),
+            packet_len,
+            samples: Vec::new(),
+        });
+        chunk.samples.extend_from_slice(packet.data().data());
+        Ok(())
+    }
+    fn write_chunk(&mut self, device: usize, chunk: OpenChunk) -> anyhow::Result<()> {
+        if chunk.samples.is_empty() {
+            return Ok(());
+        }
+        let mut payload: Vec<u8> = chunk.samples.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let mut flags = if chunk.gap { CHUNK_FLAG_GAP } else { 0 };
+        if let Some(level) = self.compression {
+            let compressed = zstd::bulk::compress(&payload, level)?;
+            //Noise may not compress at all.
+            if compressed.len() < payload.len() {
+                payload = compressed;
+                flags |= CHUNK_FLAG_COMPRESSED;
+            }
+        }
+        let samples = u32::try_from(chunk.samples.len())?;
+        self.index.push(IndexEntry {
+            offset: self.position,
+            device: u16::try_from(device)?,
+            samples,
+            first: chunk.timestamp,
+            last: chunk.timestamp + (chunk.samples.len() - 1) as f64 * chunk.interval,
+        });
+        let mut header = Vec::with_capacity(CHUNK_HEADER_LEN as usize);
+        header.extend_from_slice(CHUNK_MAGIC);
+        header.extend_from_slice(&u16::try_from(device)?.to_le_bytes());
+        header.push(flags);
+        header.push(0);
+        header.extend_from_slice(&chunk.counter.to_le_bytes());
+        header.extend_from_slice(&chunk.timestamp.to_le_bytes());
+        header.extend_from_slice(&chunk.interval.to_le_bytes());
+        header.extend_from_slice(&samples.to_le_bytes());
+        header.extend_from_slice(&u32::try_from(chunk.packet_len)?.to_le_bytes());
+        header.extend_from_slice(&u32::try_from(payload.len())?.to_le_bytes());
+        self.out.write_all(&header)?;
+        self.out.write_all(&payload)?;
+        self.position += CHUNK_HEADER_LEN + payload.len() as u64;
+        Ok(())
+    }
+    ///Appends `annotation`. It is written right away, so it survives a crash like the samples.
+    pub fn annotate(&mut self, annotation: &Annotation) -> anyhow::Result<()> {
+        let json = serde_json::to_vec(annotation)?;
+        self.out.write_all(NOTE_MAGIC)?;
+        self.out.write_all(&u32::try_from(json.len())?.to_le_bytes())?;
+        self.out.write_all(&json)?;
+        self.position += 8 + json.len() as u64;
+        self.annotations.push(annotation.clone());
+        Ok(())
+    }
+    ///Ends all open chunks, and flushes them to `out`.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        for device in 0..self.chunks.len() {
+            if let Some(chunk) = self.chunks[device].take() {
+                self.write_chunk(device, chunk)?;
+            }
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+    ///Writes all open chunks and the index, and returns `out`.
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        self.flush()?;
+        let index_offset = self.position;
+        self.out.write_all(INDEX_MAGIC)?;
+        self.out.write_all(&u32::try_from(self.index.len())?.to_le_bytes())?;
+        for entry in &self.index {
+            self.out.write_all(&entry.offset.to_le_bytes())?;
+            self.out.write_all(&entry.device.to_le_bytes())?;
+            self.out.write_all(&entry.samples.to_le_bytes())?;
+            self.out.write_all(&entry.first.to_le_bytes())?;
+            self.out.write_all(&entry.last.to_le_bytes())?;
+        }
+        let annotations = serde_json::to_vec(&self.annotations)?;
+        self.out.write_all(&u32::try_from(annotations.len())?.to_le_bytes())?;
+        self.out.write_all(&annotations)?;
+        self.out.write_all(&index_offset.to_le_bytes())?;
+        self.out.write_all(TRAILER_MAGIC)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+///Reads a capture file.
+#[derive(Debug)]
+pub struct CaptureReader {
+    file: std::io::BufReader<std::fs::File>,
+    ///Length of the file, which bounds every length read from it.
+    len: u64,
+    start: f64,
+    devices: Vec<DeviceHeader>,
+    index: Vec<IndexEntry>,
+    annotations: Vec<Annotation>,
+}
+impl CaptureReader {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut header = [0; 24];
+        file.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            anyhow::bail!("{} is no capture file", path.display());
+        }
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version != VERSION {
+            anyhow::bail!("Unsupported capture file version {version}, expected {VERSION}");
+        }
+        let start = f64::from_le_bytes(header[12..20].try_into()?);
+        let devices_json = read_bytes(&mut file, len, 24, u32::from_le_bytes(header[20..24].try_into()?).into())?;
+        let devices: Vec<DeviceHeader> = serde_json::from_slice(&devices_json)?;
+        let chunks_start = 24 + devices_json.len() as u64;
+        let (index, annotations) = match Self::read_index(&mut file, len)? {
+            Some(index) => index,
+            None => Self::rebuild_index(&mut file, len, chunks_start)?,
+        };
+        Ok(Self { file, len, start, devices, index, annotations })
+    }
+    ///Reads the index and the annotations of a finished file. Returns `None`, if the file has no index.
+    fn read_index(file: &mut std::io::BufReader<std::fs::File>, len: u64) -> anyhow::Result<Option<(Vec<IndexEntry>, Vec<Annotation>)>> {
+        if len < TRAILER_LEN {
+            return Ok(None);
+        }
+        let mut trailer = [0; TRAILER_LEN as usize];
+        file.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+        file.read_exact(&mut trailer)?;
+        if &trailer[8..] != TRAILER_MAGIC {
+            return Ok(None);
+        }
+        let offset = u64::from_le_bytes(trailer[..8].try_into()?);
+        if offset.saturating_add(8) > len {
+            return Ok(None);
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0; 8];
+        file.read_exact(&mut header)?;
+        if &header[..4] != INDEX_MAGIC {
+            return Ok(None);
+        }
+        let entries = read_bytes(file, len, offset + 8, u64::from(u32::from_le_bytes(header[4..].try_into()?)) * INDEX_ENTRY_LEN as u64)?;
+        let index = entries.chunks_exact(INDEX_ENTRY_LEN).map(|entry| IndexEntry {
+            offset: u64::from_le_bytes(entry[0..8].try_into().unwrap_or_default()),
+            device: u16::from_le_bytes(entry[8..10].try_into().unwrap_or_default()),
+            samples: u32::from_le_bytes(entry[10..14].try_into().unwrap_or_default()),
+            first: f64::from_le_bytes(entry[14..22].try_into().unwrap_or_default()),
+            last: f64::from_le_bytes(entry[22..30].try_into().unwrap_or_default()),
+        }).collect();
+        let mut json_len = [0; 4];
+        file.read_exact(&mut json_len)?;
+        let json = read_bytes(file, len, offset + 12 + entries.len() as u64, u32::from_le_bytes(json_len).into())?;
+        let annotations = serde_json::from_slice(&json)?;
+        Ok(Some((index, annotations)))
+    }
+    ///Walks all chunks and annotations from `offset` on. A truncated last chunk or annotation is ignored.
+    fn rebuild_index(file: &mut std::io::BufReader<std::fs::File>, len: u64, mut offset: u64) -> anyhow::Result<(Vec<IndexEntry>, Vec<Annotation>)> {
+        let mut index = Vec::new();
+        let mut annotations = Vec::new();
+        while offset + 8 <= len {
+            file.seek(SeekFrom::Start(offset))?;
+            let mut note = [0; 8];
+            file.read_exact(&mut note)?;
+            if &note[..4] == NOTE_MAGIC {
+                let end = offset + 8 + u64::from(u32::from_le_bytes(note[4..].try_into()?));
+                if end > len {
+                    break;
+                }
+                let json = read_bytes(file, len, offset + 8, end - offset - 8)?;
+                annotations.push(serde_json::from_slice(&json)?);
+                offset = end;
+                continue;
+            }
+            if offset + CHUNK_HEADER_LEN > len {
+                break;
+            }
+            file.seek(SeekFrom::Start(offset))?;
+            let header = ChunkHeader::read(file)?;
+            let Some(header) = header else {
+                break;
+            };
+            let end = offset + CHUNK_HEADER_LEN + u64::from(header.payload);
+            if end > len {
+                break;
+            }
+            index.push(IndexEntry {
+                offset,
+                device: header.device,
+                samples: header.samples,
+                first: header.timestamp,
+                last: header.timestamp + f64::from(header.samples.saturating_sub(1)) * header.interval,
+            });
+            offset = end;
+        }
+        Ok((index, annotations))
+    }
+    ///Start time of the capture in ms since the UNIX epoch.
+    pub const fn start(&self) -> f64 {
+        self.start
+    }
+    pub fn devices(&self) -> &[DeviceHeader] {
+        &self.devices
+    }
+    ///The annotations made whilst recording, in the order they were made.
+    pub fn annotations(&self) -> &[Annotation] {
+        &self.annotations
+    }
+    ///Timestamps of the first and the last sample of the `device`th device.
+    pub fn bounds(&self, device: usize) -> Option<(f64, f64)> {
+        self.index.iter()
+            .filter(|entry| usize::from(entry.device) == device)
+            .fold(None, |bounds, entry| Some(match bounds {
+                Some((first, last)) => (entry.first.min(first), entry.last.max(last)),
+                None => (entry.first, entry.last),
+            }))
+    }
+    ///Returns the samples of the `device`th device between `tmin` and `tmax`, in milliseconds since the UNIX epoch.
+    ///
+    ///At most about `max_samples` samples are returned. Longer ranges are reduced to the minimum and the maximum
+    ///of equally long runs of samples while reading, so the whole range never has to be in memory.
+    pub fn range(&mut self, device: usize, tmin: f64, tmax: f64, max_samples: usize) -> anyhow::Result<Vec<HistorySample>> {
+        let entries: Vec<_> = self.index.iter()
+            .filter(|entry| usize::from(entry.device) == device && entry.first <= tmax && entry.last >= tmin)
+            .copied()
+            .collect();
+        let total: usize = entries.iter().map(|entry| entry.samples as usize).sum();
+        let run_len = total.div_ceil((max_samples / 2).max(1)).max(1);
+        let mut run = Run::default();
+        let mut out = Vec::new();
+        for entry in entries {
+            let chunk = self.read_chunk(entry.offset)?;
+            if chunk.header.gap {
+                run.flush(&mut out);
+                run.gap = true;
+            }
+            for (i, value) in chunk.samples.into_iter().enumerate() {
+                let timestamp = chunk.header.timestamp + i as f64 * chunk.header.interval;
+                if (tmin..=tmax).contains(&timestamp) {
+                    run.push(HistorySample::new(timestamp, value, false));
+                    if run.len == run_len {
+                        run.flush(&mut out);
+                    }
+                }
+            }
+        }
+        run.flush(&mut out);
+        Ok(out)
+    }
+    ///Reads the chunks of the `device`th device, or of all devices, in chronological order.
+    pub fn chunks(&mut self, device: Option<usize>) -> impl Iterator<Item = anyhow::Result<Chunk>> + '_ {
+        let mut entries: Vec<_> = self.index.iter()
+            .filter(|entry| device.is_none_or(|device| usize::from(entry.device) == device))
+            .copied()
+            .collect();
+        entries.sort_by(|a, b| a.first.total_cmp(&b.first));
+        entries.into_iter().map(|entry| self.read_chunk(entry.offset))
+    }
+    fn read_chunk(&mut self, offset: u64) -> anyhow::Result<Chunk> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        let Some(header) = ChunkHeader::read(&mut self.file)? else {
+            anyhow::bail!("No chunk at offset {offset}");
+        };
+        let mut payload = read_bytes(&mut self.file, self.len, offset + CHUNK_HEADER_LEN, header.payload.into())?;
+        //A chunk ends with the packet, which reached `MAX_CHUNK_SAMPLES`, so a larger one is corrupt.
+        if header.samples as usize > 2 * MAX_CHUNK_SAMPLES {
+            anyhow::bail!("Chunk at offset {offset} claims {} samples", header.samples);
+        }
+        if header.compressed {
+            payload = zstd::bulk::decompress(&payload, header.samples as usize * 2)?;
+        }
+        if payload.len() != header.samples as usize * 2 {
+            anyhow::bail!("Chunk at offset {offset} holds {} bytes instead of {} samples", payload.len(), header.samples);
+        }
+        let samples = payload.chunks_exact(2).map(|v| u16::from_le_bytes([v[0], v[1]])).collect();
+        Ok(Chunk { header, samples })
+    }
+}
+
+///Consecutive samples of a device, as read by [`CaptureReader::chunks`].
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    header: ChunkHeader,
+    samples: Vec<u16>,
+}
+impl Chunk {
+    ///Index of the device in [`CaptureReader::devices`].
+    pub fn device(&self) -> usize { usize::from(self.header.device) }
+    ///The unwrapped packet counter of the first sample.
+    pub const fn counter(&self) -> u64 { self.header.counter }
+    ///Timestamp of the first sample in ms since the UNIX epoch.
+    pub const fn timestamp(&self) -> f64 { self.header.timestamp }
+    ///Milliseconds between two samples.
+    pub const fn interval(&self) -> f64 { self.header.interval }
+    ///Samples were lost before the chunk.
+    pub const fn gap(&self) -> bool { self.header.gap }
+    ///Samples per packet.
+    pub const fn packet_len(&self) -> u32 { self.header.packet_len }
+    pub const fn samples(&self) -> &Vec<u16> { &self.samples }
+}
+
+///The minimum and the maximum of consecutive samples, see [`CaptureReader::range`].
+#[derive(Debug, Default)]
+struct Run {
+    len: usize,
+    ///Samples were lost before the run.
+    gap: bool,
+    min: Option<HistorySample>,
+    max: Option<HistorySample>,
+}
+impl Run {
+    fn push(&mut self, sample: HistorySample) {
+        self.len += 1;
+        if self.min.is_none_or(|min| sample.value() < min.value()) {
+            self.min = Some(sample);
+        }
+        if self.max.is_none_or(|max| sample.value() > max.value()) {
+            self.max = Some(sample);
+        }
+    }
+    ///Appends the minimum and the maximum in chronological order to `out`, and starts a new run.
+    fn flush(&mut self, out: &mut Vec<HistorySample>) {
+        let run = core::mem::take(self);
+        let (Some(min), Some(max)) = (run.min, run.max) else {
+            //An empty run keeps its gap for the next one.
+            self.gap = run.gap;
+            return;
+        };
+        let (first, second) = if min.timestamp() <= max.timestamp() { (min, max) } else { (max, min) };
+        out.push(HistorySample::new(first.timestamp(), first.value(), run.gap));
+        if second.timestamp() != first.timestamp() {
+            out.push(second);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ChunkHeader {
+    device: u16,
+    gap: bool,
+    compressed: bool,
+    counter: u64,
+    timestamp: f64,
+    interval: f64,
+    samples: u32,
+    packet_len: u32,
+    payload: u32,
+}
+impl ChunkHeader {
+    ///Reads a chunk header. Returns `None`, if there is no chunk.
+    fn read(reader: &mut impl Read) -> anyhow::Result<Option<Self>> {
+        let mut header = [0; CHUNK_HEADER_LEN as usize];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != CHUNK_MAGIC {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            device: u16::from_le_bytes(header[4..6].try_into()?),
+            gap: header[6] & CHUNK_FLAG_GAP != 0,
+            compressed: header[6] & CHUNK_FLAG_COMPRESSED != 0,
+            counter: u64::from_le_bytes(header[8..16].try_into()?),
+            timestamp: f64::from_le_bytes(header[16..24].try_into()?),
+            interval: f64::from_le_bytes(header[24..32].try_into()?),
+            samples: u32::from_le_bytes(header[32..36].try_into()?),
+            packet_len: u32::from_le_bytes(header[36..40].try_into()?),
+            payload: u32::from_le_bytes(header[40..44].try_into()?),
+        }))
+    }
+}
+
+///Reads `count` bytes at `offset` of a file of `len` bytes. The count comes from the file, so it is checked against
+///the length of the file before allocating.
+fn read_bytes(reader: &mut impl Read, len: u64, offset: u64, count: u64) -> anyhow::Result<Vec<u8>> {
+    if offset.saturating_add(count) > len {
+        anyhow::bail!("The capture file is corrupt: {count} bytes at offset {offset} exceed its length of {len} bytes");
+    }
+    let mut bytes = vec![0; usize::try_from(count)?];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::time::{Duration, SystemTime};
+    use crate::device::clock::DeviceClock;
+    use crate::device::messages::MeasureData;
+    use crate::device::sequence::SequenceEvent;
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 1000;
+    pub(crate) const PACKET_LEN: usize = 64;
+
+    fn devices() -> Vec<DeviceHeader> {
+        ["A1", "B2"].iter().map(|serial| serde_json::from_value(serde_json::json!({
+            "model": "OmnAIScope",
+            "serial": serial,
+            "type": "scope",
+            "sample_rate": SAMPLE_RATE,
+            "hw_version": {"major": 1, "minor": 0, "patch": 0},
+            "sw_version": {"major": 1, "minor": 2, "patch": 0},
+            "sw_git_hash": "test",
+        })).unwrap()).collect()
+    }
+
+    fn packets(device: u16, count: u64) -> Vec<Packet> {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut clock = DeviceClock::new();
+        (0..count).map(|counter| {
+            let arrival = start + Duration::from_millis((counter + 1) * PACKET_LEN as u64 * 1000 / u64::from(SAMPLE_RATE));
+            let timestamps = clock.timestamps(Some(SAMPLE_RATE), counter, PACKET_LEN, arrival);
+            let data = (0..PACKET_LEN).map(|i| device * 1000 + (counter as usize * PACKET_LEN + i) as u16 % 1000).collect();
+            Packet::new(MeasureData::new(counter as u32, data), SequenceEvent::InOrder, counter, timestamps)
+        }).collect()
+    }
+
+    ///Writes a capture of two devices with a gap and an annotation to a temporary file.
+    pub(crate) fn write(name: &str, compression: Option<i32>) -> (std::path::PathBuf, Vec<Vec<Packet>>) {
+        let path = std::env::temp_dir().join(format!("capture-test-{}-{name}.cap", std::process::id()));
+        let packets = vec![packets(0, 20), packets(1, 10)];
+        let mut writer = CaptureWriter::new(Vec::new(), 1_700_000_000_000., &devices(), compression).unwrap();
+        for (i, packet) in packets[0].iter().enumerate() {
+            writer.push(0, packet, i == 10).unwrap();
+            if let Some(packet) = packets[1].get(i) {
+                writer.push(1, packet, false).unwrap();
+            }
+            if i == 5 {
+                let annotation = serde_json::from_value(serde_json::json!({"devices": ["A1"], "timestamp": 1_700_000_000_500., "label": "relay closed"})).unwrap();
+                writer.annotate(&annotation).unwrap();
+            }
+        }
+        std::fs::write(&path, writer.finish().unwrap()).unwrap();
+        (path, packets)
+    }
+
+    fn check(reader: &mut CaptureReader, packets: &[Vec<Packet>]) {
+        assert_eq!(reader.devices().len(), 2);
+        assert_eq!(reader.devices()[1].id().serial(), "B2");
+        assert_eq!(reader.annotations().len(), 1);
+        assert_eq!(reader.annotations()[0].label(), "relay closed");
+        for (device, packets) in packets.iter().enumerate() {
+            let chunks = reader.chunks(Some(device)).collect::<anyhow::Result<Vec<_>>>().unwrap();
+            //The gap ends the first chunk of the first device.
+            assert_eq!(chunks.len(), if device == 0 { 2 } else { 1 });
+            assert_eq!(chunks.iter().map(Chunk::gap).collect::<Vec<_>>(), if device == 0 { vec![false, true] } else { vec![false] });
+            assert!(chunks.iter().all(|chunk| chunk.device() == device && chunk.packet_len() as usize == PACKET_LEN));
+            let samples: Vec<u16> = chunks.iter().flat_map(|chunk| chunk.samples().clone()).collect();
+            let expected: Vec<u16> = packets.iter().flat_map(|packet| packet.data().data().clone()).collect();
+            assert_eq!(samples, expected);
+            assert_eq!(chunks[0].timestamp(), packets[0].timestamps().host());
+            assert_eq!(chunks[0].counter(), 0);
+            let (first, last) = reader.bounds(device).unwrap();
+            assert_eq!(first, packets[0].timestamps().host());
+            //A chunk continues, as long as packets deviate by less than half an interval from the first one.
+            assert!((last - packets[packets.len() - 1].timestamps().host_at(PACKET_LEN - 1)).abs() <= chunks[0].interval() / 2.);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for compression in [None, Some(3)] {
+            let (path, packets) = write(&format!("{compression:?}"), compression);
+            let mut reader = CaptureReader::open(&path).unwrap();
+            check(&mut reader, &packets);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    ///A crashed recording has no index, which is rebuilt from the chunks.
+    #[test]
+    fn rebuilds_missing_index() {
+        let (path, packets) = write("crashed", Some(3));
+        let mut bytes = std::fs::read(&path).unwrap();
+        let index = usize::try_from(u64::from_le_bytes(bytes[bytes.len() - 16..bytes.len() - 8].try_into().unwrap())).unwrap();
+        bytes.truncate(index);
+        std::fs::write(&path, bytes).unwrap();
+        let mut reader = CaptureReader::open(&path).unwrap();
+        check(&mut reader, &packets);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    ///Lengths in a corrupt file must not make the reader allocate more than the file holds.
+    #[test]
+    fn rejects_lengths_beyond_the_file() {
+        let (path, _) = write("corrupt", None);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let index = usize::try_from(u64::from_le_bytes(bytes[bytes.len() - 16..bytes.len() - 8].try_into().unwrap())).unwrap();
+        bytes[index + 4..index + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(CaptureReader::open(&path).is_err());
+        bytes[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(CaptureReader::open(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
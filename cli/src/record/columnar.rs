@@ -0,0 +1,184 @@
+//! Export into the columnar formats Apache Parquet and Arrow IPC streams, e.g. for pandas and polars.
+//!
+//! Every sample becomes a row of `timestamp` (µs since the UNIX epoch, UTC), `device` (the serial), `value` (the raw sample)
+//! and `calibrated` (the calibrated value, null without a calibration).
+//! The `Id`, `MetaData` and calibration of every device are stored as key/value metadata, e.g. `device.<serial>.sample_rate`,
+//! and the annotations as a JSON array under `annotations`.
+//! Samples are written in batches, so captures don't have to fit into memory.
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, TimestampMicrosecondArray, UInt16Array};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use crate::calibration::Calibration;
+use crate::device::annotation::Annotation;
+use super::capture::{CaptureReader, DeviceHeader};
+
+///Most rows in a record batch.
+const BATCH_ROWS: usize = 1 << 16;
+///Most rows in a Parquet row group, which is buffered in memory until it is complete.
+const ROW_GROUP_ROWS: usize = 1 << 20;
+
+///The key/value metadata describing the capture started at `start` in ms since the UNIX epoch, its `devices` with their `calibrations`,
+///and `annotations`.
+fn metadata(start: f64, devices: &[DeviceHeader], calibrations: &[Option<Calibration>], annotations: &[Annotation]) -> anyhow::Result<HashMap<String, String>> {
+    let mut metadata = HashMap::new();
+    metadata.insert("start".to_string(), format!("{start:.3}"));
+    metadata.insert("devices".to_string(), devices.iter().map(|device| device.id().serial().as_str()).collect::<Vec<_>>().join(","));
+    for (device, calibration) in devices.iter().zip(calibrations) {
+        let id = device.id();
+        let prefix = format!("device.{}", id.serial());
+        metadata.insert(format!("{prefix}.model"), device.model().clone());
+        if let Some(alias) = device.alias() {
+            metadata.insert(format!("{prefix}.alias"), alias.clone());
+        }
+        metadata.insert(format!("{prefix}.type"), id.r#type().clone());
+        metadata.insert(format!("{prefix}.sample_rate"), id.sample_rate().to_string());
+        metadata.insert(format!("{prefix}.hw_version"), id.hw_version().to_string());
+        metadata.insert(format!("{prefix}.sw_version"), id.sw_version().to_string());
+        metadata.insert(format!("{prefix}.sw_git_hash"), id.sw_git_hash().clone());
+        if let Some(meta_data) = device.meta_data() {
+            metadata.insert(format!("{prefix}.meta_data"), meta_data.data().clone());
+        }
+        if let Some(calibration) = calibration {
+            metadata.insert(format!("{prefix}.calibration"), serde_json::to_string(calibration)?);
+            metadata.insert(format!("{prefix}.unit"), calibration.unit().clone());
+        }
+    }
+    if !annotations.is_empty() {
+        metadata.insert("annotations".to_string(), serde_json::to_string(annotations)?);
+    }
+    Ok(metadata)
+}
+
+fn schema(reader: &CaptureReader, calibrations: &[Option<Calibration>]) -> anyhow::Result<Arc<Schema>> {
+    Ok(Arc::new(Schema::new_with_metadata(vec![
+        Field::new("timestamp", DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())), false),
+        Field::new("device", DataType::Utf8, false),
+        Field::new("value", DataType::UInt16, false),
+        Field::new("calibrated", DataType::Float64, true),
+    ], metadata(reader.start(), reader.devices(), calibrations, reader.annotations())?)))
+}
+
+///Reads all samples of a capture file, calibrates them with the `calibrations` of the devices,
+///and passes them to `write` in record batches.
+fn read_batches(reader: &mut CaptureReader, schema: &Arc<Schema>, calibrations: &[Option<Calibration>], mut write: impl FnMut(&RecordBatch) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    let serials: Vec<String> = reader.devices().iter().map(|device| device.id().serial().clone()).collect();
+    let mut timestamps = Vec::with_capacity(BATCH_ROWS);
+    let mut devices = Vec::with_capacity(BATCH_ROWS);
+    let mut values = Vec::with_capacity(BATCH_ROWS);
+    let mut calibrated = Vec::with_capacity(BATCH_ROWS);
+    let mut flush = |timestamps: &mut Vec<i64>, devices: &mut Vec<&str>, values: &mut Vec<u16>, calibrated: &mut Vec<Option<f64>>| -> anyhow::Result<()> {
+        if timestamps.is_empty() {
+            return Ok(());
+        }
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(TimestampMicrosecondArray::from(core::mem::take(timestamps)).with_timezone("UTC")),
+            Arc::new(StringArray::from(core::mem::take(devices))),
+            Arc::new(UInt16Array::from(core::mem::take(values))),
+            Arc::new(Float64Array::from(core::mem::take(calibrated))),
+        ];
+        write(&RecordBatch::try_new(schema.clone(), columns)?)
+    };
+    for chunk in reader.chunks(None) {
+        let chunk = chunk?;
+        let Some(serial) = serials.get(chunk.device()) else {
+            anyhow::bail!("Chunk of unknown device {}", chunk.device());
+        };
+        let calibration = calibrations.get(chunk.device()).and_then(Option::as_ref);
+        for (i, value) in chunk.samples().iter().enumerate() {
+            timestamps.push(((chunk.timestamp() + i as f64 * chunk.interval()) * 1000.).round() as i64);
+            devices.push(serial.as_str());
+            values.push(*value);
+            calibrated.push(calibration.map(|calibration| calibration.apply(*value)));
+            if timestamps.len() == BATCH_ROWS {
+                flush(&mut timestamps, &mut devices, &mut values, &mut calibrated)?;
+            }
+        }
+    }
+    flush(&mut timestamps, &mut devices, &mut values, &mut calibrated)
+}
+
+///Exports all samples of a capture file into a zstd compressed Parquet file, calibrated with the `calibrations` of its devices.
+pub fn export_parquet<W: Write + Send>(reader: &mut CaptureReader, out: W, calibrations: &[Option<Calibration>]) -> anyhow::Result<W> {
+    let schema = schema(reader, calibrations)?;
+    let key_value_metadata = schema.metadata().iter()
+        .map(|(key, value)| parquet::format::KeyValue::new(key.clone(), value.clone()))
+        .collect();
+    let properties = parquet::file::properties::WriterProperties::builder()
+        .set_compression(parquet::basic::Compression::ZSTD(parquet::basic::ZstdLevel::default()))
+        .set_max_row_group_size(ROW_GROUP_ROWS)
+        .set_key_value_metadata(Some(key_value_metadata))
+        .build();
+    let mut writer = parquet::arrow::ArrowWriter::try_new(out, schema.clone(), Some(properties))?;
+    read_batches(reader, &schema, calibrations, |batch| Ok(writer.write(batch)?))?;
+    //Writes the footer.
+    Ok(writer.into_inner()?)
+}
+
+///Exports all samples of a capture file into an Arrow IPC stream, calibrated with the `calibrations` of its devices.
+pub fn export_ipc<W: Write>(reader: &mut CaptureReader, out: W, calibrations: &[Option<Calibration>]) -> anyhow::Result<W> {
+    let schema = schema(reader, calibrations)?;
+    let mut writer = arrow_ipc::writer::StreamWriter::try_new(out, &schema)?;
+    read_batches(reader, &schema, calibrations, |batch| Ok(writer.write(batch)?))?;
+    Ok(writer.into_inner()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+    use arrow_array::Array;
+    use crate::record::capture::tests::{write, PACKET_LEN};
+    use crate::record::ExportFormat;
+    use super::*;
+
+    ///Checks the schema and the rows of an export of the capture, in which only the first device is calibrated.
+    fn check(schema: &Schema, batches: &[RecordBatch], packets: &[Vec<crate::device::Packet>], calibration: &Calibration) {
+        let fields: Vec<(&str, &DataType, bool)> = schema.fields().iter().map(|field| (field.name().as_str(), field.data_type(), field.is_nullable())).collect();
+        assert_eq!(fields, [
+            ("timestamp", &DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())), false),
+            ("device", &DataType::Utf8, false),
+            ("value", &DataType::UInt16, false),
+            ("calibrated", &DataType::Float64, true),
+        ]);
+        assert_eq!(schema.metadata()["devices"], "A1,B2");
+        assert_eq!(schema.metadata()["device.A1.unit"], "V");
+        assert!(!schema.metadata().contains_key("device.B2.calibration"));
+        assert!(schema.metadata().contains_key("annotations"));
+        let rows: usize = batches.iter().map(RecordBatch::num_rows).sum();
+        assert_eq!(rows, (packets[0].len() + packets[1].len()) * PACKET_LEN);
+        for batch in batches {
+            let devices = batch.column(1).as_any().downcast_ref::<StringArray>().unwrap();
+            let values = batch.column(2).as_any().downcast_ref::<UInt16Array>().unwrap();
+            let calibrated = batch.column(3).as_any().downcast_ref::<Float64Array>().unwrap();
+            for row in 0..batch.num_rows() {
+                match devices.value(row) {
+                    "A1" => assert_eq!(calibrated.value(row), calibration.apply(values.value(row))),
+                    _ => assert!(calibrated.is_null(row)),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn export_capture() {
+        let (path, packets) = write("columnar", None);
+        let calibration: Calibration = serde_json::from_value(serde_json::json!({"gain": 0.5, "offset": -1., "unit": "V"})).unwrap();
+        //The capture holds no calibrations, so those of the registry are taken.
+        let calibrations = BTreeMap::from([("A1".to_string(), calibration.clone())]);
+
+        let file = ExportFormat::Parquet.export(&path, tempfile::tempfile().unwrap(), &calibrations, false).unwrap();
+        let builder = parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(file).unwrap();
+        let schema = builder.schema().clone();
+        let batches = builder.build().unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        check(&schema, &batches, &packets, &calibration);
+
+        let stream = ExportFormat::ArrowIpc.export(&path, Cursor::new(Vec::new()), &calibrations, false).unwrap();
+        let reader = arrow_ipc::reader::StreamReader::try_new(Cursor::new(stream.into_inner()), None).unwrap();
+        let schema = reader.schema();
+        let batches = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        check(&schema, &batches, &packets, &calibration);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+//! Places the samples of several devices into frames at a single sample rate, for file formats like [`super::wav`] and [`super::sigrok`],
+//! which know no timestamps.
+use std::collections::VecDeque;
+use crate::device::Packet;
+use super::capture::CaptureReader;
+
+#[derive(Debug, Default)]
+struct Channel {
+    ///The device samples at the rate of the frames, so its samples follow each other without looking at their timestamps.
+    sequential: bool,
+    ///Frames, which wait for the other channels.
+    pending: VecDeque<u16>,
+    ///Held, whilst the device sends no samples.
+    last: Option<u16>,
+}
+
+///Samples of devices placed into frames.
+///
+///Devices at the sample rate of the frames fill consecutive frames, as their host timestamps jitter.
+///Their timestamps only place the first sample and the samples after a gap.
+///Channels at a lower sample rate than the frames are placed by their timestamps,
+///and like channels with lost samples, hold their last value.
+#[derive(Debug)]
+pub struct Frames {
+    ///Timestamp of the first frame in ms since the UNIX epoch.
+    start: f64,
+    sample_rate: u32,
+    channels: Vec<Channel>,
+    ///Frames taken so far.
+    taken: u64,
+    ///How many frames a channel may get ahead of the slowest one, before the slowest one is filled up. Unlimited, if `None`.
+    max_skew: Option<usize>,
+    ///Value of channels without any sample yet.
+    silence: u16,
+}
+impl Frames {
+    ///Frames of devices with the nominal sample rates `rates`, starting at `start` in ms since the UNIX epoch,
+    ///at the highest of the rates. `silence` is the value before the first sample of a channel.
+    pub fn new(start: f64, rates: &[u32], max_skew: Option<usize>, silence: u16) -> anyhow::Result<Self> {
+        let sample_rate = rates.iter().copied().max().unwrap_or_default();
+        if sample_rate == 0 {
+            anyhow::bail!("The sample rate of the devices is unknown");
+        }
+        Ok(Self {
+            start,
+            sample_rate,
+            channels: rates.iter().map(|rate| Channel { sequential: *rate == sample_rate, ..Channel::default() }).collect(),
+            taken: 0,
+            max_skew,
+            silence,
+        })
+    }
+    ///Frames for all samples of a capture file, at the highest sample rate of its devices.
+    ///The sample rate of a device without one in its `Id` is taken from its samples.
+    pub fn for_capture(reader: &mut CaptureReader, silence: u16) -> anyhow::Result<Self> {
+        let devices = reader.devices().len();
+        let Some(start) = (0..devices).filter_map(|i| reader.bounds(i)).map(|bounds| bounds.0).reduce(f64::min) else {
+            anyhow::bail!("The capture holds no samples");
+        };
+        let mut rates = Vec::with_capacity(devices);
+        for i in 0..devices {
+            rates.push(match reader.devices()[i].id().sample_rate() {
+                0 => match reader.chunks(Some(i)).next().transpose()? {
+                    Some(chunk) if chunk.interval() > 0. => (1000. / chunk.interval()).round() as u32,
+                    _ => 0,
+                },
+                v => v,
+            });
+        }
+        Self::new(start, &rates, None, silence)
+    }
+    pub const fn start(&self) -> f64 { self.start }
+    pub const fn sample_rate(&self) -> u32 { self.sample_rate }
+    ///Places `value` of the `channel`th device, sampled at `timestamp` in ms since the UNIX epoch.
+    ///`gap` is set, if samples were lost before the sample.
+    ///Samples before the first frame are dropped, as are samples of channels placed by their timestamp before samples already placed.
+    pub fn push(&mut self, channel: usize, timestamp: f64, value: u16, gap: bool) -> anyhow::Result<()> {
+        let Some(channel) = self.channels.get_mut(channel) else {
+            anyhow::bail!("Unknown channel {channel}");
+        };
+        let next = self.taken + channel.pending.len() as u64;
+        let missing = if channel.sequential && channel.last.is_some() && !gap {
+            0
+        } else {
+            let frame = ((timestamp - self.start) * f64::from(self.sample_rate) / 1000.).round();
+            if frame < 0. {
+                return Ok(());
+            }
+            match (frame as u64).checked_sub(next) {
+                Some(missing) => missing,
+                //The timestamps of a sequential channel only line up gaps, its samples are never dropped once it started.
+                None if channel.sequential && channel.last.is_some() => 0,
+                None => return Ok(()),
+            }
+        };
+        channel.pending.extend(std::iter::repeat_n(channel.last.unwrap_or(self.silence), missing as usize));
+        channel.pending.push_back(value);
+        channel.last = Some(value);
+        Ok(())
+    }
+    ///Places the samples of `packet` of the `channel`th device. `gap` is set, if samples were lost before the packet.
+    pub fn push_packet(&mut self, channel: usize, packet: &Packet, gap: bool) -> anyhow::Result<()> {
+        let timestamps = packet.timestamps();
+        for (i, value) in packet.data().data().iter().enumerate() {
+            self.push(channel, timestamps.host_at(i), *value, gap && i == 0)?;
+        }
+        Ok(())
+    }
+    ///Takes the frames, which every channel has reached, as a column per channel.
+    ///Everything is taken, if `all` is set.
+    pub fn take(&mut self, all: bool) -> Vec<Vec<u16>> {
+        let reached = self.channels.iter().map(|channel| channel.pending.len()).min().unwrap_or_default();
+        let ahead = self.channels.iter().map(|channel| channel.pending.len()).max().unwrap_or_default();
+        let count = match self.max_skew {
+            _ if all => ahead,
+            Some(max_skew) => reached.max(ahead.saturating_sub(max_skew)),
+            None => reached,
+        };
+        self.taken += count as u64;
+        self.channels.iter_mut().map(|channel| {
+            let held = channel.last.unwrap_or(self.silence);
+            let mut column: Vec<u16> = channel.pending.drain(..count.min(channel.pending.len())).collect();
+            column.resize(count, held);
+            column
+        }).collect()
+    }
+    ///Places all samples of a capture file, and passes the frames to `write` as they become ready.
+    pub fn read_capture(&mut self, reader: &mut CaptureReader, mut write: impl FnMut(Vec<Vec<u16>>) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        for chunk in reader.chunks(None) {
+            let chunk = chunk?;
+            for (i, value) in chunk.samples().iter().enumerate() {
+                self.push(chunk.device(), chunk.timestamp() + i as f64 * chunk.interval(), *value, chunk.gap() && i == 0)?;
+            }
+            write(self.take(false))?;
+        }
+        write(self.take(true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Jittering host timestamps neither drop nor duplicate samples of a device at the frame rate.
+    #[test]
+    fn jitter_keeps_every_sample() {
+        let mut frames = Frames::new(0., &[1000, 500], None, 0).unwrap();
+        for i in 0..100u16 {
+            let jitter = if i % 2 == 0 { 0.4 } else { -0.6 };
+            frames.push(0, f64::from(i) + jitter, i, false).unwrap();
+        }
+        //Lost samples leave a hole of 10 frames, which holds the last value.
+        for i in 0..10u16 {
+            frames.push(0, f64::from(110 + i), 110 + i, i == 0).unwrap();
+        }
+        for i in 0..60u16 {
+            frames.push(1, f64::from(i) * 2., i, false).unwrap();
+        }
+        let columns = frames.take(true);
+        let expected: Vec<u16> = (0..100).chain(core::iter::repeat_n(99, 10)).chain(110..120).collect();
+        assert_eq!(columns[0], expected);
+        //The slower device is placed by its timestamps, and holds its value in between.
+        assert_eq!(columns[1][..6], [0, 0, 1, 1, 2, 2]);
+    }
+}
@@ -0,0 +1,162 @@
+//! Export into sigrok session files (`.sr`), which PulseView and sigrok-cli open directly.
+//!
+//! A session file is a zip archive of a `version` file, an INI style `metadata` file and the samples of every
+//! analog channel as little-endian `f32` in files named `analog-1-<channel>-<chunk>`.
+//! Every device becomes an analog channel named by its alias and serial, see [`Frames`] for the common sample rate.
+//! The channels hold the raw samples, or the calibrated values with the unit appended to the channel name.
+//! Session files have no place for annotations, so they are left out.
+use std::io::{Seek, Write};
+use zip::write::SimpleFileOptions;
+use crate::calibration::Calibration;
+use super::capture::{CaptureReader, DeviceHeader};
+use super::frames::Frames;
+
+///How many samples of a channel go into a single file of the archive.
+const CHUNK_SAMPLES: usize = 1 << 20;
+///The session file format written.
+const SESSION_VERSION: &str = "2";
+///The libsigrok version, whose session files are written.
+const SIGROK_VERSION: &str = "0.5.2";
+
+///Writes frames of [`Frames`] into a sigrok session file.
+pub struct SigrokWriter<W: Write + Seek> {
+    zip: zip::ZipWriter<W>,
+    options: SimpleFileOptions,
+    ///Samples of every channel, which wait for a chunk to fill.
+    columns: Vec<Vec<f32>>,
+    ///Per channel, empty for raw samples.
+    calibrations: Vec<Option<Calibration>>,
+    ///Number of the next chunk, starting at 1.
+    chunk: u32,
+}
+impl<W: Write + Seek> SigrokWriter<W> {
+    ///Writes the metadata of a session with a channel for each of `devices`.
+    ///With `calibrations` of every device, the samples are written as calibrated values.
+    pub fn new(out: W, sample_rate: u32, devices: &[DeviceHeader], calibrations: Option<&[Option<Calibration>]>) -> anyhow::Result<Self> {
+        let mut zip = zip::ZipWriter::new(out);
+        let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        zip.start_file("version", options)?;
+        zip.write_all(SESSION_VERSION.as_bytes())?;
+        zip.start_file("metadata", options)?;
+        zip.write_all(metadata(sample_rate, devices, calibrations).as_bytes())?;
+        Ok(Self {
+            zip,
+            options,
+            columns: devices.iter().map(|_| Vec::new()).collect(),
+            calibrations: calibrations.map(<[_]>::to_vec).unwrap_or_default(),
+            chunk: 1,
+        })
+    }
+    ///Writes frames given as a column per channel, as taken from [`Frames::take`].
+    pub fn write(&mut self, columns: &[Vec<u16>]) -> anyhow::Result<()> {
+        for (i, (buffer, column)) in self.columns.iter_mut().zip(columns).enumerate() {
+            match self.calibrations.get(i) {
+                Some(Some(calibration)) => buffer.extend(column.iter().map(|value| calibration.apply(*value) as f32)),
+                Some(None) | None => buffer.extend(column.iter().map(|value| f32::from(*value))),
+            }
+        }
+        while self.columns.first().is_some_and(|column| column.len() >= CHUNK_SAMPLES) {
+            self.write_chunk(CHUNK_SAMPLES)?;
+        }
+        Ok(())
+    }
+    ///Writes the remaining samples and returns the writer.
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        let remaining = self.columns.first().map(Vec::len).unwrap_or_default();
+        if remaining > 0 {
+            self.write_chunk(remaining)?;
+        }
+        Ok(self.zip.finish()?)
+    }
+    fn write_chunk(&mut self, samples: usize) -> anyhow::Result<()> {
+        for (i, column) in self.columns.iter_mut().enumerate() {
+            self.zip.start_file(format!("analog-1-{}-{}", i + 1, self.chunk), self.options)?;
+            let data: Vec<u8> = column.drain(..samples).flat_map(f32::to_le_bytes).collect();
+            self.zip.write_all(&data)?;
+        }
+        self.chunk += 1;
+        Ok(())
+    }
+}
+
+///Exports all samples of a capture file into a sigrok session file, at the highest sample rate of its devices.
+///With `calibrations` of every device, calibrated values are exported instead of the raw samples.
+pub fn export<W: Write + Seek>(reader: &mut CaptureReader, out: W, calibrations: Option<&[Option<Calibration>]>) -> anyhow::Result<W> {
+    //Channels without samples yet read a raw 0.
+    let mut frames = Frames::for_capture(reader, 0)?;
+    let mut writer = SigrokWriter::new(out, frames.sample_rate(), reader.devices(), calibrations)?;
+    frames.read_capture(reader, |columns| writer.write(&columns))?;
+    writer.finish()
+}
+
+///The `metadata` file, which describes the channels.
+fn metadata(sample_rate: u32, devices: &[DeviceHeader], calibrations: Option<&[Option<Calibration>]>) -> String {
+    let mut metadata = format!("[global]\nsigrok version={SIGROK_VERSION}\n\n[device 1]\n");
+    metadata.push_str(&format!("samplerate={}\n", samplerate_string(sample_rate)));
+    metadata.push_str("total probes=0\n");
+    metadata.push_str(&format!("total analog={}\n", devices.len()));
+    for (i, device) in devices.iter().enumerate() {
+        let mut name = match device.alias() {
+            Some(alias) => format!("{alias} ({})", device.id().serial()),
+            None => device.id().serial().clone(),
+        };
+        if let Some(Some(calibration)) = calibrations.and_then(|calibrations| calibrations.get(i)) && !calibration.unit().is_empty() {
+            name.push_str(&format!(" [{}]", calibration.unit()));
+        }
+        //A line break would end the value.
+        metadata.push_str(&format!("analog{}={}\n", i + 1, name.replace(['\n', '\r'], " ")));
+    }
+    metadata
+}
+
+///Formats a sample rate like libsigrok, e.g. `250 kHz`.
+fn samplerate_string(sample_rate: u32) -> String {
+    match sample_rate {
+        v if v >= 1_000_000_000 && v % 1_000_000_000 == 0 => format!("{} GHz", v / 1_000_000_000),
+        v if v >= 1_000_000 && v % 1_000_000 == 0 => format!("{} MHz", v / 1_000_000),
+        v if v >= 1_000 && v % 1_000 == 0 => format!("{} kHz", v / 1_000),
+        v => format!("{v} Hz"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+    use crate::record::capture::tests::{write, PACKET_LEN};
+    use super::*;
+
+    fn read_file(archive: &mut zip::ZipArchive<Cursor<Vec<u8>>>, name: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        archive.by_name(name).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn export_capture() {
+        let (path, packets) = write("sigrok", None);
+        let calibration: Calibration = serde_json::from_value(serde_json::json!({"gain": 0.5, "offset": -1., "unit": "V"})).unwrap();
+        let mut reader = CaptureReader::open(&path).unwrap();
+        let out = export(&mut reader, Cursor::new(Vec::new()), Some(&[Some(calibration.clone()), None])).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let mut archive = zip::ZipArchive::new(out).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort_unstable();
+        assert_eq!(names, ["analog-1-1-1", "analog-1-2-1", "metadata", "version"]);
+        assert_eq!(read_file(&mut archive, "version"), SESSION_VERSION.as_bytes());
+        let metadata = String::from_utf8(read_file(&mut archive, "metadata")).unwrap();
+        assert!(metadata.contains("samplerate=1 kHz\n"), "{metadata}");
+        assert!(metadata.contains("total analog=2\n"), "{metadata}");
+        //Only the calibrated channel has a unit.
+        assert!(metadata.contains("analog1=A1 [V]\n"), "{metadata}");
+        assert!(metadata.contains("analog2=B2\n"), "{metadata}");
+        let floats = |bytes: Vec<u8>| bytes.chunks(4).map(|v| f32::from_le_bytes(v.try_into().unwrap())).collect::<Vec<_>>();
+        let first = floats(read_file(&mut archive, "analog-1-1-1"));
+        let second = floats(read_file(&mut archive, "analog-1-2-1"));
+        assert_eq!(first.len(), packets[0].len() * PACKET_LEN);
+        assert_eq!(second.len(), first.len());
+        let expected: Vec<f32> = packets[0].iter().flat_map(|packet| packet.data().data().iter().map(|v| calibration.apply(*v) as f32)).collect();
+        assert_eq!(first, expected);
+        let raw: Vec<f32> = packets[1].iter().flat_map(|packet| packet.data().data().iter().map(|v| f32::from(*v))).collect();
+        assert_eq!(second[..raw.len()], raw);
+    }
+}
@@ -0,0 +1,257 @@
+//! Export of samples into 16-bit PCM WAV files, e.g. to open them in Audacity.
+//!
+//! Every device becomes a channel, see [`Frames`]. The unsigned samples are shifted into signed PCM, so the full `u16` range
+//! maps onto the full `i16` range and the middle of the range becomes silence.
+//! A `LIST` `INFO` chunk names the device serials and the start time of the samples.
+//! Annotations become cue points after the samples, with their label in a `LIST` `adtl` chunk.
+use std::io::{Seek, SeekFrom, Write};
+use crate::device::annotation::Annotation;
+use super::capture::{CaptureReader, DeviceHeader};
+use super::frames::Frames;
+
+///Length of the `RIFF` header and the `fmt ` chunk.
+const FMT_END: u64 = 36;
+///The sample, which becomes silence.
+pub const SILENCE: u16 = 0x8000;
+
+///Writes frames of [`Frames`] into a WAV file.
+#[derive(Debug)]
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    ///Where the file starts in `out`.
+    origin: u64,
+    ///Offset of the size of the `data` chunk.
+    data_size_offset: u64,
+    channels: usize,
+    ///Frames written so far.
+    frames: u64,
+    ///Timestamp of the first frame in ms since the UNIX epoch.
+    start: f64,
+    sample_rate: u32,
+    ///Written as cue points by `finish`.
+    annotations: Vec<Annotation>,
+}
+impl<W: Write + Seek> WavWriter<W> {
+    ///Writes the header of a file with a channel for each of `devices`, whose first frame is sampled at `start` in ms since the UNIX epoch.
+    pub fn new(mut out: W, start: f64, sample_rate: u32, devices: &[DeviceHeader]) -> anyhow::Result<Self> {
+        let channels = u16::try_from(devices.len())?;
+        let origin = out.stream_position()?;
+        let block_align = channels * 2;
+        out.write_all(b"RIFF")?;
+        //Both sizes are written by `finish`.
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * u32::from(block_align)).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?;
+        let info = info_chunk(start, sample_rate, devices);
+        out.write_all(&info)?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(Self {
+            out,
+            origin,
+            data_size_offset: origin + FMT_END + info.len() as u64 + 4,
+            channels: devices.len(),
+            frames: 0,
+            start,
+            sample_rate,
+            annotations: Vec::new(),
+        })
+    }
+    ///Bytes written so far.
+    pub const fn position(&self) -> u64 {
+        self.data_size_offset - self.origin + 4 + self.frames * self.channels as u64 * 2
+    }
+    ///Writes frames given as a column per channel, as taken from [`Frames::take`].
+    pub fn write(&mut self, columns: &[Vec<u16>]) -> anyhow::Result<()> {
+        let count = columns.first().map(Vec::len).unwrap_or_default();
+        if count == 0 {
+            return Ok(());
+        }
+        //The sizes in the header are 32 bit.
+        if self.position() + (count * self.channels * 2) as u64 > u64::from(u32::MAX) {
+            anyhow::bail!("WAV files can't be larger than 4 GiB, use --rotate-size to split the recording");
+        }
+        let mut data = Vec::with_capacity(count * self.channels * 2);
+        for i in 0..count {
+            for column in columns {
+                data.extend_from_slice(&pcm(column[i]).to_le_bytes());
+            }
+        }
+        self.out.write_all(&data)?;
+        self.frames += count as u64;
+        Ok(())
+    }
+    ///Marks `annotation` with a cue point.
+    pub fn annotate(&mut self, annotation: Annotation) {
+        self.annotations.push(annotation);
+    }
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+    ///Writes the cue points, completes the header and returns the writer.
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        let data_end = self.out.stream_position()?;
+        if !self.annotations.is_empty() {
+            let cues = cue_chunks(&self.annotations, self.start, self.sample_rate, self.frames);
+            self.out.write_all(&cues)?;
+        }
+        let end = self.out.stream_position()?;
+        self.out.seek(SeekFrom::Start(self.origin + 4))?;
+        self.out.write_all(&u32::try_from(end - self.origin - 8)?.to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(self.data_size_offset))?;
+        self.out.write_all(&u32::try_from(data_end - self.data_size_offset - 4)?.to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+///Exports all samples of a capture file into a WAV file, at the highest sample rate of its devices.
+pub fn export<W: Write + Seek>(reader: &mut CaptureReader, out: W) -> anyhow::Result<W> {
+    let mut frames = Frames::for_capture(reader, SILENCE)?;
+    let mut writer = WavWriter::new(out, frames.start(), frames.sample_rate(), reader.devices())?;
+    for annotation in reader.annotations() {
+        writer.annotate(annotation.clone());
+    }
+    frames.read_capture(reader, |columns| writer.write(&columns))?;
+    writer.finish()
+}
+
+///Converts an unsigned sample into signed PCM.
+const fn pcm(value: u16) -> i16 {
+    (value ^ SILENCE) as i16
+}
+
+///Appends a sub-chunk with the id `id` and `data` to `chunk`. Chunks are aligned to 2 bytes.
+fn push_chunk(chunk: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    chunk.extend_from_slice(id);
+    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+}
+
+///The `cue ` chunk with a cue point per annotation, and the `LIST` `adtl` chunk with their labels.
+///Annotated ranges get an `ltxt` chunk with their length, and key/values a `note` chunk.
+fn cue_chunks(annotations: &[Annotation], start: f64, sample_rate: u32, frames: u64) -> Vec<u8> {
+    let frame = |timestamp: f64| (((timestamp - start) * f64::from(sample_rate) / 1000.).round().max(0.) as u64).min(frames) as u32;
+    let mut cue = (annotations.len() as u32).to_le_bytes().to_vec();
+    let mut adtl = b"adtl".to_vec();
+    for (i, annotation) in annotations.iter().enumerate() {
+        let id = i as u32 + 1;
+        let position = frame(annotation.timestamp());
+        cue.extend_from_slice(&id.to_le_bytes());
+        cue.extend_from_slice(&position.to_le_bytes());
+        cue.extend_from_slice(b"data");
+        cue.extend_from_slice(&0u32.to_le_bytes());
+        cue.extend_from_slice(&0u32.to_le_bytes());
+        cue.extend_from_slice(&position.to_le_bytes());
+
+        let mut label = id.to_le_bytes().to_vec();
+        label.extend_from_slice(annotation.label().as_bytes());
+        label.push(0);
+        push_chunk(&mut adtl, b"labl", &label);
+        if let Some(end) = annotation.end() {
+            let mut text = id.to_le_bytes().to_vec();
+            text.extend_from_slice(&(frame(end) - position).to_le_bytes());
+            text.extend_from_slice(b"rgn ");
+            //Country, language, dialect and code page.
+            text.extend_from_slice(&[0; 8]);
+            push_chunk(&mut adtl, b"ltxt", &text);
+        }
+        if !annotation.values().is_empty() {
+            let mut note = id.to_le_bytes().to_vec();
+            note.extend_from_slice(serde_json::to_string(annotation.values()).unwrap_or_default().as_bytes());
+            note.push(0);
+            push_chunk(&mut adtl, b"note", &note);
+        }
+    }
+    let mut chunks = Vec::new();
+    push_chunk(&mut chunks, b"cue ", &cue);
+    push_chunk(&mut chunks, b"LIST", &adtl);
+    chunks
+}
+
+///The `LIST` `INFO` chunk with the serials of the devices and the start time.
+fn info_chunk(start: f64, sample_rate: u32, devices: &[DeviceHeader]) -> Vec<u8> {
+    let started = chrono::DateTime::from_timestamp_micros((start * 1000.) as i64)
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_default();
+    let serials: Vec<&str> = devices.iter().map(|device| device.id().serial().as_str()).collect();
+    let mut comment = String::new();
+    for (i, device) in devices.iter().enumerate() {
+        comment.push_str(&format!("channel {}: {}", i + 1, device.id().serial()));
+        if let Some(alias) = device.alias() {
+            comment.push_str(&format!(" ({alias})"));
+        }
+        comment.push_str(&format!(", {}, {} Hz\n", device.model(), device.id().sample_rate()));
+    }
+    comment.push_str(&format!("start: {start:.3} ms since the UNIX epoch, resampled to {sample_rate} Hz"));
+
+    let mut info = b"INFO".to_vec();
+    for (id, text) in [(b"ISRC", serials.join(", ")), (b"ICRD", started), (b"ICMT", comment), (b"ISFT", "OmnAIScope-DataServer".to_string())] {
+        let mut text = text.into_bytes();
+        text.push(0);
+        push_chunk(&mut info, id, &text);
+    }
+    let mut chunk = Vec::new();
+    push_chunk(&mut chunk, b"LIST", &info);
+    chunk
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::record::capture::tests::{write, PACKET_LEN};
+    use super::*;
+
+    ///The chunks of a RIFF file after the `WAVE` form type, as id and data.
+    fn chunks(bytes: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut chunks = Vec::new();
+        let mut offset = 12;
+        while offset + 8 <= bytes.len() {
+            let len = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            chunks.push((bytes[offset..offset + 4].try_into().unwrap(), &bytes[offset + 8..offset + 8 + len]));
+            offset += 8 + len + len % 2;
+        }
+        chunks
+    }
+
+    #[test]
+    fn export_capture() {
+        let (path, packets) = write("wav", None);
+        let mut reader = CaptureReader::open(&path).unwrap();
+        let bytes = export(&mut reader, Cursor::new(Vec::new())).unwrap().into_inner();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[8..12], b"WAVE");
+        let chunks = chunks(&bytes);
+        assert_eq!(chunks.iter().map(|(id, _)| id).collect::<Vec<_>>(), [b"fmt ", b"LIST", b"data", b"cue ", b"LIST"]);
+        let fmt = chunks[0].1;
+        //PCM with 2 channels of 16 bit at 1000 Hz.
+        assert_eq!(u16::from_le_bytes(fmt[0..2].try_into().unwrap()), 1);
+        assert_eq!(u16::from_le_bytes(fmt[2..4].try_into().unwrap()), 2);
+        assert_eq!(u32::from_le_bytes(fmt[4..8].try_into().unwrap()), 1000);
+        assert_eq!(u16::from_le_bytes(fmt[14..16].try_into().unwrap()), 16);
+        //The longer recording of the first device determines the number of frames, the second device holds its last sample.
+        let data: Vec<i16> = chunks[2].1.chunks(2).map(|v| i16::from_le_bytes(v.try_into().unwrap())).collect();
+        assert_eq!(data.len(), packets[0].len() * PACKET_LEN * 2);
+        let last = *packets[1].last().unwrap().data().data().last().unwrap();
+        for (frame, values) in data.chunks(2).enumerate() {
+            assert_eq!(values[0], pcm(packets[0][frame / PACKET_LEN].data().data()[frame % PACKET_LEN]));
+            let second = packets[1].get(frame / PACKET_LEN).map_or(last, |packet| packet.data().data()[frame % PACKET_LEN]);
+            assert_eq!(values[1], pcm(second));
+        }
+        //A cue point for the annotation.
+        assert_eq!(u32::from_le_bytes(chunks[3].1[0..4].try_into().unwrap()), 1);
+    }
+}
@@ -1,12 +1,26 @@
+mod aliases;
+mod calibrations;
+mod devices;
+pub mod metrics;
+pub mod recordings;
+mod sessions;
+mod statistics;
 mod uuid;
 mod ws;
 
 pub use ws::ws_impl;
 pub use uuid::get_devices;
+pub use statistics::get_statistics;
+pub use metrics::get_metrics;
+pub use recordings::{get_recordings, get_recording_downsampled, get_recording_export};
+pub use devices::{put_rgb, get_metadata, put_metadata, identify, get_history, post_annotation};
+pub use aliases::{get_aliases, put_alias, delete_alias};
+pub use calibrations::{get_calibrations, get_calibration, put_calibration, delete_calibration, get_calibration_measurement, post_calibration_fit};
+pub use sessions::{get_sessions, get_session, put_session_annotation, put_session_tag, delete_session_tag, delete_session};
 
 #[rocket::get("/help")]
 pub async fn help() -> &'static str {
-    "Starting the websocket under ip/ws. Set one or multiply UUIDs by writing them after the hello message. \nThe last input can be a sampling rate. The default sampling Rate is 60 Sa/s.\nThe sampling Rate cant be higher than 100.000 Sa/s. Press enter to start the measurement."
+    "Starting the websocket under ip/ws. Set one or multiply UUIDs by writing them after the hello message. \nThe last input can be a sampling rate. Without one, the samples are sent at the rate of the devices.\nThe sampling Rate cant be higher than 100.000 Sa/s. Press enter to start the measurement.\nAfter the sampling rate, the format of the measurements can follow: json (default), msgpack, cbor or binary.\nAlternatively send JSON commands like {\"version\": 1, \"id\": 1, \"command\": \"subscribe\", \"devices\": [\"UUID\"], \"sampling_rate\": 100}.\nCommands: subscribe, unsubscribe, start, stop, set_rate, get_devices, get_downsampled_in_range, ping, annotate, set_trigger, clear_trigger, arm_trigger.\nget_downsampled_in_range reads a capture file listed under /recordings instead, if given a \"recording\".\nSubscribe with \"values\": \"calibrated\" to also receive the values converted with the calibrations under /devices/UUID/calibration.\nWith set_trigger, frames around trigger points are sent instead of the stream, like on an oscilloscope."
 }
 
 /*
@@ -0,0 +1,85 @@
+use std::sync::Arc;
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use tokio::sync::RwLock;
+use crate::aliases::{self, AliasRegistry};
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+struct Alias {
+    alias: String,
+}
+
+#[rocket::get("/aliases")]
+pub async fn get_aliases(aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>) -> Result<String, Custom<String>> {
+    serde_json::to_string(aliases.read().await.aliases())
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+///Sets the alias of the device with the serial (or current alias) `name`.
+///With `mirror=true` the alias is also written into the metadata of the device, if it is connected.
+#[rocket::put("/aliases/<name>?<mirror>", data = "<body>")]
+pub async fn put_alias(name: &str, mirror: Option<bool>, body: String, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>) -> Result<String, Custom<String>> {
+    let alias = match serde_json::from_str::<Alias>(&body) {
+        Ok(v) => v.alias,
+        Err(_) => body.trim().to_string(),
+    };
+    let connected = device_list.read().await.serials().await;
+    let serial = {
+        let mut aliases = aliases.write().await;
+        let serial = aliases.resolve(name).to_string();
+        aliases.set(serial.clone(), alias.clone(), &connected)
+            .map_err(|err|Custom(Status::BadRequest, err.to_string()))?;
+        serial
+    };
+    if mirror.unwrap_or(false) {
+        //Only wait for the device with both registries unlocked.
+        let pending = {
+            let device_list = device_list.read().await;
+            let device = device_list.find(&serial, &*aliases.read().await).await
+                .ok_or_else(||Custom(Status::NotFound, format!("Stored the alias, but can't mirror it to the disconnected device {serial}")))?;
+            let meta_data = aliases::with_meta_data_alias(device.meta_data().await.as_ref(), Some(&alias));
+            device.set_meta_data(meta_data)
+                .map_err(|err|Custom(Status::InternalServerError, format!("Stored the alias, but failed to mirror it to the device: {err}")))?
+        };
+        pending.wait().await
+            .ok_or_else(||Custom(Status::GatewayTimeout, "Stored the alias, but the device didn't confirm the mirrored alias in time".to_string()))?;
+    }
+    serde_json::to_string(&Alias{ alias })
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+#[rocket::delete("/aliases/<name>?<mirror>")]
+pub async fn delete_alias(name: &str, mirror: Option<bool>, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>) -> Result<Status, Custom<String>> {
+    let (serial, removed) = {
+        let mut aliases = aliases.write().await;
+        let serial = aliases.resolve(name).to_string();
+        let removed = aliases.remove(&serial)
+            .map_err(|err|Custom(Status::InternalServerError, err.to_string()))?;
+        (serial, removed)
+    };
+    if mirror.unwrap_or(false) {
+        let pending = {
+            let device_list = device_list.read().await;
+            match device_list.find(&serial, &*aliases.read().await).await {
+                Some(device) => {
+                    let meta_data = device.meta_data().await;
+                    match meta_data.as_ref().and_then(aliases::meta_data_alias) {
+                        Some(_) => Some(device.set_meta_data(aliases::with_meta_data_alias(meta_data.as_ref(), None))
+                            .map_err(|err|Custom(Status::InternalServerError, format!("Removed the alias, but failed to remove it from the device: {err}")))?),
+                        None => None,
+                    }
+                },
+                None => None,
+            }
+        };
+        if let Some(pending) = pending {
+            pending.wait().await
+                .ok_or_else(||Custom(Status::GatewayTimeout, "Removed the alias, but the device didn't confirm the removal in time".to_string()))?;
+            return Ok(Status::NoContent);
+        }
+    }
+    match removed {
+        Some(_) => Ok(Status::NoContent),
+        None => Err(Custom(Status::NotFound, format!("No alias for {name}"))),
+    }
+}
@@ -0,0 +1,169 @@
+use std::sync::Arc;
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use tokio::sync::RwLock;
+use crate::aliases::AliasRegistry;
+use crate::calibration::{self, Calibration, CalibrationPoint, CalibrationRegistry};
+use crate::device::Device;
+
+///How many seconds of recent samples are averaged by `GET /devices/<serial>/calibration/measure`, if the request doesn't say otherwise.
+const DEFAULT_MEASURE_SECONDS: f64 = 1.;
+const MAX_MEASURE_SECONDS: f64 = 60.;
+
+#[derive(serde_derive::Deserialize)]
+struct Fit {
+    ///The raw samples measured at two known reference inputs, and their values.
+    points: [CalibrationPoint; 2],
+    ///Keeps the current unit, if not given.
+    #[serde(default)]
+    unit: Option<String>,
+}
+
+#[derive(serde_derive::Serialize)]
+struct Measurement {
+    ///Mean of the raw samples.
+    raw: f64,
+    samples: usize,
+    ///The mean converted with the current calibration, if the device has one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    calibrated: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit: Option<String>,
+}
+
+///Finds the device with the serial or alias `name`, and returns its serial. A disconnected device is assumed to be named by its serial or a stored alias.
+async fn find<'a>(name: &str, device_list: &'a crate::DeviceList, aliases: &AliasRegistry) -> (String, Option<&'a Device>) {
+    match device_list.find(name, aliases).await {
+        Some(device) => match device.id().await {
+            Some(id) => (id.serial().clone(), Some(device)),
+            None => (aliases.resolve(name).to_string(), Some(device)),
+        },
+        None => (aliases.resolve(name).to_string(), None),
+    }
+}
+
+///Writes `calibration` into the metadata of `device`, or removes it from there.
+async fn mirror(device: &Device, calibration: Option<&Calibration>) -> anyhow::Result<()> {
+    let meta_data = calibration::with_meta_data_calibration(device.meta_data().await.as_ref(), calibration)?;
+    if device.set_meta_data(meta_data)?.wait().await.is_none() {
+        anyhow::bail!("The device didn't confirm the metadata in time");
+    }
+    Ok(())
+}
+
+#[rocket::get("/calibrations")]
+pub async fn get_calibrations(calibrations: &rocket::State<Arc<RwLock<CalibrationRegistry>>>) -> Result<String, Custom<String>> {
+    serde_json::to_string(calibrations.read().await.profiles())
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+///Returns the calibration of a device, or the calibration stored in its metadata.
+#[rocket::get("/devices/<serial>/calibration")]
+pub async fn get_calibration(serial: &str, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>, calibrations: &rocket::State<Arc<RwLock<CalibrationRegistry>>>) -> Result<String, Custom<String>> {
+    let device_list = device_list.read().await;
+    let calibrations = calibrations.read().await;
+    let calibration = match find(serial, &device_list, &*aliases.read().await).await {
+        (_, Some(device)) => device.calibration(&calibrations).await,
+        (serial, None) => calibrations.calibration(&serial).cloned(),
+    };
+    let calibration = calibration.ok_or_else(||Custom(Status::NotFound, format!("No calibration for {serial}")))?;
+    serde_json::to_string(&calibration)
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+///Sets the calibration of the device with the serial (or alias) `serial`, e.g. `{"offset": -1.65, "gain": 0.00005, "unit": "V"}`.
+///With `mirror=true` the calibration is also written into the metadata of the device, if it is connected.
+#[rocket::put("/devices/<serial>/calibration?<mirror>", data = "<body>")]
+pub async fn put_calibration(serial: &str, mirror: Option<bool>, body: String, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>, calibrations: &rocket::State<Arc<RwLock<CalibrationRegistry>>>) -> Result<String, Custom<String>> {
+    let calibration = serde_json::from_str::<Calibration>(&body)
+        .map_err(|err|Custom(Status::BadRequest, format!("Expected a calibration like {{\"offset\":0,\"gain\":1,\"unit\":\"V\"}}: {err}")))?;
+    calibration.validate()
+        .map_err(|err|Custom(Status::BadRequest, err.to_string()))?;
+    let device_list = device_list.read().await;
+    let (serial, device) = find(serial, &device_list, &*aliases.read().await).await;
+    calibrations.write().await.set(serial.clone(), calibration.clone())
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))?;
+    if mirror.unwrap_or(false) {
+        let device = device.ok_or_else(||Custom(Status::NotFound, format!("Stored the calibration, but can't mirror it to the disconnected device {serial}")))?;
+        self::mirror(device, Some(&calibration)).await
+            .map_err(|err|Custom(Status::InternalServerError, format!("Stored the calibration, but failed to mirror it to the device: {err}")))?;
+    }
+    serde_json::to_string(&calibration)
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+#[rocket::delete("/devices/<serial>/calibration?<mirror>")]
+pub async fn delete_calibration(serial: &str, mirror: Option<bool>, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>, calibrations: &rocket::State<Arc<RwLock<CalibrationRegistry>>>) -> Result<Status, Custom<String>> {
+    let device_list = device_list.read().await;
+    let (serial, device) = find(serial, &device_list, &*aliases.read().await).await;
+    let removed = calibrations.write().await.remove(&serial)
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))?;
+    if mirror.unwrap_or(false) && let Some(device) = device
+        && device.meta_data().await.as_ref().and_then(calibration::meta_data_calibration).is_some() {
+        self::mirror(device, None).await
+            .map_err(|err|Custom(Status::InternalServerError, format!("Removed the calibration, but failed to remove it from the device: {err}")))?;
+        return Ok(Status::NoContent);
+    }
+    match removed {
+        Some(_) => Ok(Status::NoContent),
+        None => Err(Custom(Status::NotFound, format!("No calibration for {serial}"))),
+    }
+}
+
+///Averages the raw samples of the last `seconds` (default 1), while a known reference input is applied to the device.
+///Two such measurements make the points for `POST /devices/<serial>/calibration/fit`.
+#[rocket::get("/devices/<serial>/calibration/measure?<seconds>")]
+pub async fn get_calibration_measurement(serial: &str, seconds: Option<f64>, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>, calibrations: &rocket::State<Arc<RwLock<CalibrationRegistry>>>) -> Result<String, Custom<String>> {
+    let seconds = seconds.unwrap_or(DEFAULT_MEASURE_SECONDS);
+    if !(seconds > 0. && seconds <= MAX_MEASURE_SECONDS) {
+        return Err(Custom(Status::BadRequest, format!("Can average between 0 and {MAX_MEASURE_SECONDS} seconds")));
+    }
+    let device_list = device_list.read().await;
+    let device = device_list.find(serial, &*aliases.read().await).await
+        .ok_or_else(||Custom(Status::NotFound, format!("Device not found: {serial}")))?;
+    let Some((_, last)) = device.history_bounds().await else {
+        return Err(Custom(Status::Conflict, format!("Device {serial} has no samples yet, start capturing first")));
+    };
+    let (mut sum, mut samples) = (0., 0);
+    device.history(last - seconds * 1000., last).await.for_each(|sample| {
+        sum += f64::from(sample.value());
+        samples += 1;
+    });
+    let raw = sum / samples as f64;
+    let calibration = device.calibration(&*calibrations.read().await).await;
+    serde_json::to_string(&Measurement {
+        raw,
+        samples,
+        calibrated: calibration.as_ref().map(|calibration| calibration.convert(raw)),
+        unit: calibration.map(|calibration| calibration.unit().clone()),
+    })
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+///Fits gain and offset of the calibration of a device from two reference inputs, e.g.
+///`{"points": [{"raw": 1200, "value": 0}, {"raw": 52000, "value": 5}], "unit": "V"}`, and stores it.
+///A polynomial or lookup table of the current calibration is kept. With `mirror=true` the result is also written into the metadata of the device.
+#[rocket::post("/devices/<serial>/calibration/fit?<mirror>", data = "<body>")]
+pub async fn post_calibration_fit(serial: &str, mirror: Option<bool>, body: String, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>, calibrations: &rocket::State<Arc<RwLock<CalibrationRegistry>>>) -> Result<String, Custom<String>> {
+    let fit = serde_json::from_str::<Fit>(&body)
+        .map_err(|err|Custom(Status::BadRequest, format!("Expected two reference points like {{\"points\":[{{\"raw\":1200,\"value\":0}},{{\"raw\":52000,\"value\":5}}],\"unit\":\"V\"}}: {err}")))?;
+    let device_list = device_list.read().await;
+    let (serial, device) = find(serial, &device_list, &*aliases.read().await).await;
+    let mut calibrations = calibrations.write().await;
+    let current = match device {
+        Some(device) => device.calibration(&calibrations).await,
+        None => calibrations.calibration(&serial).cloned(),
+    };
+    let calibration = current.unwrap_or_default().fit(fit.points, fit.unit)
+        .map_err(|err|Custom(Status::BadRequest, err.to_string()))?;
+    calibrations.set(serial.clone(), calibration.clone())
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))?;
+    drop(calibrations);
+    if mirror.unwrap_or(false) {
+        let device = device.ok_or_else(||Custom(Status::NotFound, format!("Stored the calibration, but can't mirror it to the disconnected device {serial}")))?;
+        self::mirror(device, Some(&calibration)).await
+            .map_err(|err|Custom(Status::InternalServerError, format!("Stored the calibration, but failed to mirror it to the device: {err}")))?;
+    }
+    serde_json::to_string(&calibration)
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
@@ -0,0 +1,141 @@
+use std::sync::Arc;
+use std::time::Duration;
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use tokio::sync::RwLock;
+use crate::aliases::AliasRegistry;
+use crate::device::annotation::AnnotationRequest;
+use crate::device::messages::SetRGB;
+use super::ws::{WSMeasurement, WSMeasurementData};
+
+///How long a device identifies itself, if the request doesn't say otherwise.
+const DEFAULT_IDENTIFY_SECONDS: u64 = 5;
+const MAX_IDENTIFY_SECONDS: u64 = 60;
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+struct MetaData {
+    data: Option<String>,
+}
+
+fn not_found(serial: &str) -> Custom<String> {
+    Custom(Status::NotFound, format!("Device not found: {serial}"))
+}
+
+#[rocket::put("/devices/<serial>/rgb", data = "<body>")]
+pub async fn put_rgb(serial: &str, body: String, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>) -> Result<String, Custom<String>> {
+    let rgb = serde_json::from_str::<SetRGB>(&body)
+        .map_err(|err|Custom(Status::BadRequest, format!("Expected a colour like {{\"r\":0,\"g\":0,\"b\":255}}: {err}")))?;
+    let device_list = device_list.read().await;
+    let device = device_list.find(serial, &*aliases.read().await).await.ok_or_else(||not_found(serial))?;
+    if !device.has_led() {
+        return Err(no_led(serial));
+    }
+    device.set_rgb(rgb.clone()).await
+        .map_err(|err|Custom(Status::InternalServerError, format!("Failed to set the colour: {err}")))?;
+    serde_json::to_string(&rgb)
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+fn no_led(serial: &str) -> Custom<String> {
+    Custom(Status::UnprocessableEntity, format!("Device {serial} has no controllable LED"))
+}
+
+fn no_answer(serial: &str) -> Custom<String> {
+    Custom(Status::GatewayTimeout, format!("Device {serial} didn't answer in time"))
+}
+
+#[rocket::get("/devices/<serial>/metadata")]
+pub async fn get_metadata(serial: &str, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>) -> Result<String, Custom<String>> {
+    let pending = {
+        let device_list = device_list.read().await;
+        let device = device_list.find(serial, &*aliases.read().await).await.ok_or_else(||not_found(serial))?;
+        device.request_meta_data()
+            .map_err(|err|Custom(Status::InternalServerError, format!("Failed to request the metadata: {err}")))?
+    };
+    let meta_data = pending.wait().await.ok_or_else(||no_answer(serial))?;
+    serde_json::to_string(&MetaData{ data: Some(meta_data.data().clone()) })
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+#[rocket::put("/devices/<serial>/metadata", data = "<body>")]
+pub async fn put_metadata(serial: &str, body: String, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>) -> Result<String, Custom<String>> {
+    let data = match serde_json::from_str::<MetaData>(&body) {
+        Ok(MetaData{ data: Some(data) }) => data,
+        Ok(MetaData{ data: None }) => return Err(Custom(Status::BadRequest, "The metadata must be a string, not null".to_string())),
+        Err(err) => return Err(Custom(Status::BadRequest, format!("Expected metadata like {{\"data\":\"...\"}}: {err}"))),
+    };
+    let pending = {
+        let device_list = device_list.read().await;
+        let device = device_list.find(serial, &*aliases.read().await).await.ok_or_else(||not_found(serial))?;
+        device.set_meta_data(data)
+            .map_err(|err|Custom(Status::InternalServerError, format!("Failed to set the metadata: {err}")))?
+    };
+    let meta_data = pending.wait().await.ok_or_else(||no_answer(serial))?;
+    serde_json::to_string(&MetaData{ data: Some(meta_data.data().clone()) })
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+///Makes the device blink for `seconds` (default 5), to find it on the bench.
+#[rocket::post("/devices/<serial>/identify?<seconds>")]
+pub async fn identify(serial: &str, seconds: Option<u64>, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>) -> Result<Status, Custom<String>> {
+    let seconds = seconds.unwrap_or(DEFAULT_IDENTIFY_SECONDS);
+    if seconds > MAX_IDENTIFY_SECONDS {
+        return Err(Custom(Status::BadRequest, format!("Can identify for at most {MAX_IDENTIFY_SECONDS} seconds")));
+    }
+    let device_list = device_list.read().await;
+    let device = device_list.find(serial, &*aliases.read().await).await.ok_or_else(||not_found(serial))?;
+    if !device.has_led() {
+        return Err(no_led(serial));
+    }
+    match device.identify(Duration::from_secs(seconds)) {
+        Ok(true) => Ok(Status::Accepted),
+        Ok(false) => Err(Custom(Status::Conflict, format!("Device {serial} is already identifying itself"))),
+        Err(err) => Err(Custom(Status::InternalServerError, err.to_string())),
+    }
+}
+
+///Returns the recorded samples of a device between `tmin` and `tmax`, in milliseconds since the UNIX epoch.
+///Without bounds, everything still held in memory is returned.
+#[rocket::get("/devices/<serial>/history?<tmin>&<tmax>")]
+pub async fn get_history(serial: &str, tmin: Option<f64>, tmax: Option<f64>, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>) -> Result<String, Custom<String>> {
+    let device_list = device_list.read().await;
+    let device = device_list.find(serial, &*aliases.read().await).await.ok_or_else(||not_found(serial))?;
+    let id = device.id().await.ok_or_else(||not_found(serial))?;
+    let mut data = Vec::new();
+    device.history(tmin.unwrap_or(f64::NEG_INFINITY), tmax.unwrap_or(f64::INFINITY)).await.for_each(|sample| data.push(WSMeasurementData{
+        timestamp: sample.timestamp(),
+        device_time: None,
+        value: vec![sample.value()],
+        gap: sample.gap(),
+        calibrated: None,
+    }));
+    serde_json::to_string(&WSMeasurement{ devices: vec![id.serial().clone()], data })
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+///Annotates the `devices` given in the body, like the websocket command `annotate`.
+///The annotation is recorded and sent to the websocket subscribers of the devices.
+#[rocket::post("/annotations", data = "<body>")]
+pub async fn post_annotation(body: String, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>) -> Result<String, Custom<String>> {
+    let request = serde_json::from_str::<AnnotationRequest>(&body)
+        .map_err(|err|Custom(Status::BadRequest, format!("Expected an annotation like {{\"devices\":[\"serial\"],\"label\":\"relay closed\"}}: {err}")))?;
+    let device_list = device_list.read().await;
+    let aliases = aliases.read().await;
+    let mut devices = Vec::with_capacity(request.devices().len());
+    let mut serials = Vec::with_capacity(request.devices().len());
+    for name in request.devices() {
+        let device = device_list.find(name, &aliases).await.ok_or_else(||not_found(name))?;
+        let id = device.id().await.ok_or_else(||not_found(name))?;
+        if !serials.contains(id.serial()) {
+            serials.push(id.serial().clone());
+            devices.push(device);
+        }
+    }
+    let annotation = Arc::new(request.into_annotation(serials, None)
+        .map_err(|err|Custom(Status::BadRequest, err.to_string()))?);
+    for device in devices {
+        device.annotate(annotation.clone());
+    }
+    serde_json::to_string(&*annotation)
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
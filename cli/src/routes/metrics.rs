@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+
+///Counters of one websocket session, updated by the session and read by `GET /metrics`.
+#[derive(Debug, Default)]
+pub struct SessionMetrics {
+    id: u64,
+    devices: Mutex<Vec<String>>,
+    ///Samples collected, but not yet sent to the client.
+    queue_depth: AtomicU64,
+    ///Packets forwarded from the devices, but not yet processed by the session.
+    channel_depth: AtomicU64,
+    dropped_samples: AtomicU64,
+    lagged_packets: AtomicU64,
+    decimation: AtomicU64,
+    messages_sent: AtomicU64,
+}
+impl SessionMetrics {
+    pub const fn id(&self) -> u64 { self.id }
+    pub async fn set_devices(&self, devices: Vec<String>) {
+        *self.devices.lock().await = devices;
+    }
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.queue_depth.store(depth as u64, Ordering::Relaxed);
+    }
+    pub fn set_channel_depth(&self, depth: usize) {
+        self.channel_depth.store(depth as u64, Ordering::Relaxed);
+    }
+    pub fn add_dropped_samples(&self, samples: u64) {
+        self.dropped_samples.fetch_add(samples, Ordering::Relaxed);
+    }
+    pub fn add_lagged_packets(&self, packets: u64) {
+        self.lagged_packets.fetch_add(packets, Ordering::Relaxed);
+    }
+    pub fn set_decimation(&self, decimation: usize) {
+        self.decimation.store(decimation as u64, Ordering::Relaxed);
+    }
+    pub fn add_message_sent(&self) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+    async fn snapshot(&self) -> SessionMetricsSnapshot {
+        SessionMetricsSnapshot {
+            session: self.id,
+            devices: self.devices.lock().await.clone(),
+            queue_depth: self.queue_depth.load(Ordering::Relaxed),
+            channel_depth: self.channel_depth.load(Ordering::Relaxed),
+            dropped_samples: self.dropped_samples.load(Ordering::Relaxed),
+            lagged_packets: self.lagged_packets.load(Ordering::Relaxed),
+            decimation: self.decimation.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+        }
+    }
+}
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+struct SessionMetricsSnapshot {
+    session: u64,
+    devices: Vec<String>,
+    queue_depth: u64,
+    channel_depth: u64,
+    dropped_samples: u64,
+    lagged_packets: u64,
+    decimation: u64,
+    messages_sent: u64,
+}
+
+///All open websocket sessions.
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    next_id: u64,
+    sessions: BTreeMap<u64, Arc<SessionMetrics>>,
+}
+impl SessionRegistry {
+    pub fn register(&mut self) -> Arc<SessionMetrics> {
+        let metrics = Arc::new(SessionMetrics {
+            id: self.next_id,
+            decimation: AtomicU64::new(1),
+            ..SessionMetrics::default()
+        });
+        self.next_id += 1;
+        self.sessions.insert(metrics.id, metrics.clone());
+        metrics
+    }
+    pub fn unregister(&mut self, id: u64) {
+        self.sessions.remove(&id);
+    }
+}
+
+#[rocket::get("/metrics")]
+pub async fn get_metrics(sessions: &rocket::State<Arc<RwLock<SessionRegistry>>>) -> Result<String, String> {
+    let list: Vec<_> = sessions.read().await.sessions.values().cloned().collect();
+    let mut snapshots = Vec::with_capacity(list.len());
+    for metrics in list {
+        snapshots.push(metrics.snapshot().await);
+    }
+    serde_json::to_string(&serde_json::json!({ "sessions": snapshots }))
+        .map_err(|v|v.to_string())
+}
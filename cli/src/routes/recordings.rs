@@ -0,0 +1,163 @@
+use std::io::Seek;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use rocket::http::{ContentType, Status};
+use rocket::response::status::Custom;
+use tokio::sync::RwLock;
+use crate::calibration::CalibrationRegistry;
+use crate::device::annotation::Annotation;
+use crate::device::history::HistorySample;
+use crate::record::ExportFormat;
+use crate::record::capture::{CaptureReader, DeviceHeader};
+use crate::signal::align::Interpolation;
+use crate::signal::downsample::{downsample, DownsampleAlgorithm};
+use super::ws::align_downsampled;
+
+///How many samples `GET /recordings/<name>/downsampled` returns, if the request doesn't say otherwise.
+const DEFAULT_DOWNSAMPLED_SAMPLES: usize = 1000;
+///How many samples per requested sample are read from a recording at most, before they are downsampled.
+///Longer ranges are reduced to their minimum and maximum while reading.
+const READ_SAMPLES_PER_SAMPLE: usize = 16;
+
+///The directory of the capture files, which can be queried.
+#[derive(Debug)]
+pub struct Recordings {
+    dir: PathBuf,
+}
+impl Recordings {
+    pub const fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+    ///The path of the existing recording `name`. Names pointing outside of the directory are rejected.
+    pub fn find(&self, name: &str) -> Option<PathBuf> {
+        let file_name = Path::new(name).file_name()?;
+        if file_name != std::ffi::OsStr::new(name) {
+            return None;
+        }
+        Some(self.dir.join(file_name)).filter(|path| path.is_file())
+    }
+}
+
+#[derive(serde_derive::Serialize)]
+struct RecordingInfo {
+    name: String,
+    ///Milliseconds since the UNIX epoch.
+    start: f64,
+    devices: Vec<RecordedDevice>,
+    annotations: Vec<Annotation>,
+}
+#[derive(serde_derive::Serialize)]
+struct RecordedDevice {
+    #[serde(flatten)]
+    header: DeviceHeader,
+    ///Timestamps of the first and the last recorded sample.
+    first: Option<f64>,
+    last: Option<f64>,
+}
+
+///Reads the samples of every device of the capture file at `path` between `tmin` and `tmax`, and downsamples them to `desired` samples.
+///Returns the ids of the devices with their samples.
+pub(super) async fn read_downsampled(path: PathBuf, tmin: f64, tmax: f64, desired: usize, algorithm: DownsampleAlgorithm) -> anyhow::Result<(Vec<String>, Vec<Vec<HistorySample>>)> {
+    tokio::task::spawn_blocking(move || {
+        let mut reader = CaptureReader::open(&path)?;
+        let devices: Vec<String> = reader.devices().iter().map(|device| device.id().serial().clone()).collect();
+        let mut downsampled = Vec::with_capacity(devices.len());
+        for i in 0..devices.len() {
+            let samples = reader.range(i, tmin, tmax, desired.saturating_mul(READ_SAMPLES_PER_SAMPLE))?;
+            downsampled.push(downsample(&samples, desired, algorithm));
+        }
+        Ok((devices, downsampled))
+    }).await?
+}
+
+///Lists the capture files in the recordings directory.
+#[rocket::get("/recordings")]
+pub async fn get_recordings(recordings: &rocket::State<Arc<Recordings>>) -> Result<String, Custom<String>> {
+    let dir = recordings.dir.clone();
+    let list = tokio::task::spawn_blocking(move || {
+        let mut list = Vec::new();
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(v) => v,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(list),
+            Err(err) => anyhow::bail!("Failed to list {}: {err}", dir.display()),
+        };
+        for entry in entries.flatten() {
+            //Other files may share the directory.
+            let Ok(reader) = CaptureReader::open(&entry.path()) else {
+                continue;
+            };
+            list.push(RecordingInfo {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                start: reader.start(),
+                devices: reader.devices().iter().enumerate().map(|(i, header)| {
+                    let bounds = reader.bounds(i);
+                    RecordedDevice {
+                        header: header.clone(),
+                        first: bounds.map(|v| v.0),
+                        last: bounds.map(|v| v.1),
+                    }
+                }).collect(),
+                annotations: reader.annotations().to_vec(),
+            });
+        }
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(list)
+    }).await
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))?
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))?;
+    serde_json::to_string(&list)
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+///Returns the samples of a recording between `tmin` and `tmax` in milliseconds since the UNIX epoch, downsampled like `get_downsampled_in_range`.
+#[rocket::get("/recordings/<name>/downsampled?<tmin>&<tmax>&<samples>&<algorithm>")]
+pub async fn get_recording_downsampled(
+    name: &str,
+    tmin: Option<f64>,
+    tmax: Option<f64>,
+    samples: Option<usize>,
+    algorithm: Option<&str>,
+    recordings: &rocket::State<Arc<Recordings>>,
+) -> Result<String, Custom<String>> {
+    let path = recordings.find(name).ok_or_else(||Custom(Status::NotFound, format!("Recording not found: {name}")))?;
+    let algorithm = match algorithm {
+        Some(algorithm) => serde_json::from_value(serde_json::Value::String(algorithm.to_string()))
+            .map_err(|err|Custom(Status::BadRequest, format!("Invalid algorithm {algorithm}: {err}")))?,
+        None => DownsampleAlgorithm::default(),
+    };
+    let (devices, downsampled) = read_downsampled(path, tmin.unwrap_or(f64::NEG_INFINITY), tmax.unwrap_or(f64::INFINITY), samples.unwrap_or(DEFAULT_DOWNSAMPLED_SAMPLES), algorithm).await
+        .map_err(|err|Custom(Status::InternalServerError, format!("Failed to read recording {name}: {err}")))?;
+    serde_json::to_string(&align_downsampled(devices, &downsampled, Interpolation::default()))
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+///Downloads a recording exported into the format of the extension appended to its name, e.g. `/recordings/capture.cap.wav`.
+///With `calibrated=true`, sigrok files get calibrated values instead of raw samples.
+#[rocket::get("/recordings/<file>?<calibrated>")]
+pub async fn get_recording_export(file: &str, calibrated: Option<bool>, recordings: &rocket::State<Arc<Recordings>>, calibrations: &rocket::State<Arc<RwLock<CalibrationRegistry>>>) -> Result<(ContentType, tokio::fs::File), Custom<String>> {
+    let Some((name, format)) = file.rsplit_once('.').and_then(|(name, extension)| Some((name, ExportFormat::from_extension(extension)?))) else {
+        return Err(Custom(Status::NotFound, format!("Unknown export format of {file}")));
+    };
+    let calibrated = calibrated.unwrap_or(false);
+    format.check(calibrated)
+        .map_err(|err|Custom(Status::BadRequest, err.to_string()))?;
+    let path = recordings.find(name).ok_or_else(||Custom(Status::NotFound, format!("Recording not found: {name}")))?;
+    let calibrations = calibrations.read().await.profiles().clone();
+    //The exports may be larger than the memory, so they are written to an anonymous temporary file, which is streamed from there.
+    let file = tokio::task::spawn_blocking(move || {
+        let file = format.export(&path, std::io::BufWriter::new(tempfile::tempfile()?), &calibrations, calibrated)?;
+        let mut file = file.into_inner()?;
+        file.rewind()?;
+        anyhow::Ok(file)
+    })
+        .await
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))?
+        .map_err(|err|Custom(Status::InternalServerError, format!("Failed to export recording {name}: {err}")))?;
+    let content_type = match format {
+        ExportFormat::Wav => ContentType::WAV,
+        ExportFormat::Sigrok => ContentType::ZIP,
+        ExportFormat::Parquet => ContentType::new("application", "vnd.apache.parquet"),
+        ExportFormat::ArrowIpc => ContentType::new("application", "vnd.apache.arrow.stream"),
+    };
+    Ok((content_type, tokio::fs::File::from_std(file)))
+}
@@ -0,0 +1,98 @@
+use std::sync::Arc;
+use rocket::http::{ContentType, Status};
+use rocket::response::status::Custom;
+use crate::catalog::{Catalog, SessionFilter};
+
+#[derive(serde_derive::Deserialize)]
+struct Annotation {
+    annotation: String,
+}
+
+///Runs `f` with the catalog on a blocking thread, because SQLite blocks. Fails, if the catalog couldn't be opened.
+async fn with_catalog<T: Send + 'static>(catalog: &Option<Arc<Catalog>>, f: impl FnOnce(&Catalog) -> anyhow::Result<T> + Send + 'static) -> Result<T, Custom<String>> {
+    let Some(catalog) = catalog.clone() else {
+        return Err(Custom(Status::ServiceUnavailable, "The catalog couldn't be opened, see the server log".to_string()));
+    };
+    tokio::task::spawn_blocking(move || f(&catalog)).await
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))?
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+///Lists the recorded sessions matching the filter, the latest first.
+#[rocket::get("/sessions?<filter..>")]
+pub async fn get_sessions(filter: SessionFilter, catalog: &rocket::State<Option<Arc<Catalog>>>) -> Result<String, Custom<String>> {
+    let sessions = with_catalog(catalog, move |catalog| catalog.search(&filter)).await?;
+    serde_json::to_string(&sessions)
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+#[rocket::get("/sessions/<id>")]
+pub async fn get_session(id: i64, catalog: &rocket::State<Option<Arc<Catalog>>>) -> Result<String, Custom<String>> {
+    let session = with_catalog(catalog, move |catalog| catalog.get(id)).await?
+        .ok_or_else(||Custom(Status::NotFound, format!("Session {id} not found")))?;
+    serde_json::to_string(&session)
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+///Replaces the annotation of a session, given as `{"annotation": "..."}`, or as plain text with the content type `text/plain`.
+#[rocket::put("/sessions/<id>/annotation", data = "<body>")]
+pub async fn put_session_annotation(id: i64, body: String, content_type: Option<&ContentType>, catalog: &rocket::State<Option<Arc<Catalog>>>) -> Result<String, Custom<String>> {
+    let annotation = if content_type.is_some_and(|v| v.is_plain()) {
+        body.trim().to_string()
+    } else {
+        serde_json::from_str::<Annotation>(&body)
+            .map_err(|err|Custom(Status::BadRequest, format!("Expected an annotation like {{\"annotation\":\"...\"}} or text/plain: {err}")))?
+            .annotation
+    };
+    let session = with_catalog(catalog, move |catalog| match catalog.annotate(id, &annotation)? {
+        true => catalog.get(id),
+        false => Ok(None),
+    }).await?
+        .ok_or_else(||Custom(Status::NotFound, format!("Session {id} not found")))?;
+    serde_json::to_string(&session)
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+#[rocket::put("/sessions/<id>/tags/<tag>")]
+pub async fn put_session_tag(id: i64, tag: &str, catalog: &rocket::State<Option<Arc<Catalog>>>) -> Result<String, Custom<String>> {
+    let tag = tag.to_string();
+    if tag.trim().is_empty() {
+        return Err(Custom(Status::BadRequest, "Tag must not be empty".to_string()));
+    }
+    let session = with_catalog(catalog, move |catalog| match catalog.add_tag(id, &tag)? {
+        true => catalog.get(id),
+        false => Ok(None),
+    }).await?
+        .ok_or_else(||Custom(Status::NotFound, format!("Session {id} not found")))?;
+    serde_json::to_string(&session)
+        .map_err(|err|Custom(Status::InternalServerError, err.to_string()))
+}
+
+#[rocket::delete("/sessions/<id>/tags/<tag>")]
+pub async fn delete_session_tag(id: i64, tag: &str, catalog: &rocket::State<Option<Arc<Catalog>>>) -> Result<Status, Custom<String>> {
+    let tag = tag.to_string();
+    match with_catalog(catalog, move |catalog| catalog.remove_tag(id, &tag)).await? {
+        true => Ok(Status::NoContent),
+        false => Err(Custom(Status::NotFound, format!("Session {id} has no such tag"))),
+    }
+}
+
+///Removes a session from the catalog. With `delete_file=true` its file is deleted as well, unless it is still being recorded.
+#[rocket::delete("/sessions/<id>?<delete_file>")]
+pub async fn delete_session(id: i64, delete_file: Option<bool>, catalog: &rocket::State<Option<Arc<Catalog>>>) -> Result<Status, Custom<String>> {
+    let session = with_catalog(catalog, move |catalog| catalog.get(id)).await?
+        .ok_or_else(||Custom(Status::NotFound, format!("Session {id} not found")))?;
+    if delete_file.unwrap_or(false) {
+        if session.end().is_none() {
+            return Err(Custom(Status::Conflict, format!("Session {id} is still being recorded or was interrupted, delete its file by hand")));
+        }
+        match tokio::fs::remove_file(session.path()).await {
+            Ok(()) => {},
+            //Deleted already, so only the session is left to remove.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {},
+            Err(err) => return Err(Custom(Status::InternalServerError, format!("Failed to delete {}: {err}", session.path().display()))),
+        }
+    }
+    with_catalog(catalog, move |catalog| catalog.delete(id)).await?;
+    Ok(Status::NoContent)
+}
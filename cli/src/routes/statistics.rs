@@ -0,0 +1,37 @@
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use crate::device::clock::ClockEstimate;
+use crate::device::reader::UsbStatisticsSnapshot;
+use crate::device::sequence::LossStatistics;
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+struct DeviceStatistics{
+    #[serde(rename = "UUID")]
+    uuid: String,
+    loss_ratio: f64,
+    #[serde(flatten)]
+    statistics: LossStatistics,
+    clock: ClockEstimate,
+    usb: UsbStatisticsSnapshot,
+}
+#[rocket::get("/statistics")]
+pub async fn get_statistics(device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>) -> Result<String, String> {
+    let send_list = device_list.read().await.list_send();
+    let mut devices = Vec::with_capacity(send_list.len());
+    for device in send_list {
+        let id = match device.id().await {
+            Some(id) => id,
+            None => continue,
+        };
+        let statistics = device.loss_statistics().await;
+        devices.push(DeviceStatistics{
+            uuid: id.serial().to_string(),
+            loss_ratio: statistics.loss_ratio(),
+            statistics,
+            clock: device.clock().await,
+            usb: device.usb_statistics(),
+        });
+    }
+    serde_json::to_string(&devices)
+        .map_err(|v|v.to_string())
+}
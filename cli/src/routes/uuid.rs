@@ -1,5 +1,6 @@
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use crate::aliases::AliasRegistry;
 
 #[derive(serde_derive::Serialize, serde_derive::Deserialize)]
 struct Devices{
@@ -10,6 +11,8 @@ struct Devices{
 struct Device{
     #[serde(rename = "UUID")]
     uuid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alias: Option<String>,
 }
 #[derive(serde_derive::Serialize, serde_derive::Deserialize)]
 struct Data {
@@ -22,7 +25,7 @@ struct Color{
     b: u32,
 }
 #[rocket::get("/UUID")]
-pub async fn get_devices(device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>) -> Result<String, String> {
+pub async fn get_devices(device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>) -> Result<String, String> {
     let mut device_list = device_list.write().await;
     match device_list.scan_for_new_devices().await {
         Ok(()) => {},
@@ -33,6 +36,7 @@ pub async fn get_devices(device_list: &rocket::State<Arc<RwLock<crate::DeviceLis
     tokio::time::sleep(std::time::Duration::from_secs(1)).await;
     let device_list = device_list.downgrade();
     let send_list = device_list.list_send();
+    let aliases = aliases.read().await;
     let mut devices = Vec::new();
     let mut colors = Vec::new();
     for device in send_list {
@@ -40,6 +44,7 @@ pub async fn get_devices(device_list: &rocket::State<Arc<RwLock<crate::DeviceLis
             Some(id) => {
                 devices.push(Device{
                     uuid: id.serial().to_string(),
+                    alias: device.alias(&aliases).await,
                 });
                 let rgb = device.rgb().await;
                 colors.push(Data {
@@ -1,33 +1,112 @@
-use std::borrow::Cow;
+mod backpressure;
+mod format;
+mod protocol;
+mod session;
+
 use std::sync::Arc;
-use std::time::Duration;
-use serde_with::serde_as;
 use tokio::sync::RwLock;
-use crate::device::messages::MeasureData;
-use crate::device::SendDevice;
-use crate::MAX_MESSAGE_BUF;
+use crate::aliases::AliasRegistry;
+use crate::calibration::CalibrationRegistry;
+use crate::device::history::HistorySample;
+use crate::signal::align::{Aligner, Interpolation};
+use crate::signal::downsample::DownsampleAlgorithm;
+use format::StreamFormat;
+use protocol::{Command, CommandError, ErrorCode, Reply};
+use session::{Forwarded, Session, StreamOptions};
+use super::metrics::SessionRegistry;
+use super::recordings::Recordings;
 
 #[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
-struct WSMeasurement {
-    devices: Vec<String>,
-    data: Vec<WSMeasurementData>,
+pub(super) struct WSMeasurement {
+    pub(super) devices: Vec<String>,
+    pub(super) data: Vec<WSMeasurementData>,
 }
 
 #[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
-struct WSMeasurementData{
-    timestamp: f64,
-    value: Vec<u16>,
+pub(super) struct WSMeasurementData{
+    pub(super) timestamp: f64,
+    ///Seconds since the capture start, according to the clock of the device, which defines the timeline
+    ///(the one with the highest sample rate). Missing, if the sample rate of that device isn't known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) device_time: Option<f64>,
+    pub(super) value: Vec<u16>,
+    ///Set on the first sample after packets were lost, so clients don't connect it to the sample before.
+    #[serde(default, skip_serializing_if = "core::ops::Not::not")]
+    pub(super) gap: bool,
+    ///`value` converted into the units of the devices, if the client asked for calibrated values.
+    ///Values of devices without a calibration are passed on unchanged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) calibrated: Option<Vec<f64>>,
 }
 
-#[rocket::get("/ws")]
-pub async fn ws_impl(shutdown: rocket::Shutdown, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, ws: rocket_ws::WebSocket) -> rocket_ws::Channel<'static> {
-    #[derive(Clone, serde_derive::Serialize, serde_derive::Deserialize)]
-    struct DownsampleRequest{
-        command: String,
-        tmin: chrono::DateTime<chrono::FixedOffset>,
-        tmax: chrono::DateTime<chrono::FixedOffset>,
-        desired_number_of_samples: u128
+///Samples around a trigger point, sent as a single message instead of the measurements, while a trigger is set.
+#[derive(Debug, Clone, serde_derive::Serialize)]
+#[serde(tag = "type", rename = "frame")]
+pub(super) struct WSFrame {
+    pub(super) devices: Vec<String>,
+    ///Counts the frames since the trigger was set, starting at 1.
+    pub(super) sequence: u64,
+    ///`false` for a frame the auto mode took without a trigger.
+    pub(super) triggered: bool,
+    ///Index of the sample in `data`, at which the trigger fired.
+    pub(super) trigger_index: usize,
+    pub(super) trigger_timestamp: f64,
+    pub(super) data: Vec<WSMeasurementData>,
+}
+
+///Sent after subscribing, to tell the client at which rate it will receive samples.
+#[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+struct WSSamplingRate {
+    ///0 if no rate was requested and the devices don't report theirs.
+    sampling_rate: u32,
+    ///Whether the device samples at `sampling_rate` itself, or the server resamples the device data, per device.
+    resampled: Vec<bool>,
+    ///How the samples of every device are brought down to `sampling_rate`.
+    decimation: Vec<session::DecimationStage>,
+}
+
+///Aligns the downsampled samples of `devices` onto a common timeline.
+///The device with the most samples left after downsampling defines the timeline.
+pub(super) fn align_downsampled(devices: Vec<String>, downsampled: &[Vec<HistorySample>], interpolation: Interpolation) -> WSMeasurement {
+    let reference = downsampled.iter().enumerate().max_by_key(|(_, samples)| samples.len()).map_or(0, |(i, _)| i);
+    let mut aligner = Aligner::new(downsampled.len(), reference, interpolation);
+    for (i, samples) in downsampled.iter().enumerate() {
+        for sample in samples {
+            if sample.gap() {
+                aligner.mark_gap(i);
+            }
+            aligner.push(i, sample.timestamp(), None, sample.value());
+        }
+    }
+    let mut data = Vec::new();
+    aligner.finish(|timestamp, device_time, value, gap| data.push(WSMeasurementData{
+        timestamp,
+        device_time,
+        value,
+        gap,
+        calibrated: None,
+    }));
+    WSMeasurement{
+        devices,
+        data,
     }
+}
+
+///`get_downsampled_in_range` of the unversioned protocol of earlier clients.
+#[derive(Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+struct DownsampleRequest{
+    command: String,
+    tmin: chrono::DateTime<chrono::FixedOffset>,
+    tmax: chrono::DateTime<chrono::FixedOffset>,
+    desired_number_of_samples: u128,
+    #[serde(default)]
+    algorithm: DownsampleAlgorithm,
+    #[serde(default)]
+    recording: Option<String>,
+}
+
+#[rocket::get("/ws")]
+pub async fn ws_impl(shutdown: rocket::Shutdown, device_list: &rocket::State<Arc<RwLock<crate::DeviceList>>>, aliases: &rocket::State<Arc<RwLock<AliasRegistry>>>, calibrations: &rocket::State<Arc<RwLock<CalibrationRegistry>>>, sessions: &rocket::State<Arc<RwLock<SessionRegistry>>>, recordings: &rocket::State<Arc<Recordings>>, ws: rocket_ws::WebSocket) -> rocket_ws::Channel<'static> {
     #[derive(Clone)]
     struct DeviceConfig<'a>{
         uuid: Vec<&'a str>,
@@ -41,33 +120,19 @@ pub async fn ws_impl(shutdown: rocket::Shutdown, device_list: &rocket::State<Arc
                 sampling_rate: None,
                 format: None,
             };
-            let mut vec: Vec<_> = value.rsplit(" ").collect();
-            let last = vec.pop();
-            let last_2nd = vec.pop();
-            match (last, last_2nd) {
-                (Some(last), Some(last_2nd)) => {
-                    if let Ok(v) = last.parse::<u32>() {
-                        config.sampling_rate = Some(v);
-                        config.uuid.push(last_2nd);
-                    } else {
-                        if let Ok(v) = last_2nd.parse::<u32>() {
-                            config.sampling_rate = Some(v);
-                            config.format = Some(last);
-                        } else {
-                            config.uuid.push(last);
-                            config.uuid.push(last_2nd);
-                        }
-                    }
-                },
-                (Some(last), None) => {
-                    config.uuid.push(last);
-                },
-                (None, Some(_)) => {
-                    panic!("didn't get last element, but got second last?");
-                },
-                (None, None) => {}
+            //UUID... [sampling rate [format]]
+            let mut tokens: Vec<_> = value.split_whitespace().collect();
+            let rate_at = match tokens.as_slice() {
+                [.., rate, _] if rate.parse::<u32>().is_ok() => Some(tokens.len() - 2),
+                [.., rate] if rate.parse::<u32>().is_ok() => Some(tokens.len() - 1),
+                _ => None,
+            };
+            if let Some(rate_at) = rate_at {
+                config.sampling_rate = tokens[rate_at].parse().ok();
+                config.format = tokens.get(rate_at + 1).copied();
+                tokens.truncate(rate_at);
             }
-            config.uuid.append(&mut vec);
+            config.uuid = tokens;
             config
         }
     }
@@ -85,25 +150,19 @@ pub async fn ws_impl(shutdown: rocket::Shutdown, device_list: &rocket::State<Arc
             out
         }
     }
-    struct Measure<T>{
-        uuids: Vec<String>,
-        rx: T,
-    }
-    #[serde_as]
-    #[derive(serde_derive::Serialize, serde_derive::Deserialize)]
-    #[serde(untagged)]
-    enum Messages{
-        DownsampleRequest(DownsampleRequest),
-    }
     use rocket::futures::{SinkExt, StreamExt};
     let device_list = device_list.inner().clone();
+    let aliases = aliases.inner().clone();
+    let calibrations = calibrations.inner().clone();
+    let sessions = sessions.inner().clone();
+    let recordings = recordings.inner().clone();
     ws.channel(move |mut stream|Box::pin(async move {
+        let metrics = sessions.write().await.register();
         let mut timer:Option<tokio::time::Interval> = None;
-        let mut rx:Option<Measure<tokio::sync::mpsc::Receiver<(usize, MeasureData)>>> = None;
-        let mut js = tokio::task::JoinSet::new();
+        let mut rx:Option<tokio::sync::mpsc::Receiver<(usize, Forwarded)>> = None;
+        let mut session = Session::new(metrics.clone());
         let mut result = None;
         let mut shutdown = shutdown;
-        let mut measure_data = Vec::with_capacity(MAX_MESSAGE_BUF as usize);
         macro_rules! merge_err {
             ($err:expr, $reason:expr) => {
                 let err = $err;
@@ -141,6 +200,29 @@ pub async fn ws_impl(shutdown: rocket::Shutdown, device_list: &rocket::State<Arc
                 }
             };
         }
+        macro_rules! send_measurement {
+            () => {
+                if let Some(notification) = session.take_notification() {
+                    send_json!(notification, "notification");
+                }
+                while let Some(frame) = session.take_frame() {
+                    let message = error!(session.format().encode_frame(&frame).map_err(|err|rocket_ws::result::Error::Io(std::io::Error::other(err))), err, format!("error encoding frame: {err}"));
+                    error!(stream.send(message).await, err, format!("error sending frame: {err}"));
+                }
+                if let Some(message) = session.take_measurement() {
+                    let frames = error!(session.format().encode(&message).map_err(|err|rocket_ws::result::Error::Io(std::io::Error::other(err))), err, format!("error encoding measurement: {err}"));
+                    for frame in frames {
+                        error!(stream.send(frame).await, err, format!("error sending measurement: {err}"));
+                    }
+                }
+            };
+        }
+        macro_rules! send_json {
+            ($value:expr, $what:expr) => {
+                let string = error!(serde_json::to_string(&$value).map_err(|err|rocket_ws::result::Error::Io(std::io::Error::other(err))), err, format!("error serializing {}: {err}", $what));
+                error!(stream.send(rocket_ws::Message::Text(string.clone())).await, err, format!("error sending message {string}: {err}"));
+            };
+        }
         loop{
             tokio::select! {
                 _ = &mut shutdown => {
@@ -162,38 +244,31 @@ pub async fn ws_impl(shutdown: rocket::Shutdown, device_list: &rocket::State<Arc
                     Some(timer) => Some(timer.tick().await),
                     None => None,
                 }}, if timer.is_some() && rx.is_some() => {
-                    let rx = match &rx {
-                        Some(v) => v,
-                        None => continue,
-                    };
-                    let message = WSMeasurement{
-                        devices: rx.uuids.clone(),
-                        data: core::mem::replace(&mut measure_data, Vec::with_capacity(MAX_MESSAGE_BUF as usize)),
-                    };
-                    let string = error!(serde_json::to_string(&message).map_err(|err|rocket_ws::result::Error::Io(std::io::Error::new(std::io::ErrorKind::Other, err))), err, format!("error serializing message {message:?}: {err}"));
-                    error!(stream.send(rocket_ws::Message::Text(string.clone())).await, err, format!("error sending message {string}: {err}"));
+                    send_measurement!();
                 },
                 Some(message) = async{ match &mut rx {
-                    Some(measure) => Some(Measure{
-                        uuids: measure.uuids.clone(),
-                        rx: (measure.rx.recv().await, &measure.rx)
-                    }),
+                    Some(rx) => Some(rx.recv().await),
                     None => None
                 }}, if rx.is_some() => {
-                    let (message, _) = message.rx;
-                    let (_, message) = match message{
-                        Some(v) => v,
-                        None => continue,
-                    };
-
-                    if message.counter() % 50 != 0 { continue; }
-                    let timestamp = std::time::UNIX_EPOCH.elapsed().unwrap_or(Duration::ZERO) //Get the current time
-                        .saturating_mul(1000) //new Data(number) uses milliseconds. Multiply first, then convert to floating-point, to not unnecessarily lose precision
-                        .as_secs_f64(); //and then convert to floating-point
-                    measure_data.extend(message.data().iter().map(|value|WSMeasurementData{
-                            timestamp,
-                            value: vec![*value],
-                    }));
+                    if let Some(rx) = &rx {
+                        metrics.set_channel_depth(rx.len());
+                    }
+                    match message {
+                        Some((i, Forwarded::Packet(message))) => {
+                            let push = error!(session.push(i, &message).map_err(|err|rocket_ws::result::Error::Io(std::io::Error::other(err.message))), err, format!("Closing slow connection: {err}"));
+                            if push {
+                                send_measurement!();
+                            }
+                        },
+                        Some((i, Forwarded::Lagged(packets))) => {
+                            error!(session.lagged(i, packets).map_err(|err|rocket_ws::result::Error::Io(std::io::Error::other(err.message))), err, format!("Closing slow connection: {err}"));
+                        },
+                        Some((_, Forwarded::Annotation(annotation))) => if let Some(notification) = session.annotation(&annotation) {
+                            send_json!(notification, "annotation");
+                        },
+                        //All subscribed devices are gone.
+                        None => rx = None,
+                    }
                 },
                 Some(message) = stream.next() => {
                     let message = match message {
@@ -206,117 +281,46 @@ pub async fn ws_impl(shutdown: rocket::Shutdown, device_list: &rocket::State<Arc
                     };
                     match message {
                         rocket_ws::Message::Text(text) => {
-                            match serde_json::from_str::<Messages>(text.as_str()) {
-                                Ok(Messages::DownsampleRequest(rq)) => {
-                                    if rq.command != "get_downsampled_in_range" {
-                                        error!(Err(rocket_ws::result::Error::Io(std::io::Error::new(std::io::ErrorKind::Other, "Unknown command"))), err, format!("Unknown command: {}", rq.command));
-                                    }
-                                    //Todo: Implement downsampling
+                            println!("Received message: {text}");
+                            match protocol::parse(text.as_str()) {
+                                Some(Ok(request)) => {
+                                    let command = request.command.name();
+                                    let reply = match execute(request.command, &mut session, &mut rx, &mut timer, Server { device_list: &device_list, aliases: &aliases, calibrations: &calibrations, recordings: &recordings }).await {
+                                        Ok(value) => Reply::response(request.id, command, value),
+                                        Err(err) => Reply::error(request.id, err),
+                                    };
+                                    send_json!(reply, "reply");
                                 },
-                                Err(_) => {
-                                    let config = DeviceConfig::from(text.as_str());
-                                    let mut set:std::collections::HashSet<_> = config.uuid.iter().copied().collect();
-                                    let device_list = device_list.read().await;
-                                    let mut subscribed_devices = Vec::new();
-                                    for device in device_list.list() {
-                                        let id = match device.id().await {
-                                            Some(id) => id,
-                                            None => continue,
-                                        };
-                                        set.remove(id.serial().as_str());
-                                        subscribed_devices.push(device);
-                                    }
-
-                                    //Todo: Support multiple devices
-                                    if subscribed_devices.len() > 1 {
-                                        if let Err(err) = stream.close(Some(rocket_ws::frame::CloseFrame {
-                                            code: rocket_ws::frame::CloseCode::Invalid,
-                                            reason: format!("Subscribed to too many devices. Currently only one device is supported. You tried to subscribe to {} devices", subscribed_devices.len()).into(),
-                                        })).await {
-                                            eprintln!("error closing websocket: {}", err);
-                                            result = Some(Err(err));
-                                            break;
-                                        }
-                                    }
-
-                                    if !set.is_empty() {
-                                        if let Err(err) = stream.close(Some(rocket_ws::frame::CloseFrame {
-                                            code: rocket_ws::frame::CloseCode::Invalid,
-                                            reason: format!("Device(s) not found: {}", set.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(", ")).into(),
-                                        })).await {
-                                            eprintln!("error closing websocket: {}", err);
-                                            result = Some(Err(err));
-                                            break;
-                                        }
-                                    } else {
-                                        let (tx, rx_) = tokio::sync::mpsc::channel(MAX_MESSAGE_BUF as usize*8);
-                                        let mut devices = Vec::new();
-                                        for (i, device) in subscribed_devices.into_iter().enumerate() {
-                                            let id = match device.id().await {
-                                                Some(id) => id,
-                                                None => {
-                                                    result = Some(match stream.close(Some(rocket_ws::frame::CloseFrame {
-                                                        code: rocket_ws::frame::CloseCode::Invalid,
-                                                        reason: Cow::Borrowed("Device only had an id spuriously?"),
-                                                    })).await {
-                                                        Ok(_) => Ok(()),
-                                                        Err(err) => {
-                                                            eprintln!("error closing websocket: {}", err);
-                                                            Err(err)
-                                                        }
-                                                    });
-                                                    break;
-                                                },
-                                            };
-                                            println!("Subscribing to device: {}", id.serial());
-
-                                            devices.push(id.serial().clone());
-                                            let tx = tx.clone();
-                                            {
-                                                let device = SendDevice::from(device);
-                                                js.spawn(async move {
-                                                    let mut rx = device.rx_queue().resubscribe();
-                                                    loop {
-                                                        match rx.recv().await {
-                                                            Ok(message) => {
-                                                                if let Err(err) = tx.send((i, message)).await {
-                                                                    eprintln!("error sending message: {}", err);
-                                                                    break;
-                                                                }
-                                                            }
-                                                            Err(tokio::sync::broadcast::error::RecvError::Lagged(num)) => {
-                                                                eprintln!("Lagged {num} messages");
-                                                                continue;
-                                                            }
-                                                            Err(tokio::sync::broadcast::error::RecvError::Closed) => {
-                                                                println!("Device disconnected");
-                                                                break;
-                                                            }
-                                                        }
-                                                    }
-                                                });
-                                            }
-                                            match tokio::task::block_in_place(||device.start_capture()) {
-                                                Ok(_) => {},
-                                                Err(err) => {
-                                                    eprintln!("error starting capture: {err}");
-                                                    let err = rocket_ws::result::Error::Io(std::io::Error::new(std::io::ErrorKind::Other, err));
-                                                    error!(Err(err), err, format!("error starting capture: {err}"));
-                                                }
-                                            }
+                                Some(Err(reply)) => {
+                                    send_json!(reply, "reply");
+                                },
+                                //The unversioned protocol of earlier clients
+                                //Parsed directly, because an untagged enum would buffer the `u128` field, which serde can't.
+                                None => match serde_json::from_str::<DownsampleRequest>(text.as_str()) {
+                                    Ok(rq) => {
+                                        if rq.command != "get_downsampled_in_range" {
+                                            error!(Err(rocket_ws::result::Error::Io(std::io::Error::other("Unknown command"))), err, format!("Unknown command: {}", rq.command));
                                         }
-                                        rx = Some(Measure{
-                                            uuids: devices,
-                                            rx: rx_
-                                        });
-                                        let mut interval = tokio::time::interval(Duration::from_millis(500));
-                                        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-                                        timer = Some(interval);
+                                        let desired = usize::try_from(rq.desired_number_of_samples).unwrap_or(usize::MAX);
+                                        let message = error!(session.downsampled_in_range(&recordings, rq.recording.as_deref(), rq.tmin, rq.tmax, desired, rq.algorithm).await.map_err(|err|rocket_ws::result::Error::Io(std::io::Error::other(err.message))), err, format!("error getting downsampled data: {err}"));
+                                        send_json!(message, "downsampled data");
+                                    },
+                                    Err(_) => {
+                                        let config = DeviceConfig::from(text.as_str());
+                                        let format = error!(config.format.map_or(Ok(StreamFormat::default()), str::parse).map_err(|err|rocket_ws::result::Error::Io(std::io::Error::other(err))), err, format!("error subscribing: {err}"));
+                                        let subscribed = {
+                                            let device_list = device_list.read().await;
+                                            let aliases = aliases.read().await;
+                                            let calibrations = calibrations.read().await;
+                                            session.subscribe(&device_list, &aliases, &calibrations, &config.uuid, StreamOptions{ sampling_rate: config.sampling_rate, format, ..StreamOptions::default() }).await
+                                        };
+                                        let (subscription, rx_) = error!(subscribed.map_err(|err|rocket_ws::result::Error::Io(std::io::Error::other(err.message))), err, format!("error subscribing: {err}"));
+                                        send_json!(WSSamplingRate{ sampling_rate: subscription.sampling_rate.unwrap_or(0), resampled: subscription.resampled, decimation: subscription.decimation }, "sampling rate");
+                                        rx = Some(rx_);
+                                        timer = session.push_policy().timer();
                                     }
-                                }
+                                },
                             }
-
-                            println!("Received message: {text}");
                         },
                         rocket_ws::Message::Pong(pong) => {
                             println!("Received pong: {pong:?}");
@@ -328,14 +332,14 @@ pub async fn ws_impl(shutdown: rocket::Shutdown, device_list: &rocket::State<Arc
                                 },
                                 Err(err) => {
                                     eprintln!("error sending pong: {err}");
-                                    result = Some(Err(err.into()));
+                                    result = Some(Err(err));
                                     break;
                                 }
                             }
                         },
                         rocket_ws::Message::Close(_) => {
                             println!("Websocket connection closed");
-                            return Ok(());
+                            break;
                         },
                         _ => {},
                     }
@@ -347,25 +351,108 @@ pub async fn ws_impl(shutdown: rocket::Shutdown, device_list: &rocket::State<Arc
         {
             rx = None; // dropping the receiver should make the tasks in the join-set stop, as soon as they have a new message themselves.
         }
-        js.abort_all();
-        while let Some(join) = js.join_next().await {
-            match join {
-                Ok(()) => {},
-                Err(err) => {
-                    if err.is_cancelled() { continue; }
-                    eprintln!("task panicked: {err}");
-                    match result {
-                        Some(Err(err_old)) => {
-                            result = Some(Err(rocket_ws::result::Error::Io(std::io::Error::new(std::io::ErrorKind::Other, anyhow::format_err!("{err_old:?}\nTask panicked: {err:?}")))));
-                        },
-                        None | Some(Ok(())) => {
-                            result = Some(Err(rocket_ws::result::Error::Io(std::io::Error::new(std::io::ErrorKind::Other, err))));
-                        },
-                    }
-                    break;
-                }
+        if let Err(err) = session.close().await {
+            eprintln!("task panicked: {err}");
+            match result {
+                Some(Err(err_old)) => {
+                    result = Some(Err(rocket_ws::result::Error::Io(std::io::Error::other(anyhow::format_err!("{err_old:?}\nTask panicked: {err:?}")))));
+                },
+                None | Some(Ok(())) => {
+                    result = Some(Err(rocket_ws::result::Error::Io(std::io::Error::other(err))));
+                },
             }
         }
+        sessions.write().await.unregister(metrics.id());
         result.unwrap_or(Ok(()))
     }))
-}
\ No newline at end of file
+}
+
+///The state of the server, which commands work on.
+struct Server<'a> {
+    device_list: &'a RwLock<crate::DeviceList>,
+    aliases: &'a RwLock<AliasRegistry>,
+    calibrations: &'a RwLock<CalibrationRegistry>,
+    recordings: &'a Recordings,
+}
+
+///Executes a command of the versioned protocol and returns its result.
+async fn execute(
+    command: Command,
+    session: &mut Session,
+    rx: &mut Option<tokio::sync::mpsc::Receiver<(usize, Forwarded)>>,
+    timer: &mut Option<tokio::time::Interval>,
+    server: Server<'_>,
+) -> Result<serde_json::Value, CommandError> {
+    let Server { device_list, aliases, calibrations, recordings } = server;
+    let to_value = |value: Result<_, serde_json::Error>| value.map_err(|err| CommandError::new(ErrorCode::Internal, format!("error serializing result: {err}")));
+    match command {
+        Command::Subscribe { devices, mut options } => {
+            options.notifications = true;
+            let names: Vec<_> = devices.iter().map(String::as_str).collect();
+            let device_list = device_list.read().await;
+            let aliases = aliases.read().await;
+            let calibrations = calibrations.read().await;
+            let (subscription, rx_) = session.subscribe(&device_list, &aliases, &calibrations, &names, options).await?;
+            *rx = Some(rx_);
+            *timer = session.push_policy().timer();
+            to_value(serde_json::to_value(subscription))
+        },
+        Command::Unsubscribe => {
+            session.unsubscribe();
+            *rx = None;
+            *timer = None;
+            Ok(serde_json::Value::Null)
+        },
+        Command::Start => {
+            let device_list = device_list.read().await;
+            let aliases = aliases.read().await;
+            to_value(serde_json::to_value(session.start(&device_list, &aliases).await?))
+        },
+        Command::Stop => to_value(serde_json::to_value(session.stop()?)),
+        Command::SetRate { sampling_rate } => {
+            let device_list = device_list.read().await;
+            let aliases = aliases.read().await;
+            to_value(serde_json::to_value(session.set_rate(&device_list, &aliases, sampling_rate).await?))
+        },
+        Command::SetPush { push } => {
+            let subscription = session.set_push_policy(push)?;
+            if session.is_subscribed() {
+                *timer = session.push_policy().timer();
+            }
+            to_value(serde_json::to_value(subscription))
+        },
+        Command::SetBackpressure { backpressure } => to_value(serde_json::to_value(session.set_backpressure(backpressure)?)),
+        Command::GetDevices => {
+            let device_list = device_list.read().await;
+            let aliases = aliases.read().await;
+            let mut devices = Vec::new();
+            for device in device_list.list_send() {
+                devices.push(device.serializable_device(&aliases).await);
+            }
+            to_value(serde_json::to_value(devices))
+        },
+        Command::GetDownsampledInRange { tmin, tmax, desired_number_of_samples, algorithm, recording } => {
+            let desired = usize::try_from(desired_number_of_samples).unwrap_or(usize::MAX);
+            to_value(serde_json::to_value(session.downsampled_in_range(recordings, recording.as_deref(), tmin, tmax, desired, algorithm).await?))
+        },
+        Command::Ping => {
+            let timestamp = chrono::Utc::now().timestamp_micros() as f64 / 1000.;
+            Ok(serde_json::json!({ "timestamp": timestamp }))
+        },
+        Command::Annotate { annotation } => {
+            let device_list = device_list.read().await;
+            let aliases = aliases.read().await;
+            to_value(serde_json::to_value(&*session.annotate(&device_list, &aliases, annotation).await?))
+        },
+        Command::SetTrigger { source, trigger } => {
+            let device_list = device_list.read().await;
+            let aliases = aliases.read().await;
+            to_value(serde_json::to_value(session.set_trigger(&device_list, &aliases, source.as_deref(), trigger).await?))
+        },
+        Command::ClearTrigger => {
+            session.clear_trigger();
+            Ok(serde_json::Value::Null)
+        },
+        Command::ArmTrigger => to_value(serde_json::to_value(session.arm_trigger()?)),
+    }
+}
@@ -0,0 +1,44 @@
+use super::protocol::{CommandError, ErrorCode};
+
+const DEFAULT_MAX_QUEUED_SAMPLES: usize = 100_000;
+const MAX_MAX_QUEUED_SAMPLES: usize = 10_000_000;
+///The coarsest [`BackpressurePolicy::Decimate`] goes. Beyond that, the oldest samples are dropped.
+pub(super) const MAX_DECIMATION: usize = 1024;
+
+///What a session does, when the client doesn't keep up with the samples.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum BackpressurePolicy {
+    ///Drop the oldest samples not sent yet.
+    #[default]
+    DropOldest,
+    ///Halve the sample rate, every time the queue overflows. The rate recovers, once the client catches up.
+    Decimate,
+    ///Close the connection.
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(default)]
+pub(super) struct Backpressure {
+    pub(super) policy: BackpressurePolicy,
+    ///How many samples may wait for the client, before `policy` applies.
+    pub(super) max_queued_samples: usize,
+}
+impl Default for Backpressure {
+    fn default() -> Self {
+        Self {
+            policy: BackpressurePolicy::default(),
+            max_queued_samples: DEFAULT_MAX_QUEUED_SAMPLES,
+        }
+    }
+}
+impl Backpressure {
+    pub(super) fn validate(self) -> Result<Self, CommandError> {
+        if (1..=MAX_MAX_QUEUED_SAMPLES).contains(&self.max_queued_samples) {
+            Ok(self)
+        } else {
+            Err(CommandError::new(ErrorCode::InvalidRequest, format!("max_queued_samples must be between 1 and {MAX_MAX_QUEUED_SAMPLES}, but was {}", self.max_queued_samples)))
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use super::{WSFrame, WSMeasurement};
+
+///Magic bytes at the start of every [`StreamFormat::Binary`] frame.
+const BINARY_MAGIC: &[u8; 4] = b"OMNB";
+const BINARY_VERSION: u8 = 1;
+///Flag of a [`StreamFormat::Binary`] frame, whose first sample follows a gap.
+const BINARY_FLAG_GAP: u8 = 1;
+///Flag of a [`StreamFormat::Binary`] frame, which holds calibrated `f64` values instead of raw `u16` samples.
+const BINARY_FLAG_CALIBRATED: u8 = 2;
+///Flag of a [`StreamFormat::Binary`] frame, which holds the device time of its samples.
+const BINARY_FLAG_DEVICE_TIME: u8 = 4;
+///Flag of a [`StreamFormat::Binary`] frame, whose samples carry their own timestamps, as they have no constant interval.
+const BINARY_FLAG_TIMESTAMPS: u8 = 8;
+///Magic bytes at the start of a [`WSFrame`] in [`StreamFormat::Binary`].
+const BINARY_TRIGGER_MAGIC: &[u8; 4] = b"OMNF";
+///Flag of a [`WSFrame`] in [`StreamFormat::Binary`], which was triggered and not taken by the auto mode.
+const BINARY_FLAG_TRIGGERED: u8 = 1;
+
+///How measurements are sent to a websocket client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum StreamFormat {
+    ///[`WSMeasurement`] as JSON text frames.
+    #[default]
+    Json,
+    ///[`WSMeasurement`] as MessagePack binary frames.
+    #[serde(alias = "messagepack")]
+    Msgpack,
+    ///[`WSMeasurement`] as CBOR binary frames.
+    Cbor,
+    ///Packed samples in binary frames.
+    ///
+    ///Every frame holds samples with a constant interval, or samples with their own timestamps,
+    ///if the interval isn't known (e.g. the sample rate of the device isn't). All numbers are little-endian:
+    ///
+    ///| size          | content                                            |
+    ///|---------------|----------------------------------------------------|
+    ///| 4             | magic `OMNB`                                       |
+    ///| 1             | version, currently 1                               |
+    ///| 1             | flags, bit 0 is set, if the first sample follows a gap, bit 1, if the samples are calibrated, bit 2, if the device time follows, bit 3, if every sample has its own timestamp |
+    ///| 2             | number of devices `n`                              |
+    ///| per device    | `u8` length and the UTF-8 device id                |
+    ///| 8             | `f64` timestamp of the first sample, in ms since the UNIX epoch |
+    ///| 8             | `f64` sample interval in ms, 0 with bit 3          |
+    ///| 16            | only with bit 2 and without bit 3: `f64` device time of the first sample and `f64` sample interval, in s according to the device clock |
+    ///| 4             | `u32` number of samples `m`                        |
+    ///| 2 × n × m     | `u16` samples, all devices of the first sample, then of the second sample ... |
+    ///
+    ///With calibrated values, the raw samples are replaced by `f64` values, taking 8 × n × m bytes.
+    ///With bit 3, the values of every sample are preceded by its `f64` timestamp in ms since the UNIX epoch
+    ///and, with bit 2, its `f64` device time in s.
+    ///
+    ///A [`WSFrame`] of a trigger is sent as a single binary frame:
+    ///
+    ///| size          | content                                            |
+    ///|---------------|----------------------------------------------------|
+    ///| 4             | magic `OMNF`                                       |
+    ///| 1             | version, currently 1                               |
+    ///| 1             | flags, bit 0 is set, if the frame was triggered, bit 1, if the samples are calibrated, bit 2, if the device time follows |
+    ///| 2             | number of devices `n`                              |
+    ///| per device    | `u8` length and the UTF-8 device id                |
+    ///| 8             | `u64` sequence number of the frame                 |
+    ///| 4             | `u32` index of the sample, at which the trigger fired |
+    ///| 4             | `u32` number of samples `m`                        |
+    ///| per sample    | `f64` timestamp in ms since the UNIX epoch, only with bit 2 the `f64` device time in s (NaN if unknown), `u8` 1 if the sample follows a gap, and the `u16` or calibrated `f64` values of all devices |
+    Binary,
+}
+impl core::str::FromStr for StreamFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "msgpack" | "messagepack" => Ok(Self::Msgpack),
+            "cbor" => Ok(Self::Cbor),
+            "binary" => Ok(Self::Binary),
+            _ => Err(format!("Unknown format {s}, expected json, msgpack, cbor or binary")),
+        }
+    }
+}
+impl StreamFormat {
+    ///Encodes `measurement` into websocket frames.
+    pub(super) fn encode(self, measurement: &WSMeasurement) -> anyhow::Result<Vec<rocket_ws::Message>> {
+        Ok(match self {
+            Self::Json => vec![rocket_ws::Message::Text(serde_json::to_string(measurement)?)],
+            Self::Msgpack => vec![rocket_ws::Message::Binary(rmp_serde::to_vec_named(measurement)?)],
+            Self::Cbor => {
+                let mut out = Vec::new();
+                ciborium::into_writer(measurement, &mut out)?;
+                vec![rocket_ws::Message::Binary(out)]
+            },
+            Self::Binary => encode_binary(measurement)?.into_iter().map(rocket_ws::Message::Binary).collect(),
+        })
+    }
+    ///Encodes the trigger `frame` into a single websocket frame.
+    pub(super) fn encode_frame(self, frame: &WSFrame) -> anyhow::Result<rocket_ws::Message> {
+        Ok(match self {
+            Self::Json => rocket_ws::Message::Text(serde_json::to_string(frame)?),
+            Self::Msgpack => rocket_ws::Message::Binary(rmp_serde::to_vec_named(frame)?),
+            Self::Cbor => {
+                let mut out = Vec::new();
+                ciborium::into_writer(frame, &mut out)?;
+                rocket_ws::Message::Binary(out)
+            },
+            Self::Binary => rocket_ws::Message::Binary(encode_binary_frame(frame)?),
+        })
+    }
+}
+
+fn encode_binary_frame(frame: &WSFrame) -> anyhow::Result<Vec<u8>> {
+    let calibrated = frame.data.first().is_some_and(|sample| sample.calibrated.is_some());
+    let device_time = frame.data.iter().any(|sample| sample.device_time.is_some());
+    let sample_size = 9 + if device_time { 8 } else { 0 } + frame.devices.len() * if calibrated { 8 } else { 2 };
+    let mut out = Vec::with_capacity(24 + frame.devices.iter().map(|v| v.len() + 1).sum::<usize>() + frame.data.len() * sample_size);
+    out.extend_from_slice(BINARY_TRIGGER_MAGIC);
+    out.push(BINARY_VERSION);
+    out.push(if frame.triggered { BINARY_FLAG_TRIGGERED } else { 0 } | if calibrated { BINARY_FLAG_CALIBRATED } else { 0 } | if device_time { BINARY_FLAG_DEVICE_TIME } else { 0 });
+    out.extend_from_slice(&u16::try_from(frame.devices.len())?.to_le_bytes());
+    for device in &frame.devices {
+        out.push(u8::try_from(device.len())?);
+        out.extend_from_slice(device.as_bytes());
+    }
+    out.extend_from_slice(&frame.sequence.to_le_bytes());
+    out.extend_from_slice(&u32::try_from(frame.trigger_index)?.to_le_bytes());
+    out.extend_from_slice(&u32::try_from(frame.data.len())?.to_le_bytes());
+    for sample in &frame.data {
+        out.extend_from_slice(&sample.timestamp.to_le_bytes());
+        if device_time {
+            out.extend_from_slice(&sample.device_time.unwrap_or(f64::NAN).to_le_bytes());
+        }
+        out.push(u8::from(sample.gap));
+        match &sample.calibrated {
+            Some(calibrated) => for value in calibrated {
+                out.extend_from_slice(&value.to_le_bytes());
+            },
+            None => for value in &sample.value {
+                out.extend_from_slice(&value.to_le_bytes());
+            },
+        }
+    }
+    Ok(out)
+}
+
+///Splits `measurement` into runs with a constant sample interval, and encodes every run into a frame.
+fn encode_binary(measurement: &WSMeasurement) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut header = Vec::with_capacity(8 + measurement.devices.iter().map(|v| v.len() + 1).sum::<usize>());
+    header.extend_from_slice(BINARY_MAGIC);
+    header.push(BINARY_VERSION);
+    header.push(0);
+    header.extend_from_slice(&u16::try_from(measurement.devices.len())?.to_le_bytes());
+    for device in &measurement.devices {
+        header.push(u8::try_from(device.len())?);
+        header.extend_from_slice(device.as_bytes());
+    }
+
+    let data = &measurement.data;
+    let mut frames = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        //A run ends at a gap, where the interval deviates by more than half an interval from the first one,
+        //or where the device time becomes (un)known.
+        //Samples without a positive interval to the next one (e.g. all samples of a packet of a device with an unknown rate)
+        //can't be described by an interval, so they are sent with their timestamps until the next gap.
+        let next = data.get(start + 1).filter(|v| !v.gap && v.device_time.is_some() == data[start].device_time.is_some());
+        let interval = next.map_or(0., |v| v.timestamp - data[start].timestamp);
+        let timestamps = next.is_some() && interval <= 0.;
+        let mut end = start + 1;
+        while let Some(next) = data.get(end) {
+            let deviation = (next.timestamp - data[end - 1].timestamp - interval).abs();
+            if next.gap || next.device_time.is_some() != data[start].device_time.is_some() || !timestamps && (interval <= 0. || deviation > interval / 2.) {
+                break;
+            }
+            end += 1;
+        }
+        let run = &data[start..end];
+        let calibrated = run[0].calibrated.is_some();
+        let sample_size = if calibrated { 8 } else { 2 } * measurement.devices.len() + if timestamps { 16 } else { 0 };
+        let mut frame = Vec::with_capacity(header.len() + 36 + run.len() * sample_size);
+        frame.extend_from_slice(&header);
+        if run[0].gap {
+            frame[5] |= BINARY_FLAG_GAP;
+        }
+        if calibrated {
+            frame[5] |= BINARY_FLAG_CALIBRATED;
+        }
+        if timestamps {
+            frame[5] |= BINARY_FLAG_TIMESTAMPS;
+        }
+        frame.extend_from_slice(&run[0].timestamp.to_le_bytes());
+        frame.extend_from_slice(&interval.max(0.).to_le_bytes());
+        if let (Some(first), Some(last)) = (run[0].device_time, run[run.len() - 1].device_time) {
+            frame[5] |= BINARY_FLAG_DEVICE_TIME;
+            if !timestamps {
+                let device_interval = if run.len() > 1 { (last - first) / (run.len() - 1) as f64 } else { 0. };
+                frame.extend_from_slice(&first.to_le_bytes());
+                frame.extend_from_slice(&device_interval.to_le_bytes());
+            }
+        }
+        frame.extend_from_slice(&u32::try_from(run.len())?.to_le_bytes());
+        for sample in run {
+            if timestamps {
+                frame.extend_from_slice(&sample.timestamp.to_le_bytes());
+                if let Some(device_time) = sample.device_time {
+                    frame.extend_from_slice(&device_time.to_le_bytes());
+                }
+            }
+            match &sample.calibrated {
+                Some(calibrated) => for value in calibrated {
+                    frame.extend_from_slice(&value.to_le_bytes());
+                },
+                None => for value in &sample.value {
+                    frame.extend_from_slice(&value.to_le_bytes());
+                },
+            }
+        }
+        frames.push(frame);
+        start = end;
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::WSMeasurementData;
+
+    ///Reads the little-endian numbers of a frame in order.
+    struct Reader<'a>(&'a [u8]);
+    impl Reader<'_> {
+        fn take<const N: usize>(&mut self) -> [u8; N] {
+            let (head, tail) = self.0.split_at(N);
+            self.0 = tail;
+            head.try_into().unwrap()
+        }
+        fn u8(&mut self) -> u8 { u8::from_le_bytes(self.take()) }
+        fn u16(&mut self) -> u16 { u16::from_le_bytes(self.take()) }
+        fn u32(&mut self) -> u32 { u32::from_le_bytes(self.take()) }
+        fn u64(&mut self) -> u64 { u64::from_le_bytes(self.take()) }
+        fn f64(&mut self) -> f64 { f64::from_le_bytes(self.take()) }
+        ///Reads the header up to the device ids, and returns the flags and ids.
+        fn header(&mut self, magic: &[u8; 4]) -> (u8, Vec<String>) {
+            assert_eq!(&self.take::<4>(), magic);
+            assert_eq!(self.u8(), BINARY_VERSION);
+            let flags = self.u8();
+            let devices = (0..self.u16()).map(|_| {
+                let len = usize::from(self.u8());
+                let (id, tail) = self.0.split_at(len);
+                self.0 = tail;
+                String::from_utf8(id.to_vec()).unwrap()
+            }).collect();
+            (flags, devices)
+        }
+    }
+
+    fn sample(timestamp: f64, device_time: Option<f64>, value: Vec<u16>, gap: bool) -> WSMeasurementData {
+        WSMeasurementData { timestamp, device_time, value, gap, calibrated: None }
+    }
+
+    fn encode(data: Vec<WSMeasurementData>) -> Vec<Vec<u8>> {
+        encode_binary(&WSMeasurement { devices: vec!["A1".to_string(), "B22".to_string()], data }).unwrap()
+    }
+
+    #[test]
+    fn binary_runs_with_device_time() {
+        let data = (0..5).map(|i| sample(1000. + f64::from(i), Some(2. + f64::from(i) / 1000.), vec![i as u16, 100 + i as u16], i == 3)).collect();
+        let frames = encode(data);
+        assert_eq!(frames.len(), 2);
+        let mut frame = Reader(&frames[0]);
+        assert_eq!(frame.header(BINARY_MAGIC), (BINARY_FLAG_DEVICE_TIME, vec!["A1".to_string(), "B22".to_string()]));
+        assert_eq!((frame.f64(), frame.f64()), (1000., 1.));
+        assert_eq!(frame.f64(), 2.);
+        assert!((frame.f64() - 0.001).abs() < 1e-12);
+        assert_eq!(frame.u32(), 3);
+        assert_eq!((0..6).map(|_| frame.u16()).collect::<Vec<_>>(), [0, 100, 1, 101, 2, 102]);
+        assert!(frame.0.is_empty());
+        //The gap starts the second frame.
+        let mut frame = Reader(&frames[1]);
+        assert_eq!(frame.header(BINARY_MAGIC).0, BINARY_FLAG_GAP | BINARY_FLAG_DEVICE_TIME);
+        assert_eq!((frame.f64(), frame.f64(), frame.f64()), (1003., 1., 2.003));
+        frame.f64();
+        assert_eq!(frame.u32(), 2);
+        assert_eq!((0..4).map(|_| frame.u16()).collect::<Vec<_>>(), [3, 103, 4, 104]);
+        assert!(frame.0.is_empty());
+    }
+
+    ///Samples of a device with an unknown rate all share the arrival time of their packet.
+    #[test]
+    fn binary_timestamps_without_interval() {
+        let data = [1000., 1000., 1000., 1010., 1010., 1010.].iter().enumerate().map(|(i, timestamp)| sample(*timestamp, None, vec![i as u16, 0], false)).collect();
+        let frames = encode(data);
+        assert_eq!(frames.len(), 1);
+        let mut frame = Reader(&frames[0]);
+        assert_eq!(frame.header(BINARY_MAGIC).0, BINARY_FLAG_TIMESTAMPS);
+        assert_eq!((frame.f64(), frame.f64()), (1000., 0.));
+        assert_eq!(frame.u32(), 6);
+        let samples: Vec<_> = (0..6).map(|_| (frame.f64(), frame.u16(), frame.u16())).collect();
+        assert_eq!(samples, [(1000., 0, 0), (1000., 1, 0), (1000., 2, 0), (1010., 3, 0), (1010., 4, 0), (1010., 5, 0)]);
+        assert!(frame.0.is_empty());
+    }
+
+    #[test]
+    fn binary_calibrated() {
+        let mut data = vec![sample(1000., None, vec![1, 2], false), sample(1002., None, vec![3, 4], false)];
+        data.iter_mut().for_each(|sample| sample.calibrated = Some(sample.value.iter().map(|v| f64::from(*v) / 2.).collect()));
+        let frames = encode(data);
+        let mut frame = Reader(&frames[0]);
+        assert_eq!(frame.header(BINARY_MAGIC).0, BINARY_FLAG_CALIBRATED);
+        assert_eq!((frame.f64(), frame.f64(), frame.u32()), (1000., 2., 2));
+        assert_eq!((0..4).map(|_| frame.f64()).collect::<Vec<_>>(), [0.5, 1., 1.5, 2.]);
+        assert!(frame.0.is_empty());
+    }
+
+    #[test]
+    fn binary_trigger_frame() {
+        let frame = WSFrame {
+            devices: vec!["A1".to_string()],
+            sequence: 7,
+            triggered: true,
+            trigger_index: 1,
+            trigger_timestamp: 1001.,
+            data: vec![sample(1000., Some(2.), vec![10], false), sample(1001., None, vec![20], true)],
+        };
+        let encoded = encode_binary_frame(&frame).unwrap();
+        let mut frame = Reader(&encoded);
+        assert_eq!(frame.header(BINARY_TRIGGER_MAGIC), (BINARY_FLAG_TRIGGERED | BINARY_FLAG_DEVICE_TIME, vec!["A1".to_string()]));
+        assert_eq!((frame.u64(), frame.u32(), frame.u32()), (7, 1, 2));
+        assert_eq!((frame.f64(), frame.f64(), frame.u8(), frame.u16()), (1000., 2., 0, 10));
+        let (timestamp, device_time, gap, value) = (frame.f64(), frame.f64(), frame.u8(), frame.u16());
+        assert_eq!((timestamp, gap, value), (1001., 1, 20));
+        assert!(device_time.is_nan());
+        assert!(frame.0.is_empty());
+    }
+}
@@ -0,0 +1,366 @@
+//! The versioned JSON command protocol of the websocket.
+//!
+//! Every request is a JSON object with a `version`, an optional `id` and a `command`:
+//! `{"version": 1, "id": 7, "command": "subscribe", "devices": ["E6614C311B6C5A2B"], "sampling_rate": 100}`.
+//! Every request is answered with a reply carrying the same `id`, either
+//! `{"version": 1, "id": 7, "type": "response", "command": "subscribe", "result": {...}}` or
+//! `{"version": 1, "id": 7, "type": "error", "error": {"code": "device_not_found", "message": "..."}}`.
+//! Measurements are sent without `type`, as before.
+use crate::device::annotation::{Annotation, AnnotationRequest};
+use crate::signal::downsample::DownsampleAlgorithm;
+use crate::signal::trigger::TriggerSettings;
+use super::backpressure::{Backpressure, BackpressurePolicy};
+use super::session::{PushPolicy, StreamOptions};
+
+pub(super) const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, serde_derive::Deserialize)]
+pub(super) struct Request {
+    ///Echoed in the reply, so clients can match replies to requests.
+    #[serde(default)]
+    pub(super) id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub(super) command: Command,
+}
+
+#[derive(Debug, Clone, serde_derive::Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub(super) enum Command {
+    ///Replaces the current subscription with the given devices (serial numbers or aliases) and starts streaming.
+    ///`format` sets how measurements are encoded. Replies are always JSON.
+    ///`"values": "calibrated"` adds the values converted with the calibrations of the devices to every sample.
+    Subscribe {
+        devices: Vec<String>,
+        #[serde(flatten)]
+        options: StreamOptions,
+    },
+    Unsubscribe,
+    ///Resumes streaming of the subscribed devices.
+    Start,
+    ///Pauses streaming. The devices keep capturing, because other clients may use them.
+    Stop,
+    SetRate {
+        sampling_rate: u32,
+    },
+    ///Changes when measurements are sent, e.g. `{"mode": "interval", "interval_ms": 50}`, `{"mode": "samples", "samples": 1000}` or `{"mode": "immediate"}`.
+    SetPush {
+        push: PushPolicy,
+    },
+    ///Changes what happens, when the client doesn't keep up, e.g. `{"policy": "decimate", "max_queued_samples": 10000}`.
+    SetBackpressure {
+        backpressure: Backpressure,
+    },
+    GetDevices,
+    ///Downsamples the history of the subscribed devices, or the capture file `recording` from the recordings directory.
+    GetDownsampledInRange {
+        tmin: chrono::DateTime<chrono::FixedOffset>,
+        tmax: chrono::DateTime<chrono::FixedOffset>,
+        ///`u64`, because serde can't buffer `u128` for the internally tagged enum.
+        desired_number_of_samples: u64,
+        #[serde(default)]
+        algorithm: DownsampleAlgorithm,
+        #[serde(default)]
+        recording: Option<String>,
+    },
+    Ping,
+    ///Labels a point in time, or a range up to `end`, of the given devices or of the subscribed devices, e.g.
+    ///`{"label": "relay closed", "timestamp": "2025-01-31T12:00:00Z", "values": {"relay": 3}}`. Without `timestamp` it labels now.
+    ///The annotation is recorded and sent to the other subscribers of the devices.
+    Annotate {
+        #[serde(flatten)]
+        annotation: AnnotationRequest,
+    },
+    ///Sends frames around trigger points of the subscribed device `source` (default the first one) instead of the stream, e.g.
+    ///`{"source": "E6614C311B6C5A2B", "trigger": {"type": "edge", "level": 32768, "slope": "rising", "hysteresis": 100, "mode": "normal", "pre_samples": 200, "post_samples": 800}}`.
+    ///Levels are in the calibrated units of the device, if subscribed with calibrated values, otherwise raw samples.
+    ///The frames hold the samples at the rate of the devices, without decimation, and count against `max_queued_samples`.
+    SetTrigger {
+        #[serde(default)]
+        source: Option<String>,
+        trigger: TriggerSettings,
+    },
+    ///Returns to streaming.
+    ClearTrigger,
+    ///Waits for the next trigger, e.g. after a frame of the `single` mode.
+    ArmTrigger,
+}
+impl Command {
+    ///The `command` tag of the command.
+    pub(super) const fn name(&self) -> &'static str {
+        match self {
+            Self::Subscribe { .. } => "subscribe",
+            Self::Unsubscribe => "unsubscribe",
+            Self::Start => "start",
+            Self::Stop => "stop",
+            Self::SetRate { .. } => "set_rate",
+            Self::SetPush { .. } => "set_push",
+            Self::SetBackpressure { .. } => "set_backpressure",
+            Self::GetDevices => "get_devices",
+            Self::GetDownsampledInRange { .. } => "get_downsampled_in_range",
+            Self::Ping => "ping",
+            Self::Annotate { .. } => "annotate",
+            Self::SetTrigger { .. } => "set_trigger",
+            Self::ClearTrigger => "clear_trigger",
+            Self::ArmTrigger => "arm_trigger",
+        }
+    }
+    ///Returns the known command tags, as derived by serde, if `command` isn't one of them.
+    fn unknown(command: &str) -> Option<&'static [&'static str]> {
+        let tag = serde::de::value::MapDeserializer::<_, CommandTagError>::new(core::iter::once(("command", command)));
+        <Self as serde::Deserialize>::deserialize(tag).err()?.expected
+    }
+}
+
+///Error of deserializing a [`Command`] from its tag alone. Only an unknown tag is of interest, missing fields are expected.
+#[derive(Debug)]
+struct CommandTagError {
+    expected: Option<&'static [&'static str]>,
+}
+impl core::fmt::Display for CommandTagError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.expected {
+            Some(expected) => write!(f, "Unknown command, expected one of {}", expected.join(", ")),
+            None => write!(f, "Invalid command"),
+        }
+    }
+}
+impl std::error::Error for CommandTagError {}
+impl serde::de::Error for CommandTagError {
+    fn custom<T: core::fmt::Display>(_: T) -> Self {
+        Self { expected: None }
+    }
+    fn unknown_variant(_: &str, expected: &'static [&'static str]) -> Self {
+        Self { expected: Some(expected) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum ErrorCode {
+    ///The message isn't valid JSON, or misses fields of its command.
+    InvalidRequest,
+    UnsupportedVersion,
+    UnknownCommand,
+    DeviceNotFound,
+    RecordingNotFound,
+    InvalidSampleRate,
+    ///The command needs a subscription.
+    NotSubscribed,
+    ///The command needs a trigger.
+    NoTrigger,
+    ///Talking to a device failed.
+    DeviceError,
+    ///The client didn't keep up with the samples, see [`BackpressurePolicy::Disconnect`].
+    SlowConsumer,
+    Internal,
+}
+
+#[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(super) struct CommandError {
+    pub(super) code: ErrorCode,
+    pub(super) message: String,
+}
+impl CommandError {
+    pub(super) fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+impl core::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+#[derive(Debug, Clone, serde_derive::Serialize)]
+pub(super) struct Reply {
+    version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    body: ReplyBody,
+}
+#[derive(Debug, Clone, serde_derive::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ReplyBody {
+    Response {
+        command: &'static str,
+        result: serde_json::Value,
+    },
+    Error {
+        error: CommandError,
+    },
+}
+impl Reply {
+    pub(super) const fn response(id: Option<serde_json::Value>, command: &'static str, result: serde_json::Value) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id,
+            body: ReplyBody::Response { command, result },
+        }
+    }
+    pub(super) const fn error(id: Option<serde_json::Value>, error: CommandError) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id,
+            body: ReplyBody::Error { error },
+        }
+    }
+}
+
+///Sent to clients of this protocol without a request.
+#[derive(Debug, Clone, serde_derive::Serialize)]
+pub(super) struct Notification {
+    version: u32,
+    #[serde(flatten)]
+    body: NotificationBody,
+}
+#[derive(Debug, Clone, serde_derive::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum NotificationBody {
+    ///Samples were lost since the last notification, because the client didn't keep up.
+    DataDropped {
+        ///Samples dropped by the [`BackpressurePolicy`].
+        samples: u64,
+        ///Packets lost before they reached the session.
+        packets: u64,
+        ///By how much the sample rate is reduced currently.
+        decimation: usize,
+        policy: BackpressurePolicy,
+    },
+    ///Another client annotated a subscribed device.
+    Annotation {
+        annotation: Annotation,
+    },
+}
+impl Notification {
+    pub(super) const fn data_dropped(samples: u64, packets: u64, decimation: usize, policy: BackpressurePolicy) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            body: NotificationBody::DataDropped { samples, packets, decimation, policy },
+        }
+    }
+    pub(super) const fn annotation(annotation: Annotation) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            body: NotificationBody::Annotation { annotation },
+        }
+    }
+}
+
+///Parses a message in the versioned protocol. The version is checked here, so [`Request`] doesn't keep it.
+///
+///Returns `None`, if the message isn't meant for this protocol, because it has no `version`.
+///Otherwise a parse failure is returned as an error reply for the client.
+pub(super) fn parse(text: &str) -> Option<Result<Request, Reply>> {
+    let value: serde_json::Value = match serde_json::from_str(text) {
+        Ok(v) => v,
+        Err(err) if text.trim_start().starts_with('{') => {
+            return Some(Err(Reply::error(None, CommandError::new(ErrorCode::InvalidRequest, format!("Invalid JSON: {err}")))));
+        },
+        Err(_) => return None,
+    };
+    let version = value.get("version")?;
+    let id = value.get("id").cloned();
+    if version.as_u64() != Some(u64::from(PROTOCOL_VERSION)) {
+        return Some(Err(Reply::error(id, CommandError::new(ErrorCode::UnsupportedVersion, format!("Unsupported protocol version {version}, expected {PROTOCOL_VERSION}")))));
+    }
+    match value.get("command").and_then(serde_json::Value::as_str) {
+        Some(command) => if let Some(expected) = Command::unknown(command) {
+            return Some(Err(Reply::error(id, CommandError::new(ErrorCode::UnknownCommand, format!("Unknown command {command}, expected one of {}", expected.join(", "))))));
+        },
+        None => return Some(Err(Reply::error(id, CommandError::new(ErrorCode::InvalidRequest, "Missing command")))),
+    }
+    Some(serde_json::from_value(value).map_err(|err| Reply::error(id, CommandError::new(ErrorCode::InvalidRequest, format!("Invalid request: {err}")))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(command: &str) -> Request {
+        match parse(command) {
+            Some(Ok(request)) => request,
+            Some(Err(reply)) => panic!("{command} failed: {}", serde_json::to_string(&reply).unwrap()),
+            None => panic!("{command} isn't versioned"),
+        }
+    }
+
+    fn error(command: &str) -> serde_json::Value {
+        match parse(command) {
+            Some(Err(reply)) => serde_json::to_value(&reply).unwrap(),
+            Some(Ok(request)) => panic!("{command} parsed as {request:?}"),
+            None => panic!("{command} isn't versioned"),
+        }
+    }
+
+    ///Every command is parsed from its tag and reports the same tag as its name.
+    #[test]
+    fn commands_round_trip() {
+        let commands = [
+            r#""command": "subscribe", "devices": ["A1", "B2"], "sampling_rate": 100, "format": "binary""#,
+            r#""command": "unsubscribe""#,
+            r#""command": "start""#,
+            r#""command": "stop""#,
+            r#""command": "set_rate", "sampling_rate": 100"#,
+            r#""command": "set_push", "push": {"mode": "interval", "interval_ms": 50}"#,
+            r#""command": "set_backpressure", "backpressure": {"policy": "decimate", "max_queued_samples": 10000}"#,
+            r#""command": "get_devices""#,
+            r#""command": "get_downsampled_in_range", "tmin": "2025-01-31T12:00:00Z", "tmax": "2025-01-31T12:01:00+01:00", "desired_number_of_samples": 1000"#,
+            r#""command": "ping""#,
+            r#""command": "annotate", "label": "relay closed", "values": {"relay": 3}"#,
+            r#""command": "set_trigger", "trigger": {"type": "edge", "level": 32768, "slope": "rising"}"#,
+            r#""command": "clear_trigger""#,
+            r#""command": "arm_trigger""#,
+        ];
+        for (id, fields) in commands.iter().enumerate() {
+            let request = request(&format!(r#"{{"version": 1, "id": {id}, {fields}}}"#));
+            assert_eq!(request.id, Some(serde_json::json!(id)));
+            let tag = fields.split('"').nth(3).unwrap();
+            assert_eq!(request.command.name(), tag);
+        }
+        let Command::Subscribe { devices, options } = request(r#"{"version": 1, "command": "subscribe", "devices": ["A1"], "sampling_rate": 100}"#).command else {
+            panic!("Not a subscription");
+        };
+        assert_eq!(devices, ["A1"]);
+        assert_eq!(options.sampling_rate, Some(100));
+    }
+
+    #[test]
+    fn unknown_command() {
+        let reply = error(r#"{"version": 1, "id": "a", "command": "subscribe_all"}"#);
+        assert_eq!(reply["id"], "a");
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["error"]["code"], "unknown_command");
+        let message = reply["error"]["message"].as_str().unwrap();
+        assert!(message.starts_with("Unknown command subscribe_all, expected one of subscribe, unsubscribe,"), "{message}");
+        assert!(message.ends_with("arm_trigger"), "{message}");
+    }
+
+    #[test]
+    fn invalid_requests() {
+        assert_eq!(error(r#"{"version": 2, "command": "ping"}"#)["error"]["code"], "unsupported_version");
+        assert_eq!(error(r#"{"version": 1}"#)["error"]["code"], "invalid_request");
+        assert_eq!(error(r#"{"version": 1, "command": "set_rate"}"#)["error"]["code"], "invalid_request");
+        assert_eq!(error(r#"{"version": 1, "#)["error"]["code"], "invalid_request");
+        //Messages of the unversioned protocol are left to the caller.
+        assert!(parse(r#"{"command": "ping"}"#).is_none());
+        assert!(parse("E6614C311B6C5A2B").is_none());
+    }
+
+    ///The versioned protocol takes the sample count as `u64`, the unversioned one as `u128`.
+    #[test]
+    fn desired_number_of_samples() {
+        let range = r#""command": "get_downsampled_in_range", "tmin": "2025-01-31T12:00:00Z", "tmax": "2025-01-31T12:01:00Z""#;
+        let Command::GetDownsampledInRange { desired_number_of_samples, .. } = request(&format!(r#"{{"version": 1, {range}, "desired_number_of_samples": {}}}"#, u64::MAX)).command else {
+            panic!("Not a range request");
+        };
+        assert_eq!(desired_number_of_samples, u64::MAX);
+        let reply = error(&format!(r#"{{"version": 1, {range}, "desired_number_of_samples": {}}}"#, u128::MAX));
+        assert_eq!(reply["error"]["code"], "invalid_request");
+
+        let legacy = format!(r#"{{{range}, "desired_number_of_samples": {}}}"#, u128::MAX);
+        assert!(parse(&legacy).is_none());
+        let request = serde_json::from_str::<super::super::DownsampleRequest>(&legacy).unwrap();
+        assert_eq!(request.desired_number_of_samples, u128::MAX);
+    }
+}
@@ -0,0 +1,1057 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use crate::aliases::AliasRegistry;
+use crate::calibration::{Calibration, CalibrationRegistry};
+use crate::device::{Device, DeviceList, Packet, SendDevice};
+use crate::device::annotation::{Annotation, AnnotationRequest, Seen};
+use crate::signal::align::{Aligner, Interpolation};
+use crate::signal::downsample::{downsample, DownsampleAlgorithm};
+use crate::signal::decimate::{DecimationFilter, Decimator};
+use crate::signal::resample::Resampler;
+use crate::signal::trigger::{Trigger, TriggerSettings, TriggerState};
+use crate::{signal, MAX_MESSAGE_BUF};
+use crate::routes::metrics::SessionMetrics;
+use crate::routes::recordings::{read_downsampled, Recordings};
+use super::backpressure::{Backpressure, BackpressurePolicy, MAX_DECIMATION};
+use super::format::StreamFormat;
+use super::protocol::{CommandError, ErrorCode, Notification};
+use super::{align_downsampled, WSFrame, WSMeasurement, WSMeasurementData};
+
+///Push intervals clients may choose, in milliseconds.
+const MIN_PUSH_INTERVAL: u64 = 10;
+const MAX_PUSH_INTERVAL: u64 = 10_000;
+///Most samples clients may let the server collect before pushing them.
+const MAX_PUSH_SAMPLES: usize = 1_000_000;
+///Most trigger frames waiting to be sent. The oldest are dropped beyond.
+const MAX_QUEUED_FRAMES: usize = 16;
+
+///When collected samples are sent to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub(super) enum PushPolicy {
+    ///Every `interval_ms` milliseconds, even if no samples were collected.
+    Interval {
+        interval_ms: u64,
+    },
+    ///As soon as `samples` samples were collected.
+    Samples {
+        samples: usize,
+    },
+    ///As soon as a packet of a device arrives. This has the lowest latency, but sends the most messages.
+    Immediate,
+}
+impl Default for PushPolicy {
+    fn default() -> Self {
+        Self::Interval { interval_ms: 500 }
+    }
+}
+impl PushPolicy {
+    fn validate(self) -> Result<Self, CommandError> {
+        match self {
+            Self::Interval { interval_ms } if !(MIN_PUSH_INTERVAL..=MAX_PUSH_INTERVAL).contains(&interval_ms) => {
+                Err(CommandError::new(ErrorCode::InvalidRequest, format!("The push interval must be between {MIN_PUSH_INTERVAL} and {MAX_PUSH_INTERVAL} ms, but was {interval_ms} ms")))
+            },
+            Self::Samples { samples } if !(1..=MAX_PUSH_SAMPLES).contains(&samples) => {
+                Err(CommandError::new(ErrorCode::InvalidRequest, format!("The number of samples per push must be between 1 and {MAX_PUSH_SAMPLES}, but was {samples}")))
+            },
+            Self::Interval { .. } | Self::Samples { .. } | Self::Immediate => Ok(self),
+        }
+    }
+    ///A timer for [`Self::Interval`]. The other policies push, when samples arrive.
+    pub(super) fn timer(self) -> Option<tokio::time::Interval> {
+        match self {
+            Self::Interval { interval_ms } => {
+                let mut interval = tokio::time::interval(Duration::from_millis(interval_ms));
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+                Some(interval)
+            },
+            Self::Samples { .. } | Self::Immediate => None,
+        }
+    }
+}
+
+///Which values of the samples are sent to the client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Values {
+    ///The raw samples of the devices.
+    #[default]
+    Raw,
+    ///The raw samples, and the values converted with the calibrations of the devices at the time of subscribing.
+    Calibrated,
+}
+
+///How a subscription streams its devices.
+#[derive(Debug, Clone, Copy, Default, serde_derive::Deserialize)]
+#[serde(default)]
+pub(super) struct StreamOptions {
+    ///`None` streams at the rate of the devices.
+    pub(super) sampling_rate: Option<u32>,
+    pub(super) interpolation: Interpolation,
+    pub(super) format: StreamFormat,
+    pub(super) push: PushPolicy,
+    pub(super) backpressure: Backpressure,
+    ///How the device data is brought down to `sampling_rate`.
+    pub(super) decimation: DecimationFilter,
+    pub(super) values: Values,
+    ///Whether the client understands [`Notification`]s. Clients of the unversioned protocol don't.
+    #[serde(skip)]
+    pub(super) notifications: bool,
+}
+
+///What the forwarding tasks of a [`Session`] send to the connection.
+pub(super) enum Forwarded {
+    Packet(Packet),
+    ///The forwarding task fell behind the device, and the given number of packets were lost.
+    Lagged(u64),
+    ///An annotation of the device, maybe made by this session.
+    Annotation(Arc<Annotation>),
+}
+
+///How the samples of a device are decimated, as documented to the client.
+#[derive(Debug, Clone, Copy, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(super) struct DecimationStage {
+    pub(super) filter: DecimationFilter,
+    ///Input samples per output sample of the filter.
+    pub(super) ratio: usize,
+    ///Sample rate of the device, 0 if unknown.
+    pub(super) input_rate: u32,
+    ///Sample rate sent to the client, after the filter and resampling to the exact rate.
+    pub(super) output_rate: u32,
+}
+
+///The trigger of a subscription.
+#[derive(Debug, Clone, serde_derive::Serialize)]
+pub(super) struct TriggerStatus {
+    ///The device, whose values are checked.
+    source: String,
+    trigger: TriggerSettings,
+    state: TriggerState,
+    ///Frames taken since the trigger was set.
+    frames: u64,
+}
+
+///The outcome of subscribing or changing the sample rate.
+#[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(super) struct Subscription {
+    pub(super) devices: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) sampling_rate: Option<u32>,
+    ///Whether the device samples at `sampling_rate` itself, or the server resamples the device data, per device.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) resampled: Vec<bool>,
+    pub(super) format: StreamFormat,
+    pub(super) push: PushPolicy,
+    pub(super) backpressure: Backpressure,
+    ///Per device.
+    pub(super) decimation: Vec<DecimationStage>,
+    pub(super) values: Values,
+    ///Per device for [`Values::Calibrated`], `None` for devices without a calibration.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) calibrations: Vec<Option<Calibration>>,
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub(super) trigger: Option<TriggerStatus>,
+}
+
+///The subscription state of one websocket connection.
+pub(super) struct Session {
+    devices: Vec<String>,
+    subscribed: Vec<SendDevice>,
+    ///Forward the packets of every subscribed device into the channel of the connection.
+    forwarders: tokio::task::JoinSet<()>,
+    sampling_rate: Option<u32>,
+    interpolation: Interpolation,
+    format: StreamFormat,
+    push: PushPolicy,
+    backpressure: Backpressure,
+    notifications: bool,
+    filter: DecimationFilter,
+    values: Values,
+    ///Per device for [`Values::Calibrated`], otherwise empty.
+    calibrations: Vec<Option<Calibration>>,
+    ///Sample rates of the devices, 0 if unknown.
+    input_rates: Vec<u32>,
+    stages: Vec<DecimationStage>,
+    decimators: Vec<Decimator>,
+    ///Output of a decimator, before it is resampled.
+    decimated: Vec<u16>,
+    resamplers: Vec<Resampler>,
+    aligner: Aligner,
+    measure_data: Vec<WSMeasurementData>,
+    ///Cuts frames out of the stream, which are sent instead of `measure_data`, with the index of the device it checks.
+    trigger: Option<(usize, Trigger<WSMeasurementData>)>,
+    frames: VecDeque<WSFrame>,
+    streaming: bool,
+    ///Extra decimation ratio of the [`Decimator`]s, while [`BackpressurePolicy::Decimate`] is in effect.
+    decimation: usize,
+    ///Whether `decimation` was raised since the last measurement was taken.
+    ///Samples already in the pipeline still arrive at the previous rate, so it is raised once per measurement.
+    decimation_raised: bool,
+    ///Losses since the last [`Notification`].
+    dropped_samples: u64,
+    lagged_packets: u64,
+    ///Annotations of several subscribed devices arrive once per device.
+    seen_annotations: Seen,
+    metrics: Arc<SessionMetrics>,
+}
+impl Session {
+    pub(super) fn new(metrics: Arc<SessionMetrics>) -> Self {
+        Self {
+            devices: Vec::new(),
+            subscribed: Vec::new(),
+            forwarders: tokio::task::JoinSet::new(),
+            sampling_rate: None,
+            interpolation: Interpolation::default(),
+            format: StreamFormat::default(),
+            push: PushPolicy::default(),
+            backpressure: Backpressure::default(),
+            notifications: false,
+            filter: DecimationFilter::default(),
+            values: Values::default(),
+            calibrations: Vec::new(),
+            input_rates: Vec::new(),
+            stages: Vec::new(),
+            decimators: Vec::new(),
+            decimated: Vec::new(),
+            resamplers: Vec::new(),
+            aligner: Aligner::new(0, 0, Interpolation::default()),
+            measure_data: Vec::with_capacity(MAX_MESSAGE_BUF as usize),
+            trigger: None,
+            frames: VecDeque::new(),
+            streaming: false,
+            decimation: 1,
+            decimation_raised: false,
+            dropped_samples: 0,
+            lagged_packets: 0,
+            seen_annotations: Seen::default(),
+            metrics,
+        }
+    }
+    pub(super) const fn is_subscribed(&self) -> bool {
+        !self.devices.is_empty()
+    }
+    pub(super) const fn format(&self) -> StreamFormat {
+        self.format
+    }
+    pub(super) const fn push_policy(&self) -> PushPolicy {
+        self.push
+    }
+
+    ///Subscribes to `names` (serial numbers or aliases), replacing the previous subscription, and starts streaming.
+    ///Samples of the devices arrive on the returned channel, tagged with the index of the device.
+    pub(super) async fn subscribe(
+        &mut self,
+        device_list: &DeviceList,
+        aliases: &AliasRegistry,
+        calibrations: &CalibrationRegistry,
+        names: &[&str],
+        options: StreamOptions,
+    ) -> Result<(Subscription, mpsc::Receiver<(usize, Forwarded)>), CommandError> {
+        let StreamOptions { sampling_rate, interpolation, format, push, backpressure, decimation, values, notifications } = options;
+        let backpressure = backpressure.validate()?;
+        if let Some(sampling_rate) = sampling_rate {
+            validate_sample_rate(sampling_rate)?;
+        }
+        let push = push.validate()?;
+        if names.is_empty() {
+            return Err(CommandError::new(ErrorCode::InvalidRequest, "No devices given"));
+        }
+        //In the requested order, because the values of every sample are sent in this order.
+        let mut devices: Vec<&Device> = Vec::new();
+        let mut not_found = std::collections::BTreeSet::new();
+        for name in names {
+            match device_list.find(name, aliases).await {
+                Some(device) => if !devices.iter().any(|v| core::ptr::eq(*v, device)) {
+                    devices.push(device);
+                },
+                None => {
+                    not_found.insert(*name);
+                },
+            }
+        }
+        if !not_found.is_empty() {
+            return Err(CommandError::new(ErrorCode::DeviceNotFound, format!("Device(s) not found: {}", not_found.into_iter().collect::<Vec<_>>().join(", "))));
+        }
+
+        self.unsubscribe();
+        let (tx, rx) = mpsc::channel(MAX_MESSAGE_BUF as usize*8);
+        let mut serials = Vec::with_capacity(devices.len());
+        let mut sample_rates = Vec::with_capacity(devices.len());
+        let mut device_calibrations = Vec::new();
+        for (i, device) in devices.iter().enumerate() {
+            let id = match device.id().await {
+                Some(id) => id,
+                None => {
+                    self.unsubscribe();
+                    return Err(CommandError::new(ErrorCode::Internal, "Device only had an id spuriously?"));
+                },
+            };
+            println!("Subscribing to device: {}", id.serial());
+            serials.push(id.serial().clone());
+            sample_rates.push(id.sample_rate());
+            if values == Values::Calibrated {
+                device_calibrations.push(device.calibration(calibrations).await);
+            }
+            let device = SendDevice::from(*device);
+            self.subscribed.push(device.clone());
+            let tx = tx.clone();
+            self.forwarders.spawn(async move {
+                let mut rx = device.subscribe();
+                let mut annotations = device.annotations();
+                loop {
+                    let received = tokio::select! {
+                        received = rx.recv() => received,
+                        //Everyone holding the device can annotate it, so only the end of the packets tells that the device is gone.
+                        Ok(annotation) = annotations.recv() => {
+                            if tx.send((i, Forwarded::Annotation(annotation))).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        },
+                    };
+                    match received {
+                        Ok(message) => {
+                            if let Err(err) = tx.send((i, Forwarded::Packet(message))).await {
+                                eprintln!("error sending message: {}", err);
+                                break;
+                            }
+                        }
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(num)) => {
+                            eprintln!("Lagged {num} messages");
+                            if tx.send((i, Forwarded::Lagged(num))).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                            println!("Device disconnected");
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+        self.devices = serials;
+        self.interpolation = interpolation;
+        self.format = format;
+        self.push = push;
+        self.backpressure = backpressure;
+        self.notifications = notifications;
+        self.filter = decimation;
+        self.values = values;
+        self.calibrations = device_calibrations;
+        self.metrics.set_devices(self.devices.clone()).await;
+        if let Err(err) = tokio::task::block_in_place(|| devices.iter().try_for_each(|device| device.start_capture())) {
+            eprintln!("error starting capture: {err}");
+            self.unsubscribe();
+            return Err(CommandError::new(ErrorCode::DeviceError, format!("error starting capture: {err}")));
+        }
+        let resampled = match sampling_rate {
+            Some(sampling_rate) => {
+                let (resampled, input_rates) = Self::set_sample_rates(&devices, sampling_rate).await;
+                sample_rates = input_rates;
+                resampled
+            },
+            None => vec![false; devices.len()],
+        };
+        self.sampling_rate = sampling_rate;
+        self.input_rates = sample_rates;
+        self.restart_stream();
+        self.streaming = true;
+        Ok((self.subscription(resampled), rx))
+    }
+    ///Drops the subscription. The devices keep capturing, because other clients may use them.
+    pub(super) fn unsubscribe(&mut self) {
+        //Dropping the tasks aborts them.
+        self.forwarders = tokio::task::JoinSet::new();
+        self.devices.clear();
+        self.subscribed.clear();
+        self.resamplers.clear();
+        self.decimators.clear();
+        self.stages.clear();
+        self.input_rates.clear();
+        self.calibrations.clear();
+        self.sampling_rate = None;
+        self.aligner = Aligner::new(0, 0, self.interpolation);
+        self.measure_data.clear();
+        self.trigger = None;
+        self.frames.clear();
+        self.streaming = false;
+        self.decimation = 1;
+        self.decimation_raised = false;
+        self.metrics.set_queue_depth(0);
+        self.metrics.set_decimation(1);
+    }
+    pub(super) async fn start(&mut self, device_list: &DeviceList, aliases: &AliasRegistry) -> Result<Subscription, CommandError> {
+        let devices = self.find_subscribed(device_list, aliases).await?;
+        if let Err(err) = tokio::task::block_in_place(|| devices.iter().try_for_each(|device| device.start_capture())) {
+            return Err(CommandError::new(ErrorCode::DeviceError, format!("error starting capture: {err}")));
+        }
+        if !self.streaming {
+            //Samples received while stopped were dropped.
+            for i in 0..self.devices.len() {
+                self.aligner.mark_gap(i);
+            }
+            self.resamplers.iter_mut().for_each(Resampler::reset);
+            self.decimators.iter_mut().for_each(Decimator::reset);
+            if let Some((_, trigger)) = &mut self.trigger {
+                trigger.reset();
+            }
+            self.streaming = true;
+        }
+        Ok(self.subscription(Vec::new()))
+    }
+    pub(super) fn stop(&mut self) -> Result<Subscription, CommandError> {
+        if !self.is_subscribed() {
+            return Err(CommandError::new(ErrorCode::NotSubscribed, "Subscribe to a device before stopping the stream"));
+        }
+        self.streaming = false;
+        Ok(self.subscription(Vec::new()))
+    }
+    pub(super) async fn set_rate(&mut self, device_list: &DeviceList, aliases: &AliasRegistry, sampling_rate: u32) -> Result<Subscription, CommandError> {
+        validate_sample_rate(sampling_rate)?;
+        let devices = self.find_subscribed(device_list, aliases).await?;
+        let (resampled, input_rates) = Self::set_sample_rates(&devices, sampling_rate).await;
+        self.sampling_rate = Some(sampling_rate);
+        self.input_rates = input_rates;
+        self.restart_stream();
+        Ok(self.subscription(resampled))
+    }
+
+    ///Changes when samples are pushed. Samples collected so far are kept.
+    pub(super) fn set_push_policy(&mut self, push: PushPolicy) -> Result<Subscription, CommandError> {
+        self.push = push.validate()?;
+        Ok(self.subscription(Vec::new()))
+    }
+    pub(super) fn set_backpressure(&mut self, backpressure: Backpressure) -> Result<Subscription, CommandError> {
+        let backpressure = backpressure.validate()?;
+        if let Some((_, trigger)) = &self.trigger {
+            validate_frame_size(trigger.settings(), backpressure)?;
+        }
+        self.backpressure = backpressure;
+        Ok(self.subscription(Vec::new()))
+    }
+
+    ///Feeds a packet of the device at `index` into the stream.
+    ///Returns `true`, if the [`PushPolicy`] asks to send the collected samples now,
+    ///or an error, if the [`BackpressurePolicy`] asks to disconnect.
+    pub(super) fn push(&mut self, index: usize, message: &Packet) -> Result<bool, CommandError> {
+        if !self.streaming {
+            return Ok(false);
+        }
+        if message.sequence().is_discontinuity() {
+            self.aligner.mark_gap(index);
+        }
+        //Timestamps are in milliseconds, because new Data(number) uses milliseconds
+        let aligner = &mut self.aligner;
+        let timestamps = message.timestamps();
+        //A trigger sees every sample of the devices, like the trigger of an oscilloscope.
+        let decimate = self.trigger.is_none();
+        match (self.decimators.get_mut(index).filter(|_| decimate), self.resamplers.get_mut(index)) {
+            (Some(decimator), Some(resampler)) => {
+                if message.sequence().is_discontinuity() {
+                    decimator.reset();
+                    resampler.reset();
+                }
+                let decimated = &mut self.decimated;
+                decimated.clear();
+                let mut first = None;
+                decimator.push(timestamps, message.data().data(), |timestamp, value| {
+                    first.get_or_insert(timestamp);
+                    decimated.push(value);
+                });
+                //The resampler only bridges the remaining fraction between the decimated and the requested rate.
+                if let Some(first) = first {
+                    resampler.push(&timestamps.decimated(first, decimator.ratio()), decimated, |timestamp, value| aligner.push(index, timestamp, timestamps.device_time(timestamp), value));
+                }
+            },
+            _ => {
+                for (i, value) in message.data().data().iter().enumerate() {
+                    let timestamp = timestamps.host_at(i);
+                    aligner.push(index, timestamp, timestamps.device_time(timestamp), *value);
+                }
+            },
+        }
+        let measure_data = &mut self.measure_data;
+        let dropped = &mut self.dropped_samples;
+        let (values, calibrations) = (self.values, &self.calibrations);
+        let (devices, trigger, frames, metrics) = (&self.devices, &mut self.trigger, &mut self.frames, &self.metrics);
+        aligner.drain(|timestamp, device_time, value, gap| {
+            let calibrated: Option<Vec<f64>> = (values == Values::Calibrated).then(|| value.iter().zip(calibrations).map(|(value, calibration)| match calibration {
+                Some(calibration) => calibration.apply(*value),
+                None => f64::from(*value),
+            }).collect());
+            let Some((source, trigger)) = trigger else {
+                measure_data.push(WSMeasurementData{
+                    timestamp,
+                    device_time,
+                    value,
+                    gap,
+                    calibrated,
+                });
+                return;
+            };
+            let level = match &calibrated {
+                Some(calibrated) => calibrated.get(*source).copied().unwrap_or_default(),
+                None => value.get(*source).copied().map(f64::from).unwrap_or_default(),
+            };
+            let Some(frame) = trigger.push(timestamp, level, WSMeasurementData{ timestamp, device_time, value, gap, calibrated }) else {
+                return;
+            };
+            //The backpressure limits the samples of all frames, see `enforce_backpressure`.
+            if frames.len() == MAX_QUEUED_FRAMES && let Some(oldest) = frames.pop_front() {
+                *dropped += oldest.data.len() as u64;
+                metrics.add_dropped_samples(oldest.data.len() as u64);
+            }
+            frames.push_back(WSFrame {
+                devices: devices.clone(),
+                sequence: trigger.frames(),
+                triggered: frame.triggered,
+                trigger_index: frame.trigger_index,
+                trigger_timestamp: frame.trigger_timestamp,
+                data: frame.samples,
+            });
+        });
+        self.enforce_backpressure()?;
+        if !self.frames.is_empty() {
+            return Ok(true);
+        }
+        Ok(match self.push {
+            PushPolicy::Interval { .. } => false,
+            PushPolicy::Samples { samples } => self.measure_data.len() >= samples,
+            PushPolicy::Immediate => !self.measure_data.is_empty(),
+        })
+    }
+    ///Records that `packets` packets of the device at `index` were lost, because the session fell behind.
+    pub(super) fn lagged(&mut self, index: usize, packets: u64) -> Result<(), CommandError> {
+        self.lagged_packets += packets;
+        self.metrics.add_lagged_packets(packets);
+        if self.backpressure.policy == BackpressurePolicy::Disconnect {
+            return Err(CommandError::new(ErrorCode::SlowConsumer, format!("The client fell behind and {packets} packets were lost")));
+        }
+        self.aligner.mark_gap(index);
+        if let Some(resampler) = self.resamplers.get_mut(index) {
+            resampler.reset();
+        }
+        if let Some(decimator) = self.decimators.get_mut(index) {
+            decimator.reset();
+        }
+        Ok(())
+    }
+    fn enforce_backpressure(&mut self) -> Result<(), CommandError> {
+        let max = self.backpressure.max_queued_samples;
+        let mut policy = self.backpressure.policy;
+        if self.measure_data.len() > max && policy == BackpressurePolicy::Decimate {
+            if self.decimation < MAX_DECIMATION && !self.decimation_raised {
+                self.set_decimation(self.decimation * 2);
+                self.decimation_raised = true;
+            }
+            //What is queued already is too much anyway, so the oldest samples are dropped.
+            policy = BackpressurePolicy::DropOldest;
+        }
+        if self.measure_data.len() > max {
+            match policy {
+                BackpressurePolicy::Disconnect => {
+                    return Err(CommandError::new(ErrorCode::SlowConsumer, format!("The client fell behind by more than {max} samples")));
+                },
+                BackpressurePolicy::DropOldest | BackpressurePolicy::Decimate => {
+                    let excess = self.measure_data.len() - max;
+                    self.measure_data.drain(..excess);
+                    if let Some(first) = self.measure_data.first_mut() {
+                        first.gap = true;
+                    }
+                    self.dropped_samples += excess as u64;
+                    self.metrics.add_dropped_samples(excess as u64);
+                },
+            }
+        }
+        //Frames count like samples, but are only dropped whole. The newest frame always fits, see `set_trigger`.
+        let mut queued: usize = self.frames.iter().map(|frame| frame.data.len()).sum();
+        if queued > max && self.backpressure.policy == BackpressurePolicy::Disconnect {
+            return Err(CommandError::new(ErrorCode::SlowConsumer, format!("The client fell behind by more than {max} samples of trigger frames")));
+        }
+        while queued > max && self.frames.len() > 1 && let Some(oldest) = self.frames.pop_front() {
+            queued -= oldest.data.len();
+            self.dropped_samples += oldest.data.len() as u64;
+            self.metrics.add_dropped_samples(oldest.data.len() as u64);
+        }
+        self.metrics.set_queue_depth(self.measure_data.len() + queued);
+        Ok(())
+    }
+    ///Takes the losses since the last call, if there were any and the client wants to know.
+    pub(super) fn take_notification(&mut self) -> Option<Notification> {
+        if !self.notifications || (self.dropped_samples == 0 && self.lagged_packets == 0) {
+            return None;
+        }
+        Some(Notification::data_dropped(
+            core::mem::take(&mut self.dropped_samples),
+            core::mem::take(&mut self.lagged_packets),
+            self.decimation,
+            self.backpressure.policy,
+        ))
+    }
+    ///The notification of an annotation of a subscribed device, unless this session made it, already sent it,
+    ///or the client doesn't want notifications.
+    pub(super) fn annotation(&mut self, annotation: &Annotation) -> Option<Notification> {
+        if !self.notifications || annotation.origin() == Some(self.metrics.id()) || !self.seen_annotations.insert(annotation) {
+            return None;
+        }
+        Some(Notification::annotation(annotation.clone()))
+    }
+    ///Annotates the devices given by `request`, or the subscribed devices, and sends the annotation to everyone using them.
+    pub(super) async fn annotate(&self, device_list: &DeviceList, aliases: &AliasRegistry, request: AnnotationRequest) -> Result<Arc<Annotation>, CommandError> {
+        let names: Vec<String> = match request.devices() {
+            [] if !self.is_subscribed() => return Err(CommandError::new(ErrorCode::NotSubscribed, "Subscribe to a device or give the devices to annotate")),
+            [] => self.devices.clone(),
+            names => names.to_vec(),
+        };
+        let mut devices = Vec::with_capacity(names.len());
+        let mut serials = Vec::with_capacity(names.len());
+        for name in &names {
+            let device = device_list.find(name, aliases).await
+                .ok_or_else(|| CommandError::new(ErrorCode::DeviceNotFound, format!("Device not found: {name}")))?;
+            let Some(id) = device.id().await else {
+                return Err(CommandError::new(ErrorCode::DeviceNotFound, format!("Device {name} didn't report its id yet")));
+            };
+            if !serials.contains(id.serial()) {
+                serials.push(id.serial().clone());
+                devices.push(device);
+            }
+        }
+        let annotation = Arc::new(request.into_annotation(serials, Some(self.metrics.id()))
+            .map_err(|err| CommandError::new(ErrorCode::InvalidRequest, err.to_string()))?);
+        for device in devices {
+            device.annotate(annotation.clone());
+        }
+        Ok(annotation)
+    }
+    ///Cuts frames around trigger points out of the stream of the device `source` (serial or alias, default the first subscribed device),
+    ///which are sent instead of the stream. The frames hold the samples at the rate of the devices, without decimation.
+    pub(super) async fn set_trigger(&mut self, device_list: &DeviceList, aliases: &AliasRegistry, source: Option<&str>, settings: TriggerSettings) -> Result<TriggerStatus, CommandError> {
+        if !self.is_subscribed() {
+            return Err(CommandError::new(ErrorCode::NotSubscribed, "Subscribe to a device before setting a trigger"));
+        }
+        let source = match source {
+            Some(name) => {
+                let serial = match device_list.find(name, aliases).await {
+                    Some(device) => device.id().await.map(|id| id.serial().clone()),
+                    None => None,
+                };
+                serial.and_then(|serial| self.devices.iter().position(|v| *v == serial))
+                    .ok_or_else(|| CommandError::new(ErrorCode::DeviceNotFound, format!("The trigger source {name} isn't subscribed")))?
+            },
+            None => 0,
+        };
+        validate_frame_size(&settings, self.backpressure)?;
+        let trigger = Trigger::new(settings)
+            .map_err(|err| CommandError::new(ErrorCode::InvalidRequest, err.to_string()))?;
+        self.trigger = Some((source, trigger));
+        self.frames.clear();
+        self.measure_data.clear();
+        self.restart_alignment();
+        self.trigger_status()
+    }
+    ///Returns to streaming.
+    pub(super) fn clear_trigger(&mut self) {
+        if self.trigger.take().is_some() {
+            self.restart_alignment();
+        }
+        self.frames.clear();
+    }
+    ///Waits for the next trigger, e.g. after a frame of [`crate::signal::trigger::TriggerMode::Single`].
+    pub(super) fn arm_trigger(&mut self) -> Result<TriggerStatus, CommandError> {
+        if let Some((_, trigger)) = &mut self.trigger {
+            trigger.arm();
+        }
+        self.trigger_status()
+    }
+    fn trigger_status(&self) -> Result<TriggerStatus, CommandError> {
+        let Some((source, trigger)) = &self.trigger else {
+            return Err(CommandError::new(ErrorCode::NoTrigger, "Set a trigger first"));
+        };
+        Ok(TriggerStatus {
+            source: self.devices[*source].clone(),
+            trigger: *trigger.settings(),
+            state: trigger.state(),
+            frames: trigger.frames(),
+        })
+    }
+    ///Takes the next complete frame of the trigger.
+    pub(super) fn take_frame(&mut self) -> Option<WSFrame> {
+        let frame = self.frames.pop_front()?;
+        self.metrics.set_queue_depth(self.frames.iter().map(|frame| frame.data.len()).sum());
+        self.metrics.add_message_sent();
+        Some(frame)
+    }
+    ///Takes the samples collected since the last call. Nothing is collected, while a trigger is set.
+    pub(super) fn take_measurement(&mut self) -> Option<WSMeasurement> {
+        if !self.streaming || self.trigger.is_some() {
+            return None;
+        }
+        let data = core::mem::replace(&mut self.measure_data, Vec::with_capacity(MAX_MESSAGE_BUF as usize));
+        //The client keeps up again, so give back some of the resolution.
+        if self.decimation > 1 && !self.decimation_raised && data.len() < self.backpressure.max_queued_samples / 4 {
+            self.set_decimation(self.decimation / 2);
+        }
+        self.decimation_raised = false;
+        self.metrics.set_queue_depth(0);
+        self.metrics.add_message_sent();
+        Some(WSMeasurement{
+            devices: self.devices.clone(),
+            data,
+        })
+    }
+
+    ///Answers a `get_downsampled_in_range` request from the history of the subscribed devices,
+    ///or from the capture file `recording`, if given.
+    pub(super) async fn downsampled_in_range(
+        &self,
+        recordings: &Recordings,
+        recording: Option<&str>,
+        tmin: chrono::DateTime<chrono::FixedOffset>,
+        tmax: chrono::DateTime<chrono::FixedOffset>,
+        desired: usize,
+        algorithm: DownsampleAlgorithm,
+    ) -> Result<WSMeasurement, CommandError> {
+        //Timestamps are in milliseconds, like in the live stream
+        let tmin = tmin.timestamp_micros() as f64 / 1000.;
+        let tmax = tmax.timestamp_micros() as f64 / 1000.;
+        if let Some(name) = recording {
+            let path = recordings.find(name).ok_or_else(|| CommandError::new(ErrorCode::RecordingNotFound, format!("Recording not found: {name}")))?;
+            let (uuids, downsampled) = read_downsampled(path, tmin, tmax, desired, algorithm).await
+                .map_err(|err| CommandError::new(ErrorCode::Internal, format!("error reading recording {name}: {err}")))?;
+            return Ok(align_downsampled(uuids, &downsampled, self.interpolation));
+        }
+        if self.subscribed.is_empty() {
+            return Err(CommandError::new(ErrorCode::NotSubscribed, "Subscribe to a device before requesting downsampled data"));
+        }
+        let mut uuids = Vec::with_capacity(self.subscribed.len());
+        let mut downsampled = Vec::with_capacity(self.subscribed.len());
+        for device in &self.subscribed {
+            let serial = match device.id().await {
+                Some(id) => id.serial().clone(),
+                None => continue,
+            };
+            let samples = device.history(tmin, tmax).await.samples();
+            uuids.push(serial);
+            downsampled.push(downsample(&samples, desired, algorithm));
+        }
+        Ok(align_downsampled(uuids, &downsampled, self.interpolation))
+    }
+
+    ///Stops forwarding and waits for the forwarding tasks. Returns the first panic of a task.
+    pub(super) async fn close(&mut self) -> Result<(), tokio::task::JoinError> {
+        self.forwarders.abort_all();
+        while let Some(join) = self.forwarders.join_next().await {
+            match join {
+                Ok(()) => {},
+                Err(err) if err.is_cancelled() => {},
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn subscription(&self, resampled: Vec<bool>) -> Subscription {
+        Subscription {
+            devices: self.devices.clone(),
+            sampling_rate: self.effective_rate(),
+            resampled,
+            format: self.format,
+            push: self.push,
+            backpressure: self.backpressure,
+            decimation: self.stages.clone(),
+            values: self.values,
+            calibrations: self.calibrations.clone(),
+            trigger: self.trigger_status().ok(),
+        }
+    }
+    ///The rate samples are sent at: the requested one, or else the highest rate of the devices, if known.
+    fn effective_rate(&self) -> Option<u32> {
+        self.sampling_rate.or_else(|| self.input_rates.iter().copied().filter(|&rate| rate != 0).max())
+    }
+    async fn find_subscribed<'a>(&self, device_list: &'a DeviceList, aliases: &AliasRegistry) -> Result<Vec<&'a Device>, CommandError> {
+        if !self.is_subscribed() {
+            return Err(CommandError::new(ErrorCode::NotSubscribed, "Subscribe to a device first"));
+        }
+        let mut devices = Vec::with_capacity(self.devices.len());
+        for serial in &self.devices {
+            match device_list.find(serial, aliases).await {
+                Some(device) => devices.push(device),
+                None => return Err(CommandError::new(ErrorCode::DeviceNotFound, format!("Device {serial} disappeared"))),
+            }
+        }
+        Ok(devices)
+    }
+    ///Asks every device to sample at `sampling_rate`.
+    ///Returns per device, whether its data has to be resampled, and the rate it samples at (0 if unknown).
+    async fn set_sample_rates(devices: &[&Device], sampling_rate: u32) -> (Vec<bool>, Vec<u32>) {
+        let mut resampled = Vec::with_capacity(devices.len());
+        let mut input_rates = Vec::with_capacity(devices.len());
+        for device in devices {
+            let id = match device.id().await {
+                Some(id) => id,
+                None => {
+                    resampled.push(true);
+                    input_rates.push(0);
+                    continue;
+                },
+            };
+            let confirmed = match device.set_sample_rate(&id, sampling_rate).await {
+                Ok(confirmed) => confirmed,
+                Err(err) => {
+                    eprintln!("error setting sample rate, resampling instead: {err}");
+                    false
+                }
+            };
+            if confirmed {
+                resampled.push(false);
+                input_rates.push(sampling_rate);
+                continue;
+            }
+            //The device may have changed its rate anyway, so resample from the rate it reports now.
+            let input_rate = device.id().await.map_or(id.sample_rate(), |id| id.sample_rate());
+            println!("Device {} didn't change its sample rate. Resampling from {input_rate} Sa/s to {sampling_rate} Sa/s", id.serial());
+            resampled.push(true);
+            input_rates.push(input_rate);
+        }
+        (resampled, input_rates)
+    }
+    ///Starts a new stream for the current devices and sample rate, discarding samples not sent yet.
+    fn restart_stream(&mut self) {
+        self.stages = self.input_rates.iter().map(|&input_rate| match self.sampling_rate {
+            //Every device streams at its own rate.
+            None => DecimationStage {
+                filter: DecimationFilter::None,
+                ratio: 1,
+                input_rate,
+                output_rate: input_rate,
+            },
+            Some(output_rate) => DecimationStage {
+                filter: self.filter,
+                //Without a filter, the resampler alone brings the samples to the requested rate.
+                ratio: match (self.filter, input_rate) {
+                    (DecimationFilter::None, _) | (_, 0) => 1,
+                    (_, input_rate) => (input_rate / output_rate).max(1) as usize,
+                },
+                input_rate,
+                output_rate,
+            },
+        }).collect();
+        self.build_decimators();
+        self.restart_alignment();
+        self.measure_data.clear();
+        self.decimation_raised = false;
+    }
+    ///Aligns the devices anew, at the rates of the decimators, or at the rates of the devices while a trigger is set.
+    ///Samples in the decimators and the trigger are lost.
+    fn restart_alignment(&mut self) {
+        let rates: Vec<u32> = match self.trigger {
+            Some(_) => self.input_rates.clone(),
+            None => self.stages.iter().map(|stage| stage.output_rate).collect(),
+        };
+        //The fastest device defines the common timeline, so no samples of it are lost.
+        let reference = rates.iter().enumerate().max_by_key(|(_, rate)| **rate).map_or(0, |(i, _)| i);
+        self.aligner = Aligner::new(self.devices.len(), reference, self.interpolation);
+        self.decimators.iter_mut().for_each(Decimator::reset);
+        self.resamplers.iter_mut().for_each(Resampler::reset);
+        if let Some((_, trigger)) = &mut self.trigger {
+            trigger.reset();
+        }
+    }
+    ///Builds the decimators and resamplers of the [`DecimationStage`]s, reducing the rate further by `self.decimation`.
+    fn build_decimators(&mut self) {
+        let decimation = self.decimation;
+        (self.decimators, self.resamplers) = if decimation == 1 && self.stages.iter().all(|stage| stage.ratio == 1 && stage.output_rate == stage.input_rate) {
+            (Vec::new(), Vec::new())
+        } else {
+            //If a device samples at the requested rate, its decimator and resampler are no-ops.
+            self.stages.iter().map(|stage| {
+                //Without a filter, the decimation of the backpressure averages like the default filter.
+                let filter = if stage.filter == DecimationFilter::None { DecimationFilter::default() } else { stage.filter };
+                (Decimator::new(filter, stage.ratio * decimation), Resampler::new(f64::from(stage.output_rate) / decimation as f64))
+            }).unzip()
+        };
+    }
+    ///Changes the extra decimation of [`BackpressurePolicy::Decimate`]. Samples in the decimators are lost, so the next row is a gap.
+    fn set_decimation(&mut self, decimation: usize) {
+        self.decimation = decimation;
+        self.metrics.set_decimation(decimation);
+        self.build_decimators();
+        for i in 0..self.devices.len() {
+            self.aligner.mark_gap(i);
+        }
+    }
+}
+
+///Checks that a frame of the trigger `settings` fits into the queue of `backpressure`.
+fn validate_frame_size(settings: &TriggerSettings, backpressure: Backpressure) -> Result<(), CommandError> {
+    let samples = settings.pre_samples.saturating_add(settings.post_samples);
+    if samples > backpressure.max_queued_samples {
+        return Err(CommandError::new(ErrorCode::InvalidRequest, format!("A frame of {samples} samples exceeds max_queued_samples of {}", backpressure.max_queued_samples)));
+    }
+    Ok(())
+}
+
+fn validate_sample_rate(sampling_rate: u32) -> Result<(), CommandError> {
+    if (signal::MIN_SAMPLE_RATE..=signal::MAX_SAMPLE_RATE).contains(&sampling_rate) {
+        Ok(())
+    } else {
+        Err(CommandError::new(ErrorCode::InvalidSampleRate, format!("The sampling rate must be between {} and {} Sa/s, but was {sampling_rate} Sa/s", signal::MIN_SAMPLE_RATE, signal::MAX_SAMPLE_RATE)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use crate::device::clock::DeviceClock;
+    use crate::device::messages::MeasureData;
+    use crate::device::sequence::SequenceEvent;
+    use crate::routes::metrics::SessionRegistry;
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 1000;
+    const PACKET_LEN: usize = 50;
+
+    ///A session streaming a single device at its own rate, as if it had subscribed.
+    fn session(policy: BackpressurePolicy, max_queued_samples: usize) -> Session {
+        let mut session = Session::new(SessionRegistry::default().register());
+        session.devices = vec!["A1".to_string()];
+        session.input_rates = vec![SAMPLE_RATE];
+        session.sampling_rate = Some(SAMPLE_RATE);
+        session.backpressure = Backpressure { policy, max_queued_samples };
+        session.notifications = true;
+        session.restart_stream();
+        session.streaming = true;
+        session
+    }
+
+    ///Packets with the values `counter * PACKET_LEN + i`.
+    struct Packets {
+        clock: DeviceClock,
+        counter: u64,
+    }
+    impl Packets {
+        fn new() -> Self {
+            Self { clock: DeviceClock::new(), counter: 0 }
+        }
+        fn next(&mut self) -> Packet {
+            let counter = self.counter;
+            self.counter += 1;
+            let arrival = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000) + Duration::from_millis((counter + 1) * PACKET_LEN as u64);
+            let timestamps = self.clock.timestamps(Some(SAMPLE_RATE), counter, PACKET_LEN, arrival);
+            let data = (0..PACKET_LEN).map(|i| (counter as usize * PACKET_LEN + i) as u16).collect();
+            let sequence = if counter == 0 { SequenceEvent::First } else { SequenceEvent::InOrder };
+            Packet::new(MeasureData::new(counter as u32, data), sequence, counter, timestamps)
+        }
+    }
+
+    fn values(measurement: &WSMeasurement) -> Vec<u16> {
+        measurement.data.iter().map(|sample| sample.value[0]).collect()
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let mut session = session(BackpressurePolicy::DropOldest, 100);
+        let mut packets = Packets::new();
+        for _ in 0..3 {
+            session.push(0, &packets.next()).unwrap();
+        }
+        let measurement = session.take_measurement().unwrap();
+        assert_eq!(values(&measurement), (50..150).collect::<Vec<_>>());
+        assert!(measurement.data[0].gap);
+        assert!(!measurement.data[1].gap);
+        let notification = serde_json::to_value(session.take_notification().unwrap()).unwrap();
+        assert_eq!(notification["type"], "data_dropped");
+        assert_eq!(notification["samples"], 50);
+        assert!(session.take_notification().is_none());
+    }
+
+    #[test]
+    fn disconnect() {
+        let mut session = session(BackpressurePolicy::Disconnect, 100);
+        let mut packets = Packets::new();
+        session.push(0, &packets.next()).unwrap();
+        session.push(0, &packets.next()).unwrap();
+        let err = session.push(0, &packets.next()).unwrap_err();
+        assert_eq!(err.code, ErrorCode::SlowConsumer);
+        assert!(session.lagged(0, 1).is_err());
+    }
+
+    #[test]
+    fn decimate() {
+        let mut session = session(BackpressurePolicy::Decimate, 100);
+        let mut packets = Packets::new();
+        for _ in 0..3 {
+            session.push(0, &packets.next()).unwrap();
+        }
+        //Halved once, the oldest samples are dropped like with drop_oldest.
+        assert_eq!(session.decimation, 2);
+        assert_eq!(session.measure_data.len(), 100);
+        //Still in the pipeline at the previous rate, so the rate isn't halved again before the client took the samples.
+        session.push(0, &packets.next()).unwrap();
+        assert_eq!(session.decimation, 2);
+        let measurement = session.take_measurement().unwrap();
+        assert_eq!(measurement.data.len(), 100);
+        //After a change of the rate, the stream continues with a gap at half the rate.
+        let change = measurement.data.iter().rposition(|sample| sample.gap).unwrap();
+        let decimated = &measurement.data[change..];
+        assert_eq!(decimated.len(), 25);
+        assert!(decimated.windows(2).all(|v| (v[1].timestamp - v[0].timestamp - 2.).abs() < 1e-6));
+        //The client keeps up again.
+        session.take_measurement().unwrap();
+        assert_eq!(session.decimation, 1);
+    }
+
+    #[test]
+    fn rebuilding_the_stream_resets_the_decimation() {
+        let mut session = session(BackpressurePolicy::Decimate, 100);
+        let mut packets = Packets::new();
+        for _ in 0..3 {
+            session.push(0, &packets.next()).unwrap();
+        }
+        assert!(session.decimation_raised);
+        session.restart_stream();
+        assert!(!session.decimation_raised);
+        session.unsubscribe();
+        assert!(!session.decimation_raised);
+        assert_eq!(session.decimation, 1);
+    }
+
+    #[test]
+    fn without_a_rate_the_device_rate_is_kept() {
+        let mut session = session(BackpressurePolicy::DropOldest, 10_000);
+        session.sampling_rate = None;
+        session.filter = DecimationFilter::default();
+        session.restart_stream();
+        let stage = session.stages[0];
+        assert_eq!(stage.filter, DecimationFilter::None);
+        assert_eq!((stage.ratio, stage.input_rate, stage.output_rate), (1, SAMPLE_RATE, SAMPLE_RATE));
+        assert!(session.decimators.is_empty());
+        assert_eq!(session.subscription(Vec::new()).sampling_rate, Some(SAMPLE_RATE));
+        let mut packets = Packets::new();
+        for _ in 0..2 {
+            session.push(0, &packets.next()).unwrap();
+        }
+        assert_eq!(values(&session.take_measurement().unwrap()), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn without_a_filter_the_requested_rate_is_resampled() {
+        let mut session = session(BackpressurePolicy::DropOldest, 10_000);
+        session.sampling_rate = Some(100);
+        session.filter = DecimationFilter::None;
+        session.restart_stream();
+        let stage = session.stages[0];
+        assert_eq!((stage.ratio, stage.input_rate, stage.output_rate), (1, SAMPLE_RATE, 100));
+        assert_eq!(session.subscription(Vec::new()).sampling_rate, Some(100));
+        let mut packets = Packets::new();
+        for _ in 0..20 {
+            session.push(0, &packets.next()).unwrap();
+        }
+        let measurement = session.take_measurement().unwrap();
+        assert_eq!(measurement.data.len(), 100);
+        assert!(measurement.data.windows(2).all(|v| (v[1].timestamp - v[0].timestamp - 10.).abs() < 1e-3));
+    }
+}
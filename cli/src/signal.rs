@@ -0,0 +1,11 @@
+//! Host-side processing of device sample streams.
+
+pub mod align;
+pub mod decimate;
+pub mod downsample;
+pub mod resample;
+pub mod trigger;
+
+///Sample rates clients may request, in Sa/s.
+pub const MIN_SAMPLE_RATE: u32 = 1;
+pub const MAX_SAMPLE_RATE: u32 = 100_000;
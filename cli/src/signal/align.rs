@@ -0,0 +1,265 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+///How long a sample may wait for a device that stopped delivering samples, measured on the wall-clock.
+///After that, the last known value of the device is held, and the row is marked as a gap.
+///If the reference device stopped, the samples of the other devices are dropped after that long.
+const MAX_ALIGNMENT_DELAY: Duration = Duration::from_secs(1);
+
+///How values of a device are estimated between two of its samples.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    ///Linear interpolation between the samples before and after.
+    #[default]
+    Linear,
+    ///The value of the sample before.
+    SampleAndHold,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    ///Host time in milliseconds since the UNIX epoch.
+    timestamp: f64,
+    ///Device time in seconds since the capture start, if known.
+    device_time: Option<f64>,
+    value: u16,
+    gap: bool,
+    ///When the sample was pushed.
+    received: Instant,
+}
+
+///Which reference samples are emitted, even though a device has no samples past them yet.
+#[derive(Debug, Clone, Copy)]
+enum Staleness {
+    ///None, as no sample can have waited long enough yet.
+    Never,
+    ///Those received before the instant. Rows holding the value of a stalled device are marked as gaps.
+    Before(Instant),
+    ///All, as no more samples will arrive.
+    Always,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Lane {
+    queue: VecDeque<Sample>,
+    ///The newest sample, which is already older than the timeline.
+    before: Option<Sample>,
+    pending_gap: bool,
+}
+
+///Aligns the sample streams of several devices onto a common timeline.
+///
+///The timeline consists of the sample timestamps of a reference device (usually the one with the highest sample rate).
+///For every reference sample, the values of all other devices are interpolated at the reference timestamp.
+///Rows are only emitted, once every device has delivered samples past the reference timestamp,
+///or the reference sample waited for longer than [`MAX_ALIGNMENT_DELAY`].
+///Samples of other devices, which waited that long while the reference device delivered nothing, are dropped,
+///and the next row containing samples of that device is marked as a gap.
+#[derive(Debug, Clone)]
+pub struct Aligner {
+    lanes: Vec<Lane>,
+    reference: usize,
+    interpolation: Interpolation,
+}
+impl Aligner {
+    pub fn new(devices: usize, reference: usize, interpolation: Interpolation) -> Self {
+        Self {
+            lanes: vec![Lane::default(); devices],
+            reference: reference.min(devices.saturating_sub(1)),
+            interpolation,
+        }
+    }
+    ///Marks a discontinuity in the stream of `device`. The next row containing samples after it is marked as a gap.
+    pub fn mark_gap(&mut self, device: usize) {
+        if let Some(lane) = self.lanes.get_mut(device) {
+            lane.pending_gap = true;
+        }
+    }
+    ///Adds a sample of `device`, with its host timestamp in milliseconds and its device time in seconds.
+    pub fn push(&mut self, device: usize, timestamp: f64, device_time: Option<f64>, value: u16) {
+        if let Some(lane) = self.lanes.get_mut(device) {
+            let gap = core::mem::take(&mut lane.pending_gap);
+            lane.queue.push_back(Sample { timestamp, device_time, value, gap, received: Instant::now() });
+        }
+    }
+    ///Emits all rows, which can be aligned with the samples received so far.
+    ///`output` gets the timestamp, the device time of the reference device, one value per device and whether the row follows a gap.
+    pub fn drain(&mut self, output: impl FnMut(f64, Option<f64>, Vec<u16>, bool)) {
+        self.drain_at(Instant::now(), output);
+    }
+    ///Emits all remaining rows, holding the last known value of devices without newer samples.
+    pub fn finish(&mut self, output: impl FnMut(f64, Option<f64>, Vec<u16>, bool)) {
+        self.emit(Staleness::Always, output);
+    }
+
+    fn drain_at(&mut self, now: Instant, output: impl FnMut(f64, Option<f64>, Vec<u16>, bool)) {
+        //Shortly after boot, nothing can have waited long enough.
+        let Some(stale_before) = now.checked_sub(MAX_ALIGNMENT_DELAY) else {
+            self.emit(Staleness::Never, output);
+            return;
+        };
+        self.emit(Staleness::Before(stale_before), output);
+        if self.lanes.get(self.reference).is_some_and(|lane| lane.queue.is_empty()) {
+            self.drop_stale(stale_before);
+        }
+    }
+
+    ///Emits rows for reference samples, until a device is missing data for a reference sample, which isn't stale.
+    fn emit(&mut self, staleness: Staleness, mut output: impl FnMut(f64, Option<f64>, Vec<u16>, bool)) {
+        let devices = self.lanes.len();
+        while let Some(reference) = self.lanes.get(self.reference).and_then(|lane| lane.queue.front()).copied() {
+            let timestamp = reference.timestamp;
+            let stale = match staleness {
+                Staleness::Never => false,
+                Staleness::Before(stale_before) => reference.received < stale_before,
+                Staleness::Always => true,
+            };
+            let mut values = vec![0; devices];
+            let mut gap = reference.gap;
+            let mut ready = true;
+            for (i, lane) in self.lanes.iter_mut().enumerate() {
+                if i == self.reference {
+                    values[i] = reference.value;
+                    continue;
+                }
+                while let Some(sample) = lane.queue.front().filter(|v| v.timestamp <= timestamp).copied() {
+                    gap |= sample.gap;
+                    lane.before = Some(sample);
+                    lane.queue.pop_front();
+                }
+                values[i] = match (lane.before, lane.queue.front()) {
+                    (Some(before), Some(after)) => match self.interpolation {
+                        Interpolation::SampleAndHold => before.value,
+                        Interpolation::Linear => {
+                            let span = after.timestamp - before.timestamp;
+                            let position = if span > 0. { (timestamp - before.timestamp) / span } else { 0. };
+                            (f64::from(before.value) + (f64::from(after.value) - f64::from(before.value)) * position).round() as u16
+                        },
+                    },
+                    (Some(before), None) if stale => {
+                        gap |= matches!(staleness, Staleness::Before(_)) && before.timestamp < timestamp;
+                        before.value
+                    },
+                    //The device started later than the reference. Extend its first sample backwards.
+                    (None, Some(after)) => after.value,
+                    //The device hasn't delivered anything yet.
+                    (None, None) if stale => {
+                        gap = true;
+                        0
+                    },
+                    (Some(_) | None, None) => {
+                        ready = false;
+                        break;
+                    },
+                };
+            }
+            if !ready {
+                break;
+            }
+            if let Some(lane) = self.lanes.get_mut(self.reference) {
+                lane.queue.pop_front();
+            }
+            output(timestamp, reference.device_time, values, gap);
+        }
+    }
+    ///Drops the samples of the other devices received before `stale_before`, as no reference sample will use them.
+    fn drop_stale(&mut self, stale_before: Instant) {
+        let reference = self.reference;
+        for (_, lane) in self.lanes.iter_mut().enumerate().filter(|(i, _)| *i != reference) {
+            let mut dropped = false;
+            while let Some(sample) = lane.queue.front().filter(|v| v.received < stale_before).copied() {
+                lane.before = Some(sample);
+                lane.queue.pop_front();
+                dropped = true;
+            }
+            if dropped {
+                match lane.queue.front_mut() {
+                    Some(next) => next.gap = true,
+                    None => lane.pending_gap = true,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Row = (f64, Vec<u16>, bool);
+
+    fn drain_at(aligner: &mut Aligner, now: Instant) -> Vec<Row> {
+        let mut rows = Vec::new();
+        aligner.drain_at(now, |timestamp, _, values, gap| rows.push((timestamp, values, gap)));
+        rows
+    }
+
+    ///Device 0 samples every millisecond, device 1 every 5 milliseconds, both with the value `10 * t`.
+    fn two_rates(aligner: &mut Aligner, until: u16) {
+        for t in 0..=until {
+            aligner.push(0, f64::from(t), None, 10 * t);
+            if t % 5 == 0 {
+                aligner.push(1, f64::from(t), None, 10 * t);
+            }
+        }
+    }
+
+    #[test]
+    fn aligns_devices_at_different_rates() {
+        let mut aligner = Aligner::new(2, 0, Interpolation::Linear);
+        two_rates(&mut aligner, 10);
+        let rows = drain_at(&mut aligner, Instant::now());
+        //The row at 10ms waits for the next sample of device 1.
+        let expected: Vec<Row> = (0..10).map(|t| (f64::from(t), vec![10 * t, 10 * t], false)).collect();
+        assert_eq!(rows, expected);
+
+        let mut aligner = Aligner::new(2, 0, Interpolation::SampleAndHold);
+        two_rates(&mut aligner, 10);
+        let rows = drain_at(&mut aligner, Instant::now());
+        let expected: Vec<Row> = (0..10).map(|t| (f64::from(t), vec![10 * t, 10 * (t - t % 5)], false)).collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn stalled_device_becomes_a_gap() {
+        let mut aligner = Aligner::new(2, 0, Interpolation::Linear);
+        two_rates(&mut aligner, 5);
+        for t in 6..10 {
+            aligner.push(0, f64::from(t), None, 10 * t);
+        }
+        let now = Instant::now();
+        assert_eq!(drain_at(&mut aligner, now).len(), 5);
+        assert!(drain_at(&mut aligner, now + MAX_ALIGNMENT_DELAY / 2).is_empty());
+        //Device 1 stopped at 5ms, its last value is held.
+        let rows = drain_at(&mut aligner, now + MAX_ALIGNMENT_DELAY * 2);
+        let expected: Vec<Row> = (5..10).map(|t| (f64::from(t), vec![10 * t, 50], t > 5)).collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn stalled_reference_drops_samples() {
+        let mut aligner = Aligner::new(2, 0, Interpolation::Linear);
+        two_rates(&mut aligner, 0);
+        aligner.push(1, 5., None, 50);
+        aligner.push(1, 10., None, 100);
+        let now = Instant::now();
+        assert_eq!(drain_at(&mut aligner, now), [(0., vec![0, 0], false)]);
+        //The reference device delivered nothing for too long, so the samples of device 1 are dropped.
+        assert!(drain_at(&mut aligner, now + MAX_ALIGNMENT_DELAY * 2).is_empty());
+        aligner.push(0, 20., None, 200);
+        aligner.push(1, 20., None, 200);
+        aligner.push(1, 25., None, 250);
+        assert_eq!(drain_at(&mut aligner, Instant::now()), [(20., vec![200, 200], true)]);
+    }
+
+    #[test]
+    fn finish_holds_without_gaps() {
+        let mut aligner = Aligner::new(2, 0, Interpolation::Linear);
+        two_rates(&mut aligner, 7);
+        let mut rows = Vec::new();
+        aligner.finish(|timestamp, _, values, gap| rows.push((timestamp, values, gap)));
+        assert_eq!(rows.len(), 8);
+        assert_eq!(rows[7], (7., vec![70, 50], false));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::device::clock::PacketTimestamps;
+
+///Longest low-pass filter [`DecimationFilter::Fir`] uses. Longer filters would cost too much per sample at high ratios.
+const MAX_FIR_TAPS: usize = 1025;
+///Taps of [`DecimationFilter::Fir`] per unit of decimation ratio.
+const FIR_TAPS_PER_RATIO: usize = 8;
+///Cutoff of [`DecimationFilter::Fir`], relative to the output Nyquist frequency.
+const FIR_CUTOFF: f64 = 0.9;
+const CIC_STAGES: usize = 3;
+
+///How [`Decimator`] reduces the sample rate by an integer ratio `n`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecimationFilter {
+    ///No filter. Every sample is kept at the sample rate of the device, unless a sample rate is requested,
+    ///which is then produced by resampling alone.
+    None,
+    ///The first of every `n` samples, without any anti-aliasing.
+    KeepEveryN,
+    ///The mean of every `n` samples.
+    #[default]
+    Boxcar,
+    ///The minimum and the maximum of every `2n` samples in chronological order, so peaks survive.
+    MinMax,
+    ///A three stage cascaded integrator-comb filter. Cheap at any ratio, with better stopband attenuation than boxcar.
+    Cic,
+    ///A Blackman windowed-sinc low-pass filter. The sharpest, but the most expensive filter.
+    Fir,
+}
+
+///Reduces the sample rate of a stream by an integer ratio with a [`DecimationFilter`].
+///
+///The output samples have a constant interval of `ratio` input samples.
+///Filters with a delay have their output timestamps corrected by the group delay.
+#[derive(Debug, Clone)]
+pub struct Decimator {
+    filter: DecimationFilter,
+    ratio: usize,
+    ///Input samples since the last output.
+    phase: usize,
+    sum: u64,
+    ///Minimum and maximum of the current [`DecimationFilter::MinMax`] block, with their position in the block.
+    min: Option<(usize, u16)>,
+    max: Option<(usize, u16)>,
+    integrators: [i128; CIC_STAGES],
+    combs: [i128; CIC_STAGES],
+    taps: Vec<f64>,
+    ///The last `taps.len()` input samples, as a ring buffer starting at `position`.
+    history: Vec<f64>,
+    position: usize,
+    ///Whether `history` holds samples of the current stream. Otherwise it is filled with the next sample,
+    ///so the filter doesn't ramp up from zero.
+    primed: bool,
+}
+impl Decimator {
+    ///A decimator by `ratio`. A ratio of 0 is treated as 1.
+    pub fn new(filter: DecimationFilter, ratio: usize) -> Self {
+        let ratio = ratio.max(1);
+        let taps = match filter {
+            DecimationFilter::Fir if ratio > 1 => low_pass((ratio * FIR_TAPS_PER_RATIO + 1).min(MAX_FIR_TAPS), FIR_CUTOFF * 0.5 / ratio as f64),
+            _ => Vec::new(),
+        };
+        Self {
+            filter,
+            ratio,
+            phase: 0,
+            sum: 0,
+            min: None,
+            max: None,
+            integrators: [0; CIC_STAGES],
+            combs: [0; CIC_STAGES],
+            history: vec![0.; taps.len()],
+            taps,
+            position: 0,
+            primed: false,
+        }
+    }
+    ///Input samples per output sample.
+    pub const fn ratio(&self) -> usize {
+        match self.filter {
+            DecimationFilter::None => 1,
+            _ => self.ratio,
+        }
+    }
+    ///Discards all partially processed samples, e.g. because the input stream has a gap.
+    pub fn reset(&mut self) {
+        self.phase = 0;
+        self.sum = 0;
+        self.min = None;
+        self.max = None;
+        self.integrators = [0; CIC_STAGES];
+        self.combs = [0; CIC_STAGES];
+        self.primed = false;
+    }
+
+    ///Feeds the samples of a packet into the decimator. Produced samples are passed to `output` with their host timestamp.
+    pub fn push(&mut self, timestamps: &PacketTimestamps, data: &[u16], mut output: impl FnMut(f64, u16)) {
+        let ratio = self.ratio();
+        if ratio == 1 {
+            for (i, value) in data.iter().enumerate() {
+                output(timestamps.host_at(i), *value);
+            }
+            return;
+        }
+        //Host time `delay` input samples before the `i`th sample of the packet.
+        let at = |i: usize, delay: f64| timestamps.host() + (i as f64 - delay) * timestamps.host_interval();
+        for (i, value) in data.iter().enumerate() {
+            let phase = self.phase;
+            self.phase = (self.phase + 1) % match self.filter {
+                DecimationFilter::MinMax => 2 * ratio,
+                _ => ratio,
+            };
+            match self.filter {
+                DecimationFilter::None => output(at(i, 0.), *value),
+                DecimationFilter::KeepEveryN => {
+                    if phase == 0 {
+                        output(at(i, 0.), *value);
+                    }
+                },
+                DecimationFilter::Boxcar => {
+                    self.sum += u64::from(*value);
+                    if self.phase == 0 {
+                        output(at(i, (ratio - 1) as f64 / 2.), (self.sum as f64 / ratio as f64).round() as u16);
+                        self.sum = 0;
+                    }
+                },
+                DecimationFilter::MinMax => {
+                    if self.min.is_none_or(|(_, min)| *value < min) {
+                        self.min = Some((phase, *value));
+                    }
+                    if self.max.is_none_or(|(_, max)| *value > max) {
+                        self.max = Some((phase, *value));
+                    }
+                    if self.phase == 0 && let (Some(min), Some(max)) = (self.min.take(), self.max.take()) {
+                        let (first, second) = if min.0 <= max.0 { (min, max) } else { (max, min) };
+                        let start = (2 * ratio - 1) as f64;
+                        output(at(i, start), first.1);
+                        output(at(i, start - ratio as f64), second.1);
+                    }
+                },
+                DecimationFilter::Cic => {
+                    let mut value = i128::from(*value);
+                    for integrator in &mut self.integrators {
+                        *integrator = integrator.wrapping_add(value);
+                        value = *integrator;
+                    }
+                    if self.phase == 0 {
+                        for comb in &mut self.combs {
+                            let previous = core::mem::replace(comb, value);
+                            value = value.wrapping_sub(previous);
+                        }
+                        let gain = (ratio as f64).powi(CIC_STAGES as i32);
+                        let delay = CIC_STAGES as f64 * (ratio - 1) as f64 / 2.;
+                        output(at(i, delay), (value as f64 / gain).round().clamp(0., f64::from(u16::MAX)) as u16);
+                    }
+                },
+                DecimationFilter::Fir => {
+                    if !core::mem::replace(&mut self.primed, true) {
+                        self.history.fill(f64::from(*value));
+                    }
+                    self.history[self.position] = f64::from(*value);
+                    self.position = (self.position + 1) % self.history.len();
+                    if self.phase == 0 {
+                        //The oldest sample is at `position`, and meets the last tap.
+                        let (newest, oldest) = self.history.split_at(self.position);
+                        let value: f64 = oldest.iter().chain(newest).zip(self.taps.iter().rev()).map(|(x, tap)| x * tap).sum();
+                        let delay = (self.taps.len() - 1) as f64 / 2.;
+                        output(at(i, delay), value.round().clamp(0., f64::from(u16::MAX)) as u16);
+                    }
+                },
+            }
+        }
+    }
+}
+
+///A Blackman windowed-sinc low-pass filter with `len` taps, a cutoff of `cutoff` cycles per sample and unity gain.
+fn low_pass(len: usize, cutoff: f64) -> Vec<f64> {
+    use core::f64::consts::PI;
+    let middle = (len - 1) as f64 / 2.;
+    let mut taps: Vec<f64> = (0..len).map(|i| {
+        let x = i as f64 - middle;
+        let sinc = if x == 0. { 2. * cutoff } else { (2. * PI * cutoff * x).sin() / (PI * x) };
+        let window = 0.42 - 0.5 * (2. * PI * i as f64 / (len - 1) as f64).cos() + 0.08 * (4. * PI * i as f64 / (len - 1) as f64).cos();
+        sinc * window
+    }).collect();
+    let sum: f64 = taps.iter().sum();
+    taps.iter_mut().for_each(|tap| *tap /= sum);
+    taps
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use crate::device::clock::DeviceClock;
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 1000;
+    const PACKET_LEN: usize = 100;
+
+    ///Decimates `values` sampled at 1 kHz in packets, and returns the output samples with their timestamps in ms since the first input sample.
+    fn decimate(filter: DecimationFilter, ratio: usize, values: &[u16]) -> Vec<(f64, u16)> {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut clock = DeviceClock::new();
+        let mut decimator = Decimator::new(filter, ratio);
+        let mut first = None;
+        let mut out = Vec::new();
+        for (counter, packet) in values.chunks(PACKET_LEN).enumerate() {
+            let arrival = start + Duration::from_millis(((counter + 1) * PACKET_LEN) as u64);
+            let timestamps = clock.timestamps(Some(SAMPLE_RATE), counter as u64, packet.len(), arrival);
+            let first = *first.get_or_insert(timestamps.host());
+            decimator.push(&timestamps, packet, |timestamp, value| out.push((timestamp - first, value)));
+        }
+        out
+    }
+
+    ///The timestamps of linear phase filters are corrected by their group delay, so the output of a ramp lies on the ramp.
+    #[test]
+    fn group_delay() {
+        let ramp: Vec<u16> = (0..20_000).collect();
+        for filter in [DecimationFilter::KeepEveryN, DecimationFilter::Boxcar, DecimationFilter::Cic, DecimationFilter::Fir] {
+            for ratio in [2, 5, 16] {
+                let out = decimate(filter, ratio, &ramp);
+                assert_eq!(out.len(), ramp.len() / ratio, "{filter:?} by {ratio}");
+                //The filters need a moment to settle after the start.
+                for (timestamp, value) in &out[out.len() / 4..] {
+                    assert!((timestamp - f64::from(*value)).abs() <= 1., "{filter:?} by {ratio} put {value} at {timestamp} ms");
+                }
+                let interval = out[1].0 - out[0].0;
+                assert!((interval - ratio as f64).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_dc() {
+        let constant = vec![1234; 5000];
+        for filter in [DecimationFilter::Boxcar, DecimationFilter::Cic, DecimationFilter::Fir] {
+            let out = decimate(filter, 8, &constant);
+            assert!(out[out.len() / 4..].iter().all(|(_, value)| *value == 1234), "{filter:?}");
+        }
+    }
+
+    ///A tone above the Nyquist frequency of the output aliases through boxcar, but is removed by FIR and mostly by CIC.
+    #[test]
+    fn attenuates_aliases() {
+        let ratio = 8;
+        //A period of 7 samples passes the mean of 8 samples with an eighth of its amplitude.
+        let tone: Vec<u16> = (0..20_000).map(|i| (30_000. + 10_000. * (2. * core::f64::consts::PI * f64::from(i) / 7.).sin()).round() as u16).collect();
+        let ripple = |filter| {
+            let out = decimate(filter, ratio, &tone);
+            out[out.len() / 4..].iter().map(|(_, value)| (f64::from(*value) - 30_000.).abs()).fold(0., f64::max)
+        };
+        assert!(ripple(DecimationFilter::Boxcar) > 1000.);
+        assert!(ripple(DecimationFilter::Cic) < 100.);
+        assert!(ripple(DecimationFilter::Fir) < 10.);
+    }
+
+    ///Min-max keeps the extremes of every `2n` samples in the order they occurred.
+    #[test]
+    fn min_max() {
+        let values = [5, 1, 9, 3, 9, 5, 5, 1, 4, 4, 4, 4];
+        let out = decimate(DecimationFilter::MinMax, 2, &values);
+        assert_eq!(out.iter().map(|(_, value)| *value).collect::<Vec<_>>(), [1, 9, 9, 1, 4, 4]);
+        assert_eq!(out.iter().map(|(timestamp, _)| *timestamp).collect::<Vec<_>>(), [0., 2., 4., 6., 8., 10.]);
+    }
+}
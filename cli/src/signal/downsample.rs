@@ -0,0 +1,205 @@
+use crate::device::history::HistorySample;
+
+///How [`downsample`] reduces the number of samples.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownsampleAlgorithm {
+    ///The minimum and maximum of every bucket, so peaks survive and the envelope of the signal is preserved.
+    #[default]
+    MinMax,
+    ///Largest-Triangle-Three-Buckets, which picks the samples that preserve the visual shape best.
+    Lttb,
+    ///The mean of every bucket.
+    Average,
+}
+
+///Reduces `samples` to at most `desired` samples.
+///
+///Samples are expected in chronological order. If there are at most `desired` samples, they are returned unchanged.
+///A bucket containing a gap marks its first output sample as a gap.
+pub fn downsample(samples: &[HistorySample], desired: usize, algorithm: DownsampleAlgorithm) -> Vec<HistorySample> {
+    if samples.len() <= desired {
+        return samples.to_vec();
+    }
+    if desired == 0 {
+        return Vec::new();
+    }
+    match algorithm {
+        DownsampleAlgorithm::MinMax => min_max(samples, desired),
+        DownsampleAlgorithm::Lttb => lttb(samples, desired),
+        DownsampleAlgorithm::Average => average(samples, desired),
+    }
+}
+
+///Splits `samples` into `buckets` buckets of (almost) equal size.
+fn buckets(samples: &[HistorySample], buckets: usize) -> impl Iterator<Item = &[HistorySample]> {
+    let len = samples.len();
+    (0..buckets).map(move |i| &samples[i * len / buckets..(i + 1) * len / buckets])
+}
+
+fn min_max(samples: &[HistorySample], desired: usize) -> Vec<HistorySample> {
+    if desired < 2 {
+        return average(samples, desired);
+    }
+    let mut out = Vec::with_capacity(desired);
+    for bucket in buckets(samples, desired / 2) {
+        let gap = bucket.iter().any(HistorySample::gap);
+        let min = bucket.iter().min_by_key(|v| v.value());
+        let max = bucket.iter().max_by_key(|v| v.value());
+        let (first, second) = match (min, max) {
+            (Some(min), Some(max)) if min.timestamp() <= max.timestamp() => (min, max),
+            (Some(min), Some(max)) => (max, min),
+            _ => continue,
+        };
+        out.push(HistorySample::new(first.timestamp(), first.value(), gap));
+        if !core::ptr::eq(first, second) {
+            out.push(HistorySample::new(second.timestamp(), second.value(), false));
+        }
+    }
+    out
+}
+
+fn average(samples: &[HistorySample], desired: usize) -> Vec<HistorySample> {
+    buckets(samples, desired)
+        .filter(|bucket| !bucket.is_empty())
+        .map(|bucket| {
+            let len = bucket.len() as f64;
+            let timestamp = bucket.iter().map(HistorySample::timestamp).sum::<f64>() / len;
+            let value = bucket.iter().map(|v| f64::from(v.value())).sum::<f64>() / len;
+            HistorySample::new(timestamp, value.round() as u16, bucket.iter().any(HistorySample::gap))
+        })
+        .collect()
+}
+
+fn lttb(samples: &[HistorySample], desired: usize) -> Vec<HistorySample> {
+    if desired < 3 {
+        return average(samples, desired);
+    }
+    let (first, rest) = match samples.split_first() {
+        Some(v) => v,
+        None => return Vec::new(),
+    };
+    let (last, middle) = match rest.split_last() {
+        Some(v) => v,
+        None => return vec![*first],
+    };
+    let middle_buckets: Vec<_> = buckets(middle, desired - 2).collect();
+    let mut out = Vec::with_capacity(desired);
+    out.push(*first);
+    let mut previous = *first;
+    for (i, bucket) in middle_buckets.iter().enumerate() {
+        //The average of the next bucket is the third corner of the triangle.
+        let (next_timestamp, next_value) = match middle_buckets.get(i + 1).filter(|v| !v.is_empty()) {
+            Some(next) => {
+                let len = next.len() as f64;
+                (
+                    next.iter().map(HistorySample::timestamp).sum::<f64>() / len,
+                    next.iter().map(|v| f64::from(v.value())).sum::<f64>() / len,
+                )
+            },
+            None => (last.timestamp(), f64::from(last.value())),
+        };
+        let selected = bucket.iter().max_by(|a, b| {
+            let area = |v: &HistorySample| ((previous.timestamp() - next_timestamp) * (f64::from(v.value()) - f64::from(previous.value()))
+                - (previous.timestamp() - v.timestamp()) * (next_value - f64::from(previous.value()))).abs();
+            area(a).total_cmp(&area(b))
+        });
+        if let Some(selected) = selected {
+            let gap = bucket.iter().any(HistorySample::gap);
+            previous = *selected;
+            out.push(HistorySample::new(selected.timestamp(), selected.value(), gap));
+        }
+    }
+    out.push(*last);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(values: impl IntoIterator<Item = u16>) -> Vec<HistorySample> {
+        values.into_iter().enumerate().map(|(i, value)| HistorySample::new(i as f64, value, false)).collect()
+    }
+
+    fn points(samples: &[HistorySample]) -> Vec<(f64, u16)> {
+        samples.iter().map(|v| (v.timestamp(), v.value())).collect()
+    }
+
+    fn gaps(samples: &[HistorySample]) -> Vec<bool> {
+        samples.iter().map(HistorySample::gap).collect()
+    }
+
+    #[test]
+    fn min_max_keeps_the_extremes_in_order() {
+        let input = samples([5, 1, 9, 3, 8, 6, 5, 0, 4, 4]);
+        //Two buckets of five, as every bucket needs two outputs.
+        let out = downsample(&input, 5, DownsampleAlgorithm::MinMax);
+        assert_eq!(points(&out), [(1., 1), (2., 9), (5., 6), (7., 0)]);
+        let out = downsample(&input, 4, DownsampleAlgorithm::MinMax);
+        assert_eq!(points(&out), [(1., 1), (2., 9), (5., 6), (7., 0)]);
+    }
+
+    #[test]
+    fn min_max_marks_gaps() {
+        let mut input = samples([5, 1, 9, 3, 8, 6, 5, 0, 4, 4]);
+        input[6] = HistorySample::new(6., 5, true);
+        let out = downsample(&input, 4, DownsampleAlgorithm::MinMax);
+        assert_eq!(gaps(&out), [false, false, true, false]);
+    }
+
+    #[test]
+    fn average_of_every_bucket() {
+        let mut input = samples(0..10);
+        input[7] = HistorySample::new(7., 7, true);
+        let out = downsample(&input, 2, DownsampleAlgorithm::Average);
+        assert_eq!(points(&out), [(2., 2), (7., 7)]);
+        assert_eq!(gaps(&out), [false, true]);
+        //Three buckets of uneven size.
+        let out = downsample(&samples(0..10), 3, DownsampleAlgorithm::Average);
+        assert_eq!(points(&out), [(1., 1), (4., 4), (7.5, 8)]);
+    }
+
+    #[test]
+    fn passes_few_samples_through() {
+        let mut input = samples([1, 5, 2, 8]);
+        input[2] = HistorySample::new(2., 2, true);
+        for algorithm in [DownsampleAlgorithm::MinMax, DownsampleAlgorithm::Lttb, DownsampleAlgorithm::Average] {
+            let out = downsample(&input, 10, algorithm);
+            assert_eq!(points(&out), points(&input));
+            assert_eq!(gaps(&out), gaps(&input));
+            assert!(downsample(&input, 0, algorithm).is_empty());
+        }
+    }
+
+    #[test]
+    fn lttb_keeps_the_ends_and_the_shape() {
+        //A flat line with a single spike and a single dip.
+        let mut input = samples(std::iter::repeat_n(100, 1000));
+        input[300] = HistorySample::new(300., 4000, false);
+        input[700] = HistorySample::new(700., 0, false);
+        let out = downsample(&input, 20, DownsampleAlgorithm::Lttb);
+        let out = points(&out);
+        assert_eq!(out.len(), 20);
+        assert_eq!(out.first(), Some(&(0., 100)));
+        assert_eq!(out.last(), Some(&(999., 100)));
+        assert!(out.windows(2).all(|v| v[0].0 < v[1].0));
+        assert!(out.contains(&(300., 4000)));
+        assert!(out.contains(&(700., 0)));
+    }
+
+    #[test]
+    fn lttb_marks_gaps() {
+        let mut input = samples((0..1000).map(|i| (i % 50) as u16));
+        input[500] = HistorySample::new(500., input[500].value(), true);
+        let out = downsample(&input, 10, DownsampleAlgorithm::Lttb);
+        //The gap falls into the fifth of the eight middle buckets.
+        assert_eq!(out.iter().map(HistorySample::gap).collect::<Vec<_>>(), [false, false, false, false, false, true, false, false, false, false]);
+    }
+
+    #[test]
+    fn lttb_with_too_few_buckets_averages() {
+        let input = samples([1, 5, 2]);
+        assert_eq!(downsample(&input, 2, DownsampleAlgorithm::Lttb).len(), 2);
+    }
+}
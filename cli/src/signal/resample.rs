@@ -0,0 +1,162 @@
+use crate::device::clock::PacketTimestamps;
+
+///Converts a sample stream from the device sample rate to a requested output rate.
+///
+///When decimating, every output sample is the average of all input samples in its output period,
+///which acts as a boxcar anti-aliasing filter.
+///When interpolating, output samples are linearly interpolated between the two closest input samples.
+///The input rate is taken from the packet timestamps, so a device changing its sample rate is handled transparently.
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    ///Output samples per second. 0 passes the samples through.
+    output_rate: f64,
+    ///Position of the next output sample, in input samples since the start of the current output period
+    ///(when decimating), or since the previous input sample (when interpolating).
+    phase: f64,
+    sum: f64,
+    count: u32,
+    ///Host timestamp of the first input sample in the current output period.
+    period_start: Option<f64>,
+    ///Previous input sample and its host timestamp.
+    previous: Option<(f64, u16)>,
+}
+impl Resampler {
+    pub const fn new(output_rate: f64) -> Self {
+        Self {
+            output_rate,
+            phase: 0.,
+            sum: 0.,
+            count: 0,
+            period_start: None,
+            previous: None,
+        }
+    }
+    ///Discards all partially processed samples, e.g. because the input stream has a gap.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.output_rate);
+    }
+    ///Feeds the samples of a packet into the resampler. Produced samples are passed to `output` with their host timestamp.
+    pub fn push(&mut self, timestamps: &PacketTimestamps, data: &[u16], mut output: impl FnMut(f64, u16)) {
+        let interval = timestamps.device_interval();
+        if interval <= 0. || self.output_rate <= 0. {
+            //Without a known input rate there is nothing to resample against.
+            for (i, value) in data.iter().enumerate() {
+                output(timestamps.host_at(i), *value);
+            }
+            return;
+        }
+        //Input samples per output sample.
+        let ratio = 1. / (interval * self.output_rate);
+        if ratio >= 1. {
+            self.decimate(ratio, timestamps, data, output);
+        } else {
+            self.interpolate(ratio, timestamps, data, output);
+        }
+    }
+
+    fn decimate(&mut self, ratio: f64, timestamps: &PacketTimestamps, data: &[u16], mut output: impl FnMut(f64, u16)) {
+        for (i, value) in data.iter().enumerate() {
+            let start = *self.period_start.get_or_insert_with(|| timestamps.host_at(i));
+            self.sum += f64::from(*value);
+            self.count += 1;
+            self.phase += 1.;
+            if self.phase >= ratio {
+                output(start, (self.sum / f64::from(self.count)).round() as u16);
+                self.phase -= ratio;
+                self.sum = 0.;
+                self.count = 0;
+                self.period_start = None;
+            }
+        }
+    }
+
+    fn interpolate(&mut self, ratio: f64, timestamps: &PacketTimestamps, data: &[u16], mut output: impl FnMut(f64, u16)) {
+        for (i, value) in data.iter().enumerate() {
+            let current = (timestamps.host_at(i), *value);
+            let (previous_timestamp, previous_value) = match self.previous.replace(current) {
+                Some(v) => v,
+                None => {
+                    output(current.0, current.1);
+                    self.phase = ratio;
+                    continue;
+                }
+            };
+            let slope = f64::from(current.1) - f64::from(previous_value);
+            while self.phase < 1. {
+                let timestamp = previous_timestamp + (current.0 - previous_timestamp) * self.phase;
+                output(timestamp, (f64::from(previous_value) + slope * self.phase).round() as u16);
+                self.phase += ratio;
+            }
+            self.phase -= 1.;
+            if self.phase == 0. {
+                output(current.0, current.1);
+                self.phase = ratio;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use crate::device::clock::DeviceClock;
+    use super::*;
+
+    ///Timestamps of consecutive packets of `packet_len` samples at `sample_rate`, in ms since the first sample.
+    struct Packets {
+        clock: DeviceClock,
+        sample_rate: u32,
+        packet_len: usize,
+        counter: u64,
+        first: Option<f64>,
+    }
+    impl Packets {
+        fn new(sample_rate: u32, packet_len: usize) -> Self {
+            Self { clock: DeviceClock::new(), sample_rate, packet_len, counter: 0, first: None }
+        }
+        ///Pushes the next packet through `resampler` and returns its output.
+        fn push(&mut self, resampler: &mut Resampler, data: &[u16]) -> Vec<(f64, u16)> {
+            assert_eq!(data.len(), self.packet_len);
+            let seconds = (self.counter + 1) as f64 * self.packet_len as f64 / f64::from(self.sample_rate);
+            let arrival = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000) + Duration::from_secs_f64(seconds);
+            let timestamps = self.clock.timestamps(Some(self.sample_rate), self.counter, self.packet_len, arrival);
+            self.counter += 1;
+            let first = *self.first.get_or_insert(timestamps.host());
+            let mut out = Vec::new();
+            resampler.push(&timestamps, data, |timestamp, value| out.push((((timestamp - first) * 1000.).round() / 1000., value)));
+            out
+        }
+    }
+
+    #[test]
+    fn averages_when_decimating() {
+        let mut packets = Packets::new(1000, 7);
+        let mut resampler = Resampler::new(100.);
+        let ramp: Vec<u16> = (0..70).collect();
+        let out: Vec<_> = ramp.chunks(7).flat_map(|packet| packets.push(&mut resampler, packet)).collect();
+        //Every output is the mean of 10 samples, rounded half away from zero, at the time of the first of them.
+        let expected: Vec<_> = (0..7).map(|i| (f64::from(i) * 10., i * 10 + 5)).collect();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn interpolates_across_packets() {
+        let mut packets = Packets::new(100, 4);
+        let mut resampler = Resampler::new(400.);
+        let out: Vec<_> = [[0, 100, 200, 300], [400, 500, 600, 700]].iter().flat_map(|packet| packets.push(&mut resampler, packet)).collect();
+        //Every 2.5ms on the line through the samples, including between the last sample of the first packet and the first of the second.
+        let expected: Vec<_> = (0..=28).map(|i| (f64::from(i) * 2.5, (i * 25) as u16)).collect();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn reset_discards_the_partial_period() {
+        let mut packets = Packets::new(1000, 5);
+        let mut resampler = Resampler::new(100.);
+        assert!(packets.push(&mut resampler, &[1000; 5]).is_empty());
+        //A gap in the stream, the samples before it must not be mixed into the next output.
+        resampler.reset();
+        assert!(packets.push(&mut resampler, &[10; 5]).is_empty());
+        assert_eq!(packets.push(&mut resampler, &[20; 5]), [(5., 15)]);
+    }
+}
@@ -0,0 +1,466 @@
+//! A software trigger, which cuts frames around events out of a sample stream, like the trigger of an oscilloscope.
+//!
+//! The [`TriggerCondition`] is checked on one value per sample. A frame holds `pre_samples` samples before the
+//! sample, at which the trigger fired, and `post_samples` samples from that sample on.
+use std::collections::VecDeque;
+
+///Most samples of a frame, before and after the trigger point together.
+pub const MAX_FRAME_SAMPLES: usize = 1_000_000;
+
+const fn default_post_samples() -> usize {
+    1000
+}
+const fn default_auto_timeout_ms() -> f64 {
+    100.
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Slope {
+    #[default]
+    Rising,
+    Falling,
+    Either,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Polarity {
+    ///Pulses above the level.
+    #[default]
+    Positive,
+    ///Pulses below the level.
+    Negative,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowEvent {
+    #[default]
+    Enter,
+    Exit,
+}
+
+///When the trigger fires.
+#[derive(Debug, Clone, Copy, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TriggerCondition {
+    ///The value crosses `level` in the direction of `slope`.
+    ///Before, it has to be more than `hysteresis` away from `level` on the other side, so noise doesn't retrigger.
+    Edge {
+        level: f64,
+        #[serde(default)]
+        slope: Slope,
+        #[serde(default)]
+        hysteresis: f64,
+    },
+    ///The value is at or above `level`, or below it with `below`.
+    Level {
+        level: f64,
+        #[serde(default)]
+        below: bool,
+    },
+    ///The value enters or exits the range from `low` to `high`.
+    Window {
+        low: f64,
+        high: f64,
+        #[serde(default)]
+        event: WindowEvent,
+    },
+    ///A pulse beyond `level` ends, which lasted at least `min_ms` and at most `max_ms`.
+    ///The pulse ends, once the value is back by more than `hysteresis`.
+    PulseWidth {
+        level: f64,
+        #[serde(default)]
+        polarity: Polarity,
+        #[serde(default)]
+        hysteresis: f64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min_ms: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_ms: Option<f64>,
+    },
+}
+impl TriggerCondition {
+    fn validate(&self) -> anyhow::Result<()> {
+        match *self {
+            Self::Edge { level, hysteresis, .. } | Self::PulseWidth { level, hysteresis, .. } if !(level.is_finite() && hysteresis >= 0. && hysteresis.is_finite()) => {
+                anyhow::bail!("The level must be finite, and the hysteresis must be finite and not negative");
+            },
+            Self::Level { level, .. } if !level.is_finite() => anyhow::bail!("The level must be finite"),
+            Self::Window { low, high, .. } if !(low.is_finite() && high.is_finite() && low <= high) => {
+                anyhow::bail!("The window must be finite, and low must not be above high");
+            },
+            Self::PulseWidth { min_ms, max_ms, .. } if !(min_ms.is_none_or(|v| v >= 0.) && max_ms.is_none_or(|v| v >= 0.)) => {
+                anyhow::bail!("The pulse widths must not be negative");
+            },
+            Self::PulseWidth { min_ms: Some(min_ms), max_ms: Some(max_ms), .. } if min_ms > max_ms => {
+                anyhow::bail!("The minimum pulse width must not be above the maximum");
+            },
+            Self::Edge { .. } | Self::Level { .. } | Self::Window { .. } | Self::PulseWidth { .. } => Ok(()),
+        }
+    }
+}
+
+///What happens after a frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerMode {
+    ///The trigger stops after a frame, until it is armed again.
+    Single,
+    ///A frame is taken at every trigger.
+    #[default]
+    Normal,
+    ///Like [`Self::Normal`], but without a trigger for `auto_timeout_ms`, a frame is taken anyway, so the signal stays visible.
+    Auto,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct TriggerSettings {
+    #[serde(flatten)]
+    pub condition: TriggerCondition,
+    #[serde(default)]
+    pub mode: TriggerMode,
+    ///Samples before the trigger point in a frame. The trigger only fires, once that many samples were collected.
+    #[serde(default)]
+    pub pre_samples: usize,
+    ///Samples from the trigger point on in a frame, at least 1.
+    #[serde(default = "default_post_samples")]
+    pub post_samples: usize,
+    ///How long [`TriggerMode::Auto`] waits for a trigger, in ms of sample time.
+    #[serde(default = "default_auto_timeout_ms")]
+    pub auto_timeout_ms: f64,
+}
+impl TriggerSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.condition.validate()?;
+        if self.post_samples == 0 {
+            anyhow::bail!("A frame needs at least 1 sample after the trigger point");
+        }
+        if self.pre_samples.saturating_add(self.post_samples) > MAX_FRAME_SAMPLES {
+            anyhow::bail!("A frame can have at most {MAX_FRAME_SAMPLES} samples");
+        }
+        if !(self.auto_timeout_ms > 0. && self.auto_timeout_ms.is_finite()) {
+            anyhow::bail!("The auto timeout must be greater than 0");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerState {
+    ///Waiting for the trigger.
+    Armed,
+    ///Collecting the samples after the trigger point.
+    Triggered,
+    ///A [`TriggerMode::Single`] frame was taken.
+    Stopped,
+}
+
+///Samples around a trigger point.
+#[derive(Debug, Clone)]
+pub struct Frame<T> {
+    pub samples: Vec<T>,
+    ///Index of the sample in `samples`, at which the trigger fired.
+    pub trigger_index: usize,
+    pub trigger_timestamp: f64,
+    ///`false` for a frame [`TriggerMode::Auto`] took without a trigger.
+    pub triggered: bool,
+}
+
+///The state of a [`TriggerCondition`] between samples.
+#[derive(Debug, Clone, Copy, Default)]
+struct Detector {
+    ///The value was far enough below (`rising`) or above (`falling`) the level, to cross it next.
+    rising: bool,
+    falling: bool,
+    ///Whether the previous value was inside the window. `None` before the first value.
+    inside: Option<bool>,
+    ///Start of the current pulse.
+    pulse: Option<f64>,
+}
+impl Detector {
+    ///Feeds the value of the next sample, and returns `true`, if the condition fires at it.
+    fn push(&mut self, condition: &TriggerCondition, timestamp: f64, value: f64) -> bool {
+        match *condition {
+            TriggerCondition::Edge { level, slope, hysteresis } => {
+                let rising = self.rising && value >= level;
+                let falling = self.falling && value <= level;
+                if rising || value < level - hysteresis {
+                    self.rising = !rising;
+                }
+                if falling || value > level + hysteresis {
+                    self.falling = !falling;
+                }
+                match slope {
+                    Slope::Rising => rising,
+                    Slope::Falling => falling,
+                    Slope::Either => rising || falling,
+                }
+            },
+            TriggerCondition::Level { level, below } => (value < level) == below,
+            TriggerCondition::Window { low, high, event } => {
+                let inside = (low..=high).contains(&value);
+                let previous = self.inside.replace(inside);
+                match event {
+                    WindowEvent::Enter => previous == Some(false) && inside,
+                    WindowEvent::Exit => previous == Some(true) && !inside,
+                }
+            },
+            TriggerCondition::PulseWidth { level, polarity, hysteresis, min_ms, max_ms } => {
+                //Negative pulses are positive pulses of the negated signal.
+                let (value, level) = match polarity {
+                    Polarity::Positive => (value, level),
+                    Polarity::Negative => (-value, -level),
+                };
+                if value < level - hysteresis {
+                    self.rising = true;
+                    if let Some(start) = self.pulse.take() {
+                        let width = timestamp - start;
+                        return min_ms.is_none_or(|min| width >= min) && max_ms.is_none_or(|max| width <= max);
+                    }
+                } else if self.rising && value >= level {
+                    self.rising = false;
+                    self.pulse = Some(timestamp);
+                }
+                false
+            },
+        }
+    }
+}
+
+///Cuts [`Frame`]s of samples `T` out of a stream, according to [`TriggerSettings`].
+#[derive(Debug, Clone)]
+pub struct Trigger<T> {
+    settings: TriggerSettings,
+    detector: Detector,
+    state: TriggerState,
+    ///The last `pre_samples` samples, while armed.
+    pre: VecDeque<T>,
+    ///The frame being collected, while triggered.
+    frame: Vec<T>,
+    trigger_index: usize,
+    trigger_timestamp: f64,
+    triggered: bool,
+    ///Timestamp of the first sample since the trigger was armed, for [`TriggerMode::Auto`].
+    armed_at: Option<f64>,
+    ///Frames taken so far.
+    frames: u64,
+}
+impl<T: Clone> Trigger<T> {
+    pub fn new(settings: TriggerSettings) -> anyhow::Result<Self> {
+        settings.validate()?;
+        Ok(Self {
+            settings,
+            detector: Detector::default(),
+            state: TriggerState::Armed,
+            pre: VecDeque::with_capacity(settings.pre_samples),
+            frame: Vec::new(),
+            trigger_index: 0,
+            trigger_timestamp: 0.,
+            triggered: false,
+            armed_at: None,
+            frames: 0,
+        })
+    }
+    pub const fn settings(&self) -> &TriggerSettings { &self.settings }
+    pub const fn state(&self) -> TriggerState { self.state }
+    pub const fn frames(&self) -> u64 { self.frames }
+    ///Waits for the next trigger, discarding the samples collected so far.
+    pub fn arm(&mut self) {
+        self.state = TriggerState::Armed;
+        self.pre.clear();
+        self.frame.clear();
+        self.armed_at = None;
+    }
+    ///Forgets all samples, e.g. because the sample rate changed, and arms the trigger unless it is stopped.
+    pub fn reset(&mut self) {
+        self.detector = Detector::default();
+        if self.state != TriggerState::Stopped {
+            self.arm();
+        }
+    }
+    ///Feeds the next `sample` of the stream, whose trigger source has `value`. Returns a frame, once one is complete.
+    pub fn push(&mut self, timestamp: f64, value: f64, sample: T) -> Option<Frame<T>> {
+        let fired = self.detector.push(&self.settings.condition, timestamp, value);
+        match self.state {
+            TriggerState::Stopped => None,
+            TriggerState::Triggered => {
+                self.frame.push(sample);
+                self.complete()
+            },
+            TriggerState::Armed => {
+                let armed_at = *self.armed_at.get_or_insert(timestamp);
+                let filled = self.pre.len() >= self.settings.pre_samples;
+                let timed_out = self.settings.mode == TriggerMode::Auto && timestamp - armed_at >= self.settings.auto_timeout_ms;
+                if filled && (fired || timed_out) {
+                    self.frame = Vec::with_capacity(self.settings.pre_samples + self.settings.post_samples);
+                    self.frame.extend(self.pre.drain(..));
+                    self.trigger_index = self.frame.len();
+                    self.trigger_timestamp = timestamp;
+                    self.triggered = fired;
+                    self.frame.push(sample);
+                    self.state = TriggerState::Triggered;
+                    return self.complete();
+                }
+                if self.settings.pre_samples > 0 {
+                    if self.pre.len() == self.settings.pre_samples {
+                        self.pre.pop_front();
+                    }
+                    self.pre.push_back(sample);
+                }
+                None
+            },
+        }
+    }
+    ///Returns the frame, if all samples after the trigger point were collected, and rearms for the next one.
+    fn complete(&mut self) -> Option<Frame<T>> {
+        if self.frame.len() < self.trigger_index + self.settings.post_samples {
+            return None;
+        }
+        let samples = core::mem::take(&mut self.frame);
+        //The end of this frame is already the start of the next one.
+        let pre = samples.len().saturating_sub(self.settings.pre_samples);
+        self.pre.extend(samples[pre..].iter().cloned());
+        self.frames += 1;
+        self.armed_at = None;
+        self.state = match self.settings.mode {
+            TriggerMode::Single => TriggerState::Stopped,
+            TriggerMode::Normal | TriggerMode::Auto => TriggerState::Armed,
+        };
+        Some(Frame {
+            samples,
+            trigger_index: self.trigger_index,
+            trigger_timestamp: self.trigger_timestamp,
+            triggered: self.triggered,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edge(slope: Slope, hysteresis: f64) -> Trigger<usize> {
+        Trigger::new(TriggerSettings {
+            condition: TriggerCondition::Edge { level: 100., slope, hysteresis },
+            mode: TriggerMode::Normal,
+            pre_samples: 2,
+            post_samples: 3,
+            auto_timeout_ms: default_auto_timeout_ms(),
+        }).unwrap()
+    }
+
+    ///Feeds `values` at 1 ms intervals, and returns the index of the trigger point of every frame.
+    fn trigger_points(trigger: &mut Trigger<usize>, values: &[f64]) -> Vec<usize> {
+        values.iter().enumerate()
+            .filter_map(|(i, value)| trigger.push(i as f64, *value, i))
+            .map(|frame| {
+                assert_eq!(frame.samples.len(), 5);
+                assert!(frame.triggered);
+                frame.samples[frame.trigger_index]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn edge_with_hysteresis() {
+        //Noise around the level only crosses it again, once the value was more than the hysteresis below it.
+        let values = [80., 90., 101., 99., 102., 98., 101., 95., 103., 104., 85., 90., 110., 120., 130., 140., 150.];
+        assert_eq!(trigger_points(&mut edge(Slope::Rising, 10.), &values), [2, 12]);
+        //Without hysteresis, every crossing fires, once the frame before is complete.
+        assert_eq!(trigger_points(&mut edge(Slope::Rising, 0.), &values), [2, 6, 12]);
+        let values = [120., 115., 99., 101., 98., 105., 111., 90., 80., 70.];
+        assert_eq!(trigger_points(&mut edge(Slope::Falling, 10.), &values), [2, 7]);
+    }
+
+    #[test]
+    fn edge_waits_for_pre_samples() {
+        let values = [0., 200., 0., 0., 200., 200., 200., 200.];
+        assert_eq!(trigger_points(&mut edge(Slope::Rising, 0.), &values), [4]);
+    }
+
+    fn trigger(condition: TriggerCondition, mode: TriggerMode, pre_samples: usize, post_samples: usize) -> Trigger<usize> {
+        Trigger::new(TriggerSettings { condition, mode, pre_samples, post_samples, auto_timeout_ms: 5. }).unwrap()
+    }
+
+    ///Feeds `values` at 1 ms intervals, and returns the frames with the indices of their samples.
+    fn frames(trigger: &mut Trigger<usize>, values: &[f64]) -> Vec<Frame<usize>> {
+        values.iter().enumerate().filter_map(|(i, value)| trigger.push(i as f64, *value, i)).collect()
+    }
+
+    fn trigger_timestamps(frames: &[Frame<usize>]) -> Vec<f64> {
+        frames.iter().map(|frame| frame.trigger_timestamp).collect()
+    }
+
+    #[test]
+    fn level() {
+        let values = [50., 150., 100., 40., 120.];
+        let mut above = trigger(TriggerCondition::Level { level: 100., below: false }, TriggerMode::Normal, 0, 1);
+        assert_eq!(trigger_timestamps(&frames(&mut above, &values)), [1., 2., 4.]);
+        let mut below = trigger(TriggerCondition::Level { level: 100., below: true }, TriggerMode::Normal, 0, 1);
+        assert_eq!(trigger_timestamps(&frames(&mut below, &values)), [0., 3.]);
+    }
+
+    #[test]
+    fn window() {
+        //Starting inside the window isn't entering it.
+        let values = [15., 0., 10., 16., 25., 20., 5.];
+        let mut enter = trigger(TriggerCondition::Window { low: 10., high: 20., event: WindowEvent::Enter }, TriggerMode::Normal, 0, 1);
+        assert_eq!(trigger_timestamps(&frames(&mut enter, &values)), [2., 5.]);
+        let mut exit = trigger(TriggerCondition::Window { low: 10., high: 20., event: WindowEvent::Exit }, TriggerMode::Normal, 0, 1);
+        assert_eq!(trigger_timestamps(&frames(&mut exit, &values)), [1., 4., 6.]);
+    }
+
+    #[test]
+    fn pulse_width() {
+        let condition = TriggerCondition::PulseWidth { level: 100., polarity: Polarity::Positive, hysteresis: 0., min_ms: Some(2.), max_ms: Some(4.) };
+        //Pulses of 2, 1, 6 and 3 ms. The first one fires, once the signal is back below the level.
+        let values = [0., 150., 150., 0., 150., 0., 150., 150., 150., 150., 150., 150., 0., 150., 150., 150., 0.];
+        assert_eq!(trigger_timestamps(&frames(&mut trigger(condition, TriggerMode::Normal, 0, 1), &values)), [3., 16.]);
+        //A signal above the level from the start is no pulse.
+        let values = [150., 150., 0., 150., 150., 0.];
+        assert_eq!(trigger_timestamps(&frames(&mut trigger(condition, TriggerMode::Normal, 0, 1), &values)), [5.]);
+        let condition = TriggerCondition::PulseWidth { level: 100., polarity: Polarity::Negative, hysteresis: 0., min_ms: Some(2.), max_ms: None };
+        let values = [200., 50., 50., 200., 50., 200.];
+        assert_eq!(trigger_timestamps(&frames(&mut trigger(condition, TriggerMode::Normal, 0, 1), &values)), [3.]);
+    }
+
+    #[test]
+    fn single_stops_after_a_frame() {
+        let mut single = trigger(TriggerCondition::Level { level: 100., below: false }, TriggerMode::Single, 0, 2);
+        let frames = frames(&mut single, &[150.; 10]);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].samples, [0, 1]);
+        assert_eq!(single.state(), TriggerState::Stopped);
+        assert!(single.push(10., 150., 10).is_none());
+        single.arm();
+        assert_eq!(single.state(), TriggerState::Armed);
+        single.push(11., 150., 11);
+        assert_eq!(single.push(12., 150., 12).unwrap().samples, [11, 12]);
+        assert_eq!(single.frames(), 2);
+    }
+
+    #[test]
+    fn auto_takes_frames_without_a_trigger() {
+        let condition = TriggerCondition::Level { level: 1000., below: false };
+        let values = [0.; 16];
+        assert!(frames(&mut trigger(condition, TriggerMode::Normal, 0, 2), &values).is_empty());
+        let frames = frames(&mut trigger(condition, TriggerMode::Auto, 0, 2), &values);
+        //The timeout starts again with the first sample after a frame.
+        assert_eq!(trigger_timestamps(&frames), [5., 12.]);
+        assert_eq!(frames[0].samples, [5, 6]);
+        assert!(frames.iter().all(|frame| !frame.triggered));
+    }
+
+    ///The samples before a trigger point can overlap with the previous frame.
+    #[test]
+    fn pre_samples_span_consecutive_frames() {
+        let mut normal = trigger(TriggerCondition::Level { level: 100., below: false }, TriggerMode::Normal, 2, 2);
+        let frames = frames(&mut normal, &[150.; 8]);
+        assert_eq!(frames.iter().map(|frame| frame.samples.clone()).collect::<Vec<_>>(), [[0, 1, 2, 3], [2, 3, 4, 5], [4, 5, 6, 7]]);
+        assert!(frames.iter().all(|frame| frame.trigger_index == 2 && frame.triggered));
+        assert_eq!(trigger_timestamps(&frames), [2., 4., 6.]);
+    }
+}